@@ -0,0 +1,416 @@
+//! Assembles hand-written assembly into instructions, without going through the compiler.
+//!
+//! Each line contains one instruction, written the same way as the `--assembly` output of the compiler.
+//! Anything after a `;` is a comment, and blank lines are ignored.
+//!
+//! A line may start with a label, e.g. `loop:`, which names the address of the next instruction.
+//! Jump instructions can then use the label in place of an address, e.g. `JUMP loop`.
+//! It may instead start with the address of the instruction, e.g. `3: ADD`, as in the output of `--assembly` and `disasm`,
+//! which must match the position of the instruction.
+//!
+//! A file can instead hold functions to be linked with a program written in LFL, each beginning with a directive such
+//! as `.func fast_blit args=3 returns=1`. Labels and addresses are then relative to the start of each function, and
+//! `JSR` can call the other functions in the file by name.
+
+use std::{sync::Arc, collections::HashMap};
+
+use crate::{assembly::Instruction, compiler::{CompileOptions, ObjectCode, ObjectFile, ObjectFunction, SourceMap, StackUsage},
+    error_handling::{SourceFile, CompileResult, CompileErrors, FileRef, FileTaggedError}, untagged_err};
+
+// The directive that begins a function, e.g. `.func fast_blit args=3 returns=1`.
+const FUNCTION_DIRECTIVE: &str = ".func";
+
+// A use of a label that is resolved once all labels have been defined.
+struct LabelReference {
+    instruction_idx: usize,
+    jump: fn(i32) -> Instruction,
+    label: String,
+    position: FileRef
+}
+
+// A function declared with `.func`.
+struct FunctionDirective {
+    name: String,
+    name_ref: FileRef,
+    arg_count: usize,
+    returns_value: bool,
+    // Position of the arguments and return type, pointed at when a call doesn't match them.
+    signature_ref: FileRef
+}
+
+// The instructions following a `.func` directive, or those before the first directive.
+#[derive(Default)]
+struct Section {
+    function: Option<FunctionDirective>,
+    instructions: Vec<Instruction>,
+    source_map: SourceMap,
+    labels: HashMap<String, (i32, FileRef)>,
+    label_references: Vec<LabelReference>
+}
+
+pub fn assemble(source: Arc<SourceFile>) -> CompileResult<Vec<Instruction>> {
+    let (mut sections, mut errors) = parse_sections(&source);
+    if let Some(function) = sections.iter().find_map(|section| section.function.as_ref()) {
+        errors.push(error_at(function.name_ref.clone(), format!("`{FUNCTION_DIRECTIVE}` declares a function to be linked with a program written \
+            in LFL, e.g. `lflc compile main.lfl {}`", source.path)));
+    }
+
+    let mut section = sections.remove(0);
+    resolve_labels(&mut section, &HashMap::new(), &mut errors);
+
+    if errors.is_empty() {
+        Ok(section.instructions)
+    }   else {
+        Err(CompileErrors(errors))
+    }
+}
+
+// Assembles a file of functions declared with `.func` into an object, to be linked with a program written in LFL.
+// The arguments and return type of each function are used to check how it is called, but its use of the stack isn't checked,
+// and it must follow the calling convention of `options`.
+pub fn assemble_object(source: Arc<SourceFile>, options: &CompileOptions) -> CompileResult<ObjectFile> {
+    let (mut sections, mut errors) = parse_sections(&source);
+
+    let before_functions = sections.remove(0);
+    if let Some(Some(position)) = before_functions.source_map.first() {
+        errors.push(error_at(position.clone(), format!("Instructions must come after a `{FUNCTION_DIRECTIVE}` directive naming the function they belong to, \
+            e.g. `{FUNCTION_DIRECTIVE} fast_blit args=3 returns=1`")));
+    }
+
+    // Functions whose directive couldn't be read have already been reported.
+    sections.retain(|section| section.function.is_some());
+    if sections.is_empty() && errors.is_empty() {
+        return untagged_err!("{} has no functions declared with `{FUNCTION_DIRECTIVE}`, so there is nothing to link", source.path);
+    }
+
+    // Calls to the functions in the file are resolved to their index in the object.
+    let mut function_ids = HashMap::new();
+    for (idx, section) in sections.iter().enumerate() {
+        let function = section.function.as_ref().expect("Sections after the first begin with a directive");
+        if function_ids.insert(function.name.clone(), idx as i32).is_some() {
+            errors.push(error_at(function.name_ref.clone(), format!("Function `{}` is already defined in this file", function.name)));
+        }
+    }
+
+    let mut functions = Vec::new();
+    for mut section in sections {
+        resolve_labels(&mut section, &function_ids, &mut errors);
+        let function = section.function.expect("Sections after the first begin with a directive");
+
+        functions.push(ObjectFunction {
+            name: function.name,
+            arg_count: function.arg_count,
+            returns_value: function.returns_value,
+            code: Some(ObjectCode {
+                instructions: section.instructions,
+                source_map: section.source_map,
+                stack_usage: StackUsage { hand_written: true, ..StackUsage::default() },
+                inlined: Vec::new()
+            }),
+            name_ref: Some(function.name_ref),
+            argument_list_ref: Some(function.signature_ref)
+        });
+    }
+
+    if !errors.is_empty() {
+        return Err(CompileErrors(errors));
+    }
+
+    Ok(ObjectFile {
+        signal_count: options.signal_count,
+        call_convention: options.call_convention,
+        globals: Vec::new(),
+        initializers: Vec::new(),
+        initializers_source_map: Vec::new(),
+        initializers_stack_peak: 0,
+        functions,
+        uses_ticks: false,
+        pass_dumps: Vec::new()
+    })
+}
+
+// Splits a file into the instructions before the first `.func` directive, followed by those of each function.
+// Labels are found, but not yet resolved.
+fn parse_sections(source: &Arc<SourceFile>) -> (Vec<Section>, Vec<FileTaggedError>) {
+    let mut sections = vec![Section::default()];
+    let mut errors = Vec::new();
+
+    for (line_index, line) in source.text.lines().enumerate() {
+        let position_of = |text: &str| text_ref(source, line_index, line, text);
+
+        let mut code = line.split(';').next().unwrap_or("").trim();
+        if let Some(directive) = code.strip_prefix(FUNCTION_DIRECTIVE).filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace)) {
+            match parse_function_directive(directive.trim(), position_of) {
+                Ok(function) => sections.push(Section { function: Some(function), ..Section::default() }),
+                Err(error) => {
+                    errors.push(error);
+                    // The instructions are still checked, as part of a function without a name.
+                    sections.push(Section::default());
+                }
+            }
+            continue;
+        }
+
+        let section = sections.last_mut().expect("There is always a section");
+        if let Some((label, rest)) = code.split_once(':') {
+            let label = label.trim();
+            let address = section.instructions.len() as i32 + 1;
+
+            if let Ok(written_address) = label.parse::<i32>() {
+                if written_address != address {
+                    errors.push(error_at(position_of(label), format!("Address {written_address} doesn't match the position of the instruction, \
+                        which is at address {address}")));
+                }
+            }   else if !is_valid_label(label) {
+                errors.push(error_at(position_of(label), format!("Invalid label `{label}`, labels must be made up of letters, \
+                    digits and `_` and cannot start with a digit")));
+            }   else if let Some((_, existing)) = section.labels.get(label) {
+                errors.push(error_at(position_of(label), format!("Label `{label}` is already defined at {existing:?}")));
+            }   else {
+                section.labels.insert(label.to_owned(), (address, position_of(label)));
+            }
+
+            code = rest.trim();
+        }
+
+        if code.is_empty() {
+            continue;
+        }
+
+        section.source_map.push(Some(position_of(code)));
+
+        // Jump targets that aren't a number are label names, which are filled in later.
+        if let Some((mnemonic, argument)) = code.split_once(' ') {
+            let argument = argument.trim();
+            if let (Some(jump), false) = (jump_instruction(mnemonic), argument.parse::<i32>().is_ok()) {
+                section.label_references.push(LabelReference {
+                    instruction_idx: section.instructions.len(),
+                    jump,
+                    label: argument.to_owned(),
+                    position: position_of(argument)
+                });
+                section.instructions.push(jump(-1));
+                continue;
+            }
+        }
+
+        match Instruction::try_from(code) {
+            Ok(instruction) => section.instructions.push(instruction),
+            Err(err) => {
+                errors.push(error_at(position_of(code), err.to_string()));
+                section.source_map.pop();
+            }
+        }
+    }
+
+    (sections, errors)
+}
+
+// Parses the name, arguments and return type following `.func`, e.g. `fast_blit args=3 returns=1`.
+fn parse_function_directive<'a>(text: &'a str, position_of: impl Fn(&'a str) -> FileRef) -> Result<FunctionDirective, FileTaggedError> {
+    let syntax_error = |position| error_at(position, format!("Expected a function name, its number of arguments and whether it returns a value, \
+        e.g. `{FUNCTION_DIRECTIVE} fast_blit args=3 returns=1`"));
+
+    let mut parts = text.split_whitespace();
+    let name = match parts.next() {
+        Some(name) if is_valid_label(name) => name,
+        Some(name) => return Err(error_at(position_of(name), format!("Invalid function name `{name}`, names must be made up of letters, \
+            digits and `_` and cannot start with a digit"))),
+        None => return Err(syntax_error(position_of(text)))
+    };
+
+    let arguments = parts.next().and_then(|part| part.strip_prefix("args=")).and_then(|count| count.parse::<usize>().ok());
+    let returns = parts.next().and_then(|part| part.strip_prefix("returns=")).and_then(|returns| match returns {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None
+    });
+
+    match (arguments, returns, parts.next()) {
+        (Some(arg_count), Some(returns_value), None) => {
+            let signature = text[name.len()..].trim();
+            Ok(FunctionDirective {
+                name: name.to_owned(),
+                name_ref: position_of(name),
+                arg_count,
+                returns_value,
+                signature_ref: position_of(signature)
+            })
+        },
+        _ => Err(syntax_error(position_of(text)))
+    }
+}
+
+// Fills in the address of each label used by a section, or the index of the function called by a JSR.
+fn resolve_labels(section: &mut Section, function_ids: &HashMap<String, i32>, errors: &mut Vec<FileTaggedError>) {
+    for reference in section.label_references.drain(..) {
+        let target = match section.labels.get(&reference.label) {
+            Some((address, _)) => Some(*address),
+            None if (reference.jump)(0) == Instruction::JumpSubRoutine(0) => function_ids.get(&reference.label).copied(),
+            None => None
+        };
+
+        match target {
+            Some(target) => section.instructions[reference.instruction_idx] = (reference.jump)(target),
+            None => errors.push(error_at(reference.position, format!("No label named `{}` exists", reference.label)))
+        }
+    }
+}
+
+// Gets the instruction for a mnemonic whose argument is an address in the program ROM, so can be a label.
+fn jump_instruction(mnemonic: &str) -> Option<fn(i32) -> Instruction> {
+    match mnemonic {
+        "JUMP" => Some(Instruction::Jump),
+        "JMPIF" => Some(Instruction::JumpIfNonZero),
+        "JMPNIF" => Some(Instruction::JumpIfZero),
+        "JSR" => Some(Instruction::JumpSubRoutine),
+        _ => None
+    }
+}
+
+// Labels follow the same rules as identifiers in LFL.
+fn is_valid_label(label: &str) -> bool {
+    label.chars().next().is_some_and(|first| !first.is_ascii_digit())
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Creates a reference to `text`, which must be a slice of `line`.
+fn text_ref(source: &Arc<SourceFile>, line_index: usize, line: &str, text: &str) -> FileRef {
+    let byte_offset = text.as_ptr() as usize - line.as_ptr() as usize;
+    let begin_char_index = line[..byte_offset].chars().count() as u32;
+
+    FileRef {
+        file: source.clone(),
+        line_index: line_index as u32,
+        begin_char_index,
+        end_line_index: line_index as u32,
+        end_char_index: begin_char_index + text.chars().count().max(1) as u32
+    }
+}
+
+fn error_at(position: FileRef, msg: String) -> FileTaggedError {
+    FileTaggedError {
+        position: Some(position),
+        msg,
+        notes: Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::CompileOptions, testing};
+
+    fn assemble_text(text: &str) -> CompileResult<Vec<Instruction>> {
+        assemble(Arc::new(SourceFile::from_text("test.asm".to_owned(), text.to_owned())))
+    }
+
+    fn assemble_object_text(text: &str) -> CompileResult<ObjectFile> {
+        assemble_object(Arc::new(SourceFile::from_text("test.asm".to_owned(), text.to_owned())), &CompileOptions::default())
+    }
+
+    fn error_messages(err: &CompileErrors) -> Vec<&str> {
+        err.0.iter().map(|error| error.msg.as_str()).collect()
+    }
+
+    #[test]
+    fn disassembly_round_trips() {
+        let module = testing::compile("int double(x) { return x * 2; }
+            void main(n) { while n > 0 { signal_1 = double(n); n -= 1; } }", &CompileOptions::default());
+
+        // Written the same way as the `--assembly` output, with the address of each instruction.
+        let text: String = module.instructions.iter()
+            .enumerate()
+            .map(|(idx, instruction)| format!("{}: {instruction}\n", idx + 1))
+            .collect();
+        assert_eq!(assemble_text(&text).unwrap(), module.instructions);
+    }
+
+    #[test]
+    fn labels_are_resolved() {
+        let instructions = assemble_text("JUMP end ; skip the constant\nCNST 1\nend: HLT\nloop:\nJMPIF loop").unwrap();
+        assert_eq!(instructions, [Instruction::Jump(3), Instruction::Constant(1), Instruction::Halt, Instruction::JumpIfNonZero(4)]);
+    }
+
+    #[test]
+    fn mismatched_address_is_an_error() {
+        let err = assemble_text("1: CNST 1\n3: POP").unwrap_err();
+        assert_eq!(err.0.len(), 1);
+        assert!(err.0[0].msg.contains("Address 3 doesn't match"));
+    }
+
+    #[test]
+    fn undefined_label_and_unknown_instruction_are_errors() {
+        let err = assemble_text("JUMP nowhere\nFROB\n1bad: ADD").unwrap_err();
+        let messages: Vec<&str> = err.0.iter().map(|error| error.msg.as_str()).collect();
+        assert_eq!(messages.len(), 3);
+        assert!(messages.iter().any(|msg| msg.contains("No label named `nowhere`")));
+        assert!(messages.iter().any(|msg| msg.contains("Unknown instruction FROB")));
+        assert!(messages.iter().any(|msg| msg.contains("Invalid label `1bad`")));
+    }
+
+    #[test]
+    fn functions_are_assembled_into_object() {
+        let object = assemble_object_text(".func clamp args=1 returns=1 ; negative values become 0
+            LOAD 2
+            JMPNIF done
+            CNST 0
+            SAVE 3
+            done: RET
+            .func zero_signal args=0 returns=0
+            CNST 0
+            JSR clamp
+            3: SAVE -1
+            RET").unwrap();
+
+        assert_eq!(object.functions.len(), 2);
+        let clamp = &object.functions[0];
+        assert_eq!((clamp.name.as_str(), clamp.arg_count, clamp.returns_value), ("clamp", 1, true));
+        // Labels are relative to the start of the function.
+        assert_eq!(clamp.code.as_ref().unwrap().instructions[1], Instruction::JumpIfZero(5));
+
+        let zero_signal = &object.functions[1];
+        assert_eq!((zero_signal.name.as_str(), zero_signal.arg_count, zero_signal.returns_value), ("zero_signal", 0, false));
+        // Calls to other functions in the file give their index in the object.
+        assert_eq!(zero_signal.code.as_ref().unwrap().instructions[1], Instruction::JumpSubRoutine(0));
+        assert!(zero_signal.code.as_ref().unwrap().stack_usage.hand_written);
+    }
+
+    #[test]
+    fn function_directive_errors() {
+        let err = assemble_object_text("CNST 1\n.func f args=1\n.func 2g args=0 returns=0\n.func h args=0 returns=2\n.func k args=0 returns=0\n.func k args=1 returns=0").err().expect("Assembled successfully");
+        let messages = error_messages(&err);
+        assert_eq!(messages.len(), 5, "{messages:?}");
+        assert!(messages[0].starts_with("Expected a function name, its number of arguments"));
+        assert!(messages[1].starts_with("Invalid function name `2g`"));
+        assert!(messages[2].starts_with("Expected a function name"));
+        assert!(messages[3].starts_with("Instructions must come after a `.func` directive"));
+        assert_eq!(messages[4], "Function `k` is already defined in this file");
+
+        let err = assemble_object_text("; nothing here").err().expect("Assembled successfully");
+        assert!(err.0[0].msg.contains("has no functions declared with `.func`"));
+
+        // Assembling a whole program doesn't link it with anything that could call the functions.
+        let err = assemble_text(".func f args=0 returns=0\nRET").unwrap_err();
+        assert!(err.0[0].msg.starts_with("`.func` declares a function to be linked with a program written in LFL, e.g. `lflc compile main.lfl test.asm`"));
+    }
+
+    #[test]
+    fn calls_to_assembly_functions_are_checked() {
+        let assembly = ".func triple args=1 returns=1\nCNST 3\nLOAD 3\nMUL\nSAVE 4\nRET\n.func emit args=1 returns=0\nLOAD 2\nSAVE -2\nRET";
+        let options = CompileOptions::default();
+
+        let err = testing::try_compile_with_assembly("void main() { signal_1 = triple(1, 2); }", assembly, &options).err().expect("Compiled successfully");
+        assert_eq!(err.0[0].msg, "Wrong number of arguments, expected 1, got 2");
+        // The note points at the directive that declares the arguments.
+        let (note_ref, note) = &err.0[0].notes[0];
+        assert_eq!((note_ref.file.path.as_str(), note_ref.line_index), ("test.asm", 0));
+        assert_eq!(note, "`triple` is defined with these arguments");
+
+        let err = testing::try_compile_with_assembly("void main() { signal_1 = emit(1); }", assembly, &options).err().expect("Compiled successfully");
+        assert_eq!(err.0[0].msg, "Cannot use a function that does not return a value within an expression");
+
+        let err = testing::try_compile_with_assembly("int triple(x) { return x; } void main() {}", assembly, &options).err().expect("Compiled successfully");
+        assert_eq!(err.0[0].msg, "Function `triple` is defined both here and in the program");
+    }
+}
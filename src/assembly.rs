@@ -2,10 +2,11 @@ use core::fmt;
 use std::fmt::Display;
 use phf::phf_map;
 use anyhow::anyhow;
+use serde::{Serialize, Deserialize};
 
 use crate::blueprint::SignalId;
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Instruction {
     Jump(i32),
     JumpIfNonZero(i32),
@@ -33,9 +34,20 @@ pub enum Instruction {
     LessThanOrEqual,
     Pop,
     JumpSubRoutine(i32),
-    Return
+    Return,
+    // Stops the program.
+    Halt,
+    // Does nothing, e.g. to leave space for instructions to be added by hand.
+    Nop,
+    // Pushes a copy of the top value.
+    Duplicate,
+    // Replaces the top value with its negative.
+    Negate
 }
 
+// Number of bits used for the argument of a packed instruction, below the opcode.
+pub const PACKED_ARGUMENT_BITS: u32 = 24;
+
 static NO_ARG_INSTRUCTIONS: phf::Map<&'static str, Instruction> = phf_map! {
     "ADD" => Instruction::Add,
     "SUB" => Instruction::Subtract,
@@ -56,9 +68,27 @@ static NO_ARG_INSTRUCTIONS: phf::Map<&'static str, Instruction> = phf_map! {
     "GTE" => Instruction::GreaterThanOrEqual,
     "LTE" => Instruction::LessThanOrEqual,
     "POP" => Instruction::Pop,
-    "RET" => Instruction::Return
+    "RET" => Instruction::Return,
+    "HLT" => Instruction::Halt,
+    "NOP" => Instruction::Nop,
+    "DUP" => Instruction::Duplicate,
+    "NEG" => Instruction::Negate
 };
 
+// Gets the constructor of the instruction with the given mnemonic, if it takes an argument.
+fn argument_instruction(mnemonic: &str) -> Option<fn(i32) -> Instruction> {
+    Some(match mnemonic {
+        "JUMP" => Instruction::Jump,
+        "JMPIF" => Instruction::JumpIfNonZero,
+        "JMPNIF" => Instruction::JumpIfZero,
+        "SAVE" => Instruction::Save,
+        "LOAD" => Instruction::Load,
+        "CNST" => Instruction::Constant,
+        "JSR" => Instruction::JumpSubRoutine,
+        _ => return None
+    })
+}
+
 impl TryFrom<&str> for Instruction {
     type Error = anyhow::Error;
 
@@ -67,21 +97,15 @@ impl TryFrom<&str> for Instruction {
             Some(index) => {
                 let (label, arg_str) = value.split_at(index);
 
-                let parsed_arg = arg_str[1..].parse::<i32>()?;
-                if label == "JUMP" {
-                    Ok(Instruction::Jump(parsed_arg))
-                }   else if label == "JMPIF" {
-                    Ok(Instruction::JumpIfNonZero(parsed_arg))
-                }   else if label == "JMPNIF" {
-                    Ok(Instruction::JumpIfZero(parsed_arg))
-                }   else if label == "SAVE" {
-                    Ok(Instruction::Save(parsed_arg))
-                }   else if label == "LOAD" {
-                    Ok(Instruction::Load(parsed_arg))
-                }   else if label == "CNST" {
-                    Ok(Instruction::Constant(parsed_arg))
-                }   else {
-                    Err(anyhow!("Unknown instruction {value}"))
+                let instruction = match argument_instruction(label) {
+                    Some(instruction) => instruction,
+                    None => return Err(anyhow!("Unknown instruction {value}"))
+                };
+
+                let arg_str = arg_str.trim();
+                match arg_str.parse::<i32>() {
+                    Ok(parsed_arg) => Ok(instruction(parsed_arg)),
+                    Err(_) => Err(anyhow!("Invalid argument `{arg_str}` for {label}, expected an integer"))
                 }
             },
             None => match NO_ARG_INSTRUCTIONS.get(value) {
@@ -122,6 +146,10 @@ impl Display for Instruction {
             Instruction::Pop => write!(f, "POP"),
             Instruction::JumpSubRoutine(addr) => write!(f, "JSR {addr}"),
             Instruction::Return => write!(f, "RET"),
+            Instruction::Halt => write!(f, "HLT"),
+            Instruction::Nop => write!(f, "NOP"),
+            Instruction::Duplicate => write!(f, "DUP"),
+            Instruction::Negate => write!(f, "NEG"),
         }
     }
 }
@@ -156,9 +184,89 @@ impl Instruction {
             Instruction::Pop => 24,
             Instruction::JumpSubRoutine(_) => 26,
             Instruction::Return => 27,
+            Instruction::Halt => 28,
+            Instruction::Nop => 29,
+            Instruction::Duplicate => 30,
+            Instruction::Negate => 31,
         }
     }
 
+    // Gets the instruction with the given mnemonic, e.g. `ADD` or `JUMP`. Instructions that take an argument are given 0.
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Self> {
+        match NO_ARG_INSTRUCTIONS.get(mnemonic) {
+            Some(instruction) => Some(*instruction),
+            None => argument_instruction(mnemonic).map(|instruction| instruction(0))
+        }
+    }
+
+    // Gets the mnemonic used for the instruction in assembly, e.g. `ADD` or `JUMP`.
+    pub fn mnemonic(&self) -> String {
+        let text = self.to_string();
+        match text.split_once(' ') {
+            Some((mnemonic, _)) => mnemonic.to_owned(),
+            None => text
+        }
+    }
+
+    // Creates the instruction with the given opcode, the inverse of `get_opcode`.
+    // Opcodes are numbered from 1, without any gaps.
+    // The argument is ignored by instructions that do not take one.
+    pub fn from_opcode(opcode: i32, argument: i32) -> Option<Self> {
+        Some(match opcode {
+            1 => Instruction::Jump(argument),
+            2 => Instruction::JumpIfNonZero(argument),
+            3 => Instruction::Save(argument),
+            4 => Instruction::Load(argument),
+            5 => Instruction::Constant(argument),
+            25 => Instruction::JumpIfZero(argument),
+            6 => Instruction::Add,
+            7 => Instruction::Subtract,
+            8 => Instruction::Divide,
+            9 => Instruction::Multiply,
+            10 => Instruction::Power,
+            11 => Instruction::Remainder,
+            12 => Instruction::ShiftLeft,
+            13 => Instruction::ShiftRight,
+            14 => Instruction::And,
+            15 => Instruction::Or,
+            16 => Instruction::Xor,
+            17 => Instruction::Not,
+            18 => Instruction::Equal,
+            19 => Instruction::NotEqual,
+            20 => Instruction::GreaterThan,
+            21 => Instruction::LessThan,
+            22 => Instruction::GreaterThanOrEqual,
+            23 => Instruction::LessThanOrEqual,
+            24 => Instruction::Pop,
+            26 => Instruction::JumpSubRoutine(argument),
+            27 => Instruction::Return,
+            28 => Instruction::Halt,
+            29 => Instruction::Nop,
+            30 => Instruction::Duplicate,
+            31 => Instruction::Negate,
+            _ => return None
+        })
+    }
+
+    // Packs the opcode and argument into a single value, `opcode * 2^24 + argument`, with the argument stored in
+    // two's complement in the lower 24 bits. Fails if the argument doesn't fit.
+    pub fn encode_packed(&self) -> anyhow::Result<i32> {
+        let argument = self.get_argument_signal().map_or(0, |(_, argument)| argument);
+        let limit = 1 << (PACKED_ARGUMENT_BITS - 1);
+        if !(-limit..limit).contains(&argument) {
+            return Err(anyhow!("Argument {argument} does not fit in a packed instruction, which allows arguments from {} to {}", -limit, limit - 1));
+        }
+
+        Ok((self.get_opcode() << PACKED_ARGUMENT_BITS) | (argument & ((1 << PACKED_ARGUMENT_BITS) - 1)))
+    }
+
+    // Unpacks an instruction packed by `encode_packed`, or returns None if the opcode is unknown.
+    pub fn decode_packed(value: i32) -> Option<Self> {
+        // Shifting the argument to the top of the value and back extends its sign.
+        let unused_bits = 32 - PACKED_ARGUMENT_BITS;
+        Self::from_opcode(value >> PACKED_ARGUMENT_BITS, (value << unused_bits) >> unused_bits)
+    }
+
     pub fn get_argument_signal(&self) -> Option<(SignalId, i32)> {
         let address_signal = SignalId {
             r#type: "virtual".to_owned(),
@@ -181,4 +289,37 @@ impl Instruction {
             _ => None
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    // Every instruction, found from the mnemonics the assembler accepts.
+    fn every_instruction() -> Vec<Instruction> {
+        let argument_mnemonics = ["JUMP", "JMPIF", "JMPNIF", "SAVE", "LOAD", "CNST", "JSR"];
+        NO_ARG_INSTRUCTIONS.values().copied()
+            .chain(argument_mnemonics.iter().map(|mnemonic| argument_instruction(mnemonic).unwrap()(-3)))
+            .collect()
+    }
+
+    #[test]
+    fn opcodes_are_unique() {
+        let instructions = every_instruction();
+        let opcodes: HashSet<i32> = instructions.iter().map(Instruction::get_opcode).collect();
+        assert_eq!(opcodes.len(), instructions.len());
+        assert_eq!(opcodes, (1..=instructions.len() as i32).collect());
+    }
+
+    #[test]
+    fn opcodes_round_trip() {
+        for instruction in every_instruction() {
+            let argument = instruction.get_argument_signal().map_or(0, |(_, argument)| argument);
+            assert_eq!(Instruction::from_opcode(instruction.get_opcode(), argument), Some(instruction));
+        }
+
+        assert_eq!(Instruction::from_opcode(0, 0), None);
+        assert_eq!(Instruction::from_opcode(every_instruction().len() as i32 + 1, 0), None);
+    }
+}
@@ -4,6 +4,40 @@ use std::fmt::Debug;
 
 use crate::error_handling::FileRef;
 
+// A declaration at the root of a module.
+#[derive(Clone, Debug)]
+pub enum Item {
+    Function(Function),
+    Global(Global),
+    Constant(Constant)
+}
+
+// An `import` of another file, whose items are compiled along with those of the importing file.
+#[derive(Clone, Debug)]
+pub struct Import {
+    // Relative to the directory containing the importing file.
+    pub path: String,
+    pub path_ref: FileRef
+}
+
+// A variable declared with `global`, which can be accessed from every function.
+#[derive(Clone, Debug)]
+pub struct Global {
+    pub name: String,
+    pub name_ref: FileRef,
+    // Assigned to the variable before `main` is called.
+    pub initializer: Option<Expression>
+}
+
+// A constant declared with `const`, which is replaced by its value wherever it is used.
+#[derive(Clone, Debug)]
+pub struct Constant {
+    pub name: String,
+    pub name_ref: FileRef,
+    // Must only contain literals, operators and other constants declared before this one.
+    pub value: Expression,
+    pub value_ref: FileRef
+}
 
 // A function definition.
 #[derive(Clone, Debug)]
@@ -11,8 +45,15 @@ pub struct Function {
     pub name: String,
     pub name_ref: FileRef,
     pub argument_names: Vec<String>,
+    // Position of each argument name.
+    pub argument_name_refs: Vec<FileRef>,
+    // Position of the brackets around the arguments, and everything between them.
+    pub argument_list_ref: FileRef,
     pub block: Vec<Statement>,
-    pub returns_value: bool
+    pub returns_value: bool,
+    // Hash of the tokens the function was parsed from and their positions relative to its first line, used to reuse its
+    // compiled code with `--watch` until it changes.
+    pub token_hash: u64
 }
 
 // A statement within a block of code
@@ -30,9 +71,19 @@ pub enum Statement {
     },
     While {
         condition: Expression,
-        block: Vec<Statement>
+        condition_ref: FileRef,
+        block: Vec<Statement>,
+        // A `do ... while` loop, which runs the block once before checking the condition.
+        is_do_while: bool
     },
+    // A bare `{ ... }` block, which has its own scope.
+    Block(Vec<Statement>),
     Call(Call),
+    // An expression whose value is discarded, e.g. `(a && f());`. A call on its own is a `Call` statement instead.
+    Expression {
+        value: Expression,
+        value_ref: FileRef
+    },
     Return(FileRef), // Position of the return keyword
     ReturnValue {
         value: Expression,
@@ -56,6 +107,7 @@ pub struct Call {
 #[derive(Clone, Debug)]
 pub struct IfSegment {
     pub condition: Expression,
+    pub condition_ref: FileRef,
     pub block: Vec<Statement>
 }
 
@@ -64,7 +116,9 @@ pub enum Expression {
     Binary {
         left: Box<Expression>,
         right: Box<Expression>,
-        operator: BinaryOperator
+        operator: BinaryOperator,
+        operator_ref: FileRef, // Position of the operator, or the compound assignment for `+=`, etc.
+        expression_ref: FileRef // Position of the whole expression, from the start of the left operand to the end of the right.
     },
     Unary {
         value: Box<Expression>,
@@ -96,11 +150,201 @@ pub enum BinaryOperator {
     GreaterThanOrEqual,
     Remainder,
     LessThanOrEqual,
-    Power
+    Power,
+    // Logical operators only evaluate the right hand side if needed, and result in 0 or 1.
+    LogicalAnd,
+    LogicalOr
+}
+
+impl BinaryOperator {
+    // Every binary operator, in the order they are declared.
+    pub const ALL: [BinaryOperator; 19] = [
+        BinaryOperator::Add,
+        BinaryOperator::Subtract,
+        BinaryOperator::Multiply,
+        BinaryOperator::Divide,
+        BinaryOperator::And,
+        BinaryOperator::Or,
+        BinaryOperator::Xor,
+        BinaryOperator::ShiftLeft,
+        BinaryOperator::ShiftRight,
+        BinaryOperator::Equals,
+        BinaryOperator::NotEquals,
+        BinaryOperator::GreaterThan,
+        BinaryOperator::LessThan,
+        BinaryOperator::GreaterThanOrEqual,
+        BinaryOperator::Remainder,
+        BinaryOperator::LessThanOrEqual,
+        BinaryOperator::Power,
+        BinaryOperator::LogicalAnd,
+        BinaryOperator::LogicalOr
+    ];
+
+    // Gets the symbol used for the operator in source code.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Subtract => "-",
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+            BinaryOperator::And => "&",
+            BinaryOperator::Or => "|",
+            BinaryOperator::Xor => "~",
+            BinaryOperator::ShiftLeft => "<<",
+            BinaryOperator::ShiftRight => ">>",
+            BinaryOperator::Equals => "==",
+            BinaryOperator::NotEquals => "!=",
+            BinaryOperator::GreaterThan => ">",
+            BinaryOperator::LessThan => "<",
+            BinaryOperator::GreaterThanOrEqual => ">=",
+            BinaryOperator::Remainder => "%",
+            BinaryOperator::LessThanOrEqual => "<=",
+            BinaryOperator::Power => "^",
+            BinaryOperator::LogicalAnd => "&&",
+            BinaryOperator::LogicalOr => "||"
+        }
+    }
+
+    // Checks if the operator compares its operands, producing 0 or 1.
+    pub fn is_comparison(&self) -> bool {
+        matches!(self, BinaryOperator::Equals
+            | BinaryOperator::NotEquals
+            | BinaryOperator::GreaterThan
+            | BinaryOperator::LessThan
+            | BinaryOperator::GreaterThanOrEqual
+            | BinaryOperator::LessThanOrEqual)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Copy)]
 pub enum UnaryOperator {
+    // `~`, which inverts every bit.
     Not,
+    // `!`, which results in 1 if the value is 0, and 0 otherwise.
+    LogicalNot,
     Negate
-}
\ No newline at end of file
+}
+
+impl UnaryOperator {
+    // Gets the symbol used for the operator in source code.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            UnaryOperator::Not => "~",
+            UnaryOperator::LogicalNot => "!",
+            UnaryOperator::Negate => "-"
+        }
+    }
+}
+// Writes a module as an indented tree, with one line for each item, statement and expression, e.g. for `--ast`.
+// Positions are left out, so that the output only changes when the meaning of the code changes.
+pub fn dump_module(items: &[Item], imports: &[Import]) -> String {
+    let mut output = String::new();
+    for import in imports {
+        dump_line(&mut output, 0, &format!("import \"{}\"", import.path));
+    }
+
+    for item in items {
+        match item {
+            Item::Function(function) => {
+                let return_type = if function.returns_value { "int" } else { "void" };
+                dump_line(&mut output, 0, &format!("function {return_type} {}({})", function.name, function.argument_names.join(", ")));
+                dump_block(&mut output, 1, &function.block);
+            },
+            Item::Global(global) => {
+                dump_line(&mut output, 0, &format!("global {}", global.name));
+                if let Some(initializer) = &global.initializer {
+                    dump_expression(&mut output, 1, initializer);
+                }
+            },
+            Item::Constant(constant) => {
+                dump_line(&mut output, 0, &format!("const {}", constant.name));
+                dump_expression(&mut output, 1, &constant.value);
+            }
+        }
+    }
+
+    output
+}
+
+fn dump_line(output: &mut String, depth: usize, text: &str) {
+    for _ in 0..depth {
+        output.push_str("  ");
+    }
+
+    output.push_str(text);
+    output.push('\n');
+}
+
+fn dump_block(output: &mut String, depth: usize, block: &[Statement]) {
+    for statement in block {
+        dump_statement(output, depth, statement);
+    }
+}
+
+fn dump_statement(output: &mut String, depth: usize, statement: &Statement) {
+    match statement {
+        Statement::Assignment { variable_name, value, .. } => {
+            dump_line(output, depth, &format!("assign {variable_name}"));
+            dump_expression(output, depth + 1, value);
+        },
+        Statement::If { segments, r#else } => {
+            for (idx, segment) in segments.iter().enumerate() {
+                dump_line(output, depth, if idx == 0 { "if" } else { "else if" });
+                dump_expression(output, depth + 1, &segment.condition);
+                dump_line(output, depth, "then");
+                dump_block(output, depth + 1, &segment.block);
+            }
+
+            if let Some(else_block) = r#else {
+                dump_line(output, depth, "else");
+                dump_block(output, depth + 1, else_block);
+            }
+        },
+        Statement::While { condition, block, is_do_while, .. } => {
+            dump_line(output, depth, if *is_do_while { "do while" } else { "while" });
+            dump_expression(output, depth + 1, condition);
+            dump_line(output, depth, "loop");
+            dump_block(output, depth + 1, block);
+        },
+        Statement::Block(block) => {
+            dump_line(output, depth, "block");
+            dump_block(output, depth + 1, block);
+        },
+        Statement::Call(call) => dump_call(output, depth, call),
+        Statement::Expression { value, .. } => {
+            dump_line(output, depth, "expression");
+            dump_expression(output, depth + 1, value);
+        },
+        Statement::Return(_) => dump_line(output, depth, "return"),
+        Statement::ReturnValue { value, .. } => {
+            dump_line(output, depth, "return");
+            dump_expression(output, depth + 1, value);
+        },
+        Statement::Continue(_) => dump_line(output, depth, "continue"),
+        Statement::Break(_) => dump_line(output, depth, "break")
+    }
+}
+
+fn dump_call(output: &mut String, depth: usize, call: &Call) {
+    dump_line(output, depth, &format!("call {}", call.function_name));
+    for argument in &call.arguments {
+        dump_expression(output, depth + 1, argument);
+    }
+}
+
+fn dump_expression(output: &mut String, depth: usize, expression: &Expression) {
+    match expression {
+        Expression::Binary { left, right, operator, .. } => {
+            dump_line(output, depth, &format!("binary {}", operator.symbol()));
+            dump_expression(output, depth + 1, left);
+            dump_expression(output, depth + 1, right);
+        },
+        Expression::Unary { value, operator } => {
+            dump_line(output, depth, &format!("unary {}", operator.symbol()));
+            dump_expression(output, depth + 1, value);
+        },
+        Expression::Call(call) => dump_call(output, depth, call),
+        Expression::Variable { name, .. } => dump_line(output, depth, &format!("variable {name}")),
+        Expression::Literal(value) => dump_line(output, depth, &format!("literal {value}"))
+    }
+}
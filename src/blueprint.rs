@@ -1,31 +1,139 @@
 //! Types/methods for manipulating factorio blueprints.
 
-use std::io::Write;
+use std::{io::Write, collections::{HashMap, HashSet}};
 
-use base64::Engine;
+use anyhow::{anyhow, Context};
+use base64::{Engine, alphabet, engine::{GeneralPurpose, GeneralPurposeConfig, DecodePaddingMode}};
 use deflate::{Compression, write::ZlibEncoder};
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
 
-use crate::assembly::Instruction;
+use crate::assembly::{Instruction, PACKED_ARGUMENT_BITS};
+
+// The version of factorio that a blueprint is generated for.
+// The way that wires and combinator settings are stored changed in 2.0.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum BlueprintFormat {
+    #[default]
+    V1_1,
+    V2_0
+}
+
+impl BlueprintFormat {
+    // Gets the format with the name used on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "1.1" => Some(Self::V1_1),
+            "2.0" => Some(Self::V2_0),
+            _ => None
+        }
+    }
+
+    // The major version of the game that reads blueprints in this format.
+    pub fn major_version(self) -> u16 {
+        match self {
+            Self::V1_1 => 1,
+            Self::V2_0 => 2
+        }
+    }
+
+    // The game version stored in blueprints in this format, unless another is given.
+    pub fn default_version(self) -> u64 {
+        match self {
+            Self::V1_1 => factorio_version(1, 1, 110),
+            Self::V2_0 => factorio_version(2, 0, 0)
+        }
+    }
+}
+
+// Packs a game version into the number stored in blueprints, where the major, minor, patch and build numbers
+// each take 16 bits. The build number is left as 0, as in blueprints exported from the game.
+pub const fn factorio_version(major: u16, minor: u16, patch: u16) -> u64 {
+    (major as u64) << 48 | (minor as u64) << 32 | (patch as u64) << 16
+}
+
+// Parses a game version written as `major.minor.patch`, e.g. `1.1.110`, into the number stored in blueprints.
+pub fn parse_factorio_version(text: &str) -> Option<u64> {
+    let mut parts = text.split('.').map(|part| part.parse::<u16>().ok());
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Some(major)), Some(Some(minor)), Some(Some(patch)), None) => Some(factorio_version(major, minor, patch)),
+        _ => None
+    }
+}
+
+// Gets the major version of the game from the number stored in a blueprint.
+pub fn major_version(version: u64) -> u16 {
+    (version >> 48) as u16
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct SerializedBlueprint {
     pub blueprint: Blueprint
 }
 
+// A blueprint book, which holds several blueprints that can be imported with one string.
+#[derive(Serialize, Deserialize)]
+pub struct SerializedBlueprintBook {
+    pub blueprint_book: BlueprintBook
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BlueprintBook {
+    pub item: String,
+    #[serde(default)]
+    pub label: String,
+    #[serde(default)]
+    pub blueprints: Vec<BookEntry>,
+    // The index of the blueprint selected when the book is used.
+    #[serde(default)]
+    pub active_index: u32,
+    pub version: u64
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BookEntry {
+    pub index: u32,
+    pub blueprint: Blueprint
+}
+
+// Fields of blueprints exported from the game that aren't needed here are ignored when loading.
+// Fields that the game leaves out when they have their default value are given the default.
 #[derive(Serialize, Deserialize)]
 pub struct Blueprint {
     pub item: String,
+    #[serde(default)]
     pub label: String,
+    // Shown below the label in the blueprint library.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    // Shown on the blueprint in the library and in books.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icons: Option<Vec<Icon>>,
+    #[serde(default)]
     pub entities: Vec<Entity>,
-    pub version: u32
+    // Only used in 2.0, where wires are no longer stored on the entities.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wires: Option<Vec<Wire>>,
+    pub version: u64
 }
 
+// A wire in a 2.0 blueprint, made up of the entity number and connector of each end.
+pub type Wire = [u32; 4];
+
+// Wire connectors in 2.0 blueprints.
+const RED_INPUT_CONNECTOR: u32 = 1;
+const GREEN_INPUT_CONNECTOR: u32 = 2;
+const RED_OUTPUT_CONNECTOR: u32 = 3;
+// Constant combinators only have one pair of connectors.
+const GREEN_CONNECTOR: u32 = 2;
+// Electric poles have one red and one green connector, for the circuit network.
+const POLE_RED_CONNECTOR: u32 = 1;
+
 #[derive(Serialize, Deserialize)]
 pub struct Entity {
     pub entity_number: u32,
     pub name: String,
     pub position: Position,
+    #[serde(default)]
     pub direction: u32,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub connections: Option<Connection>,
@@ -45,23 +153,45 @@ pub struct Connection {
 
 #[derive(Serialize, Deserialize)]
 pub struct ControlBehaviour {
-    //arithmetic_conditions: Option<ArithmeticCombinatorParameters>,
-    pub decider_conditions: Option<DeciderCombinatorParameters>,
-    pub filters: Option<Vec<ConstantCombinatorParameter>>
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arithmetic_conditions: Option<ArithmeticCombinatorParameters>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decider_conditions: Option<DeciderConditions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filters: Option<Vec<ConstantCombinatorParameter>>,
+    // Replaces `filters` in 2.0.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sections: Option<ConstantCombinatorSections>
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DeciderConditions {
+    // 2.0 blueprints are tried first, since all of the fields of a 1.1 blueprint can be left out.
+    V2_0(DeciderCombinatorParametersV2),
+    V1_1(DeciderCombinatorParameters)
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ConnectionPoint {
+    #[serde(default)]
     pub red: Vec<ConnectionData>,
+    #[serde(default)]
     pub green: Vec<ConnectionData>
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ConnectionData {
     pub entity_id: u32,
+    // Left out by the game for entities with only one connection point, e.g. electric poles.
+    #[serde(default = "default_circuit_id")]
     pub circuit_id: u32
 }
 
+fn default_circuit_id() -> u32 {
+    1
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Position {
     pub x: f32,
@@ -70,6 +200,7 @@ pub struct Position {
 
 #[derive(Serialize, Deserialize)]
 pub struct DeciderCombinatorParameters {
+    #[serde(default = "default_comparator")]
     pub comparator: char,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub first_signal: Option<SignalId>,
@@ -79,9 +210,94 @@ pub struct DeciderCombinatorParameters {
     pub constant: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub output_signal: Option<SignalId>,
+    #[serde(default = "default_copy_count_from_input")]
     pub copy_count_from_input: bool
 }
 
+fn default_comparator() -> char {
+    '<'
+}
+
+fn default_copy_count_from_input() -> bool {
+    true
+}
+
+// The same in 1.1 and 2.0, although 2.0 adds settings for which wires each signal is read from, which are ignored.
+#[derive(Serialize, Deserialize)]
+pub struct ArithmeticCombinatorParameters {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_signal: Option<SignalId>,
+    // Used in place of `first_signal` for a constant left operand.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_constant: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub second_signal: Option<SignalId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub second_constant: Option<i32>,
+    // The symbol of the operator, e.g. `+` or `<<`, or `AND`, `OR` and `XOR` for the bitwise operators.
+    #[serde(default = "default_operation")]
+    pub operation: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_signal: Option<SignalId>
+}
+
+fn default_operation() -> String {
+    "*".to_owned()
+}
+
+// In 2.0, a decider combinator can combine multiple conditions, and output multiple signals.
+#[derive(Serialize, Deserialize)]
+pub struct DeciderCombinatorParametersV2 {
+    pub conditions: Vec<DeciderCondition>,
+    #[serde(default)]
+    pub outputs: Vec<DeciderOutput>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeciderCondition {
+    #[serde(default = "default_comparator")]
+    pub comparator: char,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_signal: Option<SignalId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub second_signal: Option<SignalId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub constant: Option<i32>,
+    // How the condition is combined with the one before it, `and` or `or`. The game leaves it out for `or`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compare_type: Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeciderOutput {
+    pub signal: SignalId,
+    #[serde(default = "default_copy_count_from_input")]
+    pub copy_count_from_input: bool
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConstantCombinatorSections {
+    pub sections: Vec<ConstantCombinatorSection>
+}
+
+// A group of signals output by a constant combinator in 2.0.
+#[derive(Serialize, Deserialize)]
+pub struct ConstantCombinatorSection {
+    pub index: u32,
+    #[serde(default)]
+    pub filters: Vec<ConstantCombinatorFilter>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConstantCombinatorFilter {
+    pub index: u32,
+    pub r#type: String,
+    pub name: String,
+    pub quality: String,
+    pub comparator: char,
+    pub count: i32
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ConstantCombinatorParameter {
     pub signal: SignalId,
@@ -91,28 +307,357 @@ pub struct ConstantCombinatorParameter {
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct SignalId {
+    // Left out of 2.0 blueprints for items.
+    #[serde(default = "default_signal_type")]
     pub r#type: String,
     pub name: String
 }
 
+fn default_signal_type() -> String {
+    "item".to_owned()
+}
+
+impl SignalId {
+    // A virtual signal, e.g. `signal-A`.
+    pub fn virtual_signal(name: &str) -> Self {
+        Self {
+            r#type: "virtual".to_owned(),
+            name: name.to_owned()
+        }
+    }
+
+    // The signal of an item, e.g. `constant-combinator`.
+    pub fn item(name: &str) -> Self {
+        Self {
+            r#type: "item".to_owned(),
+            name: name.to_owned()
+        }
+    }
+}
+
+// One of up to four signals shown as the icon of a blueprint, with the first at index 1.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Icon {
+    pub signal: SignalId,
+    pub index: u32
+}
+
+// How much a blueprint string is compressed, trading the time taken for its length.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum CompressionLevel {
+    Fast,
+    #[default]
+    Best
+}
+
+impl CompressionLevel {
+    // Gets the level with the name used on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fast" => Some(Self::Fast),
+            "best" => Some(Self::Best),
+            _ => None
+        }
+    }
+}
+
+// How the JSON of a blueprint is written into a blueprint string.
+#[derive(Clone, Copy, Default)]
+pub struct SaveOptions {
+    // Indents the JSON, which makes the string longer, but easier to read once decoded.
+    pub pretty: bool,
+    pub compression: CompressionLevel
+}
+
+// Creates a string that can be imported into factorio, containing a blueprint or blueprint book.
+fn encode_string(value: &impl Serialize, options: SaveOptions) -> anyhow::Result<String> {
+    let json = if options.pretty {
+        serde_json::to_string_pretty(value)
+    }   else {
+        serde_json::to_string(value)
+    }.context("Failed to serialize blueprint")?;
+
+    let compression = match options.compression {
+        CompressionLevel::Fast => Compression::Fast,
+        CompressionLevel::Best => Compression::Best
+    };
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), compression);
+    encoder.write_all(json.as_bytes())
+        .and_then(|_| encoder.finish())
+        .map(|compressed_data| format!("0{}", base64::engine::general_purpose::STANDARD_NO_PAD.encode(compressed_data)))
+        .context("Failed to compress blueprint")
+}
+
+// Reads a string created by `encode_string`, or exported from the game.
+fn decode_string<T: DeserializeOwned>(s: &str) -> anyhow::Result<T> {
+    // The first character is the version of the blueprint string format.
+    let encoded = match s.trim().strip_prefix('0') {
+        Some(encoded) => encoded,
+        None => return Err(anyhow!("Unsupported blueprint string version, expected version 0"))
+    };
+
+    // Strings exported from the game are padded, whereas those created by `encode_string` are not.
+    let engine = GeneralPurpose::new(&alphabet::STANDARD,
+        GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent));
+    let compressed_data = engine.decode(encoded)
+        .context("Blueprint string is not valid base64")?;
+
+    let bytes = inflate::inflate_bytes_zlib(&compressed_data)
+        .map_err(|err| anyhow!("Failed to decompress blueprint: {err}"))?;
+
+    serde_json::from_slice(&bytes)
+        .context("Failed to parse blueprint")
+}
+
 impl SerializedBlueprint {
-    pub fn save(&self) -> String {
-        let bytes = serde_json::to_string_pretty(self)
-            .expect("Failed to serialize blueprint");
-        
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::Best);
-        encoder.write_all(&bytes.as_bytes()).unwrap();
-        let compressed_data = encoder.finish().unwrap();
-    
-        let encoded = base64::engine::general_purpose::STANDARD_NO_PAD.encode(compressed_data);
+    // Creates a blueprint string with compact JSON and the best compression.
+    pub fn save(&self) -> anyhow::Result<String> {
+        self.save_with(SaveOptions::default())
+    }
+
+    pub fn save_with(&self, options: SaveOptions) -> anyhow::Result<String> {
+        encode_string(self, options)
+    }
+
+    // Loads a blueprint string created by `save`, or exported from the game.
+    pub fn load(s: &str) -> anyhow::Result<Self> {
+        decode_string(s)
+    }
+}
+
+impl SerializedBlueprintBook {
+    // Creates a blueprint book string with compact JSON and the best compression.
+    pub fn save(&self) -> anyhow::Result<String> {
+        self.save_with(SaveOptions::default())
+    }
+
+    pub fn save_with(&self, options: SaveOptions) -> anyhow::Result<String> {
+        encode_string(self, options)
+    }
+
+    // Loads a blueprint book string created by `save`, or exported from the game.
+    pub fn load(s: &str) -> anyhow::Result<Self> {
+        decode_string(s)
+    }
+}
+
+// Creates a blueprint book containing the given blueprints, in order, with the first selected.
+pub fn generate_book(label: &str, blueprints: Vec<Blueprint>, format: BlueprintFormat) -> BlueprintBook {
+    BlueprintBook {
+        item: "blueprint-book".to_owned(),
+        label: label.to_owned(),
+        blueprints: blueprints.into_iter()
+            .enumerate()
+            .map(|(idx, blueprint)| BookEntry { index: idx as u32, blueprint })
+            .collect(),
+        active_index: 0,
+        version: format.default_version()
+    }
+}
+
+// How each instruction is stored in its constant combinator.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum InstructionEncoding {
+    // The opcode in the `O` signal, and the argument in the `A` or `D` signal.
+    #[default]
+    TwoSignal,
+    // The opcode and argument packed into the `O` signal, as `opcode * 2^24 + argument`.
+    Packed
+}
+
+impl InstructionEncoding {
+    // Gets the encoding with the name used on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "two-signal" => Some(Self::TwoSignal),
+            "packed" => Some(Self::Packed),
+            _ => None
+        }
+    }
+}
+
+// How the combinators of a program ROM are arranged, and how the instructions are stored in them.
+#[derive(Clone, Copy)]
+pub struct RomLayout {
+    // The number of instructions in each column, after which a new column is started to the right.
+    pub column_height: u32,
+    // The distance between the columns, in tiles.
+    // Must be within `COLUMN_STRIDES`, so that there is room for the relay poles between them and they can reach the next column.
+    pub column_stride: u32,
+    pub encoding: InstructionEncoding,
+    // Splits the ROM into banks, each in its own column, which replaces `column_height`.
+    pub banks: Option<RomBanks>
+}
+
+impl Default for RomLayout {
+    fn default() -> Self {
+        Self {
+            column_height: 64,
+            column_stride: *COLUMN_STRIDES.start(),
+            encoding: InstructionEncoding::default(),
+            banks: None
+        }
+    }
+}
+
+// The banks of a ROM that is too long for the computer to address directly.
+// The addresses in each bank restart at 1, so the instruction at absolute address `addr` is at address
+// `(addr - 1) % size + 1` of bank `(addr - 1) / size`. The computer selects a bank by outputting its number
+// in `BANK_SIGNAL` alongside the address in the `P` signal.
+#[derive(Clone, Copy)]
+pub struct RomBanks {
+    // The number of instructions in each bank.
+    pub size: u32,
+    pub select: BankSelect
+}
+
+// The signal holding the number of the selected bank, from 0.
+// Each bank is marked with a constant combinator containing its number in this signal, below its first instruction.
+pub const BANK_SIGNAL: &str = "signal-K";
+
+// How the decider combinators of a bank only output their instruction while the bank is selected.
+#[derive(Clone, Copy, PartialEq)]
+pub enum BankSelect {
+    // A second condition on each decider combinator, checking the bank signal. Only possible in 2.0, since decider
+    // combinators in 1.1 have a single condition.
+    Condition,
+    // A decider combinator below each bank, which only passes the address on to the bank while it is selected.
+    // The inputs of these combinators are chained instead of those of the instructions, and the address takes an
+    // extra tick to reach the instructions.
+    Decider
+}
+
+impl BankSelect {
+    // Gets the method with the name used on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "condition" => Some(Self::Condition),
+            "decider" => Some(Self::Decider),
+            _ => None
+        }
+    }
 
-        return format!("0{encoded}");
+    // The method used unless another is given: a second condition where the format supports it.
+    pub fn default_for(format: BlueprintFormat) -> Self {
+        match format {
+            BlueprintFormat::V1_1 => Self::Decider,
+            BlueprintFormat::V2_0 => Self::Condition
+        }
+    }
+}
+
+// The range of distances between columns that the relay poles can connect.
+pub const COLUMN_STRIDES: std::ops::RangeInclusive<u32> = 6..=10;
+
+// The distance between relay poles, which is within the wire reach of medium electric poles and combinators.
+const RELAY_SPACING: f32 = 8.0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum WireColor {
+    Red,
+    Green
+}
+
+// One end of a wire.
+#[derive(Clone, Copy)]
+struct WireEnd {
+    entity_number: u32,
+    // The circuit in 1.1 blueprints.
+    circuit_id: u32,
+    // The red connector in 2.0 blueprints, which is always followed by the green connector.
+    connector: u32
+}
+
+impl WireEnd {
+    fn decider_input(entity_number: u32) -> Self {
+        Self { entity_number, circuit_id: 1, connector: RED_INPUT_CONNECTOR }
+    }
+
+    fn decider_output(entity_number: u32) -> Self {
+        Self { entity_number, circuit_id: 2, connector: RED_OUTPUT_CONNECTOR }
+    }
+
+    fn pole(entity_number: u32) -> Self {
+        Self { entity_number, circuit_id: 1, connector: POLE_RED_CONNECTOR }
+    }
+}
+
+// Connects `to` to the earlier entity `from` with a wire of the given color.
+// In 1.1 the wire is stored on the later entity, so the returned point must be added to its connections.
+fn connect(from: WireEnd, to: WireEnd, color: WireColor, format: BlueprintFormat, wires: &mut Vec<Wire>) -> Option<ConnectionPoint> {
+    match format {
+        BlueprintFormat::V1_1 => {
+            let data = vec![ConnectionData { entity_id: from.entity_number, circuit_id: from.circuit_id }];
+            Some(match color {
+                WireColor::Red => ConnectionPoint { red: data, green: vec![] },
+                WireColor::Green => ConnectionPoint { red: vec![], green: data }
+            })
+        },
+        BlueprintFormat::V2_0 => {
+            let offset = match color {
+                WireColor::Red => 0,
+                WireColor::Green => 1
+            };
+            wires.push([from.entity_number, from.connector + offset, to.entity_number, to.connector + offset]);
+            None
+        }
+    }
+}
+
+// Connects each pair of wire ends, where the second end of each pair is on the same entity.
+// Returns the connections to store on that entity in 1.1, where they are grouped by circuit.
+fn connect_entity(ends: &[(WireEnd, WireEnd, WireColor)], format: BlueprintFormat, wires: &mut Vec<Wire>) -> Option<Connection> {
+    let mut connection = Connection { a: None, b: None };
+    for &(from, to, color) in ends {
+        let point = match connect(from, to, color, format, wires) {
+            Some(point) => point,
+            None => continue
+        };
+
+        let existing = if to.circuit_id == 1 { &mut connection.a } else { &mut connection.b };
+        match existing {
+            Some(existing) => {
+                existing.red.extend(point.red);
+                existing.green.extend(point.green);
+            },
+            None => *existing = Some(point)
+        }
+    }
+
+    (connection.a.is_some() || connection.b.is_some()).then_some(connection)
+}
+
+// Adds a line of poles carrying a red wire from `from`, beginning at `x, top_y` and going down to the bottom of the ROM.
+// Returns the last pole, which is in reach of the bottom of the next column.
+fn add_relay_poles(from: WireEnd, x: f32, top_y: f32, format: BlueprintFormat, entities: &mut Vec<Entity>, wires: &mut Vec<Wire>) -> WireEnd {
+    let mut previous = from;
+    let mut y = top_y;
+    loop {
+        let pole = WireEnd::pole((entities.len() + 1) as u32);
+        let connection = connect(previous, pole, WireColor::Red, format, wires);
+        entities.push(Entity {
+            entity_number: pole.entity_number,
+            name: "medium-electric-pole".to_owned(),
+            position: Position { x, y },
+            direction: 0,
+            connections: connection.map(|point| Connection { a: Some(point), b: None }),
+            control_behavior: None
+        });
+        previous = pole;
+
+        if y >= 0.0 {
+            break previous;
+        }
+        y = (y + RELAY_SPACING).min(0.0);
     }
 }
 
 // Generates a blueprint containing a program ROM with the given instructions.
-pub fn generate_rom_blueprint(instructions: &[Instruction]) -> Blueprint {
+// Fails if an instruction cannot be stored with the encoding of the layout.
+pub fn generate_rom_blueprint(instructions: &[Instruction], format: BlueprintFormat, layout: RomLayout) -> anyhow::Result<Blueprint> {
     let mut entities = Vec::new();
+    let mut wires = Vec::new();
 
     let program_addr_signal = SignalId {
         r#type: "virtual".to_owned(),
@@ -129,79 +674,840 @@ pub fn generate_rom_blueprint(instructions: &[Instruction]) -> Blueprint {
         name: "signal-O".to_owned(),
     };
 
+    // 2.0 has 16 directions rather than 8.
+    let direction_scale = match format {
+        BlueprintFormat::V1_1 => 1,
+        BlueprintFormat::V2_0 => 2
+    };
+
+    let banks = layout.banks;
+    if banks.is_some_and(|banks| banks.select == BankSelect::Condition) && format == BlueprintFormat::V1_1 {
+        return Err(anyhow!("Banks can only be selected with a second condition in 2.0 blueprints"));
+    }
+
+    let column_height = match banks {
+        Some(banks) => banks.size,
+        None => layout.column_height
+    };
+    let bank_signal = SignalId::virtual_signal(BANK_SIGNAL);
+
+    // The inputs of the decider combinators are all on one red wire, and the outputs on another,
+    // which each run through every decider combinator in order.
+    let mut previous_input: Option<WireEnd> = None;
+    let mut previous_output: Option<WireEnd> = None;
+    // The input of the bank select combinator of the previous bank, when banks are selected by a decider combinator.
+    let mut previous_bank_select: Option<WireEnd> = None;
+
     for (idx, instruction) in instructions.iter().enumerate() {
+        let column = idx as u32 / column_height;
+        let row = idx as u32 % column_height;
+        let x = (column * layout.column_stride) as f32;
+        let y = -(row as f32);
+
+        // Each bank is a column, with addresses beginning at 1 again.
+        let address = match banks {
+            Some(_) => row as i32 + 1,
+            None => idx as i32 + 1 // First instruction is index 1
+        };
+
+        if row == 0 {
+            // The top of the previous column is too far from the bottom of this one to wire directly, so each wire is carried
+            // down by a line of poles to the right of the previous column.
+            let top_y = -((column_height - 1) as f32);
+            let column_x = x - layout.column_stride as f32;
+            // When banks are selected by a decider combinator, the inputs of each bank are instead connected to its own.
+            let select_by_decider = banks.is_some_and(|banks| banks.select == BankSelect::Decider);
+            previous_input = previous_input.filter(|_| !select_by_decider)
+                .map(|input| add_relay_poles(input, column_x + 2.0, top_y, format, &mut entities, &mut wires));
+            previous_output = previous_output.map(|output| add_relay_poles(output, column_x + 3.0, top_y, format, &mut entities, &mut wires));
+
+            if let Some(banks) = banks {
+                entities.push(Entity {
+                    entity_number: (entities.len() + 1) as u32,
+                    name: "constant-combinator".to_owned(),
+                    position: Position { x: x - 2.0, y: 1.0 },
+                    direction: direction_scale,
+                    connections: None,
+                    control_behavior: Some(constant_combinator_behaviour(vec![(bank_signal.clone(), column as i32)], format))
+                });
+
+                if banks.select == BankSelect::Decider {
+                    // The bank select combinators are all below the bottom of the ROM, so only one pole is needed to
+                    // reach the next one.
+                    let from = previous_bank_select.map(|input| add_relay_poles(input, column_x + 2.0, 1.0, format, &mut entities, &mut wires));
+
+                    let select_number = (entities.len() + 1) as u32;
+                    let select_input = WireEnd::decider_input(select_number);
+                    entities.push(Entity {
+                        entity_number: select_number,
+                        name: "decider-combinator".to_owned(),
+                        position: Position { x, y: 1.0 },
+                        direction: 2 * direction_scale,
+                        connections: from.and_then(|from| connect_entity(&[(from, select_input, WireColor::Red)], format, &mut wires)),
+                        control_behavior: Some(ControlBehaviour {
+                            arithmetic_conditions: None,
+                            decider_conditions: Some(compare_with_constant(&bank_signal, '=', column as i32, &program_addr_signal, format)),
+                            filters: None,
+                            sections: None
+                        })
+                    });
+
+                    // The address only reaches the instructions of this bank from its bank select combinator.
+                    previous_bank_select = Some(select_input);
+                    previous_input = Some(WireEnd::decider_output(select_number));
+                }
+            }
+        }
+
+        let decider_number = (entities.len() + 1) as u32;
+        let input = WireEnd::decider_input(decider_number);
+        let output = WireEnd::decider_output(decider_number);
+
+        // Each decider combinator is connected to the previous one, on both the input and output side.
+        let ends: Vec<_> = [
+            previous_input.map(|previous_input| (previous_input, input, WireColor::Red)),
+            previous_output.map(|previous_output| (previous_output, output, WireColor::Red))
+        ].into_iter().flatten().collect();
+        let connections = connect_entity(&ends, format, &mut wires);
+        previous_input = Some(input);
+        previous_output = Some(output);
+
+        let mut decider_conditions = compare_with_constant(&program_addr_signal, '=', address, &all_signal, format);
+        if let (Some(BankSelect::Condition), DeciderConditions::V2_0(parameters)) = (banks.map(|banks| banks.select), &mut decider_conditions) {
+            parameters.conditions.push(DeciderCondition {
+                comparator: '=',
+                first_signal: Some(bank_signal.clone()),
+                second_signal: None,
+                constant: Some(column as i32),
+                compare_type: Some("and".to_owned())
+            });
+        }
+
         entities.push(Entity {
-            entity_number: (entities.len() + 1) as u32,
+            entity_number: decider_number,
             name: "decider-combinator".to_owned(),
-            position: Position { x: 0.0, y: -(idx as f32) },
-            direction: 2,
-            connections: if entities.len() == 0 {
-                None
-            } else {
-                Some(Connection {
-                    b: Some(ConnectionPoint {
-                        red: vec![ConnectionData { entity_id: (entities.len() - 1) as u32, circuit_id: 2 }],
-                        green: vec![]
-                    }),
-                    a: Some(ConnectionPoint {
-                        red: vec![ConnectionData { entity_id: (entities.len() - 1) as u32, circuit_id: 1 }],
-                        green: vec![]
-                    }),
-                })
-            },
+            position: Position { x, y },
+            direction: 2 * direction_scale,
+            connections,
             control_behavior: Some(ControlBehaviour {
-                decider_conditions: Some(DeciderCombinatorParameters {
-                    comparator: '=',
-                    first_signal: Some(program_addr_signal.clone()),
-                    second_signal: None,
-                    constant: Some((idx + 1) as i32), // First instruction is index 1
-                    output_signal: Some(all_signal.clone()),
-                    copy_count_from_input: true,
-                }),
+                arithmetic_conditions: None,
+                decider_conditions: Some(decider_conditions),
                 filters: None,
+                sections: None
             })
         });
 
-        let mut filters = vec![
-            ConstantCombinatorParameter {
-                signal: opcode_signal.clone(),
-                count: instruction.get_opcode(),
-                index: 1
+        let signals = match layout.encoding {
+            InstructionEncoding::TwoSignal => {
+                let mut signals = vec![(opcode_signal.clone(), instruction.get_opcode())];
+                if let Some(argument) = instruction.get_argument_signal() {
+                    signals.push(argument);
+                }
+                signals
+            },
+            InstructionEncoding::Packed => {
+                let packed = instruction.encode_packed()
+                    .with_context(|| format!("Failed to pack instruction {} ({instruction})", idx + 1))?;
+                vec![(opcode_signal.clone(), packed)]
             }
-        ];
-
-        match instruction.get_argument_signal() {
-            Some((signal, count)) => filters.push(ConstantCombinatorParameter {
-                signal,
-                count,
-                index: 2
-            }),
-            None => {}
         };
 
-        entities.push(Entity {
-            entity_number: (entities.len() + 1) as u32,
-            name: "constant-combinator".to_owned(),
-            position: Position { x: -2.0, y: -(idx as f32) },
-            direction: 1,
-            connections: Some(Connection {
+        let constant_number = (entities.len() + 1) as u32;
+
+        // The constant combinator is connected to the input of its decider combinator.
+        let connections = match format {
+            BlueprintFormat::V1_1 => Some(Connection {
                 b: None,
                 a: Some(ConnectionPoint {
-                    green: vec![ConnectionData { entity_id: (entities.len()) as u32, circuit_id: 1 }],
+                    green: vec![ConnectionData { entity_id: decider_number, circuit_id: 1 }],
                     red: vec![]
                 }),
             }),
-            control_behavior: Some(ControlBehaviour {
-                decider_conditions: None,
-                filters: Some(filters),
-            })
+            BlueprintFormat::V2_0 => {
+                wires.push([constant_number, GREEN_CONNECTOR, decider_number, GREEN_INPUT_CONNECTOR]);
+                None
+            }
+        };
+
+        entities.push(Entity {
+            entity_number: constant_number,
+            name: "constant-combinator".to_owned(),
+            position: Position { x: x - 2.0, y },
+            direction: direction_scale,
+            connections,
+            control_behavior: Some(constant_combinator_behaviour(signals, format))
         });
     }
 
-    Blueprint {
+    Ok(Blueprint {
         item: "blueprint".to_string(),
         label: "Program".to_string(),
+        description: None,
+        icons: Some(vec![Icon { signal: SignalId::item("constant-combinator"), index: 1 }]),
+        entities,
+        wires: match format {
+            BlueprintFormat::V1_1 => None,
+            BlueprintFormat::V2_0 => Some(wires)
+        },
+        version: format.default_version(),
+    })
+}
+
+// The settings of a constant combinator that outputs the given signals.
+fn constant_combinator_behaviour(signals: Vec<(SignalId, i32)>, format: BlueprintFormat) -> ControlBehaviour {
+    match format {
+        BlueprintFormat::V1_1 => ControlBehaviour {
+            arithmetic_conditions: None,
+            decider_conditions: None,
+            filters: Some(signals.into_iter().enumerate().map(|(idx, (signal, count))| ConstantCombinatorParameter {
+                signal,
+                count,
+                index: idx as u32 + 1
+            }).collect()),
+            sections: None
+        },
+        BlueprintFormat::V2_0 => ControlBehaviour {
+            arithmetic_conditions: None,
+            decider_conditions: None,
+            filters: None,
+            sections: Some(ConstantCombinatorSections {
+                sections: vec![ConstantCombinatorSection {
+                    index: 1,
+                    filters: signals.into_iter().enumerate().map(|(idx, (signal, count))| ConstantCombinatorFilter {
+                        index: idx as u32 + 1,
+                        r#type: signal.r#type,
+                        name: signal.name,
+                        quality: "normal".to_owned(),
+                        comparator: '=',
+                        count
+                    }).collect()
+                }]
+            })
+        }
+    }
+}
+
+// The settings of a decider combinator that compares `signal` with `constant`, and outputs the input count of `output`
+// if the comparison is true.
+fn compare_with_constant(signal: &SignalId, comparator: char, constant: i32, output: &SignalId, format: BlueprintFormat) -> DeciderConditions {
+    match format {
+        BlueprintFormat::V1_1 => DeciderConditions::V1_1(DeciderCombinatorParameters {
+            comparator,
+            first_signal: Some(signal.clone()),
+            second_signal: None,
+            constant: Some(constant),
+            output_signal: Some(output.clone()),
+            copy_count_from_input: true,
+        }),
+        BlueprintFormat::V2_0 => DeciderConditions::V2_0(DeciderCombinatorParametersV2 {
+            conditions: vec![DeciderCondition {
+                comparator,
+                first_signal: Some(signal.clone()),
+                second_signal: None,
+                constant: Some(constant),
+                compare_type: None
+            }],
+            outputs: vec![DeciderOutput {
+                signal: output.clone(),
+                copy_count_from_input: true
+            }]
+        })
+    }
+}
+
+// The signals used to address and transfer values to and from a RAM.
+#[derive(Clone)]
+pub struct RamSignals {
+    pub address: SignalId,
+    pub data: SignalId
+}
+
+impl Default for RamSignals {
+    fn default() -> Self {
+        // The same signals used for the arguments of instructions in the ROM.
+        Self {
+            address: SignalId::virtual_signal("signal-A"),
+            data: SignalId::virtual_signal("signal-D")
+        }
+    }
+}
+
+// Generates a blueprint containing a RAM with the given number of cells, addressed from 1 at the bottom of a column.
+//
+// Each cell is a row of three decider combinators, facing east:
+// - A write enable combinator, which passes the data signal through to the memory combinator when the write address matches.
+// - A memory combinator, which holds the data signal in a loop, and drops it while its address is being written
+//   so that it is replaced by the new value the next tick.
+// - A read select combinator, which outputs the stored value when the read address matches.
+//
+// The inputs of the write enable combinators are chained on a red wire, which carries the write address, and a
+// green wire, which carries the value to write. The memory combinators only see the red wire, so that values written
+// to other cells aren't added to theirs. The read select combinators have their inputs chained on a red wire carrying
+// the read address, and their outputs on a red wire carrying the value read.
+// A value written for one tick can be read two ticks later.
+pub fn generate_ram_blueprint(cells: usize, format: BlueprintFormat, signals: &RamSignals) -> Blueprint {
+    let mut entities = Vec::new();
+    let mut wires = Vec::new();
+
+    // 2.0 has 16 directions rather than 8.
+    let direction_scale = match format {
+        BlueprintFormat::V1_1 => 1,
+        BlueprintFormat::V2_0 => 2
+    };
+
+    // The write enable and read select combinators of the previous cell.
+    let mut previous_cell: Option<(u32, u32)> = None;
+
+    for idx in 0..cells {
+        let address = (idx + 1) as i32;
+        let y = -(idx as f32);
+
+        let write_number = (entities.len() + 1) as u32;
+        let memory_number = write_number + 1;
+        let read_number = write_number + 2;
+
+        let mut write_ends = Vec::new();
+        let mut read_ends = Vec::new();
+        if let Some((previous_write, previous_read)) = previous_cell {
+            write_ends.push((WireEnd::decider_input(previous_write), WireEnd::decider_input(write_number), WireColor::Red));
+            write_ends.push((WireEnd::decider_input(previous_write), WireEnd::decider_input(write_number), WireColor::Green));
+            read_ends.push((WireEnd::decider_input(previous_read), WireEnd::decider_input(read_number), WireColor::Red));
+            read_ends.push((WireEnd::decider_output(previous_read), WireEnd::decider_output(read_number), WireColor::Red));
+        }
+        previous_cell = Some((write_number, read_number));
+
+        let memory_ends = [
+            (WireEnd::decider_input(write_number), WireEnd::decider_input(memory_number), WireColor::Red),
+            (WireEnd::decider_output(write_number), WireEnd::decider_input(memory_number), WireColor::Green),
+            (WireEnd::decider_output(memory_number), WireEnd::decider_input(memory_number), WireColor::Green)
+        ];
+        read_ends.push((WireEnd::decider_output(memory_number), WireEnd::decider_input(read_number), WireColor::Green));
+
+        let cell = [
+            (write_number, 0.0, '=', write_ends.as_slice()),
+            (memory_number, 2.0, '≠', memory_ends.as_slice()),
+            (read_number, 4.0, '=', read_ends.as_slice())
+        ];
+
+        for (entity_number, x, comparator, ends) in cell {
+            entities.push(Entity {
+                entity_number,
+                name: "decider-combinator".to_owned(),
+                position: Position { x, y },
+                direction: 2 * direction_scale,
+                connections: connect_entity(ends, format, &mut wires),
+                control_behavior: Some(ControlBehaviour {
+                    arithmetic_conditions: None,
+                    decider_conditions: Some(compare_with_constant(&signals.address, comparator, address, &signals.data, format)),
+                    filters: None,
+                    sections: None
+                })
+            });
+        }
+    }
+
+    Blueprint {
+        item: "blueprint".to_string(),
+        label: "RAM".to_string(),
+        description: None,
+        icons: Some(vec![Icon { signal: SignalId::item("decider-combinator"), index: 1 }]),
         entities,
-        version: 0,
+        wires: match format {
+            BlueprintFormat::V1_1 => None,
+            BlueprintFormat::V2_0 => Some(wires)
+        },
+        version: format.default_version(),
+    }
+}
+
+// Generates a blueprint containing a tick counter, as expected by the `ticks()` builtin: an arithmetic combinator
+// that adds 1 to `signal` every tick, with its output wired back into its input on a red wire.
+// Its output is connected to an input signal of the computer with a green wire.
+pub fn generate_counter_blueprint(signal: &SignalId, format: BlueprintFormat) -> Blueprint {
+    let mut wires = Vec::new();
+
+    // Arithmetic combinators have the same circuits and connectors as decider combinators.
+    let connections = connect_entity(&[(WireEnd::decider_output(1), WireEnd::decider_input(1), WireColor::Red)], format, &mut wires);
+    let direction_scale = match format {
+        BlueprintFormat::V1_1 => 1,
+        BlueprintFormat::V2_0 => 2
+    };
+
+    Blueprint {
+        item: "blueprint".to_string(),
+        label: "Clock".to_string(),
+        description: None,
+        icons: Some(vec![Icon { signal: SignalId::item("arithmetic-combinator"), index: 1 }]),
+        entities: vec![Entity {
+            entity_number: 1,
+            name: "arithmetic-combinator".to_owned(),
+            position: Position { x: 0.0, y: 0.0 },
+            direction: 2 * direction_scale,
+            connections,
+            control_behavior: Some(ControlBehaviour {
+                arithmetic_conditions: Some(ArithmeticCombinatorParameters {
+                    first_signal: Some(signal.clone()),
+                    first_constant: None,
+                    second_signal: None,
+                    second_constant: Some(1),
+                    operation: "+".to_owned(),
+                    output_signal: Some(signal.clone())
+                }),
+                decider_conditions: None,
+                filters: None,
+                sections: None
+            })
+        }],
+        wires: match format {
+            BlueprintFormat::V1_1 => None,
+            BlueprintFormat::V2_0 => Some(wires)
+        },
+        version: format.default_version(),
+    }
+}
+
+// Reads the instructions from a program ROM blueprint, in either format and either encoding.
+// Each constant combinator holds an instruction, at the address checked for by the decider combinator it is wired to.
+pub fn read_rom_blueprint(blueprint: &Blueprint) -> anyhow::Result<Vec<Instruction>> {
+    let entities_by_number: HashMap<u32, &Entity> = blueprint.entities.iter()
+        .map(|entity| (entity.entity_number, entity))
+        .collect();
+    let bank_columns = bank_columns(blueprint);
+
+    // Keyed by bank and the address within it. A ROM without banks is read as a single bank.
+    let mut instructions_by_address = HashMap::new();
+    for entity in blueprint.entities.iter().filter(|entity| entity.name == "constant-combinator") {
+        let signals = match constant_signals(entity) {
+            Some(signals) => signals,
+            None => continue
+        };
+
+        let get_signal = |name| signals.iter().find(|(signal, _)| *signal == name).map(|(_, count)| *count);
+        let opcode = match get_signal("signal-O") {
+            Some(opcode) => opcode,
+            None => continue // Not part of the ROM
+        };
+
+        // Packed instructions are told apart by their opcode, which is far larger than any unpacked opcode.
+        let instruction = if opcode >= 1 << PACKED_ARGUMENT_BITS {
+            Instruction::decode_packed(opcode)
+                .ok_or_else(|| anyhow!("Constant combinator {} contains unknown opcode {}", entity.entity_number, opcode >> PACKED_ARGUMENT_BITS))?
+        }   else {
+            let argument = get_signal("signal-A").or(get_signal("signal-D")).unwrap_or(0);
+            Instruction::from_opcode(opcode, argument)
+                .ok_or_else(|| anyhow!("Constant combinator {} contains unknown opcode {opcode}", entity.entity_number))?
+        };
+
+        let decider = connected_entity_numbers(blueprint, entity).into_iter()
+            .filter_map(|number| entities_by_number.get(&number))
+            .find(|connected| connected.name == "decider-combinator")
+            .ok_or_else(|| anyhow!("Constant combinator {} is not connected to a decider combinator", entity.entity_number))?;
+
+        let address = decider_constant(decider)
+            .ok_or_else(|| anyhow!("Decider combinator {} does not check for an address", decider.entity_number))?;
+
+        let bank = match (bank_columns.is_empty(), bank_of_column(&bank_columns, entity.position.x)) {
+            (true, _) => 0,
+            (false, Some(bank)) => bank,
+            (false, None) => return Err(anyhow!("Constant combinator {} is not in the column of a bank", entity.entity_number))
+        };
+
+        if instructions_by_address.insert((bank, address), instruction).is_some() {
+            return Err(anyhow!("Multiple instructions are stored at address {address}{}", bank_suffix(&bank_columns, bank)));
+        }
+    }
+
+    // Every bank but the last is full, so the size of the banks is the highest address within them.
+    let bank_size = instructions_by_address.keys().map(|(_, address)| *address).max().unwrap_or(0);
+    let mut absolute_addresses = HashMap::new();
+    for ((bank, address), instruction) in instructions_by_address {
+        if absolute_addresses.insert(bank * bank_size + address, instruction).is_some() {
+            return Err(anyhow!("Multiple instructions are stored at address {address}{}", bank_suffix(&bank_columns, bank)));
+        }
+    }
+
+    (1..=absolute_addresses.len() as i32)
+        .map(|address| absolute_addresses.get(&address)
+            .copied()
+            .ok_or_else(|| anyhow!("No instruction is stored at address {}", describe_address(address, bank_size, &bank_columns))))
+        .collect()
+}
+
+// Reads the instructions from the program ROM within a blueprint book, e.g. one created with `--book`.
+// The ROM is the first blueprint in the book that stores any instructions, so other blueprints such as a RAM are skipped.
+pub fn read_rom_from_book(book: &BlueprintBook) -> anyhow::Result<Vec<Instruction>> {
+    let mut entries: Vec<&BookEntry> = book.blueprints.iter().collect();
+    entries.sort_by_key(|entry| entry.index);
+
+    let mut first_error = None;
+    for entry in entries {
+        match read_rom_blueprint(&entry.blueprint) {
+            Ok(instructions) if !instructions.is_empty() => return Ok(instructions),
+            Ok(_) => {},
+            Err(err) => { first_error.get_or_insert(err.context(format!("Failed to read blueprint `{}` in the book", entry.blueprint.label))); }
+        }
+    }
+
+    Err(first_error.unwrap_or_else(|| anyhow!("The blueprint book doesn't contain a program ROM")))
+}
+
+// Gets the bank marked by a constant combinator, which contains the bank signal rather than an instruction.
+fn bank_marker(entity: &Entity) -> Option<i32> {
+    let signals = constant_signals(entity)?;
+    if signals.iter().any(|(name, _)| *name == "signal-O") {
+        return None;
     }
-}
\ No newline at end of file
+
+    signals.iter().find(|(name, _)| *name == BANK_SIGNAL).map(|(_, count)| *count)
+}
+
+// Gets the x position and bank of each column of a banked ROM, which is shared by the constant combinators in it.
+// Empty if the ROM doesn't have banks.
+fn bank_columns(blueprint: &Blueprint) -> Vec<(f32, i32)> {
+    blueprint.entities.iter()
+        .filter(|entity| entity.name == "constant-combinator")
+        .filter_map(|entity| bank_marker(entity).map(|bank| (entity.position.x, bank)))
+        .collect()
+}
+
+fn bank_of_column(bank_columns: &[(f32, i32)], x: f32) -> Option<i32> {
+    bank_columns.iter().find(|(column_x, _)| *column_x == x).map(|(_, bank)| *bank)
+}
+
+// Describes the bank of an address in messages, if the ROM has banks.
+fn bank_suffix(bank_columns: &[(f32, i32)], bank: i32) -> String {
+    if bank_columns.is_empty() {
+        String::new()
+    }   else {
+        format!(" of bank {bank}")
+    }
+}
+
+// Describes an absolute address in messages, along with its bank and the address within it if the ROM has banks.
+fn describe_address(address: i32, bank_size: i32, bank_columns: &[(f32, i32)]) -> String {
+    if bank_columns.is_empty() || bank_size == 0 {
+        address.to_string()
+    }   else {
+        format!("{} ({}{})", address, (address - 1) % bank_size + 1, bank_suffix(bank_columns, (address - 1) / bank_size))
+    }
+}
+
+// Gets the name and count of each signal output by a constant combinator, in either format.
+fn constant_signals(entity: &Entity) -> Option<Vec<(&str, i32)>> {
+    match &entity.control_behavior {
+        Some(ControlBehaviour { filters: Some(filters), .. }) => Some(filters.iter()
+            .map(|filter| (filter.signal.name.as_str(), filter.count))
+            .collect()),
+        Some(ControlBehaviour { sections: Some(sections), .. }) => Some(sections.sections.iter()
+            .flat_map(|section| &section.filters)
+            .map(|filter| (filter.name.as_str(), filter.count))
+            .collect()),
+        _ => None
+    }
+}
+
+// Gets the signal that the first condition of a decider combinator checks, in either format.
+fn decider_signal(entity: &Entity) -> Option<&str> {
+    let signal = match &entity.control_behavior {
+        Some(ControlBehaviour { decider_conditions: Some(DeciderConditions::V1_1(parameters)), .. }) => parameters.first_signal.as_ref(),
+        Some(ControlBehaviour { decider_conditions: Some(DeciderConditions::V2_0(parameters)), .. }) => parameters.conditions
+            .first()
+            .and_then(|condition| condition.first_signal.as_ref()),
+        _ => None
+    };
+
+    signal.map(|signal| signal.name.as_str())
+}
+
+// Gets the constant that the first condition of a decider combinator compares with, in either format.
+fn decider_constant(entity: &Entity) -> Option<i32> {
+    match &entity.control_behavior {
+        Some(ControlBehaviour { decider_conditions: Some(DeciderConditions::V1_1(parameters)), .. }) => parameters.constant,
+        Some(ControlBehaviour { decider_conditions: Some(DeciderConditions::V2_0(parameters)), .. }) => parameters.conditions
+            .first()
+            .and_then(|condition| condition.constant),
+        _ => None
+    }
+}
+
+// Gets the numbers of the entities wired to an entity.
+fn connected_entity_numbers(blueprint: &Blueprint, entity: &Entity) -> Vec<u32> {
+    let mut numbers = Vec::new();
+
+    if let Some(connection) = &entity.connections {
+        for point in [&connection.a, &connection.b].into_iter().flatten() {
+            numbers.extend(point.red.iter().chain(&point.green).map(|data| data.entity_id));
+        }
+    }
+
+    for wire in blueprint.wires.iter().flatten() {
+        if wire[0] == entity.entity_number {
+            numbers.push(wire[2]);
+        }   else if wire[2] == entity.entity_number {
+            numbers.push(wire[0]);
+        }
+    }
+
+    numbers
+}
+
+// One end of a wire found while validating a blueprint: the entity number, circuit and color.
+type ValidatedWireEnd = (u32, u32, WireColor);
+
+// Gets the size of an entity in tiles when facing north, as (width, height).
+fn entity_size(name: &str) -> (i32, i32) {
+    match name {
+        "decider-combinator" | "arithmetic-combinator" => (1, 2),
+        "big-electric-pole" | "substation" => (2, 2),
+        _ => (1, 1)
+    }
+}
+
+// Gets the number of circuits an entity has. Combinators that process signals have an input and an output circuit.
+fn circuit_count(name: &str) -> u32 {
+    match name {
+        "decider-combinator" | "arithmetic-combinator" => 2,
+        _ => 1
+    }
+}
+
+// Gets every wire in a blueprint, in either format, with the end it is stored on first.
+fn validated_wires(blueprint: &Blueprint) -> Vec<(ValidatedWireEnd, ValidatedWireEnd)> {
+    let mut wires = Vec::new();
+    for entity in &blueprint.entities {
+        let connection = match &entity.connections {
+            Some(connection) => connection,
+            None => continue
+        };
+
+        for (circuit_id, point) in [(1, &connection.a), (2, &connection.b)] {
+            let point = match point {
+                Some(point) => point,
+                None => continue
+            };
+
+            for (color, data) in [(WireColor::Red, &point.red), (WireColor::Green, &point.green)] {
+                for data in data {
+                    wires.push(((entity.entity_number, circuit_id, color), (data.entity_id, data.circuit_id, color)));
+                }
+            }
+        }
+    }
+
+    // 2.0 connectors are numbered from 1, with a red then green connector for each circuit.
+    let to_end = |number: u32, connector: u32| {
+        let color = if connector % 2 == 1 { WireColor::Red } else { WireColor::Green };
+        (number, connector.saturating_sub(1) / 2 + 1, color)
+    };
+    for wire in blueprint.wires.iter().flatten() {
+        wires.push((to_end(wire[0], wire[1]), to_end(wire[2], wire[3])));
+    }
+
+    wires
+}
+
+// Checks that the wires and positions of the entities in a blueprint are consistent, returning a description of
+// each problem found. Problems here don't stop a blueprint from being imported, but leave it wired incorrectly in game.
+pub fn validate_layout(blueprint: &Blueprint) -> Result<(), Vec<String>> {
+    let mut problems = Vec::new();
+    let entities_by_number: HashMap<u32, &Entity> = blueprint.entities.iter()
+        .map(|entity| (entity.entity_number, entity))
+        .collect();
+
+    if entities_by_number.len() != blueprint.entities.len() {
+        problems.push("Multiple entities have the same entity number".to_string());
+    }
+
+    let wires = validated_wires(blueprint);
+    let color_name = |color| if color == WireColor::Red { "red" } else { "green" };
+    for &((from, from_circuit, color), (to, to_circuit, _)) in &wires {
+        for (number, circuit_id) in [(from, from_circuit), (to, to_circuit)] {
+            match entities_by_number.get(&number) {
+                None => problems.push(format!("A {} wire connects entities {from} and {to}, but entity {number} doesn't exist", color_name(color))),
+                Some(entity) if circuit_id < 1 || circuit_id > circuit_count(&entity.name) => {
+                    let count = circuit_count(&entity.name);
+                    let plural = if count == 1 { "" } else { "s" };
+                    problems.push(format!("Entity {from} has a {} wire to circuit {circuit_id} of entity {number}, which only has {count} circuit{plural}",
+                        color_name(color)));
+                },
+                Some(_) => {}
+            }
+        }
+    }
+
+    // In 1.1, a wire can be stored on one or both of the entities it connects. Where both entities store wires to
+    // each other, each wire must be stored on both.
+    let stored: HashSet<(ValidatedWireEnd, ValidatedWireEnd)> = wires.iter().copied().collect();
+    let connected_pairs: HashSet<(u32, u32)> = wires.iter().map(|(from, to)| (from.0, to.0)).collect();
+    for &(from, to) in &wires {
+        if from.0 != to.0 && connected_pairs.contains(&(to.0, from.0)) && !stored.contains(&(to, from)) {
+            problems.push(format!("Entity {} has a {} wire to circuit {} of entity {}, but entity {} doesn't have the same wire back",
+                from.0, color_name(from.2), to.1, to.0, to.0));
+        }
+    }
+
+    // 2.0 has 16 directions rather than 8, so a quarter turn is a larger step.
+    let quarter_turn = if major_version(blueprint.version) >= 2 { 4 } else { 2 };
+    let mut occupied: HashMap<(i32, i32), u32> = HashMap::new();
+    for entity in &blueprint.entities {
+        let (width, height) = match entity_size(&entity.name) {
+            (width, height) if (entity.direction / quarter_turn) % 2 == 1 => (height, width),
+            size => size
+        };
+
+        // The corner of the entity, rounded up if it isn't aligned with the grid.
+        let left = (entity.position.x - width as f32 / 2.0 + 0.5).floor() as i32;
+        let top = (entity.position.y - height as f32 / 2.0 + 0.5).floor() as i32;
+        for x in left..left + width {
+            for y in top..top + height {
+                if let Some(other) = occupied.insert((x, y), entity.entity_number) {
+                    problems.push(format!("Entities {other} and {} both occupy the tile at ({x}, {y})", entity.entity_number));
+                }
+            }
+        }
+    }
+
+    if problems.is_empty() { Ok(()) } else { Err(problems) }
+}
+
+// Checks that a program ROM blueprint is consistent, returning a description of each problem found.
+// As well as the checks made by `validate_layout`, the decider combinators must check for each address from 1 to the
+// number of instructions exactly once, and each constant combinator must contain an opcode.
+// In a banked ROM, the addresses of each bank are checked separately, and the bank markers needn't contain an opcode.
+pub fn validate(blueprint: &Blueprint) -> Result<(), Vec<String>> {
+    let mut problems = match validate_layout(blueprint) {
+        Ok(()) => Vec::new(),
+        Err(problems) => problems
+    };
+
+    // The decider combinators of each bank are checked separately, and a ROM without banks is checked as a single bank.
+    let bank_columns = bank_columns(blueprint);
+    let mut deciders_by_bank: HashMap<i32, HashMap<i32, Vec<u32>>> = HashMap::new();
+    for decider in blueprint.entities.iter().filter(|entity| entity.name == "decider-combinator") {
+        if decider_signal(decider) == Some(BANK_SIGNAL) {
+            continue; // Selects a bank rather than an instruction
+        }
+
+        // The constant combinators, including the bank marker, are 2 tiles to the left of the decider combinators.
+        let bank = match (bank_columns.is_empty(), bank_of_column(&bank_columns, decider.position.x - 2.0)) {
+            (true, _) => 0,
+            (false, Some(bank)) => bank,
+            (false, None) => {
+                problems.push(format!("Decider combinator {} is not in the column of a bank", decider.entity_number));
+                continue;
+            }
+        };
+
+        match decider_constant(decider) {
+            Some(address) => deciders_by_bank.entry(bank).or_default().entry(address).or_default().push(decider.entity_number),
+            None => problems.push(format!("Decider combinator {} does not check for an address", decider.entity_number))
+        }
+    }
+
+    let mut banks: Vec<(i32, HashMap<i32, Vec<u32>>)> = deciders_by_bank.into_iter().collect();
+    banks.sort_by_key(|(bank, _)| *bank);
+    for (bank, deciders_by_address) in banks {
+        let bank_suffix = bank_suffix(&bank_columns, bank);
+        let instruction_count = deciders_by_address.values().map(Vec::len).sum::<usize>() as i32;
+        for address in 1..=instruction_count {
+            match deciders_by_address.get(&address).map(Vec::as_slice) {
+                None => problems.push(format!("No decider combinator checks for address {address}{bank_suffix}")),
+                Some([_]) => {},
+                Some(numbers) => problems.push(format!("Address {address}{bank_suffix} is checked for by multiple decider combinators: {numbers:?}"))
+            }
+        }
+
+        let mut outside_range: Vec<(&i32, &Vec<u32>)> = deciders_by_address.iter()
+            .filter(|(address, _)| !(1..=instruction_count).contains(*address))
+            .collect();
+        outside_range.sort();
+        for (address, numbers) in outside_range {
+            let container = if bank_columns.is_empty() { "the ROM" } else { "the bank" };
+            problems.push(format!("Decider combinators {numbers:?} check for address {address}{bank_suffix}, which is outside of {container}"));
+        }
+    }
+
+    for constant in blueprint.entities.iter().filter(|entity| entity.name == "constant-combinator") {
+        let has_opcode = constant_signals(constant)
+            .is_some_and(|signals| signals.iter().any(|(name, _)| *name == "signal-O"));
+        if !has_opcode && bank_marker(constant).is_none() {
+            problems.push(format!("Constant combinator {} does not contain an opcode", constant.entity_number));
+        }
+    }
+
+    if problems.is_empty() { Ok(()) } else { Err(problems) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A program using every instruction, with arguments of both signs.
+    fn sample_program() -> Vec<Instruction> {
+        (1..=31)
+            .map(|opcode| Instruction::from_opcode(opcode, if opcode % 2 == 0 { opcode * 7 } else { -opcode }).unwrap())
+            .cycle()
+            .take(150)
+            .collect()
+    }
+
+    #[test]
+    fn rom_decodes_in_every_layout() {
+        let instructions = sample_program();
+        for format in [BlueprintFormat::V1_1, BlueprintFormat::V2_0] {
+            for encoding in [InstructionEncoding::TwoSignal, InstructionEncoding::Packed] {
+                for banks in [None, Some(RomBanks { size: 40, select: BankSelect::Decider }), Some(RomBanks { size: 40, select: BankSelect::Condition })] {
+                    // Only 2.0 decider combinators have a second condition.
+                    if format == BlueprintFormat::V1_1 && banks.is_some_and(|banks| banks.select == BankSelect::Condition) {
+                        continue;
+                    }
+
+                    let layout = RomLayout { column_height: 50, encoding, banks, ..RomLayout::default() };
+                    let blueprint = generate_rom_blueprint(&instructions, format, layout).unwrap();
+                    assert_eq!(read_rom_blueprint(&blueprint).unwrap(), instructions);
+                    assert!(validate(&blueprint).is_ok());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rom_blueprint_string_round_trips() {
+        let instructions = sample_program();
+        let serialized = SerializedBlueprint {
+            blueprint: generate_rom_blueprint(&instructions, BlueprintFormat::V2_0, RomLayout::default()).unwrap()
+        };
+
+        let loaded = SerializedBlueprint::load(&serialized.save().unwrap()).unwrap();
+        assert_eq!(read_rom_blueprint(&loaded.blueprint).unwrap(), instructions);
+    }
+
+    #[test]
+    fn invalid_strings_are_errors() {
+        assert!(SerializedBlueprint::load("1eJyrVkrOz0nTUbJSUqoFAB3pBFs=").is_err());
+        assert!(SerializedBlueprint::load("0!!!").is_err());
+        assert!(SerializedBlueprint::load("").is_err());
+    }
+
+    #[test]
+    fn rom_is_found_within_book() {
+        let instructions = sample_program();
+        let rom = generate_rom_blueprint(&instructions, BlueprintFormat::V2_0, RomLayout::default()).unwrap();
+        let ram = generate_ram_blueprint(16, BlueprintFormat::V2_0, &RamSignals::default());
+
+        // The RAM is listed first, as a book rearranged in game may be.
+        let mut book = generate_book("test", vec![rom, ram], BlueprintFormat::V2_0);
+        book.blueprints.reverse();
+        let string = SerializedBlueprintBook { blueprint_book: book }.save().unwrap();
+
+        let loaded = SerializedBlueprintBook::load(&string).unwrap();
+        assert_eq!(read_rom_from_book(&loaded.blueprint_book).unwrap(), instructions);
+    }
+
+    #[test]
+    fn book_without_rom_is_an_error() {
+        let ram = generate_ram_blueprint(16, BlueprintFormat::V2_0, &RamSignals::default());
+        let book = generate_book("test", vec![ram], BlueprintFormat::V2_0);
+        assert_eq!(read_rom_from_book(&book).unwrap_err().to_string(), "The blueprint book doesn't contain a program ROM");
+    }
+}
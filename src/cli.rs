@@ -0,0 +1,223 @@
+//! Parsing of the command line arguments of `lflc`.
+
+use std::collections::HashMap;
+
+// What the program has been asked to do with its input file.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Command {
+    // Compile an LFL file (or assemble a `.asm` file) into a blueprint.
+    Compile,
+    Assemble,
+    // Print the instructions in the program ROM of a blueprint string.
+    Disassemble,
+    Emulate,
+    // Rewrite a source file with consistent formatting.
+    Format,
+    // Generate a RAM blueprint, with the number of cells given in place of the file.
+    Ram,
+    // Generate a tick counter blueprint, with the number of the signal it outputs given in place of the file.
+    Clock,
+    // Link object files written by `--emit obj` into a program, which is output like a compiled one.
+    Link,
+    // Print the value of an expression, given in place of the file.
+    Eval
+}
+
+const COMMANDS: &[(&str, Command, &str)] = &[
+    ("compile", Command::Compile, "Compile a program into a ROM blueprint (the default)"),
+    ("asm", Command::Assemble, "Assemble a file containing assembly into a ROM blueprint"),
+    ("disasm", Command::Disassemble, "Print the instructions in a file containing a ROM blueprint string"),
+    ("emulate", Command::Emulate, "Compile a program and run it on the emulator"),
+    ("fmt", Command::Format, "Format the code in a source file, rewriting the file"),
+    ("ram", Command::Ram, "Generate a RAM blueprint, e.g. `lflc ram 16` for 16 cells"),
+    ("clock", Command::Clock, "Generate a tick counter blueprint for `ticks()`, e.g. `lflc clock 5` for signal 5"),
+    ("link", Command::Link, "Link object files written by `--emit obj`, and files of assembly functions, into a ROM blueprint, e.g. `lflc link lib.o main.o`"),
+    ("eval", Command::Eval, "Print the value of an expression as calculated by the computer, e.g. `lflc eval \"(-7 % 3) * 2\"`")
+];
+
+struct CliOption {
+    names: &'static [&'static str],
+    // The name of the value taken by the option, or None if it is a switch.
+    value: Option<&'static str>,
+    help: &'static str
+}
+
+const OPTIONS: &[CliOption] = &[
+    CliOption { names: &["-o", "--output"], value: Some("path"), help: "Write the output to a file rather than stdout" },
+    CliOption { names: &["--assembly"], value: None, help: "Output the compiled instructions rather than a blueprint" },
+    CliOption { names: &["--map"], value: None, help: "Output the compiled instructions with the line of source code each came from" },
+    CliOption { names: &["--emit"], value: Some("obj"), help: "Output an object file containing the functions of the file, to be linked with `link`" },
+    CliOption { names: &["--dep-file"], value: Some("path"), help: "Write a Make rule listing the files read, with the output file given with `-o` as the target" },
+    CliOption { names: &["--dep-format"], value: Some("make|json"), help: "Write the `--dep-file` as a Make rule, or as a JSON object" },
+    CliOption { names: &["--watch"], value: None, help: "Build again each time the file, or a file it imports, is saved" },
+    CliOption { names: &["--clipboard"], value: None, help: "Copy the output to the clipboard rather than printing it" },
+    CliOption { names: &["--stats"], value: None, help: "Print the size of the program and of each function" },
+    CliOption { names: &["--asm"], value: None, help: "Treat the file as assembly, even without the `.asm` extension" },
+    CliOption { names: &["-e", "--eval"], value: Some("source"), help: "Compile the given source code rather than a file, or stdin with a path of `-`" },
+    CliOption { names: &["--format"], value: Some("1.1|2.0"), help: "The factorio version to generate the blueprint for" },
+    CliOption { names: &["--game-version"], value: Some("version"), help: "The game version stored in the blueprint, e.g. 1.1.110" },
+    CliOption { names: &["--pretty-json"], value: None, help: "Indent the JSON in the blueprint string, to make it easier to read once decoded" },
+    CliOption { names: &["--compression"], value: Some("fast|best"), help: "How much to compress the blueprint string, `best` by default" },
+    CliOption { names: &["--rom-height"], value: Some("instructions"), help: "Start a new column of the ROM after this many instructions" },
+    CliOption { names: &["--pad"], value: Some("instructions"), help: "Fill the ROM with NOP instructions up to this size" },
+    CliOption { names: &["--max-program-size"], value: Some("instructions"), help: "Fail if the program is longer than the computer can address" },
+    CliOption { names: &["--rom-column-stride"], value: Some("tiles"), help: "The distance between the columns of the ROM, from 6 to 10" },
+    CliOption { names: &["--encoding"], value: Some("two-signal|packed"), help: "Whether each instruction is stored in two signals, or packed into one" },
+    CliOption { names: &["--bank-size"], value: Some("instructions"), help: "Split the ROM into banks of this many instructions, each in its own column" },
+    CliOption { names: &["--bank-select"], value: Some("condition|decider"), help: "Select banks with a second condition (2.0 only) or a decider combinator for each bank" },
+    CliOption { names: &["--label"], value: Some("label"), help: "The label of the blueprint, the name of the file by default" },
+    CliOption { names: &["--embed-source"], value: None, help: "Add the source code to the description of the blueprint" },
+    CliOption { names: &["--book"], value: None, help: "Output a blueprint book containing the ROM, rather than a single blueprint" },
+    CliOption { names: &["--ram"], value: Some("cells"), help: "With `--book`, add a RAM with this many cells to the book" },
+    CliOption { names: &["--address-signal"], value: Some("signal"), help: "With `ram` or `--ram`, the signal carrying the address, `signal-A` by default" },
+    CliOption { names: &["--data-signal"], value: Some("signal"), help: "With `ram` or `--ram`, the signal carrying the value read or written, `signal-D` by default" },
+    CliOption { names: &["-O2"], value: None, help: "Merge functions that compile to identical code" },
+    CliOption { names: &["--no-fold"], value: None, help: "Don't evaluate operators with constant operands at compile time" },
+    CliOption { names: &["--optimize"], value: None, help: "Remove redundant instructions from the compiled program, inline small functions, and turn `return` of a call to the same function into a jump" },
+    CliOption { names: &["--dump-after"], value: Some("pass|all"), help: "Print the instructions after a pass of the compiler, e.g. `inlining`, to find which one changed the program" },
+    CliOption { names: &["--dump-dir"], value: Some("directory"), help: "With `--dump-after`, write the instructions after each pass to a file in this directory, rather than stderr" },
+    CliOption { names: &["--inline-threshold"], value: Some("instructions"), help: "With `--optimize`, inline functions of at most this many instructions, 8 by default" },
+    CliOption { names: &["--call-convention"], value: Some("interleaved|split-stack"), help: "Where return addresses are stored" },
+    CliOption { names: &["--profile"], value: Some("full|classic|path"), help: "The instructions supported by the computer, by name or listed in a file" },
+    CliOption { names: &["--entry"], value: Some("function"), help: "The function to call when the program starts, `main` by default" },
+    CliOption { names: &["--signals"], value: Some("count"), help: "The number of signals the computer can read and write, 5 by default" },
+    CliOption { names: &["--tick-signal"], value: Some("signal"), help: "The input signal connected to the tick counter" },
+    CliOption { names: &["-D"], value: Some("warning"), help: "Treat a kind of warning, or all `warnings`, as an error" },
+    CliOption { names: &["--color"], value: Some("always|never|auto"), help: "Whether to highlight errors and warnings with colors" },
+    CliOption { names: &["--error-format"], value: Some("human|json"), help: "Print errors and warnings as text, or as one JSON object per line" },
+    CliOption { names: &["--max-errors"], value: Some("count"), help: "Print at most this many errors, 20 by default" },
+    CliOption { names: &["--deny-warnings"], value: None, help: "Treat all warnings as errors, the same as `-D warnings`" },
+    CliOption { names: &["--ast"], value: None, help: "Print the syntax tree of the file rather than compiling it" },
+    CliOption { names: &["--tokens"], value: None, help: "Print the tokens in the file rather than compiling it" },
+    CliOption { names: &["--check"], value: None, help: "With `fmt`, print the formatted code and fail if it differs from the file, rather than rewriting it" },
+    CliOption { names: &["--input", "--signal"], value: Some("signal=value"), help: "Set an input signal when emulating, or for `eval`" },
+    CliOption { names: &["--max-steps"], value: Some("count"), help: "Stop emulating after this many instructions" },
+    CliOption { names: &["--output-format"], value: Some("human|json"), help: "Print the result of emulating as text, or as a JSON object" },
+    CliOption { names: &["-h", "--help"], value: None, help: "Print this message" }
+];
+
+// Options accepted without a command, from before commands were added.
+const LEGACY_SWITCHES: &[(&str, Command)] = &[
+    ("--emulate", Command::Emulate)
+];
+
+// The parsed command line. Options are stored under their first name in `OPTIONS`.
+pub struct Args {
+    pub command: Command,
+    pub path: Option<String>,
+    // The files after the first, which can only be given to `link`.
+    pub other_paths: Vec<String>,
+    values: HashMap<&'static str, Vec<String>>
+}
+
+impl Args {
+    // Checks if a switch was given.
+    pub fn has(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+
+    // Gets the values given each time an option was passed.
+    pub fn values(&self, name: &str) -> &[String] {
+        self.values.get(name).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    // Gets the value of an option, if it was given.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.values(name).first().map(String::as_str)
+    }
+}
+
+// Parses the given arguments, not including the program name.
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+    let mut args = args.into_iter().peekable();
+
+    // Without a command, the arguments are treated as `compile`.
+    let mut command = match args.peek().and_then(|first| COMMANDS.iter().find(|(name, _, _)| name == first)) {
+        Some((_, command, _)) => {
+            args.next();
+            Some(*command)
+        },
+        None => None
+    };
+
+    let mut path = None;
+    let mut other_paths = Vec::new();
+    let mut values: HashMap<&'static str, Vec<String>> = HashMap::new();
+
+    while let Some(arg) = args.next() {
+        if let Some((_, legacy_command)) = LEGACY_SWITCHES.iter().find(|(name, _)| *name == arg) {
+            if command.is_some_and(|command| command != Command::Compile) {
+                return Err(format!("`{arg}` cannot be used with a command"));
+            }
+
+            command = Some(*legacy_command);
+        }   else if let Some((option, inline_value)) = find_option(&arg) {
+            let value = match (option.value, inline_value) {
+                (Some(_), Some(value)) => value.to_owned(),
+                (Some(value_name), None) => match args.next() {
+                    Some(value) => value,
+                    None => return Err(format!("Expected <{value_name}> after `{arg}`"))
+                },
+                (None, Some(_)) => return Err(format!("`{}` doesn't take a value", option.names[0])),
+                (None, None) => String::new()
+            };
+
+            values.entry(option.names[0]).or_default().push(value);
+        }   else if arg.starts_with('-') && arg != "-" && !is_negated_expression(command, &path, &arg) { // A path of `-` reads the file from stdin.
+            return Err(format!("Unknown option `{arg}`"));
+        }   else if path.is_none() {
+            path = Some(arg);
+        }   else if matches!(command, None | Some(Command::Compile | Command::Emulate | Command::Link)) {
+            // Programs can be linked with files of functions written in assembly, e.g. `lflc compile main.lfl fast.asm`.
+            other_paths.push(arg);
+        }   else {
+            return Err(format!("Unexpected argument `{arg}`, only one file can be given"));
+        }
+    }
+
+    Ok(Args {
+        command: command.unwrap_or(Command::Compile),
+        path,
+        other_paths,
+        values
+    })
+}
+
+// Finds the option given by an argument, along with its value if written as `--option=value`.
+fn find_option(arg: &str) -> Option<(&'static CliOption, Option<&str>)> {
+    let (name, value) = match arg.split_once('=') {
+        Some((name, value)) if name.starts_with("--") => (name, Some(value)),
+        _ => (arg, None)
+    };
+
+    OPTIONS.iter().find(|option| option.names.contains(&name)).map(|option| (option, value))
+}
+
+// Checks if an argument starting with `-` is the expression given to `eval`, e.g. `-7 % 3`, rather than an unknown option.
+fn is_negated_expression(command: Option<Command>, path: &Option<String>, arg: &str) -> bool {
+    command == Some(Command::Eval) && path.is_none() && !arg.starts_with("--")
+}
+
+// Gets the usage message printed by `--help`, or when the arguments are invalid.
+pub fn usage() -> String {
+    let mut usage = String::from("Usage: lflc [command] <file> [options]\n\nCommands:\n");
+    for (name, _, help) in COMMANDS {
+        usage.push_str(&format!("  {name:<28}{help}\n"));
+    }
+
+    usage.push_str("\nOptions:\n");
+    for option in OPTIONS {
+        let mut syntax = option.names.join(", ");
+        if let Some(value) = option.value {
+            syntax.push_str(&format!(" <{value}>"));
+        }
+
+        if syntax.len() >= 28 {
+            usage.push_str(&format!("  {syntax}\n  {:<28}{}\n", "", option.help));
+        }   else {
+            usage.push_str(&format!("  {syntax:<28}{}\n", option.help));
+        }
+    }
+
+    usage
+}
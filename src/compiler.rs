@@ -1,31 +1,212 @@
 //! Compiles the ast into the code used for the factorio computer.
 
-use std::collections::HashMap;
+use std::{collections::{HashMap, HashSet, hash_map::Entry}, hash::{DefaultHasher, Hash, Hasher}, sync::Arc};
 
-use crate::{ast::{Statement, Expression, BinaryOperator, UnaryOperator, Function, Call}, assembly::Instruction, error_handling::{CompileResult, FileRef, CompileErrors}, error, untagged_err};
+use serde::{Serialize, Deserialize};
 
-// Number of signals we can read from or write to.
-const SIGNAL_COUNT: i32 = 5;
+use crate::{folding, lints, optimizer, semantics, profile::InstructionSet, ast::{Item, Statement, Expression, BinaryOperator, UnaryOperator, Function, Global, Constant, Call}, assembly::Instruction,
+    error_handling::{self, CompileResult, FileRef, CompileErrors, FileTaggedError, SourceFile, Warning, WarningKind},
+    visit::{self, Visitor}, error, untagged_err, warning};
 
-const ENTRY_POINT: &str = "main";
+// Number of signals we can read from or write to, unless the computer is built with a different number.
+pub const SIGNAL_COUNT: i32 = 5;
+
+// Gets the address of the first global variable, just below the addresses used to read signals.
+// Each following global has an address one lower.
+pub fn first_global_address(signal_count: i32) -> i32 {
+    -2 * signal_count - 1
+}
+
+// Name of the function called when the program starts, unless another is chosen.
+pub const ENTRY_POINT: &str = "main";
+
+// Name of the builtin function that reads the tick counter.
+const TICKS_FUNCTION: &str = "ticks";
+
+// Names of the builtin functions that access the GPIO, the same as the `signal_N` variables.
+const READ_SIGNAL_FUNCTION: &str = "read_signal";
+const WRITE_SIGNAL_FUNCTION: &str = "write_signal";
+
+// Name of the builtin function that stops the program.
+pub const HALT_FUNCTION: &str = "halt";
+
+const BUILTIN_FUNCTIONS: &[&str] = &[TICKS_FUNCTION, READ_SIGNAL_FUNCTION, WRITE_SIGNAL_FUNCTION, HALT_FUNCTION];
+
+// Where the return address pushed by JSR is stored.
+#[derive(Clone, Copy, PartialEq, Hash, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CallConvention {
+    // The return address is pushed to the data stack, between the arguments and the locals of the callee.
+    #[default]
+    Interleaved,
+    // The return address is pushed to a separate hardware stack, so JSR and RET do not touch the data stack.
+    SplitStack
+}
+
+impl CallConvention {
+    // Gets the convention with the name used on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "interleaved" => Some(Self::Interleaved),
+            "split-stack" => Some(Self::SplitStack),
+            _ => None
+        }
+    }
+}
+
+// A pass of the compiler, after which the program can be recorded with `CompileOptions::dump_after`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Pass {
+    // Evaluates operators with constant operands. The code is compiled as without `optimize`, so none of its changes are included.
+    Folding,
+    // Compiles a function returning a call to itself as a loop, with `optimize`.
+    TailCalls,
+    // Replaces calls to small functions with their body, with `optimize`.
+    Inlining,
+    // Leaves out the functions that are never called from the entry point.
+    DeadCode,
+    // Merges functions that compile to identical code, with `fold_identical_functions`.
+    FoldIdenticalFunctions,
+    // The peephole optimizer, with `optimize`.
+    Optimize
+}
+
+impl Pass {
+    // Every pass, in the order they run.
+    pub const ALL: [Pass; 6] = [Pass::Folding, Pass::TailCalls, Pass::Inlining, Pass::DeadCode, Pass::FoldIdenticalFunctions, Pass::Optimize];
+
+    // The name of the pass used with `--dump-after`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::Folding => "folding",
+            Pass::TailCalls => "tco",
+            Pass::Inlining => "inlining",
+            Pass::DeadCode => "dce",
+            Pass::FoldIdenticalFunctions => "fold-identical-functions",
+            Pass::Optimize => "optimize"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|pass| pass.name() == name)
+    }
+}
+
+// The program as it was after a pass, recorded with `CompileOptions::dump_after`.
+pub struct PassDump {
+    pub pass: Pass,
+    // False if the pass was turned off by the options, so left the program as it was.
+    pub enabled: bool,
+    // False for the passes that run before the functions are placed in the program. Jumps are then relative to the start of
+    // each function, and JSR instructions contain the index of the function called.
+    pub linked: bool,
+    pub instructions: Vec<Instruction>,
+    pub function_spans: Vec<FunctionSpan>
+}
+
+// Options controlling how a module is compiled.
+#[derive(Clone)]
+pub struct CompileOptions {
+    // Merge functions that compile to identical code so that only one copy ends up in the ROM.
+    pub fold_identical_functions: bool,
+    // Evaluate operators with constant operands at compile time.
+    pub fold_constants: bool,
+    // Run the peephole optimizer over the linked program, inline small functions, and compile a function returning a call to itself as a loop.
+    pub optimize: bool,
+    // With `optimize`, calls to functions of at most this many instructions are replaced by the body of the function.
+    pub inline_threshold: usize,
+    pub call_convention: CallConvention,
+    // The input signal connected to the tick counter, which is read by `ticks()`.
+    pub tick_signal: i32,
+    // The number of signals the computer can read from and write to, which decides the addresses of signals and globals.
+    pub signal_count: i32,
+    // The function called when the program starts. Its arguments are read from the input signals, beginning with signal 1.
+    pub entry_point: String,
+    // The instructions supported by the computer. Other instructions are replaced where possible, or give an error.
+    pub instruction_set: InstructionSet,
+    // The passes to record the program after, in `CompiledModule::pass_dumps`.
+    pub dump_after: Vec<Pass>
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            fold_identical_functions: false,
+            fold_constants: true,
+            optimize: false,
+            inline_threshold: 8,
+            call_convention: CallConvention::default(),
+            tick_signal: SIGNAL_COUNT,
+            signal_count: SIGNAL_COUNT,
+            entry_point: ENTRY_POINT.to_owned(),
+            instruction_set: InstructionSet::default(),
+            dump_after: Vec::new()
+        }
+    }
+}
+
+// The first places where the tick counter signal is read, either through `ticks()` or as a `signal_N` variable.
+// The signal cannot be used for both, since the tick counter is driven by the hardware.
+#[derive(Default)]
+struct TickSignalUses {
+    ticks_call: Option<FileRef>,
+    signal_read: Option<FileRef>
+}
+
+impl TickSignalUses {
+    // Adds the uses found in a later function, keeping the first of each.
+    fn add(&mut self, later: TickSignalUses) {
+        self.ticks_call = self.ticks_call.take().or(later.ticks_call);
+        self.signal_read = self.signal_read.take().or(later.signal_read);
+    }
+}
 
 // Keeps track of information in a particular scope.
-#[derive(Clone, PartialEq)]
 enum ScopeState {
     // Keep track of all places where `continue` or `break` statements have been placed 
     // so that we can update them with the correct jump address once this is known.
     While {
-        continue_inst_addresses: Vec<usize>,
-        break_inst_addresses: Vec<usize>
+        continue_jumps: Vec<JumpPlaceholder>,
+        break_jumps: Vec<JumpPlaceholder>
+    },
+    // The body of a function inlined at a call, whose `return` statements jump to the end of the body.
+    // The variables and loops of the calling function can't be used within it.
+    Inlined {
+        return_jumps: Vec<JumpPlaceholder>
     },
     Other
 }
 
+// A jump emitted before the address it goes to is known, which is set later with `CompileCtx::patch_jump`.
+struct JumpPlaceholder {
+    idx: usize,
+    // Creates the jump instruction with its address, e.g. `Instruction::JumpIfZero`.
+    jump: fn(i32) -> Instruction
+}
+
+// A local variable or argument, which is stored on the stack.
+struct Variable {
+    // Offset from the bottom of the stack, `0` being the first local variable.
+    offset: i32,
+    // Where the variable is first assigned, or the name of the argument.
+    name_ref: FileRef,
+    is_read: bool
+}
+
+impl Variable {
+    fn new(offset: i32, name_ref: FileRef) -> Self {
+        Self {
+            offset,
+            name_ref,
+            is_read: false
+        }
+    }
+}
+
 // Each scope needs to pop off its local variables after it exits.
 struct Scope {
-    // The variables in the scope, as an offset from the bottom of the stack
-    // `0` is the first local variable.
-    scope_vars: HashMap<String, i32>,
+    // The variables in the scope.
+    scope_vars: HashMap<String, Variable>,
     // The stack size before the scope was opened.
     starting_stack_size: i32,
     scope_type: ScopeState
@@ -33,17 +214,173 @@ struct Scope {
 
 // Keeps track of information about a function after the Function struct has been consumed.
 // Used for linking between functions.
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 struct FunctionInfo {
     arg_count: usize,
+    // Position of the arguments in the definition of the function, or None if it was read from an object file.
+    argument_list_ref: Option<FileRef>,
     returns_value: bool,
-    id: i32,
-    start_offset: i32
+    id: i32
+}
+
+// How a function uses the stack, used to find the maximum depth of the stack when running the program.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct StackUsage {
+    // The largest number of values on the stack at once within the function, not including those of the functions it calls.
+    pub peak: i32,
+    // The id of each function called, along with the stack size when calling it, which includes the arguments.
+    pub calls: Vec<(i32, i32)>,
+    // True for functions written in assembly, whose use of the stack isn't known.
+    #[serde(default)]
+    pub hand_written: bool
+}
+
+// The position in the source code that each instruction was compiled from.
+// None for instructions that aren't part of any function, such as those that call the entry point.
+pub type SourceMap = Vec<Option<FileRef>>;
+
+// The result of compiling a module.
+pub struct CompiledModule {
+    pub instructions: Vec<Instruction>,
+    pub source_map: SourceMap,
+    // The largest number of values that can be on the stack while the program runs, including return addresses.
+    // None if the program contains recursion, since the depth then depends on the values it reads.
+    pub max_stack_depth: Option<i32>,
+    // The instructions making up each function of the program, in order. Together they cover every instruction.
+    pub function_spans: Vec<FunctionSpan>,
+    // The functions left out of the program because they were identical to another, with `fold_identical_functions`.
+    pub merged_functions: Vec<MergedFunction>,
+    // True if the program calls `ticks()`, so the tick signal is driven by a clock rather than being an ordinary input.
+    pub uses_ticks: bool,
+    // The program after each of the passes in `CompileOptions::dump_after`, in the order they ran.
+    pub pass_dumps: Vec<PassDump>
+}
+
+// A function whose calls go to an identical function instead, so that its code is only in the program once.
+pub struct MergedFunction {
+    pub function_name: String,
+    // The function whose code is used in its place.
+    pub merged_into: String,
+    // The number of instructions the function would have taken up.
+    pub instructions_saved: usize
+}
+
+// A range of instructions in a compiled program that belong to one function.
+#[derive(Clone)]
+pub struct FunctionSpan {
+    // None for the code that initializes the globals and calls the entry point, which is at the start of the program.
+    pub function_name: Option<String>,
+    // Index of the first instruction.
+    pub start: usize,
+    // Index just past the last instruction.
+    pub end: usize
+}
+
+// The functions and globals of a module compiled before being placed in the program, written by `--emit obj`.
+// Object files are linked into a program by `link`, which needs every function and global used to be defined by one of them.
+#[derive(Serialize, Deserialize)]
+pub struct ObjectFile {
+    // Objects can only be linked with options that the code was compiled for.
+    pub signal_count: i32,
+    pub call_convention: CallConvention,
+    pub globals: Vec<ObjectGlobal>,
+    // Assigns the globals defined by the object their initial values. Jumps are relative to the start of the initializers.
+    pub initializers: Vec<Instruction>,
+    #[serde(skip)]
+    pub initializers_source_map: SourceMap,
+    pub initializers_stack_peak: i32,
+    // JSR instructions within the object contain the index of the function called in this list.
+    pub functions: Vec<ObjectFunction>,
+    // True if the object calls `ticks()`, so expects the tick signal to be driven by a clock.
+    #[serde(default)]
+    pub uses_ticks: bool,
+    // The passes recorded before linking, which are added to those of the linked program.
+    #[serde(skip)]
+    pub pass_dumps: Vec<PassDump>
+}
+
+// The compiled code of functions from earlier builds, kept between builds with `--watch` so that only the functions
+// which have changed are compiled again.
+#[derive(Default)]
+pub struct FunctionCache {
+    entries: HashMap<u64, CachedFunction>,
+    // The number of functions whose code was reused by the last build.
+    pub hits: usize,
+    // The number of functions compiled by the last build.
+    pub misses: usize
+}
+
+// The code of a function from an earlier build, which is reused until the function or anything it depends on changes.
+struct CachedFunction {
+    code: ObjectCode,
+    // The code recorded for the `folding` pass, if it was being dumped.
+    folded: Option<Vec<Instruction>>,
+    // The number of instructions, which decides whether the function is inlined into others.
+    len: usize,
+    // The line of the function's name when it was compiled, which the positions in its source map are moved along from.
+    name_line: u32
+}
+
+impl CachedFunction {
+    // Copies the code, moving its source map along to where the function now is, which may be on different lines.
+    fn relocated_code(&self, name_ref: &FileRef) -> ObjectCode {
+        let moved_by = name_ref.line_index as i64 - self.name_line as i64;
+        let mut code = self.code.clone();
+        for position in code.source_map.iter_mut().flatten() {
+            position.file = name_ref.file.clone();
+            position.line_index = (position.line_index as i64 + moved_by) as u32;
+            position.end_line_index = (position.end_line_index as i64 + moved_by) as u32;
+        }
+
+        code
+    }
+}
+
+// A global variable used by an object file.
+#[derive(Serialize, Deserialize)]
+pub struct ObjectGlobal {
+    pub name: String,
+    // The address the object's code uses for the global, which is changed to its address in the program when linking.
+    pub address: i32,
+    // False for globals defined by another object, e.g. those in an imported file.
+    pub defined: bool
+}
+
+// A function defined or called by an object file.
+#[derive(Serialize, Deserialize)]
+pub struct ObjectFunction {
+    pub name: String,
+    pub arg_count: usize,
+    pub returns_value: bool,
+    // None for functions defined by another object, e.g. those in an imported file.
+    pub code: Option<ObjectCode>,
+    // Positions are only known when linking straight after compiling, not when reading an object file.
+    #[serde(skip)]
+    pub name_ref: Option<FileRef>,
+    #[serde(skip)]
+    pub argument_list_ref: Option<FileRef>
+}
+
+// The compiled code of a function in an object file.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ObjectCode {
+    // Jumps are relative to the start of the function.
+    pub instructions: Vec<Instruction>,
+    #[serde(skip)]
+    pub source_map: SourceMap,
+    pub stack_usage: StackUsage,
+    // The ids of the functions inlined into this one, which are used even if they are never called.
+    #[serde(default)]
+    pub inlined: Vec<i32>
 }
 
 // Keeps track of the state of compilation within a particular function.
 struct CompileCtx<'a> {
     instructions: Vec<Instruction>,
+    // Has an entry for each instruction.
+    source_map: SourceMap,
+    // The position of the statement being compiled, which is given to each instruction emitted.
+    position: Option<FileRef>,
     // Current size of the stack.
     // Instructions such as LOAD and SAVE are relative to the top of the stack.
     // Keeping track of the stack size allows us to use certain stack values as local variables.
@@ -52,10 +389,41 @@ struct CompileCtx<'a> {
     scopes: Vec<Scope>,
     // The offset of the return value of the function from the bottom of the stack for this function.
     return_value_save_offset: Option<i32>,
-    function_ids_in_module: &'a mut HashMap<String, FunctionInfo>
+    // The name of the function being compiled, which is None for the global initializers.
+    function_name: Option<String>,
+    // The offset of each argument of the function from the bottom of the stack, in order.
+    argument_offsets: Vec<i32>,
+    function_ids_in_module: &'a mut HashMap<String, FunctionInfo>,
+    // The address of each global variable, which are used if there is no local variable with the same name.
+    globals: &'a HashMap<String, i32>,
+    options: &'a CompileOptions,
+    // The functions whose bodies replace calls to them.
+    inlined_functions: &'a HashMap<String, Function>,
+    // The ids of the functions that have been inlined.
+    inlined: Vec<i32>,
+    tick_signal_uses: &'a mut TickSignalUses,
+    warnings: &'a mut Vec<Warning>,
+    stack_usage: StackUsage
 }
 
 impl <'a> CompileCtx<'a> {
+    // Emits a jump whose address is set later with `patch_jump`.
+    fn emit_jump_placeholder(&mut self, jump: fn(i32) -> Instruction) -> JumpPlaceholder {
+        let idx = self.instructions.len();
+        self.emit(jump(-1));
+        JumpPlaceholder { idx, jump }
+    }
+
+    // Gets the address of the next instruction to be emitted.
+    fn next_address(&self) -> i32 {
+        self.instructions.len() as i32 + 1
+    }
+
+    // Sets the address of a jump emitted by `emit_jump_placeholder`.
+    fn patch_jump(&mut self, placeholder: JumpPlaceholder, addr: i32) {
+        self.instructions[placeholder.idx] = (placeholder.jump)(addr);
+    }
+
     // Creates a new scope with the given state.
     fn open_scope(&mut self, scope_type: ScopeState) {
         self.scopes.push(Scope {
@@ -68,11 +436,30 @@ impl <'a> CompileCtx<'a> {
     // Ends the current scope and returns its state.
     fn end_scope(&mut self) -> ScopeState {
         let scope: Scope = self.scopes.pop().expect("No scope to end");
+        // Arguments are also variables of the scope of a function, but were pushed before it was opened.
+        let local_count = scope.scope_vars.values()
+            .filter(|variable| variable.offset >= scope.starting_stack_size)
+            .count() as i32;
+        debug_assert_eq!(self.stack_size - scope.starting_stack_size, local_count,
+            "The stack should only hold the local variables of the scope when it ends. The stack effect of an instruction is wrong");
 
         for _ in 0..(self.stack_size - scope.starting_stack_size) {
             self.emit(Instruction::Pop);
         }
 
+        // Names beginning with `_` are unused on purpose.
+        let mut unused: Vec<(String, Variable)> = scope.scope_vars.into_iter()
+            .filter(|(name, variable)| !variable.is_read && !name.starts_with('_'))
+            .collect();
+        unused.sort_by_key(|(_, variable)| (variable.name_ref.line_index, variable.name_ref.begin_char_index));
+
+        for (name, variable) in unused {
+            // Arguments are below the bottom of the stack for the function.
+            let description = if variable.offset < 0 { "Argument" } else { "Variable" };
+            self.warnings.push(warning!(WarningKind::UnusedVariable, variable.name_ref,
+                "{description} `{name}` is never read. If this is intended, rename it to `_{name}`"));
+        }
+
         scope.scope_type
     }
 
@@ -83,11 +470,13 @@ impl <'a> CompileCtx<'a> {
         let scope: &Scope = &self.scopes[scope_idx];
         for _ in 0..(self.stack_size - scope.starting_stack_size) {
             self.instructions.push(Instruction::Pop);
+            self.source_map.push(self.position.clone());
         }
     }
 
     fn emit(&mut self, instruction: Instruction) {
         self.instructions.push(instruction);
+        self.source_map.push(self.position.clone());
         self.stack_size += match instruction {
             Instruction::JumpIfNonZero(_) => -1,
             Instruction::JumpIfZero(_) => -1,
@@ -112,39 +501,70 @@ impl <'a> CompileCtx<'a> {
             Instruction::GreaterThanOrEqual => -1,
             Instruction::LessThanOrEqual => -1,
             Instruction::Pop => -1,
+            Instruction::Duplicate => 1,
+            Instruction::Negate => 0,
+            // The return address pushed by JSR (with the interleaved convention) is popped by RET before the
+            // caller continues, so a call has no overall effect. The return value space and arguments are pushed
+            // and popped by separate instructions in `emit_call`.
+            Instruction::JumpSubRoutine(_) => 0,
             _ => 0
-        }
+        };
+
+        self.stack_usage.peak = self.stack_usage.peak.max(self.stack_size);
+    }
+
+    // Gets the index of the outermost scope of the function being compiled, which is the body of the innermost inlined function if there is one.
+    // Only the variables and loops from this scope onwards can be used.
+    fn frame_start(&self) -> usize {
+        self.scopes.iter()
+            .rposition(|scope| matches!(scope.scope_type, ScopeState::Inlined { .. }))
+            .unwrap_or(0)
     }
 
-    fn get_variable_pos(&self, name: String, name_ref: FileRef) -> CompileResult<i32> {
-        for scope in self.scopes.iter() {
-            match scope.scope_vars.get(&name) {
-                Some(offset) => return Ok(*offset),
-                None => {}
+    // Finds a variable in the scopes that are open, beginning with the innermost.
+    fn get_variable_pos(&self, name: &str) -> Option<i32> {
+        for scope in self.scopes[self.frame_start()..].iter().rev() {
+            if let Some(variable) = scope.scope_vars.get(name) {
+                return Some(variable.offset);
             }
         }
 
-        error!(name_ref, "No variable exists with this name")
+        None
     }
 
     fn get_variable_address(&self, name: String, name_ref: FileRef, reading: bool) -> CompileResult<i32> {
-        if name.starts_with("signal_") {
-            let signal_number = match name[7..].parse::<i32>() {
+        if let Some(signal_number_str) = name.strip_prefix("signal_") {
+            let signal_number = match signal_number_str.parse::<i32>() {
                 Ok(num) => num,
                 Err(_) => return error!(name_ref, "Signal number must be a valid integer")
             };
 
-            if signal_number <= 0 || signal_number > SIGNAL_COUNT {
-                return error!(name_ref, "Invalid signal number. Must be in range [0-{}]", SIGNAL_COUNT)
+            let signal_count = self.options.signal_count;
+            if signal_number <= 0 || signal_number > signal_count {
+                error!(name_ref, "Invalid signal number. Must be in range [1-{}]", signal_count)
             }   else {
-                Ok(if reading { -(SIGNAL_COUNT + signal_number)} else { -signal_number })
+                // The same name refers to both the output and the input signal, which are separate ports,
+                // so a read doesn't give back the value last written.
+                Ok(if reading { -(signal_count + signal_number)} else { -signal_number })
             }
 
-        }   else {
-            let offset_from_bottom_of_stack = self.get_variable_pos(name, name_ref)?;
-
+        }   else if let Some(offset_from_bottom_of_stack) = self.get_variable_pos(&name) {
             // Stack addresses are 1 indexed, 1 is the topmost value in the stack
             Ok(self.stack_size - offset_from_bottom_of_stack)
+        }   else if let Some(address) = self.globals.get(&name) {
+            Ok(*address)
+        }   else {
+            let signals: Vec<String> = (1..=self.options.signal_count).map(|signal_number| format!("signal_{signal_number}")).collect();
+            let visible_names = self.scopes[self.frame_start()..].iter()
+                .flat_map(|scope| scope.scope_vars.keys())
+                .chain(self.globals.keys())
+                .chain(&signals)
+                .map(String::as_str);
+
+            match error_handling::suggest_similar(&name, visible_names) {
+                Some(similar) => error!(name_ref, "No variable exists with this name, did you mean `{similar}`?"),
+                None => error!(name_ref, "No variable exists with this name")
+            }
         }
     }
 
@@ -154,17 +574,105 @@ impl <'a> CompileCtx<'a> {
     }
 
     fn load_from_variable(&mut self, name: String, name_ref: FileRef) -> CompileResult<()> {
-        self.emit(Instruction::Load(self.get_variable_address(name, name_ref, true)?));
+        let frame_start = self.frame_start();
+        if let Some(variable) = self.scopes[frame_start..].iter_mut().rev().find_map(|scope| scope.scope_vars.get_mut(&name)) {
+            variable.is_read = true;
+        }
+
+        let address = self.get_variable_address(name, name_ref.clone(), true)?;
+        self.load_address(address, name_ref);
         Ok(())
     }
 
-    fn add_variable(&mut self, name: String) {
-        self.scopes.last_mut().expect("No scope to add variable within").scope_vars.insert(name, self.stack_size - 1);
+    // Loads from an address, which may be a variable or a signal read at `position`.
+    fn load_address(&mut self, address: i32, position: FileRef) {
+        // Local and global variables never have the address of a signal, so this can only be the signal itself.
+        if address == tick_signal_address(self.options) && self.tick_signal_uses.signal_read.is_none() {
+            self.tick_signal_uses.signal_read = Some(position);
+        }
+
+        self.emit(Instruction::Load(address));
+    }
+
+    // Finds the variable in scope with the name most similar to `name`, if any are close enough that
+    // `name` may be a typo of it.
+    fn find_similar_variable(&self, name: &str) -> Option<&str> {
+        // Names that only differ in a number at the end, e.g. `a1` and `a2`, are usually meant to be different variables.
+        let without_number = |name: &str| name.trim_end_matches(|c: char| c.is_ascii_digit()).to_owned();
+        let candidates = self.scopes[self.frame_start()..].iter()
+            .flat_map(|scope| scope.scope_vars.keys())
+            .chain(self.globals.keys())
+            .map(String::as_str)
+            .filter(|existing| without_number(existing) != without_number(name));
+
+        error_handling::suggest_similar(name, candidates)
+    }
+
+    fn add_variable(&mut self, name: String, name_ref: FileRef) {
+        let variable = Variable::new(self.stack_size - 1, name_ref);
+        self.scopes.last_mut().expect("No scope to add variable within").scope_vars.insert(name, variable);
+    }
+
+    // Checks if returning the value of a call can reuse the stack of the function being compiled, which is the case when it calls itself.
+    fn is_tail_call(&self, call: &Call) -> bool {
+        self.options.optimize
+            && self.return_value_save_offset.is_some()
+            && self.function_name.as_deref() == Some(call.function_name.as_str())
+            && self.argument_offsets.len() == call.arguments.len()
+            // The return value of an inlined function is on the stack of the function it was inlined into.
+            && self.frame_start() == 0
+    }
+}
+
+// Checks if every path through a block ends in a `return` statement, or stops the program with `halt()`.
+// A while loop never counts, even if its body returns, as the condition may be false on entry.
+// A do-while loop runs its body at least once, so counts if its body returns and it has no `break` or `continue` leading to the condition.
+fn always_returns(block: &[Statement]) -> bool {
+    block.iter().any(|statement| match statement {
+        Statement::Return(_) | Statement::ReturnValue { .. } => true,
+        Statement::Call(call) => call.function_name == HALT_FUNCTION,
+        Statement::If { segments, r#else: Some(else_block) } => segments.iter().all(|segment| always_returns(&segment.block))
+            && always_returns(else_block),
+        Statement::Block(block) => always_returns(block),
+        Statement::While { block, is_do_while: true, .. } => always_returns(block) && !has_loop_jump(block),
+        _ => false
+    })
+}
+
+// Checks if a statement always leaves the block containing it with a `return`, `break`, `continue` or `halt()`,
+// so that the statements after it can never run. Like `always_returns`, a while loop never counts, but a do-while loop does
+// if its body always exits without a `break` or `continue`, which would leave the loop rather than the enclosing block.
+pub fn always_exits(statement: &Statement) -> bool {
+    match statement {
+        Statement::Return(_) | Statement::ReturnValue { .. } | Statement::Break(_) | Statement::Continue(_) => true,
+        Statement::Call(call) => call.function_name == HALT_FUNCTION,
+        Statement::If { segments, r#else: Some(else_block) } => segments.iter().all(|segment| segment.block.iter().any(always_exits))
+            && else_block.iter().any(always_exits),
+        Statement::Block(block) => block.iter().any(always_exits),
+        Statement::While { block, is_do_while: true, .. } => block.iter().any(always_exits) && !has_loop_jump(block),
+        _ => false
     }
 }
 
-fn compile_function(function: Function, functions_in_module: &mut HashMap<String, FunctionInfo>) 
-    -> CompileResult<Vec<Instruction>> {
+// Checks if a loop body contains a `break` or `continue` for the loop itself, rather than for a loop nested within it.
+fn has_loop_jump(block: &[Statement]) -> bool {
+    block.iter().any(|statement| match statement {
+        Statement::Break(_) | Statement::Continue(_) => true,
+        Statement::If { segments, r#else } => segments.iter().any(|segment| has_loop_jump(&segment.block))
+            || r#else.as_deref().is_some_and(has_loop_jump),
+        Statement::Block(block) => has_loop_jump(block),
+        _ => false
+    })
+}
+
+// Gets the address used to read the tick counter signal.
+fn tick_signal_address(options: &CompileOptions) -> i32 {
+    -(options.signal_count + options.tick_signal)
+}
+
+fn compile_function(function: Function, functions_in_module: &mut HashMap<String, FunctionInfo>, globals: &HashMap<String, i32>,
+    options: &CompileOptions, inlined_functions: &HashMap<String, Function>, tick_signal_uses: &mut TickSignalUses,
+    warnings: &mut Vec<Warning>) -> CompileResult<ObjectCode> {
     // Calling convention is to push
     // - a space for the return value to end up.
     // - the arguments
@@ -172,18 +680,25 @@ fn compile_function(function: Function, functions_in_module: &mut HashMap<String
 
     // 0 is the first variable in our function,
     // so -1 is the return address
-    // -2 is the start of the arguments
+    // -2 is the last argument, and -1 -arg_count is the first
     // -2 -arg_count is the return value
 
+    // With a split stack, the return address is not on the data stack, so the last argument is at -1
+    // and the return value at -1 -arg_count instead.
+
     let mut scope_vars = HashMap::new();
 
-    let arguments_start = -1 - function.argument_names.len() as i32;
-    for (idx, argument) in function.argument_names.iter().enumerate() {
-        scope_vars.insert(argument.clone(), arguments_start + idx as i32);
+    let arguments_start = -return_address_slots(options) - function.argument_names.len() as i32;
+    let argument_offsets = (0..function.argument_names.len() as i32).map(|idx| arguments_start + idx).collect();
+    for (idx, (argument, argument_ref)) in function.argument_names.iter().zip(function.argument_name_refs).enumerate() {
+        scope_vars.insert(argument.clone(), Variable::new(arguments_start + idx as i32, argument_ref));
     }
 
     let mut ctx = CompileCtx {
         instructions: Vec::new(),
+        source_map: Vec::new(),
+        // Instructions outside of any statement, such as the final return, belong to the function itself.
+        position: Some(function.name_ref.clone()),
         stack_size: 0,
         scopes: vec![Scope {
             scope_type: ScopeState::Other,
@@ -195,258 +710,1412 @@ fn compile_function(function: Function, functions_in_module: &mut HashMap<String
         }   else    {
             None
         },
-        function_ids_in_module: functions_in_module
+        function_name: Some(function.name),
+        argument_offsets,
+        function_ids_in_module: functions_in_module,
+        globals,
+        options,
+        inlined_functions,
+        inlined: Vec::new(),
+        tick_signal_uses,
+        warnings,
+        stack_usage: StackUsage::default()
     };
 
-    emit_block(function.block, &mut ctx)?;
+    let mut errors = Vec::new();
+    if function.returns_value && !always_returns(&function.block) {
+        errors.push(FileTaggedError {
+            position: Some(function.name_ref),
+            msg: "Not all paths through this function return a value".to_owned(),
+            notes: Vec::new()
+        });
+    }
+
+    if let Err(mut err) = emit_block(function.block, &mut ctx) {
+        errors.append(&mut err.0);
+    }
+
+    if !errors.is_empty() {
+        return Err(CompileErrors(errors));
+    }
 
     ctx.end_scope();
     if ctx.instructions.last() != Some(&Instruction::Return) {
         ctx.emit(Instruction::Return);
     }
 
-    Ok(ctx.instructions)
-
+    Ok(ObjectCode {
+        instructions: ctx.instructions,
+        source_map: ctx.source_map,
+        stack_usage: ctx.stack_usage,
+        inlined: ctx.inlined
+    })
 }
 
-pub fn compile_module(module: Vec<Function>) -> CompileResult<Vec<Instruction>> {
-    let mut functions_by_name = HashMap::new();
-    for (idx, function) in module.iter().enumerate() {
-        if functions_by_name.contains_key(&function.name) {
-            return error!(function.name_ref.clone(), "A function with this name already exists - overloading is not supported");
-        }
-
-        functions_by_name.insert(function.name.clone(), FunctionInfo {
-            id: idx as i32,
-            arg_count: function.argument_names.len(),
-            returns_value: function.returns_value,
-            start_offset: -1
-        });
-    }
+// Compiles the code that assigns each global its initial value, which runs before `main` is called.
+fn compile_global_initializers(globals: Vec<Global>, functions_in_module: &mut HashMap<String, FunctionInfo>, global_addresses: &HashMap<String, i32>,
+    options: &CompileOptions, tick_signal_uses: &mut TickSignalUses, warnings: &mut Vec<Warning>) -> CompileResult<(Vec<Instruction>, SourceMap, StackUsage)> {
+    let mut ctx = CompileCtx {
+        instructions: Vec::new(),
+        source_map: Vec::new(),
+        position: None,
+        stack_size: 0,
+        scopes: vec![Scope {
+            scope_type: ScopeState::Other,
+            starting_stack_size: 0,
+            scope_vars: HashMap::new()
+        }],
+        return_value_save_offset: None,
+        function_name: None,
+        argument_offsets: Vec::new(),
+        function_ids_in_module: functions_in_module,
+        globals: global_addresses,
+        options,
+        // Functions can't be called when initializing a global variable.
+        inlined_functions: &HashMap::new(),
+        inlined: Vec::new(),
+        tick_signal_uses,
+        warnings,
+        stack_usage: StackUsage::default()
+    };
 
-    let mut functions_by_idx = Vec::new();
-    let mut compiled_funs = Vec::new();
     let mut errors = Vec::new();
-    for function in module {
-        functions_by_idx.push(*functions_by_name.get(&function.name).unwrap());
+    for global in globals {
+        let initializer = match global.initializer {
+            Some(initializer) => initializer,
+            None => continue
+        };
+        ctx.position = Some(global.name_ref);
+
+        // Calls would need to be followed when finding which functions are used and how deep the stack gets.
+        let mut calls = FirstCall::default();
+        calls.visit_expression(&initializer);
+        if let Some(call) = calls.call {
+            errors.push(FileTaggedError {
+                position: Some(call.function_name_ref.clone()),
+                msg: "Functions cannot be called when initializing a global variable".to_owned(),
+                notes: Vec::new()
+            });
+            continue;
+        }
 
-        match compile_function(function, &mut functions_by_name) {
-            Ok(code) => compiled_funs.push(code),
-            Err(mut err) => errors.append(&mut err.0) 
+        if let Err(mut err) = emit_expression(initializer, &mut ctx) {
+            errors.append(&mut err.0);
+            continue;
         }
+        ctx.emit(Instruction::Save(global_addresses[&global.name]));
     }
 
-    if !errors.is_empty() {
-        return Err(CompileErrors(errors))
+    if errors.is_empty() {
+        Ok((ctx.instructions, ctx.source_map, ctx.stack_usage))
+    }   else {
+        Err(CompileErrors(errors))
     }
+}
 
-    let main_idx = match functions_by_name.get(ENTRY_POINT) {
-        Some(main) => {
-            if main.returns_value {
-                return untagged_err!("Entry point cannot return a value");
-            }
+// Finds the first call within an expression.
+#[derive(Default)]
+struct FirstCall<'ast> {
+    call: Option<&'ast Call>
+}
 
-            if main.arg_count != 0 {
-                return untagged_err!("Entry point must have no arguments")
-            }
+impl <'ast> Visitor<'ast> for FirstCall<'ast> {
+    fn visit_call(&mut self, call: &'ast Call) {
+        self.call.get_or_insert(call);
+    }
+}
 
-            main.id
-        },
-        None => return untagged_err!("No entry point found: A zero-arg function returning void called {ENTRY_POINT} should be created"),
-    };
+// Collects the names of the functions called within a function.
+#[derive(Default)]
+struct CalledFunctions<'ast> {
+    names: HashSet<&'ast str>
+}
 
-    // Now need to link it, steps:
-    // Write all functions one-by-one into a new array of instructions, offsetting the jump instructions in the function by the start of that function
-    // Keep track of the start index of each function
-    // Overwrite JSR instructions with the correct index to jump to
+impl <'ast> Visitor<'ast> for CalledFunctions<'ast> {
+    fn visit_call(&mut self, call: &'ast Call) {
+        self.names.insert(&call.function_name);
+        visit::walk_call(self, call);
+    }
+}
 
-    let mut program = vec![
-        Instruction::JumpSubRoutine(main_idx),
-        Instruction::Jump(-1)
-    ];
+// Hashes what the code of the functions in a module depends on besides their own tokens: the options, and the values of the
+// constants and addresses of the globals, which may be used by any function.
+fn hash_module_context(constants: &[Constant], global_addresses: &HashMap<String, i32>, options: &CompileOptions) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (options.fold_constants, options.optimize, options.inline_threshold, options.call_convention, options.tick_signal, options.signal_count).hash(&mut hasher);
+    options.instruction_set.hash(&mut hasher);
+
+    // Constants have been replaced by their values by the time functions are compiled.
+    let mut constant_values: Vec<(&str, Option<i32>)> = constants.iter()
+        .map(|constant| match constant.value {
+            Expression::Literal(value) => (constant.name.as_str(), Some(value)),
+            _ => (constant.name.as_str(), None)
+        })
+        .collect();
+    constant_values.sort_unstable();
+    constant_values.hash(&mut hasher);
+
+    let mut globals: Vec<(&String, &i32)> = global_addresses.iter().collect();
+    globals.sort_unstable();
+    globals.hash(&mut hasher);
+
+    hasher.finish()
+}
 
+// Finds the key of a function's code in a `FunctionCache`, from its tokens, the context of the module, and the id, arguments
+// and return type of each function it calls, so that calls to a function are compiled again when its signature changes.
+fn function_cache_key(function: &Function, functions_by_name: &HashMap<String, FunctionInfo>, module_context: u64) -> u64 {
+    let mut calls = CalledFunctions::default();
+    calls.visit_function(function);
+    let mut called: Vec<&str> = calls.names.into_iter().collect();
+    called.sort_unstable();
+
+    let mut hasher = DefaultHasher::new();
+    (function.token_hash, module_context).hash(&mut hasher);
+    for name in called {
+        let signature = functions_by_name.get(name).map(|info| (info.id, info.arg_count, info.returns_value));
+        (name, signature).hash(&mut hasher);
+    }
 
-    // Write in all the functions, applying necessary offsets.
-    for idx in 0..functions_by_name.len() {
-        let offset = program.len() as i32;
-        functions_by_idx[idx].start_offset = offset;
+    hasher.finish()
+}
 
-        for instruction in &compiled_funs[idx] {
-            let offset_instruction = match *instruction {
-                Instruction::Jump(addr) => Instruction::Jump(addr + offset),
-                Instruction::JumpIfZero(addr) => Instruction::JumpIfZero(addr + offset),
-                Instruction::JumpIfNonZero(addr) => Instruction::JumpIfNonZero(addr + offset),
-                _ => *instruction
-            };
+// Finds the functions that can call themselves, either directly or through other functions, which are never inlined.
+fn find_recursive_functions(functions: &[Function]) -> HashSet<String> {
+    let calls: HashMap<&str, HashSet<&str>> = functions.iter()
+        .map(|function| {
+            let mut calls = CalledFunctions::default();
+            calls.visit_function(function);
+            (function.name.as_str(), calls.names)
+        })
+        .collect();
+
+    let mut recursive = HashSet::new();
+    for function in functions {
+        let mut visited = HashSet::new();
+        let mut to_visit: Vec<&str> = calls[function.name.as_str()].iter().copied().collect();
+        while let Some(name) = to_visit.pop() {
+            if name == function.name {
+                recursive.insert(function.name.clone());
+                break;
+            }
 
-            program.push(offset_instruction);
+            if visited.insert(name) {
+                to_visit.extend(calls.get(name).into_iter().flatten());
+            }
         }
     }
-    
 
-    // Overwrite JSR instructions
-    for instruction in program.iter_mut() {
-        if let Instruction::JumpSubRoutine(idx) = instruction {
-            *instruction = Instruction::JumpSubRoutine(functions_by_idx[*idx as usize].start_offset + 1)
-        }
+    recursive
+}
+
+// Gets the number of values JSR pushes to the data stack.
+fn return_address_slots(options: &CompileOptions) -> i32 {
+    match options.call_convention {
+        CallConvention::Interleaved => 1,
+        CallConvention::SplitStack => 0
     }
+}
 
-    Ok(program)
+#[derive(Clone, Copy)]
+enum DepthState {
+    Unvisited,
+    // The function is in the chain of calls currently being followed, so calling it again is recursion.
+    Visiting,
+    Visited(Option<i32>)
 }
 
-fn emit_block(block: Vec<Statement>, ctx: &mut CompileCtx) -> CompileResult<()> {
-    let mut errors = Vec::new();
+// Follows the calls between functions to find the maximum depth of the stack.
+struct StackDepthAnalysis<'a> {
+    stack_usages: &'a [StackUsage],
+    return_address_slots: i32,
+    states: Vec<DepthState>,
+    // The ids of the functions in the chain of calls currently being followed.
+    call_chain: Vec<i32>,
+    // Each cycle of calls found, as the function ids in the cycle, with the first repeated at the end.
+    cycles: Vec<Vec<i32>>
+}
 
-    for statement in block {
-        if let Err(mut err) = emit_statement(statement, ctx) {
-            errors.append(&mut err.0);
+impl StackDepthAnalysis<'_> {
+    // Finds the largest number of values on the stack while running a function, including the functions it calls.
+    // Returns None if the function can call itself, directly or indirectly, or calls a function written in assembly.
+    fn find_max_depth(&mut self, idx: i32) -> Option<i32> {
+        match self.states[idx as usize] {
+            DepthState::Visited(depth) => return depth,
+            DepthState::Visiting => {
+                let cycle_start = self.call_chain.iter().position(|called| *called == idx).unwrap();
+                let mut cycle = self.call_chain[cycle_start..].to_vec();
+                cycle.push(idx);
+
+                if !self.cycles.contains(&cycle) {
+                    self.cycles.push(cycle);
+                }
+                return None;
+            },
+            DepthState::Unvisited => {}
+        }
+
+        self.states[idx as usize] = DepthState::Visiting;
+        self.call_chain.push(idx);
+
+        // All calls are followed, even once the depth is known to be unbounded, so that every cycle is found.
+        let usage = &self.stack_usages[idx as usize];
+        let mut depth = (!usage.hand_written).then_some(usage.peak);
+        for &(callee, stack_size) in &usage.calls {
+            let callee_depth = self.find_max_depth(callee);
+            depth = match (depth, callee_depth) {
+                (Some(depth), Some(callee_depth)) => Some(depth.max(stack_size + self.return_address_slots + callee_depth)),
+                _ => None
+            };
         }
+
+        self.call_chain.pop();
+        self.states[idx as usize] = DepthState::Visited(depth);
+        depth
     }
+}
 
-    if errors.is_empty() {
-        Ok(())
-    }   else    {
-        Err(CompileErrors(errors))
+// Finds the functions that can be called, directly or indirectly, from the function with id `entry_idx`.
+// The JSR instructions in the compiled functions must still contain function ids.
+fn find_reachable_functions(compiled_funs: &[Vec<Instruction>], entry_idx: i32) -> Vec<bool> {
+    let mut reachable = vec![false; compiled_funs.len()];
+    reachable[entry_idx as usize] = true;
+
+    let mut to_visit = vec![entry_idx];
+    while let Some(idx) = to_visit.pop() {
+        for instruction in &compiled_funs[idx as usize] {
+            if let Instruction::JumpSubRoutine(callee) = *instruction {
+                if !reachable[callee as usize] {
+                    reachable[callee as usize] = true;
+                    to_visit.push(callee);
+                }
+            }
+        }
     }
+
+    reachable
 }
 
-fn emit_statement(statement: Statement, ctx: &mut CompileCtx) -> CompileResult<()> {
-    match statement {
-        Statement::Assignment { variable_name, value, variable_name_ref } => {
-            emit_expression(value, ctx)?;
-            match ctx.save_to_variable(variable_name.clone(), variable_name_ref) {
-                Ok(_) => {},
-                Err(_) => ctx.add_variable(variable_name),
+// Finds functions with identical compiled code, and points all calls to them at a single copy.
+// Returns the id of the function whose code should be used in place of each function.
+// Unreachable functions are never merged, so that every reachable function is replaced by another reachable function.
+fn fold_identical_functions(compiled_funs: &mut [Vec<Instruction>], reachable: &[bool]) -> Vec<i32> {
+    let mut replacements: Vec<i32> = (0..compiled_funs.len() as i32).collect();
+
+    loop {
+        // Jumps within a function are relative to the start of that function until linking,
+        // but calls still refer to function ids, which must point at the surviving copy for
+        // callers of two merged functions to compare as identical.
+        for code in compiled_funs.iter_mut() {
+            for instruction in code.iter_mut() {
+                if let Instruction::JumpSubRoutine(id) = instruction {
+                    while replacements[*id as usize] != *id {
+                        *id = replacements[*id as usize];
+                    }
+                }
             }
+        }
 
-            Ok(())
-        },
-        Statement::If { segments, r#else } => {
-            let mut skip_else_instruction_idxs = Vec::new();
+        let mut first_with_code: HashMap<&[Instruction], i32> = HashMap::new();
+        let mut merged_any = false;
+        for (idx, code) in compiled_funs.iter().enumerate() {
+            if replacements[idx] != idx as i32 || !reachable[idx] {
+                continue;
+            }
 
-            let last_idx = segments.len() - 1;
-            for (idx, segment) in segments.into_iter().enumerate() {
-                let is_last = idx == last_idx;
+            match first_with_code.entry(code) {
+                Entry::Occupied(existing) => {
+                    replacements[idx] = *existing.get();
+                    merged_any = true;
+                },
+                Entry::Vacant(slot) => { slot.insert(idx as i32); }
+            }
+        }
 
-                emit_expression(segment.condition, ctx)?;
+        // Merging functions may have made their callers identical, so repeat until nothing changes.
+        if !merged_any {
+            break;
+        }
+    }
 
-                let jump_inst_idx = ctx.instructions.len();
-                ctx.emit(Instruction::JumpIfZero(-1)); // TODO: add in address later
+    // A function may have been merged into one that was itself merged later on.
+    for idx in 0..replacements.len() {
+        let mut id = replacements[idx];
+        while replacements[id as usize] != id {
+            id = replacements[id as usize];
+        }
+        replacements[idx] = id;
+    }
 
-                ctx.open_scope(ScopeState::Other);
-                emit_block(segment.block, ctx)?;
-                ctx.end_scope();
+    replacements
+}
 
-                // After each if segment, add an instruction to skip the else segment
-                if !is_last || r#else.is_some() {
-                    skip_else_instruction_idxs.push(ctx.instructions.len());
-                    ctx.emit(Instruction::Jump(-1)); // TODO: add in address later
-                }
+// Compiles and links a module. Any warnings generated are added to `warnings`.
+pub fn compile_module(module: Vec<Item>, options: &CompileOptions, warnings: &mut Vec<Warning>) -> CompileResult<CompiledModule> {
+    compile_module_with_objects(module, Vec::new(), options, None, warnings)
+}
 
-                // Skip over the if block if the condition is false
-                ctx.instructions[jump_inst_idx] = Instruction::JumpIfZero(ctx.instructions.len() as i32 + 1);
-            }
+// Compiles a module and links it with `objects`, e.g. functions written in assembly, which the module can call.
+// With a `cache`, the code of functions that haven't changed since it was last used is reused.
+pub fn compile_module_with_objects(module: Vec<Item>, mut objects: Vec<ObjectFile>, options: &CompileOptions, cache: Option<&mut FunctionCache>,
+    warnings: &mut Vec<Warning>) -> CompileResult<CompiledModule> {
+    let object = compile_object(module, None, &objects, options, cache, warnings)?;
+    objects.insert(0, object);
+    link(objects, options, warnings)
+}
 
-            match r#else {
-                Some(else_block) => {
-                    ctx.open_scope(ScopeState::Other);
-                    emit_block(else_block, ctx)?;
-                    ctx.end_scope();
+// Compiles the functions and globals of a module without placing them in the program, so that they can be linked later.
+// With `root`, only the items in that file are compiled, and those from the files it imports are left to be defined by other objects.
+// The module can call the functions defined by `external_objects`, which must be linked with the object.
+// With a `cache`, functions are only compiled if they have changed since it was last used, and the cache is then updated.
+pub fn compile_object(mut module: Vec<Item>, root: Option<&Arc<SourceFile>>, external_objects: &[ObjectFile], options: &CompileOptions,
+    cache: Option<&mut FunctionCache>, warnings: &mut Vec<Warning>) -> CompileResult<ObjectFile> {
+    folding::substitute_constants(&mut module)?;
+    if options.fold_constants {
+        folding::fold_module(&mut module)?;
+    }
+    lints::check_shift_amounts(&module, warnings);
+
+    // Constants have been replaced by their values, so are no longer needed.
+    let mut functions = Vec::new();
+    let mut globals = Vec::new();
+    let mut constants = Vec::new();
+    for item in module {
+        match item {
+            Item::Function(function) => functions.push(function),
+            Item::Global(global) => globals.push(global),
+            Item::Constant(constant) => constants.push(constant)
+        }
+    }
 
-                    for idx in skip_else_instruction_idxs {
-                        ctx.instructions[idx] = Instruction::Jump(ctx.instructions.len() as i32 + 1)
-                    }
-                },
-                None => {}
-            }
-            
+    let is_defined = |name_ref: &FileRef| root.is_none_or(|root| Arc::ptr_eq(root, &name_ref.file));
 
-            Ok(())
-        },
-        Statement::While { condition, block } => {
-            // Unconditional jump to end of loop
-            let uncond_jump_idx = ctx.instructions.len();
-            ctx.emit(Instruction::Jump(-1)); // TODO: set address later.
+    let mut global_addresses = HashMap::new();
+    for global in &globals {
+        if global_addresses.contains_key(&global.name) {
+            return error!(global.name_ref.clone(), "A global variable with this name already exists");
+        }
 
-            ctx.open_scope(ScopeState::While {
-                continue_inst_addresses: Vec::new(),
-                break_inst_addresses: Vec::new()
-            });
-            emit_block(block, ctx)?;
-            let scope_state = ctx.end_scope();
+        if global.name.starts_with("signal_") {
+            return error!(global.name_ref.clone(), "Global variables cannot be named `signal_N`, as these names are used to access the GPIO");
+        }
 
-            let (continue_inst_addresses, break_inst_addresses) = match scope_state {
-                ScopeState::While { continue_inst_addresses, break_inst_addresses } => (continue_inst_addresses, break_inst_addresses),
-                _ => unreachable!()
-            };
+        global_addresses.insert(global.name.clone(), first_global_address(options.signal_count) - global_addresses.len() as i32);
+    }
 
-            let continue_instruction = Instruction::Jump(ctx.instructions.len() as i32 + 1);
+    let object_globals = globals.iter()
+        .map(|global| ObjectGlobal {
+            name: global.name.clone(),
+            address: global_addresses[&global.name],
+            defined: is_defined(&global.name_ref)
+        })
+        .collect();
+
+    let mut functions_by_name: HashMap<String, FunctionInfo> = HashMap::new();
+    for (idx, function) in functions.iter().enumerate() {
+        if let Some(existing) = functions_by_name.get(&function.name) {
+            return Err(CompileErrors(vec![FileTaggedError {
+                position: Some(function.name_ref.clone()),
+                msg: "A function with this name already exists - overloading is not supported".to_owned(),
+                notes: vec![(functions[existing.id as usize].name_ref.clone(), format!("`{}` is first defined here", function.name))]
+            }]));
+        }
 
-            ctx.instructions[uncond_jump_idx] = continue_instruction;
-            for addr in continue_inst_addresses {
-                ctx.instructions[addr] = continue_instruction;
-            }
-            
-            emit_expression(condition, ctx)?;
-            ctx.emit(Instruction::JumpIfNonZero(uncond_jump_idx as i32 + 2));
+        if BUILTIN_FUNCTIONS.contains(&function.name.as_str()) {
+            return error!(function.name_ref.clone(), "`{}` is a builtin function and cannot be redefined", function.name);
+        }
 
-            let break_instruction = Instruction::Jump(ctx.instructions.len() as i32 + 1);
-            for addr in break_inst_addresses {
-                ctx.instructions[addr] = break_instruction;
-            }
-            
-            Ok(())
-        },
+        functions_by_name.insert(function.name.clone(), FunctionInfo {
+            id: idx as i32,
+            arg_count: function.argument_names.len(),
+            argument_list_ref: Some(function.argument_list_ref.clone()),
+            returns_value: function.returns_value
+        });
+    }
+
+    // Functions defined by other objects, e.g. those written in assembly, are declared after the functions of the module.
+    let external_functions: Vec<&ObjectFunction> = external_objects.iter()
+        .flat_map(|object| &object.functions)
+        .filter(|function| function.code.is_some())
+        .collect();
+    for (idx, function) in external_functions.iter().enumerate() {
+        if let Some(existing) = functions_by_name.get(&function.name) {
+            return Err(CompileErrors(vec![FileTaggedError {
+                position: function.name_ref.clone(),
+                msg: format!("Function `{}` is defined both here and in the program", function.name),
+                notes: vec![(functions[existing.id as usize].name_ref.clone(), format!("`{}` is also defined here", function.name))]
+            }]));
+        }
+
+        functions_by_name.insert(function.name.clone(), FunctionInfo {
+            id: (functions.len() + idx) as i32,
+            arg_count: function.arg_count,
+            argument_list_ref: function.argument_list_ref.clone(),
+            returns_value: function.returns_value
+        });
+    }
+
+    // With `optimize`, the functions are kept so that those calling small functions can be compiled again with the calls inlined.
+    let recursive_functions = if options.optimize { find_recursive_functions(&functions) } else { HashSet::new() };
+    let mut kept_functions = Vec::new();
+    let mut inlined_functions = HashMap::new();
+
+    // The `folding` dump is compiled separately, since the changes made by `optimize` are part of the first compilation.
+    let unoptimized_options = options.dump_after.contains(&Pass::Folding)
+        .then(|| CompileOptions { optimize: false, dump_after: Vec::new(), ..options.clone() });
+    let mut folded_functions = Vec::new();
+
+    let module_context = cache.is_some().then(|| hash_module_context(&constants, &global_addresses, options));
+    // The index and key of each function compiled that can be added to the cache, and the indices of those reused from it.
+    let mut uncached_functions = Vec::new();
+    let mut cached_functions = HashMap::new();
+
+    let mut object_functions = Vec::new();
+    let mut errors = Vec::new();
+    let mut tick_signal_uses = TickSignalUses::default();
+    for function in functions {
+        let mut object_function = ObjectFunction {
+            name: function.name.clone(),
+            arg_count: function.argument_names.len(),
+            returns_value: function.returns_value,
+            code: None,
+            name_ref: Some(function.name_ref.clone()),
+            argument_list_ref: Some(function.argument_list_ref.clone())
+        };
+
+        // Imported functions are compiled into their own object.
+        if is_defined(&function.name_ref) {
+            let cache_key = module_context.map(|module_context| function_cache_key(&function, &functions_by_name, module_context));
+            let cached = match (&cache, cache_key) {
+                (Some(cache), Some(key)) => cache.entries.get(&key),
+                _ => None
+            };
+
+            if let Some(unoptimized_options) = &unoptimized_options {
+                match cached.and_then(|cached| cached.folded.clone()) {
+                    Some(folded) => folded_functions.push((function.name.clone(), folded)),
+                    None => {
+                        let folded = compile_function(function.clone(), &mut functions_by_name, &global_addresses, unoptimized_options, &HashMap::new(),
+                            &mut TickSignalUses::default(), &mut Vec::new());
+                        if let Ok(code) = folded {
+                            folded_functions.push((function.name.clone(), code.instructions));
+                        }
+                    }
+                }
+            }
+
+            let kept_function = options.optimize.then(|| function.clone());
+            let compiled = match (cached, cache_key) {
+                (Some(cached), Some(key)) => {
+                    cached_functions.insert(object_functions.len(), key);
+                    Ok((cached.relocated_code(&function.name_ref), cached.len))
+                },
+                _ => {
+                    let warning_count = warnings.len();
+                    let mut function_tick_signal_uses = TickSignalUses::default();
+                    let compiled = compile_function(function, &mut functions_by_name, &global_addresses, options, &HashMap::new(),
+                        &mut function_tick_signal_uses, warnings);
+
+                    // Functions that give warnings or use the tick signal are compiled by every build, so that these are always found.
+                    let cacheable = warnings.len() == warning_count && function_tick_signal_uses.ticks_call.is_none() && function_tick_signal_uses.signal_read.is_none();
+                    if let (Some(key), true, Ok(_)) = (cache_key, cacheable, &compiled) {
+                        uncached_functions.push((object_functions.len(), key));
+                    }
+                    tick_signal_uses.add(function_tick_signal_uses);
+
+                    compiled.map(|code| {
+                        let len = code.instructions.len();
+                        (code, len)
+                    })
+                }
+            };
+
+            match compiled {
+                Ok((code, len)) => {
+                    if let Some(function) = kept_function {
+                        // The size is measured before inlining any of the functions it calls.
+                        if len <= options.inline_threshold && !recursive_functions.contains(&function.name) {
+                            inlined_functions.insert(function.name.clone(), function.clone());
+                        }
+                        kept_functions.push((object_functions.len(), function));
+                    }
+
+                    object_function.code = Some(code);
+                },
+                Err(mut err) => errors.append(&mut err.0)
+            }
+        }
+
+        object_functions.push(object_function);
+    }
+
+    object_functions.extend(external_functions.iter().map(|function| ObjectFunction {
+        name: function.name.clone(),
+        arg_count: function.arg_count,
+        returns_value: function.returns_value,
+        code: None,
+        name_ref: function.name_ref.clone(),
+        argument_list_ref: function.argument_list_ref.clone()
+    }));
+
+    let mut pass_dumps = Vec::new();
+    if unoptimized_options.is_some() {
+        pass_dumps.push(dump_functions(Pass::Folding, options.fold_constants, folded_functions.iter().map(|(name, code)| (name.as_str(), code.as_slice()))));
+    }
+    if options.dump_after.contains(&Pass::TailCalls) {
+        pass_dumps.push(dump_functions(Pass::TailCalls, options.optimize, defined_code(&object_functions)));
+    }
+
+    if errors.is_empty() && !inlined_functions.is_empty() {
+        for (idx, function) in kept_functions {
+            let mut calls = CalledFunctions::default();
+            calls.visit_function(&function);
+            if calls.names.is_disjoint(&inlined_functions.keys().map(String::as_str).collect()) {
+                continue;
+            }
+
+            // Only code without inlined calls is cached, since it doesn't record the code of the functions that were inlined.
+            cached_functions.remove(&idx);
+            uncached_functions.retain(|(uncached_idx, _)| *uncached_idx != idx);
+
+            // Compiling the same function again gives the same warnings.
+            let mut repeated_warnings = Vec::new();
+            object_functions[idx].code = Some(compile_function(function, &mut functions_by_name, &global_addresses, options,
+                &inlined_functions, &mut tick_signal_uses, &mut repeated_warnings)?);
+        }
+    }
+
+    if options.dump_after.contains(&Pass::Inlining) {
+        pass_dumps.push(dump_functions(Pass::Inlining, options.optimize, defined_code(&object_functions)));
+    }
+
+    let globals = globals.into_iter().filter(|global| is_defined(&global.name_ref)).collect();
+    let initializers = compile_global_initializers(globals, &mut functions_by_name, &global_addresses, options, &mut tick_signal_uses, warnings);
+    let (initializers, initializers_source_map, initializers_stack_usage) = match initializers {
+        Ok(compiled) => compiled,
+        Err(mut err) => {
+            errors.append(&mut err.0);
+            Default::default()
+        }
+    };
+
+    if !errors.is_empty() {
+        return Err(CompileErrors(errors))
+    }
+
+    if let TickSignalUses { ticks_call: Some(ticks_call), signal_read: Some(signal_read) } = tick_signal_uses {
+        return Err(CompileErrors(vec![FileTaggedError {
+            position: Some(signal_read),
+            msg: format!("signal_{} is connected to the tick counter read by `{TICKS_FUNCTION}()`, so cannot be read directly", options.tick_signal),
+            notes: vec![(ticks_call, format!("`{TICKS_FUNCTION}()` is called here"))]
+        }]));
+    }
+
+    if let Some(cache) = cache {
+        // Only the functions in this build are kept, so that the cache doesn't grow each time a function is changed.
+        let mut entries = HashMap::new();
+        for (idx, key) in &cached_functions {
+            if let Some(mut cached) = cache.entries.remove(key) {
+                if cached.folded.is_none() {
+                    cached.folded = folded_code(&folded_functions, &object_functions[*idx].name);
+                }
+                entries.insert(*key, cached);
+            }
+        }
+
+        for (idx, key) in uncached_functions {
+            let function = &object_functions[idx];
+            let code = function.code.clone().expect("Compiled functions have code");
+            entries.insert(key, CachedFunction {
+                folded: folded_code(&folded_functions, &function.name),
+                len: code.instructions.len(),
+                name_line: function.name_ref.as_ref().expect("Compiled functions have a position").line_index,
+                code
+            });
+        }
+
+        cache.entries = entries;
+        cache.hits = cached_functions.len();
+        cache.misses = object_functions.iter().filter(|function| function.code.is_some()).count() - cache.hits;
+    }
+
+    Ok(ObjectFile {
+        signal_count: options.signal_count,
+        call_convention: options.call_convention,
+        globals: object_globals,
+        initializers,
+        initializers_source_map,
+        initializers_stack_peak: initializers_stack_usage.peak,
+        functions: object_functions,
+        uses_ticks: tick_signal_uses.ticks_call.is_some(),
+        pass_dumps
+    })
+}
+
+// Gets the code recorded for a function by the `folding` pass, if it is being dumped.
+fn folded_code(folded_functions: &[(String, Vec<Instruction>)], name: &str) -> Option<Vec<Instruction>> {
+    folded_functions.iter().find(|(folded_name, _)| folded_name == name).map(|(_, code)| code.clone())
+}
+
+// Gets the name and code of each function defined by an object.
+fn defined_code(functions: &[ObjectFunction]) -> impl Iterator<Item = (&str, &[Instruction])> {
+    functions.iter().filter_map(|function| function.code.as_ref().map(|code| (function.name.as_str(), code.instructions.as_slice())))
+}
+
+// Records the code of each function before the functions are placed in the program.
+fn dump_functions<'a>(pass: Pass, enabled: bool, functions: impl Iterator<Item = (&'a str, &'a [Instruction])>) -> PassDump {
+    let mut instructions = Vec::new();
+    let mut function_spans = Vec::new();
+    for (name, code) in functions {
+        function_spans.push(FunctionSpan {
+            function_name: Some(name.to_owned()),
+            start: instructions.len(),
+            end: instructions.len() + code.len()
+        });
+        instructions.extend_from_slice(code);
+    }
+
+    PassDump { pass, enabled, linked: false, instructions, function_spans }
+}
+
+// Creates a warning about a function, which has no position if the function was read from an object file.
+fn function_warning(kind: WarningKind, name_ref: Option<FileRef>, msg: String) -> Warning {
+    Warning {
+        kind,
+        error: FileTaggedError {
+            position: name_ref,
+            msg,
+            notes: Vec::new()
+        }
+    }
+}
+
+// Points the loads and saves of global variables in an object's code at their addresses in the program.
+fn relocate_globals(instructions: &mut [Instruction], addresses: &HashMap<i32, i32>) {
+    for instruction in instructions {
+        if let Instruction::Load(address) | Instruction::Save(address) = instruction {
+            if let Some(linked_address) = addresses.get(address) {
+                *address = *linked_address;
+            }
+        }
+    }
+}
+
+// Places the functions of one or more objects into a program, which begins by initializing the globals then calling the entry point.
+// The functions are placed in the order of the objects, and every function and global must be defined by exactly one object.
+pub fn link(mut objects: Vec<ObjectFile>, options: &CompileOptions, warnings: &mut Vec<Warning>) -> CompileResult<CompiledModule> {
+    let mut pass_dumps: Vec<PassDump> = objects.iter_mut().flat_map(|object| std::mem::take(&mut object.pass_dumps)).collect();
+    if objects.iter().any(|object| object.signal_count != options.signal_count || object.call_convention != options.call_convention) {
+        return untagged_err!("An object file was compiled with a different signal count or calling convention to the one it is being linked with");
+    }
+    let uses_ticks = objects.iter().any(|object| object.uses_ticks);
+
+    // The id of each function is its index among the functions defined by all of the objects.
+    let mut ids_by_name: HashMap<&str, i32> = HashMap::new();
+    let mut global_addresses: HashMap<&str, i32> = HashMap::new();
+    for object in &objects {
+        for function in object.functions.iter().filter(|function| function.code.is_some()) {
+            if ids_by_name.insert(&function.name, ids_by_name.len() as i32).is_some() {
+                return untagged_err!("Function `{}` is defined by more than one object file", function.name);
+            }
+        }
+
+        for global in object.globals.iter().filter(|global| global.defined) {
+            let address = first_global_address(options.signal_count) - global_addresses.len() as i32;
+            if global_addresses.insert(&global.name, address).is_some() {
+                return untagged_err!("Global variable `{}` is defined by more than one object file", global.name);
+            }
+        }
+    }
+
+    // Maps the function ids and global addresses used within each object to those in the program.
+    let mut object_ids = Vec::new();
+    let mut object_addresses = Vec::new();
+    for object in &objects {
+        let mut ids = Vec::new();
+        for function in &object.functions {
+            let id = match ids_by_name.get(function.name.as_str()) {
+                Some(id) => *id,
+                None => return untagged_err!("Function `{}` is not defined by any of the object files", function.name)
+            };
+
+            let definition = objects.iter()
+                .flat_map(|object| &object.functions)
+                .find(|defined| defined.code.is_some() && defined.name == function.name)
+                .expect("Function must be defined by an object");
+            if definition.arg_count != function.arg_count || definition.returns_value != function.returns_value {
+                return untagged_err!("Function `{}` is defined with different arguments or return type to where it is called", function.name);
+            }
+            ids.push(id);
+        }
+        object_ids.push(ids);
+
+        let mut addresses = HashMap::new();
+        for global in &object.globals {
+            match global_addresses.get(global.name.as_str()) {
+                Some(address) => addresses.insert(global.address, *address),
+                None => return untagged_err!("Global variable `{}` is not defined by any of the object files", global.name)
+            };
+        }
+        object_addresses.push(addresses);
+    }
+
+    let mut initializers = Vec::new();
+    let mut initializers_source_map = Vec::new();
+    let mut initializers_stack_peak = 0;
+    let mut names = Vec::new();
+    let mut compiled_funs = Vec::new();
+    let mut source_maps = Vec::new();
+    let mut stack_usages = Vec::new();
+    let mut inlined = Vec::new();
+    let mut entry_point_info = None;
+    for ((object, ids), addresses) in objects.into_iter().zip(object_ids).zip(object_addresses) {
+        let offset = initializers.len() as i32;
+        let mut object_initializers = object.initializers;
+        relocate_globals(&mut object_initializers, &addresses);
+        initializers.extend(object_initializers.into_iter().map(|instruction| match instruction {
+            Instruction::Jump(addr) => Instruction::Jump(addr + offset),
+            Instruction::JumpIfZero(addr) => Instruction::JumpIfZero(addr + offset),
+            Instruction::JumpIfNonZero(addr) => Instruction::JumpIfNonZero(addr + offset),
+            _ => instruction
+        }));
+        initializers_source_map.extend(object.initializers_source_map);
+        initializers_source_map.resize(initializers.len(), None);
+        initializers_stack_peak = initializers_stack_peak.max(object.initializers_stack_peak);
+
+        for function in object.functions {
+            let mut code = match function.code {
+                Some(code) => code,
+                None => continue
+            };
+
+            for instruction in &mut code.instructions {
+                if let Instruction::JumpSubRoutine(idx) = instruction {
+                    *idx = ids[*idx as usize];
+                }
+            }
+            relocate_globals(&mut code.instructions, &addresses);
+            for (callee, _) in &mut code.stack_usage.calls {
+                *callee = ids[*callee as usize];
+            }
+            for callee in &mut code.inlined {
+                *callee = ids[*callee as usize];
+            }
+            code.source_map.resize(code.instructions.len(), None);
+
+            if function.name == options.entry_point {
+                entry_point_info = Some((compiled_funs.len() as i32, function.arg_count, function.returns_value, function.argument_list_ref));
+            }
+
+            names.push((function.name, function.name_ref));
+            compiled_funs.push(code.instructions);
+            source_maps.push(code.source_map);
+            stack_usages.push(code.stack_usage);
+            inlined.push(code.inlined);
+        }
+    }
+
+    let entry_point = &options.entry_point;
+    let (main_idx, entry_arg_count) = match entry_point_info {
+        Some((id, arg_count, returns_value, argument_list_ref)) => {
+            if returns_value {
+                return untagged_err!("Entry point `{entry_point}` cannot return a value");
+            }
+
+            if arg_count > options.signal_count as usize {
+                let plural = if options.signal_count == 1 { "" } else { "s" };
+                return Err(CompileErrors(vec![FileTaggedError {
+                    position: argument_list_ref,
+                    msg: format!("Entry point `{entry_point}` can have at most {} argument{plural}, as each is read from an input signal", options.signal_count),
+                    notes: Vec::new()
+                }]));
+            }
+
+            (id, arg_count)
+        },
+        None if names.is_empty() => return untagged_err!("No entry point found: A function returning void called {entry_point} should be created"),
+        None => {
+            let function_names: Vec<String> = names.iter().map(|(name, _)| format!("`{name}`")).collect();
+            return untagged_err!("No entry point found: There is no function called `{entry_point}`. The functions in the program are {}",
+                function_names.join(", "));
+        }
+    };
+
+    // Now need to link it, steps:
+    // Write all functions one-by-one into a new array of instructions, offsetting the jump instructions in the function by the start of that function
+    // Keep track of the start index of each function
+    // Overwrite JSR instructions with the correct index to jump to
+
+    // The initializers begin at the first address, so their jumps are already correct.
+    // The arguments of the entry point are pushed in order, so the first is read from signal 1.
+    let mut program = initializers;
+    for signal_number in 1..=entry_arg_count as i32 {
+        program.push(Instruction::Load(-(options.signal_count + signal_number)));
+    }
+    program.push(Instruction::JumpSubRoutine(main_idx));
+    program.push(Instruction::Halt);
+
+    let mut source_map = initializers_source_map;
+    source_map.resize(program.len(), None);
+
+    let mut function_spans = vec![FunctionSpan {
+        function_name: None,
+        start: 0,
+        end: program.len()
+    }];
+
+    // Functions that are never called from the entry point would only waste space in the ROM.
+    let reachable = find_reachable_functions(&compiled_funs, main_idx);
+
+    // Functions whose calls were all inlined are left out too, but are still used.
+    let mut used = reachable.clone();
+    for callee in (0..compiled_funs.len()).filter(|idx| reachable[*idx]).flat_map(|idx| &inlined[idx]) {
+        used[*callee as usize] = true;
+    }
+
+    for (idx, (name, name_ref)) in names.iter().enumerate() {
+        if !used[idx] {
+            warnings.push(function_warning(WarningKind::UnusedFunction, name_ref.clone(),
+                format!("Function `{name}` is never called from `{entry_point}`, so is left out of the program")));
+        }
+    }
+
+    let mut depth_analysis = StackDepthAnalysis {
+        stack_usages: &stack_usages,
+        return_address_slots: return_address_slots(options),
+        states: vec![DepthState::Unvisited; stack_usages.len()],
+        call_chain: Vec::new(),
+        cycles: Vec::new()
+    };
+
+    // The program begins by initializing the globals, then calling the entry point with its arguments.
+    let max_stack_depth = depth_analysis.find_max_depth(main_idx)
+        .map(|depth| (entry_arg_count as i32 + depth + depth_analysis.return_address_slots).max(initializers_stack_peak));
+
+    // Sorted so that the warnings are in the same order as the functions in the file.
+    depth_analysis.cycles.sort_by_key(|cycle| cycle[0]);
+    for cycle in depth_analysis.cycles {
+        let cycle_names: Vec<&str> = cycle.iter().map(|idx| names[*idx as usize].0.as_str()).collect();
+        warnings.push(function_warning(WarningKind::Recursion, names[cycle[0] as usize].1.clone(),
+            format!("Function `{}` is recursive ({}), so the maximum depth of the stack cannot be calculated", cycle_names[0], cycle_names.join(" -> "))));
+    }
+
+    if options.dump_after.contains(&Pass::DeadCode) {
+        let functions = (0..compiled_funs.len())
+            .filter(|idx| reachable[*idx])
+            .map(|idx| (names[idx].0.as_str(), compiled_funs[idx].as_slice()));
+        pass_dumps.push(dump_functions(Pass::DeadCode, true, functions));
+    }
+
+    let replacements = if options.fold_identical_functions {
+        fold_identical_functions(&mut compiled_funs, &reachable)
+    }   else {
+        (0..compiled_funs.len() as i32).collect()
+    };
+
+    // Write in all the functions, applying necessary offsets.
+    let mut start_offsets = vec![-1; compiled_funs.len()];
+    for idx in 0..compiled_funs.len() {
+        // Duplicates are not written, and are given the offset of their surviving copy below.
+        // Unreachable functions are not written at all, and are never called by the functions that are.
+        if replacements[idx] != idx as i32 || !reachable[idx] {
+            continue;
+        }
+
+        let offset = program.len() as i32;
+        start_offsets[idx] = offset;
+
+        for instruction in &compiled_funs[idx] {
+            let offset_instruction = match *instruction {
+                Instruction::Jump(addr) => Instruction::Jump(addr + offset),
+                Instruction::JumpIfZero(addr) => Instruction::JumpIfZero(addr + offset),
+                Instruction::JumpIfNonZero(addr) => Instruction::JumpIfNonZero(addr + offset),
+                _ => *instruction
+            };
+
+            program.push(offset_instruction);
+        }
+        source_map.extend(source_maps[idx].iter().cloned());
+
+        function_spans.push(FunctionSpan {
+            function_name: Some(names[idx].0.clone()),
+            start: offset as usize,
+            end: program.len()
+        });
+    }
+
+    for idx in 0..start_offsets.len() {
+        start_offsets[idx] = start_offsets[replacements[idx] as usize];
+    }
+
+    // The number of instructions saved is found once the program has been optimized, from the size of the surviving copy.
+    let merged: Vec<(usize, usize)> = (0..compiled_funs.len())
+        .filter(|idx| replacements[*idx] != *idx as i32)
+        .map(|idx| (idx, replacements[idx] as usize))
+        .collect();
+
+    // Overwrite JSR instructions
+    for instruction in program.iter_mut() {
+        if let Instruction::JumpSubRoutine(idx) = instruction {
+            *instruction = Instruction::JumpSubRoutine(start_offsets[*idx as usize] + 1)
+        }
+    }
+
+    let dump_program = |pass: Pass, enabled: bool, program: &[Instruction], function_spans: &[FunctionSpan]| PassDump {
+        pass,
+        enabled,
+        linked: true,
+        instructions: program.to_vec(),
+        function_spans: function_spans.to_vec()
+    };
+    if options.dump_after.contains(&Pass::FoldIdenticalFunctions) {
+        pass_dumps.push(dump_program(Pass::FoldIdenticalFunctions, options.fold_identical_functions, &program, &function_spans));
+    }
+
+    if options.optimize {
+        let (optimized, new_indices) = optimizer::optimize(program);
+
+        // Instructions that were replaced keep the position of the first instruction they replaced.
+        let mut optimized_source_map = vec![None; optimized.len()];
+        for (idx, position) in source_map.into_iter().enumerate() {
+            if new_indices[idx] != new_indices[idx + 1] {
+                optimized_source_map[new_indices[idx]] = position;
+            }
+        }
+
+        program = optimized;
+        source_map = optimized_source_map;
+
+        for span in &mut function_spans {
+            span.start = new_indices[span.start];
+            span.end = new_indices[span.end];
+        }
+    }
+
+    if options.dump_after.contains(&Pass::Optimize) {
+        pass_dumps.push(dump_program(Pass::Optimize, options.optimize, &program, &function_spans));
+    }
+
+    // Computers without `HLT` stop when jumping to an address outside of the ROM.
+    if !options.instruction_set.supports(Instruction::Halt) {
+        for instruction in &mut program {
+            if *instruction == Instruction::Halt {
+                *instruction = Instruction::Jump(-1);
+            }
+        }
+    }
+
+    check_instruction_set(&program, &source_map, &function_spans, &options.instruction_set)?;
+
+    let merged_functions = merged.into_iter().map(|(idx, survivor)| {
+        let survivor_name = &names[survivor].0;
+        let span = function_spans.iter()
+            .find(|span| span.function_name.as_ref() == Some(survivor_name))
+            .expect("Functions are only merged into one that is in the program");
+
+        MergedFunction {
+            function_name: names[idx].0.clone(),
+            merged_into: survivor_name.clone(),
+            instructions_saved: span.end - span.start
+        }
+    }).collect();
+
+    Ok(CompiledModule {
+        instructions: program,
+        source_map,
+        max_stack_depth,
+        function_spans,
+        merged_functions,
+        uses_ticks,
+        pass_dumps
+    })
+}
+
+// Checks that every instruction in a linked program is supported by the computer.
+// Each unsupported instruction is reported once for each statement that uses it.
+fn check_instruction_set(program: &[Instruction], source_map: &SourceMap, function_spans: &[FunctionSpan], instruction_set: &InstructionSet) -> CompileResult<()> {
+    let mut reported = HashSet::new();
+    let mut errors = Vec::new();
+    for (idx, instruction) in program.iter().enumerate() {
+        if instruction_set.supports(*instruction) {
+            continue;
+        }
+
+        // Instructions without a position are attributed to the function containing them.
+        let position = &source_map[idx];
+        let span = function_spans.iter().find(|span| (span.start..span.end).contains(&idx));
+        let location = position.as_ref().map(|position| (position.line_index, position.begin_char_index));
+        if !reported.insert((instruction.get_opcode(), location, span.map(|span| span.start))) {
+            continue;
+        }
+
+        let mnemonic = instruction.mnemonic();
+        let msg = match (position, span.and_then(|span| span.function_name.as_ref())) {
+            (Some(_), _) => format!("This code uses the `{mnemonic}` instruction, which is not supported by the target computer"),
+            (None, Some(name)) => format!("`{name}` uses the `{mnemonic}` instruction, which is not supported by the target computer"),
+            (None, None) => format!("The `{mnemonic}` instruction is used to start the program, but is not supported by the target computer")
+        };
+
+        errors.push(FileTaggedError {
+            position: position.clone(),
+            msg,
+            notes: Vec::new()
+        });
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    }   else {
+        Err(CompileErrors(errors))
+    }
+}
+
+fn emit_block(block: Vec<Statement>, ctx: &mut CompileCtx) -> CompileResult<()> {
+    let mut errors = Vec::new();
+
+    for statement in block {
+        // The statements after one that always exits the block can never run, so take up no space in the ROM.
+        let exits = always_exits(&statement);
+        if let Err(mut err) = emit_statement(statement, ctx) {
+            errors.append(&mut err.0);
+        }
+
+        if exits {
+            break;
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    }   else    {
+        Err(CompileErrors(errors))
+    }
+}
+
+// Gets the position of the source code that a statement's instructions are attributed to.
+// None for a block, whose instructions are attributed to the statements within it.
+pub fn statement_position(statement: &Statement) -> Option<FileRef> {
+    match statement {
+        Statement::Assignment { variable_name_ref, .. } => Some(variable_name_ref.clone()),
+        Statement::If { segments, .. } => Some(segments[0].condition_ref.clone()),
+        Statement::While { condition_ref, .. } => Some(condition_ref.clone()),
+        Statement::Block(_) => None,
+        Statement::Call(call) => Some(call.function_name_ref.clone()),
+        Statement::ReturnValue { value_ref, .. } | Statement::Expression { value_ref, .. } => Some(value_ref.clone()),
+        Statement::Return(position) | Statement::Continue(position) | Statement::Break(position) => Some(position.clone())
+    }
+}
+
+fn emit_statement(statement: Statement, ctx: &mut CompileCtx) -> CompileResult<()> {
+    // Instructions are attributed to the innermost statement they were compiled from, so the position of
+    // an enclosing statement is restored afterwards, e.g. for the jump at the end of a loop.
+    let outer_position = ctx.position.clone();
+    if let Some(position) = statement_position(&statement) {
+        ctx.position = Some(position);
+    }
+
+    let result = emit_statement_instructions(statement, ctx);
+    ctx.position = outer_position;
+
+    result
+}
+
+fn emit_statement_instructions(statement: Statement, ctx: &mut CompileCtx) -> CompileResult<()> {
+    match statement {
+        Statement::Assignment { variable_name, value, variable_name_ref } => {
+            emit_expression(value, ctx)?;
+            match ctx.save_to_variable(variable_name.clone(), variable_name_ref.clone()) {
+                Ok(_) => {},
+                // `signal_N` names are never variables, so a signal number out of range is an error rather than a new variable.
+                Err(err) if variable_name.starts_with("signal_") => return Err(err),
+                Err(_) => {
+                    if let Some(similar) = ctx.find_similar_variable(&variable_name) {
+                        let warning = warning!(WarningKind::SimilarVariableName, variable_name_ref.clone(),
+                            "New variable `{variable_name}` created here; did you mean `{similar}`?");
+                        ctx.warnings.push(warning);
+                    }
+
+                    ctx.add_variable(variable_name, variable_name_ref);
+                }
+            }
+
+            Ok(())
+        },
+        Statement::If { segments, r#else } => {
+            let mut skip_else_jumps = Vec::new();
+
+            let last_idx = segments.len() - 1;
+            for (idx, segment) in segments.into_iter().enumerate() {
+                let is_last = idx == last_idx;
+
+                // Each `else if` is attributed to its own condition.
+                ctx.position = Some(segment.condition_ref);
+                emit_expression(segment.condition, ctx)?;
+
+                let skip_block_jump = ctx.emit_jump_placeholder(Instruction::JumpIfZero);
+
+                ctx.open_scope(ScopeState::Other);
+                emit_block(segment.block, ctx)?;
+                ctx.end_scope();
+
+                // After each if segment, add an instruction to skip the remaining segments and the else block
+                if !is_last || r#else.is_some() {
+                    skip_else_jumps.push(ctx.emit_jump_placeholder(Instruction::Jump));
+                }
+
+                // Skip over the if block if the condition is false
+                ctx.patch_jump(skip_block_jump, ctx.next_address());
+            }
+
+            if let Some(else_block) = r#else {
+                ctx.open_scope(ScopeState::Other);
+                emit_block(else_block, ctx)?;
+                ctx.end_scope();
+            }
+
+            let end_addr = ctx.next_address();
+            for jump in skip_else_jumps {
+                ctx.patch_jump(jump, end_addr);
+            }
+
+            Ok(())
+        },
+        Statement::While { condition, block, is_do_while, .. } => {
+            // Unconditional jump to end of loop, where the condition is checked.
+            // A do-while loop has no jump, so that the block runs before the condition is first checked.
+            let uncond_jump = if is_do_while {
+                None
+            }   else {
+                Some(ctx.emit_jump_placeholder(Instruction::Jump))
+            };
+            let block_start_addr = ctx.next_address();
+
+            ctx.open_scope(ScopeState::While {
+                continue_jumps: Vec::new(),
+                break_jumps: Vec::new()
+            });
+            emit_block(block, ctx)?;
+            let scope_state = ctx.end_scope();
+
+            let (continue_jumps, break_jumps) = match scope_state {
+                ScopeState::While { continue_jumps, break_jumps } => (continue_jumps, break_jumps),
+                _ => unreachable!()
+            };
+
+            let condition_addr = ctx.next_address();
+            for jump in uncond_jump.into_iter().chain(continue_jumps) {
+                ctx.patch_jump(jump, condition_addr);
+            }
+            
+            emit_expression(condition, ctx)?;
+            ctx.emit(Instruction::JumpIfNonZero(block_start_addr));
+
+            let end_addr = ctx.next_address();
+            for jump in break_jumps {
+                ctx.patch_jump(jump, end_addr);
+            }
+            
+            Ok(())
+        },
         Statement::Return(position) => if ctx.return_value_save_offset.is_some() {
             error!(position, "Must return a value from this function")
         }   else    {
-            Ok(emit_return(ctx))
+            emit_return(ctx);
+            Ok(())
         },
+        Statement::ReturnValue { value: Expression::Call(call), .. } if ctx.is_tail_call(&call) => emit_tail_call(call, ctx),
         Statement::ReturnValue {
             value,
             value_ref
         } => if let Some(offset) = ctx.return_value_save_offset {
             emit_expression(value, ctx)?;
 
-            ctx.emit(Instruction::Save(ctx.stack_size - offset));
-            Ok(emit_return(ctx))
-        }   else    {
-            error!(value_ref, "Cannot return a value from this function")
-        },
-        Statement::Continue(pos) => try_emit_loop_control_flow(true, pos, ctx),
-        Statement::Break(pos) => try_emit_loop_control_flow(false, pos, ctx),
-        Statement::Call(call) => emit_call(call, ctx, false),
+            // SAVE removes the value after storing it, so the address is relative to the stack with the value still on top.
+            // This lands on the space the caller pushed before the arguments, whatever the number of arguments.
+            ctx.emit(Instruction::Save(ctx.stack_size - offset));
+            emit_return(ctx);
+            Ok(())
+        }   else    {
+            error!(value_ref, "Cannot return a value from this function")
+        },
+        Statement::Block(block) => {
+            ctx.open_scope(ScopeState::Other);
+            // Close the scope even if the block failed to compile, so that its variables do not leak into later statements.
+            let result = emit_block(block, ctx);
+            ctx.end_scope();
+
+            result
+        },
+        Statement::Continue(pos) => try_emit_loop_control_flow(true, pos, ctx),
+        Statement::Break(pos) => try_emit_loop_control_flow(false, pos, ctx),
+        Statement::Call(call) => emit_call(call, ctx, false),
+        // The value is discarded, since only the side effects of the expression are wanted.
+        Statement::Expression { value, .. } => {
+            emit_expression(value, ctx)?;
+            ctx.emit(Instruction::Pop);
+            Ok(())
+        }
+    }
+}
+
+fn try_emit_loop_control_flow(is_continue: bool, keyword_ref: FileRef, ctx: &mut CompileCtx) -> CompileResult<()> {
+    // Find the first while loop
+    let frame_start = ctx.frame_start();
+    let scope_idx = match ctx.scopes[frame_start..].iter().rposition(|scope| matches!(scope.scope_type, ScopeState::While { .. })) {
+        Some(scope_idx) => frame_start + scope_idx,
+        None => return error!(keyword_ref, "Not in a loop scope - cannot use break or continue keywords")
+    };
+
+    // The variables of the loop are popped before the jump.
+    ctx.prepare_for_premature_scope_end(scope_idx);
+    let jump = ctx.emit_jump_placeholder(Instruction::Jump);
+
+    if let ScopeState::While { ref mut continue_jumps, ref mut break_jumps } = ctx.scopes[scope_idx].scope_type {
+        if is_continue {
+            continue_jumps
+        }   else {
+            break_jumps
+        }.push(jump);
+    }
+
+    Ok(())
+}
+
+fn emit_return(ctx: &mut CompileCtx) {
+    let frame_start = ctx.frame_start();
+    ctx.prepare_for_premature_scope_end(frame_start);
+
+    // Within an inlined function, the end of its body is where the call would have returned to.
+    if matches!(ctx.scopes[frame_start].scope_type, ScopeState::Inlined { .. }) {
+        let jump = ctx.emit_jump_placeholder(Instruction::Jump);
+        if let ScopeState::Inlined { ref mut return_jumps } = ctx.scopes[frame_start].scope_type {
+            return_jumps.push(jump);
+        }
+    }   else {
+        ctx.emit(Instruction::Return);
+    }
+}
+
+// Emits `return f(...)` within `f` as a jump back to the start of the function, which reuses its arguments and return value
+// rather than calling it again, so that it can call itself any number of times without overflowing the stack.
+fn emit_tail_call(call: Call, ctx: &mut CompileCtx) -> CompileResult<()> {
+    // Every argument is evaluated before any are overwritten, since they may read the old values, e.g. `return f(n - 1, total * n);`.
+    for expr in call.arguments {
+        emit_expression(expr, ctx)?;
+    }
+
+    // The last argument is on top of the stack, so is saved first.
+    for offset in ctx.argument_offsets.clone().into_iter().rev() {
+        ctx.emit(Instruction::Save(ctx.stack_size - offset));
     }
+
+    ctx.prepare_for_premature_scope_end(0);
+    // Jumps are relative to the start of the function, which is at address 1.
+    ctx.emit(Instruction::Jump(1));
+    Ok(())
 }
 
-fn try_emit_loop_control_flow(is_continue: bool, keyword_ref: FileRef, ctx: &mut CompileCtx) -> CompileResult<()> {
-    // Find the first while loop
-    for (scope_idx, scope) in ctx.scopes.iter_mut().enumerate().rev() {
-        if let ScopeState::While { ref mut continue_inst_addresses, ref mut break_inst_addresses } = scope.scope_type {
-            if is_continue {
-                continue_inst_addresses
-            }   else {
-                break_inst_addresses
-            }.push(ctx.instructions.len());
+// Emits a call to `ticks()`, which reads the tick counter signal.
+fn emit_ticks_call(call: Call, ctx: &mut CompileCtx, using_return_value: bool) -> CompileResult<()> {
+    if !call.arguments.is_empty() {
+        return error!(call.arguments_ref, "Wrong number of arguments, expected 0, got {}", call.arguments.len());
+    }
 
-            ctx.prepare_for_premature_scope_end(scope_idx);
-            ctx.emit(Instruction::Jump(-1));
-            return Ok(());
-        }
+    if ctx.options.tick_signal <= 0 || ctx.options.tick_signal > ctx.options.signal_count {
+        return error!(call.function_name_ref, "Invalid tick counter signal {}. Must be in range [1-{}]", ctx.options.tick_signal, ctx.options.signal_count);
+    }
+
+    if ctx.tick_signal_uses.ticks_call.is_none() {
+        ctx.tick_signal_uses.ticks_call = Some(call.function_name_ref);
+    }
+
+    // Reading the signal has no side effects, so nothing is needed if the value is unused.
+    if using_return_value {
+        ctx.emit(Instruction::Load(tick_signal_address(ctx.options)));
     }
 
-    error!(keyword_ref, "Not in a loop scope - cannot use break or continue keywords")
+    Ok(())
 }
 
-fn emit_return(ctx: &mut CompileCtx) {
-    ctx.prepare_for_premature_scope_end(0);
-    ctx.emit(Instruction::Return);
+// Gets the signal number passed as the first argument to `read_signal` or `write_signal`.
+fn signal_number_argument(call: &Call, signal_count: i32) -> CompileResult<i32> {
+    match call.arguments[0] {
+        Expression::Literal(signal_number) if (1..=signal_count).contains(&signal_number) => Ok(signal_number),
+        Expression::Literal(signal_number) => error!(call.arguments_ref.clone(), "Invalid signal number {signal_number}. Must be in range [1-{signal_count}]"),
+        // There is no instruction that reads from or writes to an address computed at runtime.
+        _ => error!(call.arguments_ref.clone(), "The signal number must be known at compile time, e.g. a literal or a constant, \
+            as signals can only be accessed at a fixed address")
+    }
+}
+
+// Reads an input signal, the same as reading from a `signal_N` variable.
+fn emit_read_signal_call(call: Call, ctx: &mut CompileCtx, using_return_value: bool) -> CompileResult<()> {
+    if call.arguments.len() != 1 {
+        return error!(call.arguments_ref, "Wrong number of arguments, expected 1, got {}", call.arguments.len());
+    }
+
+    let signal_number = signal_number_argument(&call, ctx.options.signal_count)?;
+
+    // Reading the signal has no side effects, so nothing is needed if the value is unused.
+    if using_return_value {
+        ctx.load_address(-(ctx.options.signal_count + signal_number), call.function_name_ref);
+    }
+
+    Ok(())
+}
+
+// Writes to an output signal, the same as assigning to a `signal_N` variable.
+fn emit_write_signal_call(mut call: Call, ctx: &mut CompileCtx, using_return_value: bool) -> CompileResult<()> {
+    if using_return_value {
+        return error!(call.function_name_ref, "Cannot use a function that does not return a value within an expression");
+    }
+
+    if call.arguments.len() != 2 {
+        return error!(call.arguments_ref, "Wrong number of arguments, expected 2, got {}", call.arguments.len());
+    }
+
+    let signal_number = signal_number_argument(&call, ctx.options.signal_count)?;
+
+    emit_expression(call.arguments.pop().unwrap(), ctx)?;
+    ctx.emit(Instruction::Save(-signal_number));
+    Ok(())
+}
+
+// Stops the program, leaving the stack as it is.
+fn emit_halt_call(call: Call, ctx: &mut CompileCtx, using_return_value: bool) -> CompileResult<()> {
+    if using_return_value {
+        return error!(call.function_name_ref, "Cannot use a function that does not return a value within an expression");
+    }
+
+    if !call.arguments.is_empty() {
+        return error!(call.arguments_ref, "Wrong number of arguments, expected 0, got {}", call.arguments.len());
+    }
+
+    ctx.emit(Instruction::Halt);
+    Ok(())
 }
 
 fn emit_call(call: Call, ctx: &mut CompileCtx, using_return_value: bool) -> CompileResult<()> {
-    let info = *match ctx.function_ids_in_module.get(&call.function_name) {
-        Some(info) => info,
-        None => return error!(call.function_name_ref, "No function exists with name {}", call.function_name)
+    match call.function_name.as_str() {
+        TICKS_FUNCTION => return emit_ticks_call(call, ctx, using_return_value),
+        READ_SIGNAL_FUNCTION => return emit_read_signal_call(call, ctx, using_return_value),
+        WRITE_SIGNAL_FUNCTION => return emit_write_signal_call(call, ctx, using_return_value),
+        HALT_FUNCTION => return emit_halt_call(call, ctx, using_return_value),
+        _ => {}
+    }
+
+    let info = match ctx.function_ids_in_module.get(&call.function_name) {
+        Some(info) => info.clone(),
+        None => {
+            let function_names = ctx.function_ids_in_module.keys()
+                .map(String::as_str)
+                .chain(BUILTIN_FUNCTIONS.iter().copied());
+
+            return match error_handling::suggest_similar(&call.function_name, function_names) {
+                Some(similar) => error!(call.function_name_ref, "No function exists with name {}, did you mean `{similar}`?", call.function_name),
+                None => error!(call.function_name_ref, "No function exists with name {}", call.function_name)
+            };
+        }
     };
 
     if !info.returns_value && using_return_value {
@@ -454,7 +2123,20 @@ fn emit_call(call: Call, ctx: &mut CompileCtx, using_return_value: bool) -> Comp
     }
     
     if info.arg_count != call.arguments.len() {
-        return error!(call.arguments_ref, "Wrong number of arguments, expected {}, got {}", info.arg_count, call.arguments.len());
+        return Err(CompileErrors(vec![FileTaggedError {
+            position: Some(call.arguments_ref),
+            msg: format!("Wrong number of arguments, expected {}, got {}", info.arg_count, call.arguments.len()),
+            notes: info.argument_list_ref.into_iter()
+                .map(|argument_list_ref| (argument_list_ref, format!("`{}` is defined with these arguments", call.function_name)))
+                .collect()
+        }]));
+    }
+
+    if let Some(function) = ctx.inlined_functions.get(&call.function_name).cloned() {
+        if !ctx.inlined.contains(&info.id) {
+            ctx.inlined.push(info.id);
+        }
+        return emit_inlined_call(call, function, ctx, using_return_value);
     }
 
     if info.returns_value {
@@ -466,6 +2148,7 @@ fn emit_call(call: Call, ctx: &mut CompileCtx, using_return_value: bool) -> Comp
         emit_expression(expr, ctx)?;
     }
 
+    ctx.stack_usage.calls.push((info.id, ctx.stack_size));
     ctx.emit(Instruction::JumpSubRoutine(info.id)); // This will be overwritten with the correct address in the linking stage
 
     for _ in 0..arg_count {
@@ -480,12 +2163,184 @@ fn emit_call(call: Call, ctx: &mut CompileCtx, using_return_value: bool) -> Comp
     Ok(())
 }
 
+// Emits the body of a function in place of a call to it, which saves the JSR and RET, along with the function itself if every call to it is inlined.
+// The space for the return value and the arguments are pushed in the same way as for a call, so the body uses them as the function would.
+fn emit_inlined_call(call: Call, function: Function, ctx: &mut CompileCtx, using_return_value: bool) -> CompileResult<()> {
+    if function.returns_value {
+        ctx.emit(Instruction::Constant(0));
+    }
+    let return_value_offset = function.returns_value.then_some(ctx.stack_size - 1);
+
+    let arg_count = call.arguments.len();
+    for expr in call.arguments {
+        emit_expression(expr, ctx)?;
+    }
+
+    let arguments_start = ctx.stack_size - arg_count as i32;
+    let scope_vars = function.argument_names.into_iter()
+        .zip(function.argument_name_refs)
+        .enumerate()
+        .map(|(idx, (argument, argument_ref))| (argument, Variable::new(arguments_start + idx as i32, argument_ref)))
+        .collect();
+
+    let frame_start = ctx.stack_size;
+    ctx.scopes.push(Scope {
+        scope_vars,
+        starting_stack_size: frame_start,
+        scope_type: ScopeState::Inlined { return_jumps: Vec::new() }
+    });
+    let outer_return_value_offset = std::mem::replace(&mut ctx.return_value_save_offset, return_value_offset);
+    // The function has already been compiled on its own, which gave any warnings about its body.
+    let outer_warnings = std::mem::take(ctx.warnings);
+
+    // A `return` at the end of the body jumps to the next instruction, which is removed by the peephole optimizer.
+    let always_returns = always_returns(&function.block);
+    let result = emit_block(function.block, ctx);
+
+    // Every path has already jumped to the end, having popped its variables, so only the arguments are left.
+    if always_returns {
+        ctx.stack_size = frame_start;
+        ctx.scopes.last_mut().expect("No inlined scope").scope_vars.retain(|_, variable| variable.offset < frame_start);
+    }
+    let return_jumps = match ctx.end_scope() {
+        ScopeState::Inlined { return_jumps } => return_jumps,
+        _ => unreachable!()
+    };
+    *ctx.warnings = outer_warnings;
+    ctx.return_value_save_offset = outer_return_value_offset;
+    result?;
+
+    let end_addr = ctx.next_address();
+    for jump in return_jumps {
+        ctx.patch_jump(jump, end_addr);
+    }
+
+    for _ in 0..arg_count {
+        ctx.emit(Instruction::Pop);
+    }
+
+    if !using_return_value && function.returns_value {
+        ctx.emit(Instruction::Pop);
+    }
+
+    Ok(())
+}
+
+// Emits `&&` or `||`, which skip evaluating the right hand side if the left hand side decides the result.
+fn emit_logical_operator(left: Expression, right: Expression, is_and: bool, ctx: &mut CompileCtx) -> CompileResult<()> {
+    // For `&&`, a zero operand means the result is 0 without evaluating any further.
+    // For `||`, a non-zero operand means the result is 1.
+    let short_circuit_jump: fn(i32) -> Instruction = if is_and {
+        Instruction::JumpIfZero
+    }   else {
+        Instruction::JumpIfNonZero
+    };
+
+    let mut short_circuit_jumps = Vec::new();
+    for operand in [left, right] {
+        emit_expression(operand, ctx)?;
+        short_circuit_jumps.push(ctx.emit_jump_placeholder(short_circuit_jump));
+    }
+
+    // Both operands were evaluated without short circuiting.
+    ctx.emit(Instruction::Constant(if is_and { 1 } else { 0 }));
+    let skip_jump = ctx.emit_jump_placeholder(Instruction::Jump);
+
+    // Only one of the two constants is pushed at runtime.
+    ctx.stack_size -= 1;
+
+    let short_circuit_addr = ctx.next_address();
+    for jump in short_circuit_jumps {
+        ctx.patch_jump(jump, short_circuit_addr);
+    }
+
+    ctx.emit(Instruction::Constant(if is_and { 0 } else { 1 }));
+    ctx.patch_jump(skip_jump, ctx.next_address());
+
+    Ok(())
+}
+
+// The largest constant exponent that is replaced by multiplying when the computer doesn't support `POW`.
+const MAX_EXPANDED_EXPONENT: i32 = 8;
+
+// Checks if `left ^ right` should be calculated by multiplying, as the computer doesn't support `POW`.
+// Without `DUP`, the base is evaluated once for each multiplication, so must be a literal or a variable.
+fn can_expand_power(left: &Expression, right: &Expression, ctx: &CompileCtx) -> bool {
+    let exponent = match right {
+        Expression::Literal(exponent @ 0..=MAX_EXPANDED_EXPONENT) => *exponent,
+        _ => return false
+    };
+
+    let is_base_repeatable = match left {
+        Expression::Literal(_) => true,
+        // Signals may change between reads.
+        Expression::Variable { name, .. } => !name.starts_with("signal_"),
+        _ => false
+    };
+
+    !ctx.options.instruction_set.supports(Instruction::Power)
+        && (exponent <= 1 || is_base_repeatable || ctx.options.instruction_set.supports(Instruction::Duplicate))
+}
+
+// Calculates `base ^ exponent` by multiplying the base by itself.
+fn emit_expanded_power(base: Expression, exponent: i32, ctx: &mut CompileCtx) -> CompileResult<()> {
+    if exponent == 0 {
+        // The base is still evaluated, in case it calls a function.
+        emit_expression(base, ctx)?;
+        ctx.emit(Instruction::Pop);
+        ctx.emit(Instruction::Constant(1));
+        return Ok(());
+    }
+
+    if ctx.options.instruction_set.supports(Instruction::Duplicate) {
+        emit_expression(base, ctx)?;
+        for _ in 1..exponent {
+            ctx.emit(Instruction::Duplicate);
+        }
+    }   else {
+        for _ in 0..exponent {
+            emit_expression(base.clone(), ctx)?;
+        }
+    }
+
+    for _ in 1..exponent {
+        ctx.emit(Instruction::Multiply);
+    }
+
+    Ok(())
+}
+
 fn emit_expression(expr: Expression, ctx: &mut CompileCtx) -> CompileResult<()> {
     match expr {
-        Expression::Binary { left, right, operator } => {
+        Expression::Binary { left, right, operator: operator @ (BinaryOperator::LogicalAnd | BinaryOperator::LogicalOr), .. } => {
+            emit_logical_operator(*left, *right, operator == BinaryOperator::LogicalAnd, ctx)?;
+        },
+        Expression::Binary { left, right, operator: BinaryOperator::Power, .. } if can_expand_power(&left, &right, ctx) => {
+            let exponent = match *right {
+                Expression::Literal(exponent) => exponent,
+                _ => unreachable!("Only constant exponents can be expanded")
+            };
+
+            emit_expanded_power(*left, exponent, ctx)?;
+        },
+        Expression::Binary { left, right, operator, operator_ref, .. } => {
+            // With `--optimize`, a variable used as both operands, e.g. `x * x` or `x *= x`, is only loaded once.
+            // Signals are still read twice, as their value may change in between.
+            let is_same_variable = match (&*left, &*right) {
+                (Expression::Variable { name: left_name, .. }, Expression::Variable { name: right_name, .. }) =>
+                    left_name == right_name && !left_name.starts_with("signal_"),
+                _ => false
+            };
+
             emit_expression(*right, ctx)?;
-            emit_expression(*left, ctx)?;
+            if is_same_variable && ctx.options.optimize && ctx.options.instruction_set.supports(Instruction::Duplicate) {
+                ctx.emit(Instruction::Duplicate);
+            }   else {
+                emit_expression(*left, ctx)?;
+            }
 
+            // The instruction is attributed to the operator, so that an error about it, e.g. if it isn't supported, points there.
+            let statement_position = ctx.position.replace(operator_ref);
             ctx.emit(match operator {
                 BinaryOperator::Add => Instruction::Add,
                 BinaryOperator::Subtract => Instruction::Subtract,
@@ -493,7 +2348,7 @@ fn emit_expression(expr: Expression, ctx: &mut CompileCtx) -> CompileResult<()>
                 BinaryOperator::Divide => Instruction::Divide,
                 BinaryOperator::And => Instruction::And,
                 BinaryOperator::Or => Instruction::Or,
-                BinaryOperator::Xor => Instruction::Multiply,
+                BinaryOperator::Xor => Instruction::Xor,
                 BinaryOperator::ShiftLeft => Instruction::ShiftLeft,
                 BinaryOperator::ShiftRight => Instruction::ShiftRight,
                 BinaryOperator::Equals => Instruction::Equal,
@@ -503,18 +2358,32 @@ fn emit_expression(expr: Expression, ctx: &mut CompileCtx) -> CompileResult<()>
                 BinaryOperator::GreaterThanOrEqual => Instruction::GreaterThanOrEqual,
                 BinaryOperator::Remainder => Instruction::Remainder,
                 BinaryOperator::LessThanOrEqual => Instruction::LessThanOrEqual,
-                BinaryOperator::Power => Instruction::Power
+                BinaryOperator::Power => Instruction::Power,
+                BinaryOperator::LogicalAnd | BinaryOperator::LogicalOr => unreachable!("Logical operators are handled separately")
             });
+            ctx.position = statement_position;
         },
         Expression::Unary { value, operator } => {
             match operator {
-                UnaryOperator::Not => { 
+                // The NOT instruction is not used, so that `~` and `!` behave the same whichever way the hardware implements it.
+                UnaryOperator::Not => {
+                    ctx.emit(Instruction::Constant(-1));
+                    emit_expression(*value, ctx)?;
+                    ctx.emit(Instruction::Xor)
+                },
+                UnaryOperator::LogicalNot => {
                     emit_expression(*value, ctx)?;
-                    ctx.emit(Instruction::Not)
+                    ctx.emit(Instruction::Constant(0));
+                    ctx.emit(Instruction::Equal)
                 },
                 UnaryOperator::Negate => {
                     match &*value {
-                        Expression::Literal(value) => ctx.emit(Instruction::Constant(-value)),
+                        Expression::Literal(value) => ctx.emit(Instruction::Constant(semantics::fact_neg(*value))),
+                        // The NEG instruction is only used with `--optimize`, as older computers don't have it.
+                        _ if ctx.options.optimize && ctx.options.instruction_set.supports(Instruction::Negate) => {
+                            emit_expression(*value, ctx)?;
+                            ctx.emit(Instruction::Negate);
+                        },
                         _ => {
                             ctx.emit(Instruction::Constant(-1));
                             emit_expression(*value, ctx)?;
@@ -537,3 +2406,694 @@ fn emit_expression(expr: Expression, ctx: &mut CompileCtx) -> CompileResult<()>
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulator::{self, EmulatorOptions, SignalState}, testing::{compile, compile_warnings, run, try_compile, try_compile_cached}};
+
+    #[test]
+    fn only_identical_functions_are_merged() {
+        let source = "int a(x) { return x * 3 + 1; }
+            int b(x) { return x * 3 + 1; }
+            int c(x) { return x * 3 + 2; }
+            void main(n) { signal_1 = a(n); signal_2 = b(n); signal_3 = c(n); }";
+        let options = CompileOptions { fold_identical_functions: true, ..CompileOptions::default() };
+        let folded = compile(source, &options);
+        let unfolded = compile(source, &CompileOptions::default());
+
+        assert_eq!(folded.merged_functions.len(), 1);
+        assert_eq!(folded.merged_functions[0].function_name, "b");
+        assert_eq!(folded.merged_functions[0].merged_into, "a");
+        assert_eq!(folded.instructions.len() + folded.merged_functions[0].instructions_saved, unfolded.instructions.len());
+        assert!(!folded.function_spans.iter().any(|span| span.function_name.as_deref() == Some("b")));
+
+        assert_eq!(run(&folded, &[4], &options), vec![13, 13, 14, 0, 0]);
+        assert_eq!(run(&unfolded, &[4], &options), run(&folded, &[4], &options));
+    }
+
+    // Gets the span of the function with the given name in a compiled program.
+    fn function_span<'a>(module: &'a CompiledModule, name: &str) -> &'a FunctionSpan {
+        module.function_spans.iter()
+            .find(|span| span.function_name.as_deref() == Some(name))
+            .unwrap_or_else(|| panic!("No function named {name} in the program"))
+    }
+
+    // Gets the instructions of the function with the given name in a compiled program.
+    fn function_instructions<'a>(module: &'a CompiledModule, name: &str) -> &'a [Instruction] {
+        let span = function_span(module, name);
+        &module.instructions[span.start..span.end]
+    }
+
+    // Gets the instruction that calls the function with the given name.
+    fn call_to(module: &CompiledModule, name: &str) -> Instruction {
+        Instruction::JumpSubRoutine(function_span(module, name).start as i32 + 1)
+    }
+
+    // The options with each calling convention, so that a test can check the program works with both.
+    fn each_call_convention() -> [CompileOptions; 2] {
+        [CallConvention::Interleaved, CallConvention::SplitStack]
+            .map(|call_convention| CompileOptions { call_convention, ..CompileOptions::default() })
+    }
+
+    #[test]
+    fn binary_operators_lower_to_matching_instruction() {
+        for operator in BinaryOperator::ALL {
+            let expected = match operator {
+                BinaryOperator::Add => Instruction::Add,
+                BinaryOperator::Subtract => Instruction::Subtract,
+                BinaryOperator::Multiply => Instruction::Multiply,
+                BinaryOperator::Divide => Instruction::Divide,
+                BinaryOperator::And => Instruction::And,
+                BinaryOperator::Or => Instruction::Or,
+                BinaryOperator::Xor => Instruction::Xor,
+                BinaryOperator::ShiftLeft => Instruction::ShiftLeft,
+                BinaryOperator::ShiftRight => Instruction::ShiftRight,
+                BinaryOperator::Equals => Instruction::Equal,
+                BinaryOperator::NotEquals => Instruction::NotEqual,
+                BinaryOperator::GreaterThan => Instruction::GreaterThan,
+                BinaryOperator::LessThan => Instruction::LessThan,
+                BinaryOperator::GreaterThanOrEqual => Instruction::GreaterThanOrEqual,
+                BinaryOperator::Remainder => Instruction::Remainder,
+                BinaryOperator::LessThanOrEqual => Instruction::LessThanOrEqual,
+                BinaryOperator::Power => Instruction::Power,
+                // These short-circuit, so are compiled to jumps rather than a single instruction.
+                BinaryOperator::LogicalAnd | BinaryOperator::LogicalOr => continue
+            };
+
+            // The right hand side is evaluated first, so that the left hand side is on top of the stack.
+            let source = format!("void main() {{ signal_1 = signal_1 {} signal_2; }}", operator.symbol());
+            let module = compile(&source, &CompileOptions::default());
+            assert_eq!(function_instructions(&module, "main"), [Instruction::Load(-7), Instruction::Load(-6), expected, Instruction::Save(-1), Instruction::Return],
+                "{}", operator.symbol());
+        }
+    }
+
+    #[test]
+    fn xor_is_written_with_tilde() {
+        let options = CompileOptions::default();
+        let module = compile("void main(b, c) { a = b ~ c; signal_1 = a; }", &options);
+        assert!(function_instructions(&module, "main").contains(&Instruction::Xor));
+        assert!(!function_instructions(&module, "main").contains(&Instruction::Power));
+        assert_eq!(run(&module, &[0b1100, 0b1010], &options)[0], 0b0110);
+
+        // `^` is the power operator rather than XOR.
+        let module = compile("void main(b, c) { signal_1 = b ^ c; }", &options);
+        assert!(function_instructions(&module, "main").contains(&Instruction::Power));
+        assert_eq!(run(&module, &[3, 4], &options)[0], 81);
+    }
+
+    #[test]
+    fn local_loaded_after_call_in_expression() {
+        let module = compile("int g(x) { return x + 1; } void main() { a = 5; signal_1 = a - g(a); }", &CompileOptions::default());
+
+        // The return value space and argument of `g` are popped by the time `a` is loaded as the left hand side,
+        // so `a` is at the same address before and after the call.
+        assert_eq!(function_instructions(&module, "main"), [
+            Instruction::Constant(5),
+            Instruction::Constant(0),
+            Instruction::Load(2),
+            call_to(&module, "g"),
+            Instruction::Pop,
+            Instruction::Load(2),
+            Instruction::Subtract,
+            Instruction::Save(-1),
+            Instruction::Pop,
+            Instruction::Return
+        ]);
+    }
+
+    #[test]
+    fn locals_loaded_around_calls_nested_in_arithmetic() {
+        let module = compile("int g(x) { return x * 10; }
+            void main() { a = 2; b = 3; signal_1 = (a + g(b)) * (g(a) - b); }", &CompileOptions::default());
+
+        // The right hand side is evaluated first: `g(a) - b`, then `a + g(b)`.
+        // `a` is at offset 0 and `b` at offset 1, and each call leaves only its return value on the stack.
+        assert_eq!(function_instructions(&module, "main"), [
+            Instruction::Constant(2),
+            Instruction::Constant(3),
+            Instruction::Load(1),
+            Instruction::Constant(0),
+            Instruction::Load(4),
+            call_to(&module, "g"),
+            Instruction::Pop,
+            Instruction::Subtract,
+            Instruction::Constant(0),
+            Instruction::Load(3),
+            call_to(&module, "g"),
+            Instruction::Pop,
+            Instruction::Load(4),
+            Instruction::Add,
+            Instruction::Multiply,
+            Instruction::Save(-1),
+            Instruction::Pop,
+            Instruction::Pop,
+            Instruction::Return
+        ]);
+    }
+
+    #[test]
+    fn calls_nested_in_arithmetic_give_correct_results() {
+        let source = "int g(x) { return x * 10; }
+            int h(x, y) { t = x - y; return t * g(t) + g(y); }
+            void main(a, b) { c = a + 1; signal_1 = (a + g(b)) * (g(a) - b); signal_2 = h(c, b) - c; signal_3 = c; }";
+        for options in each_call_convention() {
+            let module = compile(source, &options);
+            // h(3, 3) = 0 * g(0) + g(3) = 30
+            assert_eq!(run(&module, &[2, 3], &options)[..3], [(2 + 30) * (20 - 3), 30 - 3, 3]);
+        }
+    }
+
+    #[test]
+    fn do_while_that_returns_counts_as_returning() {
+        let module = compile("int f() { do { return 1; } while 0; } void main() { signal_1 = f(); }", &CompileOptions::default());
+        assert_eq!(run(&module, &[], &CompileOptions::default())[0], 1);
+    }
+
+    #[test]
+    fn do_while_with_break_does_not_count_as_returning() {
+        let result = try_compile("int f() { do { if signal_1 { break; } return 1; } while 0; } void main() { signal_1 = f(); }",
+            &CompileOptions::default());
+        assert!(result.is_err());
+    }
+
+    // The argument counts checked by the calling convention tests.
+    const ARGUMENT_COUNTS: [usize; 4] = [0, 1, 3, 5];
+
+    // A function named `f` with the given number of arguments, returning `100 + a0 + 2 * a1 + 3 * a2 ...`,
+    // so that each argument must be read from the right place.
+    fn weighted_sum_function(argument_count: usize) -> String {
+        let arguments: Vec<String> = (0..argument_count).map(|idx| format!("a{idx}")).collect();
+        let sum: String = (0..argument_count).map(|idx| format!(" + {} * a{idx}", idx + 1)).collect();
+        format!("int f({}) {{ return 100{sum}; }}", arguments.join(", "))
+    }
+
+    #[test]
+    fn return_value_saved_to_slot_pushed_by_caller() {
+        for options in each_call_convention() {
+            for argument_count in ARGUMENT_COUNTS {
+                let arguments = vec!["0"; argument_count].join(", ");
+                let source = format!("int f({}) {{ return 7; }} void main() {{ signal_1 = f({arguments}); }}",
+                    (0..argument_count).map(|idx| format!("_a{idx}")).collect::<Vec<String>>().join(", "));
+                let module = compile(&source, &options);
+
+                // With the return value on top of the stack, the slot is below the return address (if on the stack) and the arguments.
+                let slot = 2 + return_address_slots(&options) + argument_count as i32;
+                assert_eq!(function_instructions(&module, "f"), [Instruction::Constant(7), Instruction::Save(slot), Instruction::Return],
+                    "{argument_count} argument(s) with {:?}", options.call_convention);
+
+                let mut expected_main = vec![Instruction::Constant(0); argument_count + 1];
+                expected_main.push(call_to(&module, "f"));
+                expected_main.extend(vec![Instruction::Pop; argument_count]);
+                expected_main.extend([Instruction::Save(-1), Instruction::Return]);
+                assert_eq!(function_instructions(&module, "main"), expected_main);
+            }
+        }
+    }
+
+    #[test]
+    fn calls_with_each_argument_count_return_correct_values() {
+        let inputs = [3, -4, 5, 11, -2];
+        for options in each_call_convention() {
+            for optimize in [false, true] {
+                let options = CompileOptions { optimize, call_convention: options.call_convention, ..CompileOptions::default() };
+                for argument_count in ARGUMENT_COUNTS {
+                    let arguments = ["a", "b", "c", "d", "e"][..argument_count].join(", ");
+                    // A local before the call checks that the stack is back where it started afterwards.
+                    let source = format!("{} void main(a, b, c, d, e) {{ x = 1000; signal_1 = f({arguments}) + x; signal_2 = x; }}",
+                        weighted_sum_function(argument_count));
+                    let module = compile(&source, &options);
+
+                    let sum: i32 = inputs[..argument_count].iter().enumerate().map(|(idx, input)| (idx as i32 + 1) * input).sum();
+                    assert_eq!(run(&module, &inputs, &options)[..2], [1100 + sum, 1000],
+                        "{argument_count} argument(s) with {:?}, optimize: {optimize}", options.call_convention);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn calls_as_arguments_return_correct_values() {
+        let source = "int f(x, y) { return x * 100 + y; }
+            int g(x) { return x + 1; }
+            int h(x) { return x * 2; }
+            void main(x, y) { z = 5; signal_1 = f(g(x), h(y)); signal_2 = f(h(f(x, z)), g(y) - z); signal_3 = z; }";
+        for options in each_call_convention() {
+            for optimize in [false, true] {
+                let options = CompileOptions { optimize, call_convention: options.call_convention, ..CompileOptions::default() };
+                let module = compile(source, &options);
+                // f(h(305), 8 - 5) = f(610, 3)
+                assert_eq!(run(&module, &[3, 7], &options)[..3], [4 * 100 + 14, 610 * 100 + 3, 5],
+                    "{:?}, optimize: {optimize}", options.call_convention);
+            }
+        }
+    }
+
+    // The callers push the same instructions with either calling convention, as only the callee knows where the return address is.
+    #[test]
+    fn local_loaded_after_call_as_argument() {
+        let source = "int f(a, b) { return a - b; } int g(x) { return x * 10; } void main() { x = 4; signal_1 = f(g(1), x); }";
+        for options in each_call_convention() {
+            let module = compile(source, &options);
+
+            // The result of `g(1)` is left where it was pushed as the first argument of `f`, so `x` is 3 values below the top.
+            assert_eq!(function_instructions(&module, "main"), [
+                Instruction::Constant(4),
+                Instruction::Constant(0),
+                Instruction::Constant(0),
+                Instruction::Constant(1),
+                call_to(&module, "g"),
+                Instruction::Pop,
+                Instruction::Load(3),
+                call_to(&module, "f"),
+                Instruction::Pop,
+                Instruction::Pop,
+                Instruction::Save(-1),
+                Instruction::Pop,
+                Instruction::Return
+            ]);
+            assert_eq!(run(&module, &[], &options)[0], 10 - 4);
+        }
+    }
+
+    #[test]
+    fn local_loaded_around_call_in_loop_condition() {
+        let source = "int g(x) { return x * 10; } void main() { i = 0; while g(i) < 30 { i += 1; } signal_1 = i; }";
+        for options in each_call_convention() {
+            let module = compile(source, &options);
+
+            let main_start = function_span(&module, "main").start as i32;
+            assert_eq!(function_instructions(&module, "main"), [
+                Instruction::Constant(0),
+                Instruction::Jump(main_start + 7),
+                // The body, `i += 1`, where `i` is one below the top.
+                Instruction::Constant(1),
+                Instruction::Load(2),
+                Instruction::Add,
+                Instruction::Save(2),
+                // The condition, which loads `i` with the right hand side and the return value slot above it.
+                Instruction::Constant(30),
+                Instruction::Constant(0),
+                Instruction::Load(3),
+                call_to(&module, "g"),
+                Instruction::Pop,
+                Instruction::LessThan,
+                Instruction::JumpIfNonZero(main_start + 3),
+                Instruction::Load(1),
+                Instruction::Save(-1),
+                Instruction::Pop,
+                Instruction::Return
+            ]);
+            assert_eq!(run(&module, &[], &options)[0], 3);
+        }
+    }
+
+    #[test]
+    fn local_loaded_after_calls_nested_three_deep() {
+        let source = "int f(a, b) { return a - b; } int g(x) { return x * 10; } int h(x) { return x + 1; }
+            void main() { x = 4; y = f(g(h(x)), x); signal_1 = y; }";
+        for options in each_call_convention() {
+            let module = compile(source, &options);
+
+            // Each call's return value is left in the argument slot of the call around it.
+            assert_eq!(function_instructions(&module, "main"), [
+                Instruction::Constant(4),
+                Instruction::Constant(0),
+                Instruction::Constant(0),
+                Instruction::Constant(0),
+                Instruction::Load(4),
+                call_to(&module, "h"),
+                Instruction::Pop,
+                call_to(&module, "g"),
+                Instruction::Pop,
+                Instruction::Load(3),
+                call_to(&module, "f"),
+                Instruction::Pop,
+                Instruction::Pop,
+                // `y` is the value left on top of the stack by the call.
+                Instruction::Load(1),
+                Instruction::Save(-1),
+                Instruction::Pop,
+                Instruction::Pop,
+                Instruction::Return
+            ]);
+            assert_eq!(run(&module, &[], &options)[0], 50 - 4);
+        }
+    }
+
+    #[test]
+    fn assigning_signal_out_of_range_is_an_error() {
+        let options = CompileOptions { signal_count: 5, ..CompileOptions::default() };
+        let err = try_compile("void main() { signal_6 = 1; }", &options).err().expect("Assignment to signal_6 compiled");
+        assert_eq!(err.0.len(), 1);
+        assert_eq!(err.0[0].msg, "Invalid signal number. Must be in range [1-5]");
+
+        let err = try_compile("void main() { signal_one = 1; }", &options).err().expect("Assignment to signal_one compiled");
+        assert_eq!(err.0[0].msg, "Signal number must be a valid integer");
+    }
+
+    #[test]
+    fn signal_addresses_depend_on_signal_count() {
+        let options = CompileOptions { signal_count: 8, ..CompileOptions::default() };
+        let module = compile("void main() { signal_6 = signal_2; signal_8 = signal_6; }", &options);
+
+        // Outputs are written at `-N`, and inputs read from after the outputs at `-(8 + N)`.
+        assert_eq!(function_instructions(&module, "main"), [
+            Instruction::Load(-10),
+            Instruction::Save(-6),
+            Instruction::Load(-14),
+            Instruction::Save(-8),
+            Instruction::Return
+        ]);
+    }
+
+    #[test]
+    fn reading_tick_signal_with_ticks_call_points_at_call() {
+        let err = try_compile("void main() {\n    start = ticks();\n    signal_1 = signal_5 - start;\n}", &CompileOptions::default())
+            .err().expect("Reading the tick signal compiled");
+        assert_eq!(err.0.len(), 1);
+        assert_eq!(err.0[0].msg, "signal_5 is connected to the tick counter read by `ticks()`, so cannot be read directly");
+        assert_eq!(err.0[0].position.as_ref().unwrap().line_index, 2);
+
+        let (ticks_call, note) = &err.0[0].notes[0];
+        assert_eq!((ticks_call.line_index, ticks_call.begin_char_index), (1, 12));
+        assert_eq!(note, "`ticks()` is called here");
+    }
+
+    #[test]
+    fn new_variable_similar_to_existing_gives_warning() {
+        let source = "void main() { count = 1; cuont = 2; signal_1 = count + cuont; }";
+        let warnings = compile_warnings(source, &CompileOptions::default());
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::SimilarVariableName);
+        assert_eq!(warnings[0].error.msg, "New variable `cuont` created here; did you mean `count`?");
+
+        // Numbered variables are usually meant to be different.
+        let source = "void main() { value1 = 1; value2 = 2; signal_1 = value1 + value2; }";
+        assert!(compile_warnings(source, &CompileOptions::default()).is_empty());
+    }
+
+    #[test]
+    fn inlining_removes_calls_without_changing_results() {
+        let source = "int twice(x) { return x * 2; }
+            void main(a) { signal_1 = twice(a) + twice(a + 1); }";
+        let inlined_options = CompileOptions { optimize: true, ..CompileOptions::default() };
+        let called_options = CompileOptions { optimize: true, inline_threshold: 0, ..CompileOptions::default() };
+        let inlined = compile(source, &inlined_options);
+        let called = compile(source, &called_options);
+
+        assert!(!function_instructions(&inlined, "main").iter().any(|instruction| matches!(instruction, Instruction::JumpSubRoutine(_))));
+        assert!(!inlined.function_spans.iter().any(|span| span.function_name.as_deref() == Some("twice")));
+        assert_eq!(function_instructions(&called, "main").iter().filter(|instruction| **instruction == call_to(&called, "twice")).count(), 2);
+
+        // Each call site gets its own copy of the function, but no longer runs JSR and RET.
+        assert!(function_instructions(&inlined, "main").len() > function_instructions(&called, "main").len());
+        let steps = |module: &CompiledModule| {
+            let result = emulator::run(&module.instructions, &mut SignalState::default(), &EmulatorOptions::default());
+            assert!(result.halted(), "{}", result.status);
+            result.steps
+        };
+        assert!(steps(&inlined) < steps(&called), "{} >= {}", steps(&inlined), steps(&called));
+
+        for input in [0, 5, -7, i32::MAX] {
+            assert_eq!(run(&inlined, &[input], &inlined_options), run(&called, &[input], &called_options), "{input}");
+        }
+    }
+
+    #[test]
+    fn inlined_function_with_locals_that_always_returns() {
+        let source = "int f(x) { y = x * 2; if y > 3 { z = 1; return y + z; } return y; }
+            int g(x) { total = 0; while 1 { step = x; total += step; if total > 10 { break; } } return total; }
+            void main(a) { signal_1 = f(a); signal_2 = g(a); }";
+        let options = CompileOptions { optimize: true, inline_threshold: 1000, ..CompileOptions::default() };
+        let module = compile(source, &options);
+
+        assert!(!function_instructions(&module, "main").iter().any(|instruction| matches!(instruction, Instruction::JumpSubRoutine(_))));
+        assert_eq!(run(&module, &[5], &options)[..2], [11, 15]);
+        assert_eq!(run(&module, &[1], &options)[..2], [2, 11]);
+    }
+
+    #[test]
+    fn recursive_functions_are_never_inlined() {
+        let source = "int fact(n) { if n <= 1 { return 1; } return n * fact(n - 1); }
+            int is_even(n) { if n == 0 { return 1; } return is_odd(n - 1); }
+            int is_odd(n) { if n == 0 { return 0; } return is_even(n - 1); }
+            void main(a) { signal_1 = fact(a); signal_2 = is_even(a); }";
+        let options = CompileOptions { optimize: true, inline_threshold: 1000, ..CompileOptions::default() };
+        let module = compile(source, &options);
+
+        let main = function_instructions(&module, "main");
+        assert!(main.contains(&call_to(&module, "fact")));
+        assert!(main.contains(&call_to(&module, "is_even")));
+        assert_eq!(run(&module, &[5], &options)[..2], [120, 0]);
+    }
+
+    // A profile supporting every instruction except the given ones.
+    fn profile_without(unsupported: &[&str]) -> InstructionSet {
+        let mnemonics: Vec<String> = (1..)
+            .map_while(|opcode| Instruction::from_opcode(opcode, 0))
+            .map(|instruction| instruction.mnemonic())
+            .filter(|mnemonic| !unsupported.contains(&mnemonic.as_str()))
+            .collect();
+        InstructionSet::parse(&mnemonics.join(" ")).unwrap()
+    }
+
+    #[test]
+    fn expanded_power_matches_pow_instruction() {
+        // `next` counts how many times it is called, so that the base must only be evaluated once.
+        let source = |exponent: i32| format!("global calls;
+            int next(x) {{ calls += 1; return x + calls; }}
+            void main(a) {{ signal_1 = next(a) ^ {exponent}; signal_2 = calls; signal_3 = signal_4 ^ {exponent}; signal_5 = a ^ {exponent}; }}");
+        let inputs = [[3, 0, 0, -2, 0], [-5, 0, 0, 7, 0], [46340, 0, 0, 65536, 0]];
+
+        for exponent in 0..=MAX_EXPANDED_EXPONENT {
+            let full_options = CompileOptions::default();
+            let expanded_options = CompileOptions { instruction_set: profile_without(&["POW"]), ..CompileOptions::default() };
+            let full = compile(&source(exponent), &full_options);
+            let expanded = compile(&source(exponent), &expanded_options);
+            assert!(!expanded.instructions.contains(&Instruction::Power), "^ {exponent}");
+
+            for input in inputs {
+                let outputs = run(&full, &input, &full_options);
+                assert_eq!(outputs[1], 1, "^ {exponent}");
+                assert_eq!(run(&expanded, &input, &expanded_options), outputs, "{input:?} ^ {exponent}");
+            }
+        }
+    }
+
+    #[test]
+    fn expanded_power_without_dup_only_repeats_variables() {
+        // Without folding, a literal base is expanded too.
+        let options = CompileOptions { instruction_set: profile_without(&["POW", "DUP"]), fold_constants: false, ..CompileOptions::default() };
+        for exponent in 0..=MAX_EXPANDED_EXPONENT {
+            let source = format!("void main(a) {{ signal_1 = a ^ {exponent}; signal_2 = 3 ^ {exponent}; }}");
+            let module = compile(&source, &options);
+            assert!(!module.instructions.contains(&Instruction::Power) && !module.instructions.contains(&Instruction::Duplicate));
+            assert_eq!(run(&module, &[-3], &options)[..2], [semantics::fact_pow(-3, exponent), semantics::fact_pow(3, exponent)], "^ {exponent}");
+
+            // Reading the signal again could give a different value, so only exponents that read it at most once can be expanded.
+            let result = try_compile(&format!("void main() {{ signal_1 = signal_2 ^ {exponent}; }}"), &options);
+            assert_eq!(result.is_ok(), exponent <= 1, "^ {exponent}");
+        }
+    }
+
+    #[test]
+    fn discarded_values_are_popped() {
+        let source = "global calls;
+            void count() { calls += 1; }
+            int add(x) { calls += x; return calls; }
+            void main(a) { count(); (count()); add(10); (add(100)); a + 1; signal_1 && add(1000); signal_2 = calls; signal_1 = a; }";
+        for options in each_call_convention() {
+            let module = compile(source, &options);
+            assert_eq!(run(&module, &[0], &options)[..2], [0, 112]);
+            assert_eq!(run(&module, &[5], &options)[..2], [5, 1112]);
+        }
+    }
+
+    #[test]
+    fn parenthesized_call_is_a_call_statement() {
+        let options = CompileOptions::default();
+        let source = |statement: &str| format!("int f(x) {{ signal_2 = x; return x; }} void main() {{ {statement} }}");
+        assert_eq!(compile(&source("(f(3));"), &options).instructions, compile(&source("f(3);"), &options).instructions);
+        assert!(compile_warnings(&source("(f(3));"), &options).is_empty());
+
+        let warnings = compile_warnings(&source("f(3) - 1;"), &options);
+        assert!(warnings.is_empty(), "{:?}", warnings.iter().map(|warning| &warning.error.msg).collect::<Vec<_>>());
+        let warnings = compile_warnings("void main(x) { x + 1; }", &options);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::NoEffect);
+        assert_eq!(warnings[0].error.msg, "This expression has no effect, as its value is discarded");
+    }
+
+    // Renders the single error given by a program, as `lflc` prints it without colors.
+    fn render_only_error(source: &str) -> String {
+        let err = try_compile(source, &CompileOptions::default()).err().expect("Program compiled");
+        assert_eq!(err.0.len(), 1, "{err}");
+        err.0[0].render(error_handling::Palette::PLAIN)
+    }
+
+    #[test]
+    fn duplicate_function_notes_first_definition() {
+        let source = "int add(a, b) {\n    return a + b;\n}\nvoid main() { }\nint add(c) {\n    return c;\n}";
+        assert_eq!(render_only_error(source), "\
+-------------
+error:
+at test.lfl:5:
+
+-> int add(c) {
+->     ^^^ A function with this name already exists - overloading is not supported
+
+    note:
+    at test.lfl:1:
+
+    -> int add(a, b) {
+    ->     ^^^ `add` is first defined here
+");
+    }
+
+    #[test]
+    fn wrong_argument_count_notes_definition() {
+        let source = "int add(a, b) {\n    return a + b;\n}\nvoid main() {\n    signal_1 = add(1);\n}";
+        assert_eq!(render_only_error(source), "\
+-------------
+error:
+at test.lfl:5:
+
+->     signal_1 = add(1);
+->                    ^ Wrong number of arguments, expected 2, got 1
+
+    note:
+    at test.lfl:1:
+
+    -> int add(a, b) {
+    ->        ^^^^^^ `add` is defined with these arguments
+");
+    }
+
+    #[test]
+    fn unsupported_instruction_error_points_at_operator() {
+        let instruction_set = InstructionSet::parse("CNST LOAD SAVE POP ADD MUL JSR RET HLT").unwrap();
+        let options = CompileOptions { instruction_set, ..CompileOptions::default() };
+        let err = try_compile("void main(a, b) {\n    signal_1 = a +\n        b ^ a;\n    b ^= a;\n}", &options)
+            .err().expect("POW compiled without being supported");
+
+        // Each use of `^` is reported at the operator, or the compound assignment, rather than at the start of the statement.
+        let positions: Vec<(u32, u32, u32)> = err.0.iter()
+            .map(|error| {
+                let position = error.position.as_ref().unwrap();
+                (position.line_index + 1, position.begin_char_index + 1, position.end_char_index + 1)
+            })
+            .collect();
+        assert_eq!(positions, [(3, 11, 12), (4, 7, 9)]);
+        assert!(err.0.iter().all(|error| error.msg == "This code uses the `POW` instruction, which is not supported by the target computer"));
+    }
+
+    #[test]
+    fn pass_dumps_follow_pipeline_order() {
+        let source = "int twice(x) { return x * 2; }
+            int unused() { return 1; }
+            void main(n) { signal_1 = twice(n) + 2 * 3; }";
+        let options = CompileOptions { optimize: true, dump_after: vec![Pass::Optimize, Pass::Folding, Pass::DeadCode, Pass::Inlining], ..CompileOptions::default() };
+        let module = compile(source, &options);
+
+        let passes: Vec<Pass> = module.pass_dumps.iter().map(|dump| dump.pass).collect();
+        assert_eq!(passes, [Pass::Folding, Pass::Inlining, Pass::DeadCode, Pass::Optimize]);
+        assert!(module.pass_dumps.iter().all(|dump| dump.enabled));
+
+        let dump_names = |pass: Pass| -> Vec<Option<String>> {
+            let dump = module.pass_dumps.iter().find(|dump| dump.pass == pass).unwrap();
+            dump.function_spans.iter().map(|span| span.function_name.clone()).collect()
+        };
+        let named = |names: &[&str]| -> Vec<Option<String>> { names.iter().map(|name| Some(name.to_string())).collect() };
+        assert_eq!(dump_names(Pass::Folding), named(&["twice", "unused", "main"]));
+        assert_eq!(dump_names(Pass::DeadCode), named(&["main"]));
+
+        // The inlined call is gone after inlining, but not in the code compiled for the folding dump.
+        let folded = &module.pass_dumps[0].instructions;
+        let inlined = &module.pass_dumps[1].instructions;
+        assert!(folded.contains(&Instruction::Constant(6)));
+        assert!(folded.iter().any(|instruction| matches!(instruction, Instruction::JumpSubRoutine(0))));
+        assert_eq!(inlined.iter().filter(|instruction| matches!(instruction, Instruction::JumpSubRoutine(_))).count(), 0);
+
+        // The last pass leaves the program as it is output.
+        let optimized = &module.pass_dumps[3];
+        assert!(optimized.linked);
+        assert_eq!(optimized.instructions, module.instructions);
+    }
+
+    #[test]
+    fn disabled_passes_are_dumped_unchanged() {
+        let options = CompileOptions { dump_after: Pass::ALL.to_vec(), ..CompileOptions::default() };
+        let module = compile("int f(x) { return f(x); } void main() { signal_1 = f(1); }", &options);
+
+        let enabled: Vec<(Pass, bool)> = module.pass_dumps.iter().map(|dump| (dump.pass, dump.enabled)).collect();
+        assert_eq!(enabled, [(Pass::Folding, true), (Pass::TailCalls, false), (Pass::Inlining, false), (Pass::DeadCode, true),
+            (Pass::FoldIdenticalFunctions, false), (Pass::Optimize, false)]);
+        assert_eq!(module.pass_dumps[1].instructions, module.pass_dumps[2].instructions);
+        assert_eq!(module.pass_dumps[4].instructions, module.instructions);
+        assert_eq!(module.pass_dumps[5].instructions, module.instructions);
+    }
+
+    #[test]
+    fn pass_names_round_trip() {
+        for pass in Pass::ALL {
+            assert_eq!(Pass::from_name(pass.name()), Some(pass));
+        }
+        assert_eq!(Pass::from_name("all"), None);
+    }
+
+    #[test]
+    fn cached_builds_match_cold_builds() {
+        let versions = [
+            // The first build compiles every function.
+            ("int twice(x) { return x * 2; }\nint square(x) { return x * x; }\nvoid main(n) { signal_1 = twice(n) + square(n); }", 0),
+            ("int twice(x) { return x * 2; }\nint square(x) { return x * x; }\nvoid main(n) { signal_1 = twice(n) + square(n); }", 3),
+            // Only the changed function is compiled again.
+            ("int twice(x) { return x * 3; }\nint square(x) { return x * x; }\nvoid main(n) { signal_1 = twice(n) + square(n); }", 2),
+            // Moving functions to other lines doesn't change them, but their source maps are moved along.
+            ("// doubles\n\nint twice(x) { return x * 3; }\nint square(x) { return x * x; }\nvoid main(n) { signal_1 = twice(n) + square(n); }", 3),
+            // A constant used by any function could have changed.
+            ("const K = 2;\nint twice(x) { return x * K; }\nint square(x) { return x * x; }\nvoid main(n) { signal_1 = twice(n) + square(n); }", 0),
+            // A new function changes the id of those after it, so the calls to them are compiled again.
+            ("const K = 2;\nint zero() { return 0; }\nint twice(x) { return x * K; }\nint square(x) { return x * x; }\nvoid main(n) { signal_1 = twice(n) + square(n) + zero(); }", 2)
+        ];
+
+        // Every pass is dumped with `--stats`, including the code compiled separately for the `folding` dump.
+        let dumped = CompileOptions { optimize: true, dump_after: Pass::ALL.to_vec(), ..CompileOptions::default() };
+        for options in [CompileOptions::default(), CompileOptions { optimize: true, ..CompileOptions::default() }, dumped] {
+            let mut cache = FunctionCache::default();
+            for (source, hits) in versions {
+                let cached = try_compile_cached(source, &options, &mut cache).unwrap();
+                let cold = compile(source, &options);
+                assert_eq!(cached.instructions, cold.instructions, "{source}");
+                assert_eq!(format!("{:?}", cached.source_map), format!("{:?}", cold.source_map), "{source}");
+                let dumped_code = |module: &CompiledModule| -> Vec<Vec<Instruction>> { module.pass_dumps.iter().map(|dump| dump.instructions.clone()).collect() };
+                assert_eq!(dumped_code(&cached), dumped_code(&cold), "{source}");
+
+                // With `optimize`, functions that inline others are always compiled again.
+                if !options.optimize {
+                    let function_count = cold.function_spans.iter().filter(|span| span.function_name.is_some()).count();
+                    assert_eq!((cache.hits, cache.misses), (hits, function_count - hits), "{source}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn signature_changes_recompile_callers() {
+        let options = CompileOptions::default();
+        let mut cache = FunctionCache::default();
+        try_compile_cached("int f(a) { return a; }\nvoid main() { signal_1 = f(1); }", &options, &mut cache).unwrap();
+
+        // `main` hasn't changed, but its call to `f` no longer matches.
+        let err = try_compile_cached("int f(a, b) { return a + b; }\nvoid main() { signal_1 = f(1); }", &options, &mut cache).err().expect("Compiled successfully");
+        assert_eq!(err.0[0].msg, "Wrong number of arguments, expected 2, got 1");
+
+        let err = try_compile_cached("void f(a) { signal_2 = a; }\nvoid main() { signal_1 = f(1); }", &options, &mut cache).err().expect("Compiled successfully");
+        assert_eq!(err.0[0].msg, "Cannot use a function that does not return a value within an expression");
+
+        let module = try_compile_cached("int f(a) { return a; }\nvoid main() { signal_1 = f(1); }", &options, &mut cache).unwrap();
+        assert_eq!(run(&module, &[], &options)[0], 1);
+    }
+}
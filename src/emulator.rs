@@ -0,0 +1,535 @@
+//! Runs compiled programs on the host, so that they can be debugged without importing them into factorio.
+
+use std::{collections::HashMap, fmt::{self, Display}};
+
+use serde::Serialize;
+
+use crate::{assembly::Instruction, compiler::{self, CallConvention, SIGNAL_COUNT}, semantics};
+
+// Number of values the stack of the computer can hold.
+pub const STACK_CAPACITY: usize = 32;
+
+// The version of `RunResult` written by `emulate --output-format json`.
+// This goes up whenever a field is removed or changes meaning, but not when one is added.
+pub const RUN_RESULT_VERSION: u32 = 1;
+
+// The GPIO signals that a program reads from and writes to.
+// Implement this to control the values a program reads, e.g. to change an input after it has been written to.
+pub trait Signals {
+    // Reads the green input signal `signal`, from 1 to the signal count.
+    fn read(&mut self, signal: i32) -> i32;
+
+    // Writes to the red output signal `signal`, from 1 to the signal count.
+    fn write(&mut self, signal: i32, value: i32);
+}
+
+// Signals with fixed inputs, which keeps the last value written to each output.
+#[derive(Clone, Debug)]
+pub struct SignalState {
+    pub inputs: Vec<i32>,
+    pub outputs: Vec<i32>
+}
+
+impl SignalState {
+    // Creates the state for a computer with `signal_count` signals, which are all zero.
+    pub fn new(signal_count: i32) -> Self {
+        Self {
+            inputs: vec![0; signal_count as usize],
+            outputs: vec![0; signal_count as usize]
+        }
+    }
+}
+
+impl Default for SignalState {
+    fn default() -> Self {
+        Self::new(SIGNAL_COUNT)
+    }
+}
+
+impl Signals for SignalState {
+    fn read(&mut self, signal: i32) -> i32 {
+        self.inputs[signal as usize - 1]
+    }
+
+    fn write(&mut self, signal: i32, value: i32) {
+        self.outputs[signal as usize - 1] = value;
+    }
+}
+
+// Options controlling how the emulated computer behaves.
+pub struct EmulatorOptions {
+    // The number of instructions to execute before giving up, so that a program that never halts gives an error.
+    pub max_steps: u64,
+    // Must match the convention the program was compiled with.
+    pub call_convention: CallConvention,
+    // Must match the signal count the program was compiled with, and the number of signals in `SignalState`.
+    pub signal_count: i32,
+    // The input signal connected to the tick counter read by `ticks()`, or None if the program doesn't read the clock.
+    // The counter starts at the value of the input, and goes up by one for each instruction executed.
+    pub tick_signal: Option<i32>
+}
+
+impl Default for EmulatorOptions {
+    fn default() -> Self {
+        Self {
+            max_steps: 1_000_000,
+            call_convention: CallConvention::default(),
+            signal_count: SIGNAL_COUNT,
+            tick_signal: None
+        }
+    }
+}
+
+// What happened when running a program, returned by `run`.
+#[derive(Clone, Debug, Serialize)]
+pub struct RunResult {
+    // Always `RUN_RESULT_VERSION`, so that tools reading the JSON can check they understand it.
+    pub version: u32,
+    pub status: RunStatus,
+    // The number of instructions executed, not including one that failed.
+    pub steps: u64,
+    // The last value written to each output signal, which is 0 for those never written.
+    pub outputs: Vec<i32>,
+    // The most values held on the stack at once. With the split stack convention, return addresses aren't included.
+    pub peak_stack_depth: usize
+}
+
+impl RunResult {
+    pub fn halted(&self) -> bool {
+        self.status == RunStatus::Halted
+    }
+}
+
+// Why a program stopped running.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum RunStatus {
+    // The program jumped outside of the ROM, e.g. with `HLT` or by returning from the entry point.
+    Halted,
+    // The program was still running after `EmulatorOptions::max_steps` instructions.
+    StepLimit,
+    // An instruction could not be executed.
+    RuntimeError {
+        code: RuntimeErrorCode,
+        // The address of the instruction that failed. The first instruction has address 1.
+        program_counter: i32,
+        // The failed instruction in assembly, e.g. `LOAD 3`.
+        instruction: String,
+        message: String
+    }
+}
+
+// The kinds of error that stop a program while it is running.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuntimeErrorCode {
+    StackOverflow,
+    StackUnderflow,
+    // A stack address beyond the values on the stack.
+    AddressOutsideStack,
+    ReadOutputSignal,
+    WriteInputSignal,
+    // `RET` with the split stack convention, when there is no return address.
+    ReturnWithoutCall
+}
+
+// An error executing an instruction, before the address of the instruction is known.
+struct RuntimeError {
+    code: RuntimeErrorCode,
+    message: String
+}
+
+impl RuntimeError {
+    fn new(code: RuntimeErrorCode, message: impl Display) -> Self {
+        Self { code, message: message.to_string() }
+    }
+}
+
+impl Display for RunStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunStatus::Halted => write!(f, "Halted"),
+            RunStatus::StepLimit => write!(f, "Program did not halt within the step limit"),
+            RunStatus::RuntimeError { program_counter, instruction, message, .. } => write!(f, "At instruction {program_counter} ({instruction}): {message}")
+        }
+    }
+}
+
+// The state of the computer while running a program.
+struct Machine<'a, S: Signals> {
+    program: &'a [Instruction],
+    // The top of the stack is the last value.
+    stack: Vec<i32>,
+    // Only used with the split stack calling convention.
+    return_addresses: Vec<i32>,
+    // Values saved to the addresses used for global variables. Addresses that have not been saved to contain 0.
+    globals: HashMap<i32, i32>,
+    // Address of the instruction being executed. The first instruction has address 1.
+    program_counter: i32,
+    // The value of the tick counter, which is read in place of the tick signal.
+    ticks: i32,
+    // The last value written to each output signal.
+    outputs: Vec<i32>,
+    signals: &'a mut S,
+    options: &'a EmulatorOptions
+}
+
+// Runs a program until it halts by jumping outside of the ROM, fails, or reaches the step limit.
+pub fn run<S: Signals>(program: &[Instruction], signals: &mut S, options: &EmulatorOptions) -> RunResult {
+    let ticks = options.tick_signal.map_or(0, |signal| signals.read(signal));
+    let mut machine = Machine {
+        program,
+        stack: Vec::new(),
+        return_addresses: Vec::new(),
+        globals: HashMap::new(),
+        program_counter: 1,
+        ticks,
+        outputs: vec![0; options.signal_count as usize],
+        signals,
+        options
+    };
+
+    let mut steps = 0;
+    let mut peak_stack_depth = 0;
+    let status = loop {
+        let instruction = match machine.current_instruction() {
+            Some(instruction) => instruction,
+            None => break RunStatus::Halted
+        };
+
+        if steps == options.max_steps {
+            break RunStatus::StepLimit;
+        }
+
+        if let Err(err) = machine.step(instruction) {
+            break RunStatus::RuntimeError {
+                code: err.code,
+                program_counter: machine.program_counter,
+                instruction: instruction.to_string(),
+                message: err.message
+            };
+        }
+        steps += 1;
+        peak_stack_depth = peak_stack_depth.max(machine.stack.len());
+        // The computer is modelled as executing one instruction per tick.
+        machine.ticks = machine.ticks.wrapping_add(1);
+    };
+
+    RunResult {
+        version: RUN_RESULT_VERSION,
+        status,
+        steps,
+        outputs: machine.outputs,
+        peak_stack_depth
+    }
+}
+
+impl<S: Signals> Machine<'_, S> {
+    // Gets the instruction at the program counter, or None if the program has halted.
+    fn current_instruction(&self) -> Option<Instruction> {
+        if self.program_counter < 1 {
+            None
+        }   else {
+            self.program.get(self.program_counter as usize - 1).copied()
+        }
+    }
+
+    fn push(&mut self, value: i32) -> Result<(), RuntimeError> {
+        if self.stack.len() == STACK_CAPACITY {
+            Err(RuntimeError::new(RuntimeErrorCode::StackOverflow, format!("Stack overflow: the stack can only hold {STACK_CAPACITY} values")))
+        }   else {
+            self.stack.push(value);
+            Ok(())
+        }
+    }
+
+    fn pop(&mut self) -> Result<i32, RuntimeError> {
+        self.stack.pop().ok_or_else(stack_underflow)
+    }
+
+    // Converts a stack address, where 1 is the top of the stack, into an index into `stack`.
+    fn stack_index(&self, address: i32) -> Result<usize, RuntimeError> {
+        if address >= 1 && address as usize <= self.stack.len() {
+            Ok(self.stack.len() - address as usize)
+        }   else {
+            Err(RuntimeError::new(RuntimeErrorCode::AddressOutsideStack,
+                format!("Address {address} is outside of the stack, which contains {} values", self.stack.len())))
+        }
+    }
+
+    fn load(&mut self, address: i32) -> Result<i32, RuntimeError> {
+        let signal_count = self.options.signal_count;
+        if address <= compiler::first_global_address(signal_count) {
+            Ok(self.globals.get(&address).copied().unwrap_or(0))
+        }   else if self.options.tick_signal.is_some_and(|signal| address == -(signal_count + signal)) {
+            Ok(self.ticks)
+        }   else if (-2 * signal_count..-signal_count).contains(&address) {
+            Ok(self.signals.read(-address - signal_count))
+        }   else if address < 0 {
+            Err(RuntimeError::new(RuntimeErrorCode::ReadOutputSignal, format!("Address {address} is an output signal, which cannot be read")))
+        }   else {
+            Ok(self.stack[self.stack_index(address)?])
+        }
+    }
+
+    fn save(&mut self, address: i32, value: i32) -> Result<(), RuntimeError> {
+        if address <= compiler::first_global_address(self.options.signal_count) {
+            self.globals.insert(address, value);
+        }   else if (-self.options.signal_count..0).contains(&address) {
+            self.signals.write(-address, value);
+            self.outputs[(-address - 1) as usize] = value;
+        }   else if address < 0 {
+            return Err(RuntimeError::new(RuntimeErrorCode::WriteInputSignal, format!("Address {address} is an input signal, which cannot be written to")));
+        }   else {
+            let index = self.stack_index(address)?;
+            self.stack[index] = value;
+        }
+
+        Ok(())
+    }
+
+    // Executes a single instruction and moves the program counter to the next one.
+    fn step(&mut self, instruction: Instruction) -> Result<(), RuntimeError> {
+        let mut next_instruction = self.program_counter + 1;
+
+        match instruction {
+            Instruction::Jump(addr) => next_instruction = addr,
+            Instruction::JumpIfNonZero(addr) => if self.pop()? != 0 {
+                next_instruction = addr;
+            },
+            Instruction::JumpIfZero(addr) => if self.pop()? == 0 {
+                next_instruction = addr;
+            },
+            // The address is relative to the stack before the value is popped.
+            Instruction::Save(addr) => {
+                let value = *self.stack.last().ok_or_else(stack_underflow)?;
+                self.save(addr, value)?;
+                self.pop()?;
+            },
+            Instruction::Load(addr) => {
+                let value = self.load(addr)?;
+                self.push(value)?;
+            },
+            Instruction::Constant(value) => self.push(value)?,
+            Instruction::Not => {
+                let value = self.pop()?;
+                self.push((value == 0) as i32)?;
+            },
+            Instruction::Pop => { self.pop()?; },
+            // Addresses before the first instruction are outside of the ROM, so the program stops.
+            Instruction::Halt => next_instruction = 0,
+            Instruction::Nop => {},
+            Instruction::Duplicate => {
+                let value = *self.stack.last().ok_or_else(stack_underflow)?;
+                self.push(value)?;
+            },
+            Instruction::Negate => {
+                let value = self.pop()?;
+                self.push(semantics::fact_neg(value))?;
+            },
+            Instruction::JumpSubRoutine(addr) => {
+                match self.options.call_convention {
+                    CallConvention::Interleaved => self.push(next_instruction)?,
+                    CallConvention::SplitStack => self.return_addresses.push(next_instruction)
+                }
+
+                next_instruction = addr;
+            },
+            Instruction::Return => {
+                next_instruction = match self.options.call_convention {
+                    CallConvention::Interleaved => self.pop()?,
+                    CallConvention::SplitStack => self.return_addresses.pop()
+                        .ok_or_else(|| RuntimeError::new(RuntimeErrorCode::ReturnWithoutCall, "Return without a matching JSR"))?
+                };
+            },
+            _ => {
+                // A is the top of the stack, B is the value below it.
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(evaluate_binary(instruction, a, b))?;
+            }
+        }
+
+        self.program_counter = next_instruction;
+        Ok(())
+    }
+}
+
+fn stack_underflow() -> RuntimeError {
+    RuntimeError::new(RuntimeErrorCode::StackUnderflow, "Stack underflow")
+}
+
+// Calculates the result of a binary instruction the same way as an arithmetic or decider combinator.
+fn evaluate_binary(instruction: Instruction, a: i32, b: i32) -> i32 {
+    match instruction {
+        Instruction::Add => semantics::fact_add(a, b),
+        Instruction::Subtract => semantics::fact_sub(a, b),
+        Instruction::Multiply => semantics::fact_mul(a, b),
+        Instruction::Divide => semantics::fact_div(a, b),
+        Instruction::Remainder => semantics::fact_rem(a, b),
+        Instruction::Power => semantics::fact_pow(a, b),
+        Instruction::ShiftLeft => semantics::fact_shl(a, b),
+        Instruction::ShiftRight => semantics::fact_shr(a, b),
+        Instruction::And => a & b,
+        Instruction::Or => a | b,
+        Instruction::Xor => a ^ b,
+        Instruction::Equal => (a == b) as i32,
+        Instruction::NotEqual => (a != b) as i32,
+        Instruction::GreaterThan => (a > b) as i32,
+        Instruction::LessThan => (a < b) as i32,
+        Instruction::GreaterThanOrEqual => (a >= b) as i32,
+        Instruction::LessThanOrEqual => (a <= b) as i32,
+        _ => unreachable!("{instruction} is not a binary instruction")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::CompileOptions, testing};
+
+    // Compiles and runs a program with the given input signals, returning the output signals and the number of instructions executed.
+    fn run_source(source: &str, inputs: &[i32]) -> (Vec<i32>, u64) {
+        let compile_options = CompileOptions::default();
+        let module = testing::compile(source, &compile_options);
+
+        let mut signals = SignalState::default();
+        signals.inputs[..inputs.len()].copy_from_slice(inputs);
+        let options = EmulatorOptions {
+            tick_signal: module.uses_ticks.then_some(compile_options.tick_signal),
+            ..EmulatorOptions::default()
+        };
+
+        let result = run(&module.instructions, &mut signals, &options);
+        assert!(result.halted(), "{}", result.status);
+        (result.outputs, result.steps)
+    }
+
+    #[test]
+    fn tail_recursion_only_fits_on_stack_when_optimized() {
+        let source = "int sum(n, total) {
+                if n == 0 {
+                    return total;
+                }
+                return sum(n - 1, total + n);
+            }
+            void main() { signal_1 = sum(10000, 0); }";
+
+        for call_convention in [CallConvention::Interleaved, CallConvention::SplitStack] {
+            let options = EmulatorOptions { call_convention, ..EmulatorOptions::default() };
+
+            let optimized = testing::compile(source, &CompileOptions { call_convention, optimize: true, ..CompileOptions::default() });
+            let mut signals = SignalState::default();
+            let result = run(&optimized.instructions, &mut signals, &options);
+            assert!(result.halted(), "{call_convention:?}: {}", result.status);
+            assert_eq!(result.outputs[0], 50005000, "{call_convention:?}");
+
+            // Each call keeps the return slot and arguments of the caller on the stack.
+            let unoptimized = testing::compile(source, &CompileOptions { call_convention, ..CompileOptions::default() });
+            let result = run(&unoptimized.instructions, &mut SignalState::default(), &options);
+            assert!(matches!(result.status, RunStatus::RuntimeError { code: RuntimeErrorCode::StackOverflow, .. }), "{call_convention:?}: {}", result.status);
+            assert_eq!(result.peak_stack_depth, STACK_CAPACITY, "{call_convention:?}");
+        }
+    }
+
+    #[test]
+    fn timeout_loop_ends() {
+        let source = "void main() {
+                start = ticks();
+                polls = 0;
+                while ticks() < start + 100 {
+                    polls += 1;
+                }
+                signal_1 = polls;
+                signal_2 = ticks() - start;
+            }";
+        let (outputs, steps) = run_source(source, &[]);
+
+        // Each poll takes several instructions, so there are fewer polls than ticks.
+        assert!(outputs[0] > 0 && outputs[0] < 100, "{outputs:?}");
+        assert!(outputs[1] >= 100, "{outputs:?}");
+        assert!(steps < 1000);
+    }
+
+    #[test]
+    fn tick_counter_starts_at_input_value() {
+        let (outputs, _) = run_source("void main() { signal_1 = ticks(); }", &[0, 0, 0, 0, 1000]);
+        assert!(outputs[0] >= 1000 && outputs[0] < 1010, "{outputs:?}");
+    }
+
+    #[test]
+    fn tick_signal_is_an_input_without_ticks_call() {
+        let (outputs, _) = run_source("void main() { signal_1 = signal_5; }", &[0, 0, 0, 0, 7]);
+        assert_eq!(outputs[0], 7);
+    }
+
+    #[test]
+    fn halted_result_has_outputs_and_peak_stack() {
+        let module = testing::compile("void main() { signal_1 = 3; signal_4 = signal_2 * 2; }", &CompileOptions::default());
+        let mut signals = SignalState::default();
+        signals.inputs[1] = 21;
+
+        let result = run(&module.instructions, &mut signals, &EmulatorOptions::default());
+        assert_eq!(result.status, RunStatus::Halted);
+        assert_eq!(&result.outputs[..4], &[3, 0, 0, 42]);
+        assert_eq!(result.outputs.len(), SIGNAL_COUNT as usize);
+        assert!(result.peak_stack_depth >= 2 && result.peak_stack_depth < STACK_CAPACITY, "{}", result.peak_stack_depth);
+
+        let json: serde_json::Value = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["version"], RUN_RESULT_VERSION);
+        assert_eq!(json["status"], serde_json::json!({ "kind": "halted" }));
+        assert_eq!(json["steps"], result.steps);
+        assert_eq!(json["outputs"][0], 3);
+        assert_eq!(json["outputs"][3], 42);
+    }
+
+    #[test]
+    fn runtime_error_result_has_code_and_program_counter() {
+        // Writing to the first input signal.
+        let program = [Instruction::Constant(1), Instruction::Constant(2), Instruction::Save(-SIGNAL_COUNT - 1)];
+        let result = run(&program, &mut SignalState::default(), &EmulatorOptions::default());
+
+        assert_eq!(result.steps, 2);
+        assert_eq!(result.peak_stack_depth, 2);
+        assert_eq!(result.status.to_string(), format!("At instruction 3 (SAVE {}): Address {} is an input signal, which cannot be written to", -SIGNAL_COUNT - 1, -SIGNAL_COUNT - 1));
+
+        let json: serde_json::Value = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["status"]["kind"], "runtime-error");
+        assert_eq!(json["status"]["code"], "write-input-signal");
+        assert_eq!(json["status"]["program_counter"], 3);
+        assert_eq!(json["status"]["instruction"], format!("SAVE {}", -SIGNAL_COUNT - 1));
+    }
+
+    #[test]
+    fn source_can_call_assembly_functions() {
+        let assembly = ".func triple args=1 returns=1
+            CNST 3
+            LOAD 3 ; the argument, below the constant and return address
+            MUL
+            SAVE 4 ; the return slot
+            RET
+
+            .func emit args=1 returns=0
+            LOAD 2
+            SAVE -2
+            RET";
+        let source = "void main(n) { emit(triple(n) + 1); signal_1 = triple(triple(n)); }";
+
+        for optimize in [false, true] {
+            let options = CompileOptions { optimize, ..CompileOptions::default() };
+            let module = testing::try_compile_with_assembly(source, assembly, &options).unwrap();
+            assert_eq!(testing::run(&module, &[2], &options)[..2], [18, 7], "optimize: {optimize}");
+
+            // The stack used by the assembly functions isn't known.
+            assert_eq!(module.max_stack_depth, None);
+        }
+    }
+
+    #[test]
+    fn step_limit_stops_program() {
+        let program = [Instruction::Jump(1)];
+        let result = run(&program, &mut SignalState::default(), &EmulatorOptions { max_steps: 10, ..EmulatorOptions::default() });
+        assert_eq!(result.status, RunStatus::StepLimit);
+        assert_eq!(result.steps, 10);
+    }
+}
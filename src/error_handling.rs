@@ -1,6 +1,8 @@
 //! Module for error reporting that links to source files.
 
-use std::{sync::Arc, io, fs, fmt::{Display, self}};
+use std::{collections::HashSet, sync::Arc, io, fs, fmt::{Display, self}};
+
+use serde::Serialize;
 
 // A file from which code is read.
 pub struct SourceFile {
@@ -11,20 +13,29 @@ pub struct SourceFile {
 impl SourceFile {
     // Loads the text from a particular path into a source file.
     pub fn load_from_path(path: String) -> io::Result<Self> {
-        Ok(Self {
-            text: fs::read_to_string(&path)?,
-            path,
-        })
+        let text = fs::read_to_string(&path)?;
+        Ok(Self::from_text(path, text))
+    }
+
+    // Creates a source file from text held in memory, e.g. read from stdin.
+    // The path is only used when reporting errors, so can be a label such as `<stdin>`.
+    pub fn from_text(path: String, text: String) -> Self {
+        Self {
+            text,
+            path
+        }
     }
 }
 
 // A reference to a particular character, or range of characters, within a source file.
+// The range can span multiple lines, e.g. for an expression split across lines.
 #[derive(Clone)]
 pub struct FileRef {
     pub file: Arc<SourceFile>,
     pub line_index: u32,
     pub begin_char_index: u32, // The first character of text included in the reference
-    pub length: u32
+    pub end_line_index: u32, // The line containing the last character of text included.
+    pub end_char_index: u32 // The character after the last character included, within the end line.
 }
 
 impl fmt::Debug for FileRef {
@@ -34,56 +45,434 @@ impl fmt::Debug for FileRef {
 }
 
 // A singular compilation error, linked to a location in the source file.
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct FileTaggedError {
     pub position: Option<FileRef>, // May be None in the case of linking errors.
-    pub msg: String
+    pub msg: String,
+    // Other code relevant to the error, e.g. an earlier definition, each with a message explaining it.
+    pub notes: Vec<(FileRef, String)>
 }
 
-impl Display for FileTaggedError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "-------------")?;
+// The ANSI escape codes used to highlight parts of a diagnostic, which are all empty when colors are disabled.
+#[derive(Clone, Copy)]
+pub struct Palette {
+    pub error: &'static str,
+    pub warning: &'static str,
+    // Used for the file and line of a diagnostic.
+    pub location: &'static str,
+    pub note: &'static str,
+    pub reset: &'static str
+}
+
+impl Palette {
+    pub const PLAIN: Palette = Palette {
+        error: "",
+        warning: "",
+        location: "",
+        note: "",
+        reset: ""
+    };
+
+    pub const COLORED: Palette = Palette {
+        error: "\x1b[1;31m",
+        warning: "\x1b[1;33m",
+        location: "\x1b[1m",
+        note: "\x1b[1;36m",
+        reset: "\x1b[0m"
+    };
+}
+
+// An error or warning, which can be written with or without colors.
+pub trait Diagnostic {
+    fn write(&self, f: &mut fmt::Formatter<'_>, palette: Palette) -> fmt::Result;
+}
+
+// Displays a diagnostic with the colors of a palette, e.g. `eprintln!("{}", Styled(&error, Palette::COLORED))`.
+// Displaying the diagnostic directly is the same as using `Palette::PLAIN`.
+pub struct Styled<'a, T: Diagnostic>(pub &'a T, pub Palette);
+
+impl <T: Diagnostic> Display for Styled<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.write(f, self.1)
+    }
+}
 
+impl FileTaggedError {
+    // Writes the location of the error and the message, followed by any notes, without the separator line.
+    // The part of the source code that the error refers to is underlined in the `highlight` color.
+    fn fmt_body(&self, f: &mut std::fmt::Formatter<'_>, palette: Palette, highlight: &str) -> std::fmt::Result {
         match &self.position {
-            Some(position) => {
-                let line = position.file.text
-                    .lines()
-                    .nth(position.line_index as usize)
-                    .unwrap_or("<end of file>");
-
-                writeln!(f, "at {}:{}:", position.file.path, position.line_index + 1)?;
-                writeln!(f)?;
-
-                writeln!(f, "-> {line}")?;
-                write!(f, "-> ")?;
-                for _ in 0..(position.begin_char_index)  {
-                    write!(f, " ")?;
-                }
-
-                for _ in 0..position.length {
-                    write!(f, "^")?;
-                }
-                writeln!(f, " {}", self.msg)?;
-            },
+            Some(position) => write_excerpt(f, position, &self.msg, palette, highlight, "")?,
             None => writeln!(f, "{}", self.msg)?
         }
 
+        // Each note is indented below the error, with its own excerpt of the source code.
+        for (position, msg) in &self.notes {
+            writeln!(f)?;
+            writeln!(f, "    {}note{}:", palette.note, palette.reset)?;
+            write_excerpt(f, position, msg, palette, palette.note, "    ")?;
+        }
+
         Ok(())
     }
 }
 
+// Writes the file and line of `position`, then the source code it refers to, underlined and followed by `msg`.
+// Each line written begins with `indent`.
+fn write_excerpt(f: &mut fmt::Formatter<'_>, position: &FileRef, msg: &str, palette: Palette, highlight: &str, indent: &str) -> fmt::Result {
+    writeln!(f, "{indent}{}at {}:{}:{}", palette.location, position.file.path, position.line_index + 1, palette.reset)?;
+    writeln!(f)?;
+
+    // Each line in the range is printed, with the part of it within the range underlined.
+    for line_index in position.line_index..=position.end_line_index {
+        let line = position.file.text
+            .lines()
+            .nth(line_index as usize)
+            .unwrap_or("<end of file>");
+
+        let underline_begin = if line_index == position.line_index {
+            position.begin_char_index
+        }   else {
+            // Skip indentation on the lines after the first.
+            line.chars().take_while(|c| c.is_whitespace()).count() as u32
+        };
+
+        let underline_end = if line_index == position.end_line_index {
+            position.end_char_index
+        }   else {
+            line.chars().count() as u32
+        };
+
+        // Character indices are converted to columns on screen, so that the underline lines up with the text above it.
+        // Tabs are copied, since their width depends on the terminal, and wide characters take up two columns.
+        let mut chars = line.chars();
+        writeln!(f, "{indent}-> {line}")?;
+        write!(f, "{indent}-> ")?;
+        for c in chars.by_ref().take(underline_begin as usize) {
+            match c {
+                '\t' => write!(f, "\t")?,
+                _ => write!(f, "{}", " ".repeat(display_width(c)))?
+            }
+        }
+
+        // The underline may continue past the end of the line, e.g. for an unexpected end of file.
+        write!(f, "{highlight}")?;
+        for _ in underline_begin..underline_end {
+            let width = chars.next().map_or(1, display_width).max(1);
+            write!(f, "{}", "^".repeat(width))?;
+        }
+        write!(f, "{}", palette.reset)?;
+
+        if line_index == position.end_line_index {
+            writeln!(f, " {msg}")?;
+        }   else {
+            writeln!(f)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Gets the number of columns that a character takes up in a terminal.
+// Combining marks are drawn over the previous character, and most CJK characters and emoji are drawn twice as wide.
+fn display_width(c: char) -> usize {
+    match c as u32 {
+        0x0300..=0x036F | 0x200B..=0x200F | 0xFE00..=0xFE0F => 0,
+        0x1100..=0x115F
+        | 0x2E80..=0x303E
+        | 0x3041..=0x33FF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xA000..=0xA4CF
+        | 0xAC00..=0xD7A3
+        | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F
+        | 0xFF00..=0xFF60
+        | 0xFFE0..=0xFFE6
+        | 0x1F300..=0x1F64F
+        | 0x1F900..=0x1F9FF
+        | 0x20000..=0x3FFFD => 2,
+        _ => 1
+    }
+}
+
+impl Diagnostic for FileTaggedError {
+    fn write(&self, f: &mut fmt::Formatter<'_>, palette: Palette) -> fmt::Result {
+        writeln!(f, "-------------")?;
+        writeln!(f, "{}error{}:", palette.error, palette.reset)?;
+        self.fmt_body(f, palette, palette.error)
+    }
+}
+
+impl Display for FileTaggedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write(f, Palette::PLAIN)
+    }
+}
+
+impl std::error::Error for FileTaggedError {}
+
+impl FileTaggedError {
+    // Writes the error as it would be printed by `lflc`, including the code it points to.
+    // Pass `Palette::PLAIN` to leave out the escape codes for colors.
+    pub fn render(&self, palette: Palette) -> String {
+        Styled(self, palette).to_string()
+    }
+}
+
+// The kinds of warning that can be generated.
+// Each has a name that can be passed to `-D` to promote it to an error.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WarningKind {
+    // A while loop whose condition does not read any variable modified within the loop.
+    UnmodifiedLoopCondition,
+    // A comparison whose left hand side is another comparison, e.g. `0 < x < 10`.
+    ChainedComparison,
+    // An assignment that creates a new variable with a name similar to an existing one, which may be a typo.
+    SimilarVariableName,
+    // A function that cannot be reached from the entry point, so is left out of the program.
+    UnusedFunction,
+    // A function that calls itself, directly or through other functions, so could overflow the stack.
+    Recursion,
+    // A function argument with the same name as a function.
+    ArgumentShadowsFunction,
+    // A statement after one that always leaves the block, e.g. a `return`, which can never run.
+    UnreachableCode,
+    // A local variable or argument whose value is never read.
+    UnusedVariable,
+    // An expression statement that doesn't call any functions, so only computes a value which is discarded.
+    NoEffect,
+    // A shift by a constant amount that is negative or at least 32.
+    ShiftOutOfRange
+}
+
+impl WarningKind {
+    // Every kind of warning, in the order they are listed in the README.
+    pub const ALL: [WarningKind; 10] = [
+        WarningKind::UnmodifiedLoopCondition,
+        WarningKind::ChainedComparison,
+        WarningKind::SimilarVariableName,
+        WarningKind::UnusedFunction,
+        WarningKind::Recursion,
+        WarningKind::UnreachableCode,
+        WarningKind::UnusedVariable,
+        WarningKind::NoEffect,
+        WarningKind::ShiftOutOfRange,
+        WarningKind::ArgumentShadowsFunction
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            WarningKind::UnmodifiedLoopCondition => "unmodified-loop-condition",
+            WarningKind::ChainedComparison => "chained-comparison",
+            WarningKind::SimilarVariableName => "similar-variable-name",
+            WarningKind::UnusedFunction => "unused-function",
+            WarningKind::Recursion => "recursion",
+            WarningKind::ArgumentShadowsFunction => "argument-shadows-function",
+            WarningKind::UnreachableCode => "unreachable-code",
+            WarningKind::UnusedVariable => "unused-variable",
+            WarningKind::NoEffect => "no-effect",
+            WarningKind::ShiftOutOfRange => "shift-out-of-range"
+        }
+    }
+}
+
+// Code that is suspicious, but which does not prevent compilation.
+#[derive(Clone)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub error: FileTaggedError
+}
+
+impl Warning {
+    // Converts the warning into an error, when it has been promoted with `-D` or `--deny-warnings`.
+    pub fn into_error(self) -> FileTaggedError {
+        FileTaggedError {
+            position: self.error.position,
+            msg: format!("{} (denied by `-D {}`)", self.error.msg, self.kind.name()),
+            notes: self.error.notes
+        }
+    }
+}
+
+impl Diagnostic for Warning {
+    fn write(&self, f: &mut fmt::Formatter<'_>, palette: Palette) -> fmt::Result {
+        writeln!(f, "-------------")?;
+        writeln!(f, "{}warning{} ({}):", palette.warning, palette.reset, self.kind.name())?;
+        self.error.fmt_body(f, palette, palette.warning)
+    }
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write(f, Palette::PLAIN)
+    }
+}
+
+// How serious a diagnostic is. Notes are only found within an error or warning.
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Note
+}
+
+// An error, warning or note in the form written by `--error-format json`, for editors and other tools to read.
+// Lines and columns begin at 1, and the end column is the one just after the last character included.
+// The position is null for errors that aren't linked to the source code.
+#[derive(Serialize)]
+pub struct JsonDiagnostic {
+    pub severity: Severity,
+    // The name of the warning, as passed to `-D`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<&'static str>,
+    pub message: String,
+    pub file: Option<String>,
+    pub start_line: Option<u32>,
+    pub start_col: Option<u32>,
+    pub end_line: Option<u32>,
+    pub end_col: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<JsonDiagnostic>
+}
+
+impl JsonDiagnostic {
+    fn new(severity: Severity, message: &str, position: Option<&FileRef>) -> Self {
+        Self {
+            severity,
+            kind: None,
+            message: message.to_string(),
+            file: position.map(|position| position.file.path.clone()),
+            start_line: position.map(|position| position.line_index + 1),
+            start_col: position.map(|position| position.begin_char_index + 1),
+            end_line: position.map(|position| position.end_line_index + 1),
+            end_col: position.map(|position| position.end_char_index + 1),
+            notes: Vec::new()
+        }
+    }
+
+    pub fn from_error(error: &FileTaggedError) -> Self {
+        Self::with_severity(error, Severity::Error)
+    }
+
+    pub fn from_warning(warning: &Warning) -> Self {
+        Self {
+            kind: Some(warning.kind.name()),
+            ..Self::with_severity(&warning.error, Severity::Warning)
+        }
+    }
+
+    fn with_severity(error: &FileTaggedError, severity: Severity) -> Self {
+        Self {
+            notes: error.notes.iter()
+                .map(|(position, msg)| Self::new(Severity::Note, msg, Some(position)))
+                .collect(),
+            ..Self::new(severity, &error.msg, error.position.as_ref())
+        }
+    }
+}
+
+// Written on a single line, so that each diagnostic can be read separately.
+impl Display for JsonDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(self)
+            .expect("Failed to serialize diagnostic");
+
+        write!(f, "{json}")
+    }
+}
+
+// Gets the number of single character insertions, deletions or substitutions needed to turn `a` into `b`.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // Distances from the characters of `a` processed so far to each prefix of `b`.
+    let mut distances: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut prev_diagonal = distances[0];
+        distances[0] = i + 1;
+
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = prev_diagonal + (a_char != *b_char) as usize;
+            prev_diagonal = distances[j + 1];
+            distances[j + 1] = substitution.min(distances[j] + 1).min(prev_diagonal + 1);
+        }
+    }
+
+    distances[b.len()]
+}
+
+// Finds the candidate most similar to `name`, if any is close enough that `name` may be a typo of it.
+pub fn suggest_similar<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    // Short names are often similar by chance, e.g. `x` and `y`, so at least one character must match.
+    let max_distance = name.chars().count().saturating_sub(1).min(2);
+
+    candidates.into_iter()
+        .map(|candidate| (edit_distance(candidate, name), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
 // Errors occuring during compilation
+#[derive(Clone, Debug)]
 pub struct CompileErrors(pub Vec<FileTaggedError>);
 
+impl CompileErrors {
+    // Iterates through the errors in the order they were reported.
+    // Use `sort_errors` to get them in the order they occur in the source code instead.
+    pub fn iter(&self) -> std::slice::Iter<'_, FileTaggedError> {
+        self.0.iter()
+    }
+}
+
+// Describes how many errors and warnings were reported, e.g. "2 errors, 1 warning generated".
+// The errors are left out if there are none, unless there are no warnings either.
+pub fn summary(error_count: usize, warning_count: usize) -> String {
+    let count = |count: usize, name: &str| if count == 1 {
+        format!("1 {name}")
+    }   else {
+        format!("{count} {name}s")
+    };
+
+    let mut counts = Vec::new();
+    if error_count > 0 || warning_count == 0 {
+        counts.push(count(error_count, "error"));
+    }
+    if warning_count > 0 {
+        counts.push(count(warning_count, "warning"));
+    }
+
+    format!("{} generated", counts.join(", "))
+}
+
+// Orders errors by the file and position they occur at, with errors that have no position last.
+// An error reported more than once, at the same position with the same message, is only kept once.
+pub fn sort_errors(mut errors: Vec<FileTaggedError>) -> Vec<FileTaggedError> {
+    // Stable, so that errors at the same position stay in the order they were reported.
+    errors.sort_by_key(|error| match &error.position {
+        Some(position) => (false, position.file.path.clone(), position.line_index, position.begin_char_index),
+        None => (true, String::new(), 0, 0)
+    });
+
+    let mut reported = HashSet::new();
+    errors.retain(|error| {
+        let position = error.position.as_ref().map(|position| (position.file.path.clone(), position.line_index,
+            position.begin_char_index, position.end_line_index, position.end_char_index));
+
+        reported.insert((position, error.msg.clone()))
+    });
+
+    errors
+}
+
 impl Display for CompileErrors {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.0.len() == 1 {
-            writeln!(f, "1 error generated:")?;
-        }   else {
-            writeln!(f, "{} errors generated:", self.0.len())?;
-        }
+        let errors = sort_errors(self.0.clone());
+        writeln!(f, "{}:", summary(errors.len(), 0))?;
 
-        for error in &self.0 {
+        for error in &errors {
             writeln!(f, "{error}")?;
         }
 
@@ -91,6 +480,8 @@ impl Display for CompileErrors {
     }
 }
 
+impl std::error::Error for CompileErrors {}
+
 // Represents the result of compiling a program.
 pub type CompileResult<T> = std::result::Result<T, CompileErrors>;
 
@@ -99,17 +490,161 @@ macro_rules! error {
     ($position: expr, $($arg:tt)*) => {
         Err($crate::error_handling::CompileErrors(vec![$crate::error_handling::FileTaggedError {
             position: Some($position),
-            msg: format!($($arg)*)
+            msg: format!($($arg)*),
+            notes: Vec::new()
         }]))
     };
 }
 
+#[macro_export]
+macro_rules! warning {
+    ($kind: expr, $position: expr, $($arg:tt)*) => {
+        $crate::error_handling::Warning {
+            kind: $kind,
+            error: $crate::error_handling::FileTaggedError {
+                position: Some($position),
+                msg: format!($($arg)*),
+                notes: Vec::new()
+            }
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! untagged_err {
     ($($arg:tt)*) => {
         Err($crate::error_handling::CompileErrors(vec![$crate::error_handling::FileTaggedError {
             position: None,
-            msg: format!($($arg)*)
+            msg: format!($($arg)*),
+            notes: Vec::new()
         }]))
     };
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Creates an error in a file containing `text`, spanning from the character `begin` of line `line` to the character
+    // before `end` of line `end_line`, all counting from 0.
+    fn error_at(text: &str, (line, begin): (u32, u32), (end_line, end): (u32, u32), msg: &str) -> FileTaggedError {
+        let file = Arc::new(SourceFile::from_text("test.lfl".to_owned(), text.to_owned()));
+        FileTaggedError {
+            position: Some(FileRef { file, line_index: line, begin_char_index: begin, end_line_index: end_line, end_char_index: end }),
+            msg: msg.to_owned(),
+            notes: Vec::new()
+        }
+    }
+
+    #[test]
+    fn single_line_span_is_underlined() {
+        let error = error_at("void main() {\n    signal_1 = total;\n}", (1, 15), (1, 20), "No variable exists with this name");
+        assert_eq!(error.render(Palette::PLAIN), "\
+-------------
+error:
+at test.lfl:2:
+
+->     signal_1 = total;
+->                ^^^^^ No variable exists with this name
+");
+    }
+
+    #[test]
+    fn two_line_span_is_underlined_on_each_line() {
+        let error = error_at("void main() {\n    signal_1 = a +\n        b;\n}", (1, 15), (2, 9), "Message");
+        assert_eq!(error.render(Palette::PLAIN), "\
+-------------
+error:
+at test.lfl:2:
+
+->     signal_1 = a +
+->                ^^^
+->         b;
+->         ^ Message
+");
+    }
+
+    #[test]
+    fn three_line_span_skips_indentation_after_first_line() {
+        let error = error_at("void main() {\n    x = f(1,\n      2,\n      3);\n}", (1, 9), (3, 8), "Message");
+        assert_eq!(error.render(Palette::PLAIN), "\
+-------------
+error:
+at test.lfl:2:
+
+->     x = f(1,
+->          ^^^
+->       2,
+->       ^^
+->       3);
+->       ^^ Message
+");
+    }
+
+    // Gets the line of a rendered error with the underline.
+    fn underline(error: &FileTaggedError) -> String {
+        error.render(Palette::PLAIN).lines().last().unwrap().to_owned()
+    }
+
+    #[test]
+    fn caret_after_tab_keeps_tab() {
+        let error = error_at("void main() {\n\tsignal_1 = x;\n}", (1, 12), (1, 13), "Message");
+        assert_eq!(underline(&error), format!("-> \t{}^ Message", " ".repeat(11)));
+    }
+
+    #[test]
+    fn caret_after_non_ascii_lines_up() {
+        // `é` takes up one column, and each of `日本` take up two.
+        let error = error_at("void main() {\n  é = 日本 + x;\n}", (1, 11), (1, 12), "Message");
+        assert_eq!(underline(&error), format!("-> {}^ Message", " ".repeat(13)));
+
+        let error = error_at("void main() {\n  é = 日本 + x;\n}", (1, 6), (1, 8), "Message");
+        assert_eq!(underline(&error), format!("-> {}^^^^ Message", " ".repeat(6)));
+
+        // Combining marks are drawn over the character before them.
+        let error = error_at("void main() {\n  e\u{301} = x;\n}", (1, 6), (1, 7), "Message");
+        assert_eq!(underline(&error), format!("-> {}^ Message", " ".repeat(5)));
+    }
+
+    // Writes a diagnostic as JSON and reads it back, as an editor would.
+    fn to_json(diagnostic: &JsonDiagnostic) -> serde_json::Value {
+        serde_json::from_str(&serde_json::to_string(diagnostic).unwrap()).unwrap()
+    }
+
+    // Gets the start line, start column, end line and end column of a diagnostic read back from JSON.
+    fn json_span(json: &serde_json::Value) -> [u64; 4] {
+        ["start_line", "start_col", "end_line", "end_col"].map(|field| json[field].as_u64().unwrap())
+    }
+
+    #[test]
+    fn json_diagnostic_positions_count_from_one() {
+        let mut error = error_at("void main() {\n    signal_1 = total;\n}", (1, 15), (1, 20), "No variable exists with this name");
+        let note_position = error.position.clone().unwrap();
+        error.notes.push((FileRef { line_index: 0, begin_char_index: 5, end_line_index: 0, end_char_index: 9, ..note_position }, "Note".to_owned()));
+
+        let json = to_json(&JsonDiagnostic::from_error(&error));
+        assert_eq!(json["severity"], "error");
+        assert_eq!(json["message"], "No variable exists with this name");
+        assert_eq!(json["file"], "test.lfl");
+        assert_eq!(json_span(&json), [2, 16, 2, 21]);
+        assert!(json.get("kind").is_none());
+
+        let note = &json["notes"][0];
+        assert_eq!(note["severity"], "note");
+        assert_eq!(note["message"], "Note");
+        assert_eq!(json_span(note), [1, 6, 1, 10]);
+        assert!(note.get("notes").is_none());
+    }
+
+    #[test]
+    fn json_warning_has_kind_and_unpositioned_error_has_null_position() {
+        let warning = Warning { kind: WarningKind::NoEffect, error: error_at("x + 1;", (0, 0), (0, 5), "Message") };
+        let json = to_json(&JsonDiagnostic::from_warning(&warning));
+        assert_eq!(json["severity"], "warning");
+        assert_eq!(json["kind"], "no-effect");
+        assert_eq!(json_span(&json), [1, 1, 1, 6]);
+
+        let error = FileTaggedError { position: None, msg: "Message".to_owned(), notes: Vec::new() };
+        let json = to_json(&JsonDiagnostic::from_error(&error));
+        assert!(["file", "start_line", "start_col", "end_line", "end_col"].iter().all(|field| json[field].is_null()));
+    }
+}
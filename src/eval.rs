@@ -0,0 +1,130 @@
+//! Evaluation of a single expression, for `lflc eval`, e.g. to check what `-7 % 3` gives on the computer.
+
+use std::sync::Arc;
+
+use crate::{ast::{Expression, Function, Item, Statement}, compiler::{self, CompileOptions}, emulator::{self, EmulatorOptions, RunResult, SignalState},
+    error_handling::{CompileResult, SourceFile, Warning}, folding, lexer, lints, parser::{self, TokenIterator}};
+
+// The signal the value of the expression is written to.
+pub const RESULT_SIGNAL: &str = "signal_1";
+
+// How the value of an expression was found.
+pub enum Evaluation {
+    // The expression only contained literals and operators, so was folded without running a program.
+    Constant(i32),
+    // The expression was compiled into a program that writes its value to the first output signal, which was run on the emulator.
+    Emulated(RunResult)
+}
+
+impl Evaluation {
+    // Gets the value of the expression, or None if the program failed.
+    pub fn value(&self) -> Option<i32> {
+        match self {
+            Evaluation::Constant(value) => Some(*value),
+            Evaluation::Emulated(result) => result.halted().then_some(result.outputs[0])
+        }
+    }
+}
+
+// Evaluates a file containing only an expression, which can read the input signals in `signals`.
+// The expression becomes the entry point of a program that assigns it to `signal_1`, but errors point into `source` alone.
+// Any warnings generated are added to `warnings`, even if evaluation fails.
+pub fn evaluate(source: Arc<SourceFile>, signals: &mut SignalState, options: &CompileOptions, warnings: &mut Vec<Warning>) -> CompileResult<Evaluation> {
+    let mut iter = TokenIterator::new(lexer::tokenize(source)?);
+    let parsed = parser::parse_standalone_expression(&mut iter);
+    warnings.append(&mut iter.warnings);
+    let (expression, expression_ref) = parsed?;
+
+    // The parts of the program that aren't in the source are given the position of the whole expression.
+    let mut module = vec![Item::Function(Function {
+        name: options.entry_point.clone(),
+        name_ref: expression_ref.clone(),
+        argument_names: Vec::new(),
+        argument_name_refs: Vec::new(),
+        argument_list_ref: expression_ref.clone(),
+        block: vec![Statement::Assignment {
+            variable_name: RESULT_SIGNAL.to_owned(),
+            variable_name_ref: expression_ref,
+            value: expression
+        }],
+        returns_value: false,
+        // The expression isn't compiled with a cache.
+        token_hash: 0
+    })];
+    lints::check_module(&module, warnings);
+
+    if options.fold_constants {
+        folding::fold_module(&mut module)?;
+        if let Some(Item::Function(Function { block, .. })) = module.first() {
+            if let [Statement::Assignment { value: Expression::Literal(value), .. }] = block.as_slice() {
+                return Ok(Evaluation::Constant(*value));
+            }
+        }
+    }
+
+    let program = compiler::compile_module(module, options, warnings)?;
+    let emulator_options = EmulatorOptions {
+        call_convention: options.call_convention,
+        signal_count: options.signal_count,
+        tick_signal: program.uses_ticks.then_some(options.tick_signal),
+        ..EmulatorOptions::default()
+    };
+    Ok(Evaluation::Emulated(emulator::run(&program.instructions, signals, &emulator_options)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error_handling::{Palette, Styled};
+
+    fn evaluate_with(expression: &str, inputs: &[i32], options: &CompileOptions) -> CompileResult<Evaluation> {
+        let source = Arc::new(SourceFile::from_text("<expression>".to_owned(), expression.to_owned()));
+        let mut signals = SignalState::new(options.signal_count);
+        signals.inputs[..inputs.len()].copy_from_slice(inputs);
+        evaluate(source, &mut signals, options, &mut Vec::new())
+    }
+
+    // Renders the errors from evaluating an expression, as printed by `lflc eval`.
+    fn render_errors(expression: &str) -> String {
+        let err = evaluate_with(expression, &[], &CompileOptions::default()).err().expect("Evaluated successfully");
+        err.0.iter().map(|error| Styled(error, Palette::PLAIN).to_string()).collect()
+    }
+
+    #[test]
+    fn constant_expressions_are_folded() {
+        for (expression, expected) in [("(-7 % 3) * 2", -2), ("-7 / 2", -3), ("2 ^ 31", i32::MIN), ("1 << 4", 16), ("3 > 2 && 0 || 5", 1)] {
+            match evaluate_with(expression, &[], &CompileOptions::default()).unwrap() {
+                Evaluation::Constant(value) => assert_eq!(value, expected, "{expression}"),
+                Evaluation::Emulated(_) => panic!("{expression} was not folded")
+            }
+        }
+    }
+
+    #[test]
+    fn constant_expressions_are_emulated_without_folding() {
+        let options = CompileOptions { fold_constants: false, ..CompileOptions::default() };
+        match evaluate_with("(-7 % 3) * 2", &[], &options).unwrap() {
+            Evaluation::Emulated(result) => assert_eq!(result.outputs[0], -2),
+            Evaluation::Constant(_) => panic!("Folded with folding disabled")
+        }
+    }
+
+    #[test]
+    fn expressions_reading_signals_are_emulated() {
+        let options = CompileOptions::default();
+        let evaluation = evaluate_with("signal_1 * 2 + signal_3 % 4", &[21, 0, -7], &options).unwrap();
+        assert!(matches!(evaluation, Evaluation::Emulated(_)));
+        assert_eq!(evaluation.value(), Some(39));
+
+        // The result is written to `signal_1`, but reading it gives the input.
+        assert_eq!(evaluate_with("signal_1 + 1", &[5], &options).unwrap().value(), Some(6));
+        assert_eq!(evaluate_with("-signal_2", &[0, 8], &options).unwrap().value(), Some(-8));
+    }
+
+    #[test]
+    fn errors_point_into_expression() {
+        assert_eq!(render_errors("1 + x"), "-------------\nerror:\nat <expression>:1:\n\n-> 1 + x\n->     ^ No variable exists with this name\n");
+        assert_eq!(render_errors("(-7 % 0) * 2"), "-------------\nerror:\nat <expression>:1:\n\n-> (-7 % 0) * 2\n->     ^ Remainder by zero\n");
+        assert_eq!(render_errors("1 + 2 3"), "-------------\nerror:\nat <expression>:1:\n\n-> 1 + 2 3\n->       ^ Expected the end of the expression\n");
+    }
+}
@@ -0,0 +1,340 @@
+//! Evaluates operators whose operands are known at compile time, so that they take up no space in the ROM.
+//! Also replaces uses of constants declared with `const` by their values.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{ast::{Item, Function, Statement, Expression, BinaryOperator, UnaryOperator}, error_handling::{CompileErrors, CompileResult, FileRef, FileTaggedError},
+    semantics, visit::{self, VisitorMut}};
+
+// Evaluates each constant, then replaces every use of it with a literal.
+// This happens even if folding is disabled, since the value of a constant must be known.
+pub fn substitute_constants(module: &mut [Item]) -> CompileResult<()> {
+    let mut substituter = ConstantSubstituter::default();
+
+    // A name can't be shared between a constant and any other item. The error is given at the later definition,
+    // so the first one stays valid. Functions and globals sharing a name with each other are reported by the compiler.
+    let mut first_definitions: HashMap<&str, (&FileRef, bool)> = HashMap::new();
+    let mut redefined_constants = HashSet::new();
+    for (idx, item) in module.iter().enumerate() {
+        let (name, name_ref, is_constant) = match item {
+            Item::Function(function) => (&function.name, &function.name_ref, false),
+            Item::Global(global) => (&global.name, &global.name_ref, false),
+            Item::Constant(constant) => (&constant.name, &constant.name_ref, true)
+        };
+
+        match first_definitions.get(name.as_str()) {
+            Some(&(first_ref, first_is_constant)) if is_constant || first_is_constant => {
+                let msg = if first_is_constant {
+                    format!("A constant named `{name}` already exists")
+                }   else {
+                    format!("A function or global variable named `{name}` already exists")
+                };
+
+                substituter.errors.push(FileTaggedError {
+                    position: Some(name_ref.clone()),
+                    msg,
+                    notes: vec![(first_ref.clone(), format!("`{name}` is first defined here"))]
+                });
+                if is_constant {
+                    redefined_constants.insert(idx);
+                }
+            },
+            Some(_) => {},
+            None => { first_definitions.insert(name, (name_ref, is_constant)); }
+        }
+    }
+
+    // Constants are evaluated in order, so each can use the constants declared before it.
+    for (idx, item) in module.iter_mut().enumerate() {
+        let constant = match item {
+            Item::Constant(constant) => constant,
+            _ => continue
+        };
+
+        if redefined_constants.contains(&idx) {
+            continue;
+        }
+
+        if constant.name.starts_with("signal_") {
+            substituter.error(constant.name_ref.clone(), "Constants cannot be named `signal_N`, as these names are used to access the GPIO".to_owned());
+            continue;
+        }
+
+        substituter.visit_expression_mut(&mut constant.value);
+
+        let mut folder = ConstantFolder::default();
+        folder.visit_expression_mut(&mut constant.value);
+        if !folder.errors.is_empty() {
+            substituter.errors.append(&mut folder.errors);
+            continue;
+        }
+
+        match constant.value {
+            Expression::Literal(value) => { substituter.values.insert(constant.name.clone(), value); },
+            _ => substituter.error(constant.value_ref.clone(), format!("The value of `{}` must be known at compile time, \
+                so can only use literals, operators and constants declared before it", constant.name))
+        }
+    }
+
+    for item in module.iter_mut() {
+        match item {
+            Item::Function(function) => substituter.visit_function_mut(function),
+            Item::Global(global) => substituter.visit_global_mut(global),
+            Item::Constant(_) => {}
+        }
+    }
+
+    if substituter.errors.is_empty() {
+        Ok(())
+    }   else {
+        Err(CompileErrors(substituter.errors))
+    }
+}
+
+#[derive(Default)]
+struct ConstantSubstituter {
+    // The value of each constant evaluated so far.
+    values: HashMap<String, i32>,
+    errors: Vec<FileTaggedError>
+}
+
+impl ConstantSubstituter {
+    fn error(&mut self, position: FileRef, msg: String) {
+        self.errors.push(FileTaggedError {
+            position: Some(position),
+            msg,
+            notes: Vec::new()
+        });
+    }
+}
+
+impl VisitorMut for ConstantSubstituter {
+    fn visit_function_mut(&mut self, function: &mut Function) {
+        for (argument, argument_ref) in function.argument_names.iter().zip(&function.argument_name_refs) {
+            if self.values.contains_key(argument) {
+                self.error(argument_ref.clone(), format!("Argument `{argument}` has the same name as a constant"));
+            }
+        }
+
+        visit::walk_function_mut(self, function);
+    }
+
+    fn visit_statement_mut(&mut self, statement: &mut Statement) {
+        if let Statement::Assignment { variable_name, variable_name_ref, .. } = statement {
+            if self.values.contains_key(variable_name) {
+                self.error(variable_name_ref.clone(), format!("Cannot assign to `{variable_name}`, as it is a constant"));
+            }
+        }
+
+        visit::walk_statement_mut(self, statement);
+    }
+
+    fn visit_expression_mut(&mut self, expr: &mut Expression) {
+        if let Expression::Variable { name, .. } = expr {
+            if let Some(value) = self.values.get(name) {
+                *expr = Expression::Literal(*value);
+            }
+        }
+
+        visit::walk_expression_mut(self, expr);
+    }
+}
+
+// Replaces each operator with literal operands by its result.
+pub fn fold_module(module: &mut [Item]) -> CompileResult<()> {
+    let mut folder = ConstantFolder::default();
+    visit::walk_module_mut(&mut folder, module);
+
+    if folder.errors.is_empty() {
+        Ok(())
+    }   else {
+        Err(CompileErrors(folder.errors))
+    }
+}
+
+#[derive(Default)]
+struct ConstantFolder {
+    errors: Vec<FileTaggedError>
+}
+
+impl VisitorMut for ConstantFolder {
+    fn visit_expression_mut(&mut self, expr: &mut Expression) {
+        // Fold the operands first, so that nested operators collapse into a single literal.
+        visit::walk_expression_mut(self, expr);
+
+        let folded = match expr {
+            Expression::Binary { left, right, operator, operator_ref, .. } => match (&**left, &**right) {
+                (_, Expression::Literal(0)) if matches!(operator, BinaryOperator::Divide | BinaryOperator::Remainder) => {
+                    self.division_by_zero(*operator, operator_ref.clone());
+                    None
+                },
+                (_, Expression::Literal(exponent)) if *operator == BinaryOperator::Power && *exponent < 0 => {
+                    self.errors.push(FileTaggedError {
+                        position: Some(operator_ref.clone()),
+                        msg: format!("Negative exponent {exponent}. The result of the arithmetic combinator for a negative power isn't known"),
+                        notes: Vec::new()
+                    });
+                    None
+                },
+                (Expression::Literal(left), Expression::Literal(right)) => fold_binary(*left, *right, *operator),
+                _ => None
+            },
+            Expression::Unary { value, operator } => match **value {
+                Expression::Literal(value) => Some(fold_unary(value, *operator)),
+                _ => None
+            },
+            _ => None
+        };
+
+        if let Some(value) = folded {
+            *expr = Expression::Literal(value);
+        }
+    }
+}
+
+impl ConstantFolder {
+    fn division_by_zero(&mut self, operator: BinaryOperator, operator_ref: FileRef) {
+        let operation = if operator == BinaryOperator::Divide { "Division" } else { "Remainder" };
+        self.errors.push(FileTaggedError {
+            position: Some(operator_ref),
+            msg: format!("{operation} by zero"),
+            notes: Vec::new()
+        });
+    }
+}
+
+// Evaluates a binary operator the same way as the arithmetic combinators, using the same functions as the emulator.
+// Returns None if the result depends on behaviour of the hardware that isn't modelled here,
+// in which case the operator is left to be evaluated at runtime.
+fn fold_binary(left: i32, right: i32, operator: BinaryOperator) -> Option<i32> {
+    Some(match operator {
+        BinaryOperator::Add => semantics::fact_add(left, right),
+        BinaryOperator::Subtract => semantics::fact_sub(left, right),
+        BinaryOperator::Multiply => semantics::fact_mul(left, right),
+        BinaryOperator::Divide => semantics::fact_div(left, right),
+        BinaryOperator::Remainder => semantics::fact_rem(left, right),
+        BinaryOperator::Power if right >= 0 => semantics::fact_pow(left, right),
+        BinaryOperator::ShiftLeft if (0..32).contains(&right) => semantics::fact_shl(left, right),
+        BinaryOperator::ShiftRight if (0..32).contains(&right) => semantics::fact_shr(left, right),
+        BinaryOperator::Power | BinaryOperator::ShiftLeft | BinaryOperator::ShiftRight => return None,
+        BinaryOperator::And => left & right,
+        BinaryOperator::Or => left | right,
+        BinaryOperator::Xor => left ^ right,
+        BinaryOperator::Equals => (left == right) as i32,
+        BinaryOperator::NotEquals => (left != right) as i32,
+        BinaryOperator::GreaterThan => (left > right) as i32,
+        BinaryOperator::LessThan => (left < right) as i32,
+        BinaryOperator::GreaterThanOrEqual => (left >= right) as i32,
+        BinaryOperator::LessThanOrEqual => (left <= right) as i32,
+        BinaryOperator::LogicalAnd => (left != 0 && right != 0) as i32,
+        BinaryOperator::LogicalOr => (left != 0 || right != 0) as i32
+    })
+}
+
+fn fold_unary(value: i32, operator: UnaryOperator) -> i32 {
+    match operator {
+        UnaryOperator::Not => !value,
+        UnaryOperator::LogicalNot => (value == 0) as i32,
+        UnaryOperator::Negate => semantics::fact_neg(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::CompileOptions, testing};
+
+    // Values around the edges of the range of each operator, e.g. those that overflow, divide by zero or shift too far.
+    const VALUES: [i32; 12] = [0, 1, -1, 2, -7, 7, 31, 32, 33, 1000, i32::MAX, i32::MIN];
+
+    #[test]
+    fn binary_folding_agrees_with_emulator() {
+        let options = CompileOptions { fold_constants: false, ..CompileOptions::default() };
+        for operator in BinaryOperator::ALL {
+            let source = format!("void main(a, b) {{ signal_1 = a {} b; }}", operator.symbol());
+            let module = testing::compile(&source, &options);
+
+            for left in VALUES {
+                for right in VALUES {
+                    if let Some(folded) = fold_binary(left, right, operator) {
+                        assert_eq!(folded, testing::run(&module, &[left, right], &options)[0], "{left} {} {right}", operator.symbol());
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unary_folding_agrees_with_emulator() {
+        let options = CompileOptions { fold_constants: false, ..CompileOptions::default() };
+        for operator in [UnaryOperator::Not, UnaryOperator::LogicalNot, UnaryOperator::Negate] {
+            let source = format!("void main(a) {{ signal_1 = {}a; }}", operator.symbol());
+            let module = testing::compile(&source, &options);
+
+            for value in VALUES {
+                assert_eq!(fold_unary(value, operator), testing::run(&module, &[value], &options)[0], "{}{value}", operator.symbol());
+            }
+        }
+    }
+
+    #[test]
+    fn folded_program_matches_unfolded() {
+        let source = "void main() { signal_1 = (3 * 60 + 12) << 2; signal_2 = -(7 % -2) - 10 / 3; signal_3 = 2 ^ 10 ~ 5; }";
+        let unfolded_options = CompileOptions { fold_constants: false, ..CompileOptions::default() };
+        let folded = testing::compile(source, &CompileOptions::default());
+        let unfolded = testing::compile(source, &unfolded_options);
+
+        assert!(folded.instructions.len() < unfolded.instructions.len());
+        assert_eq!(testing::run(&folded, &[], &CompileOptions::default()), testing::run(&unfolded, &[], &unfolded_options));
+    }
+
+    #[test]
+    fn division_by_constant_zero_is_an_error() {
+        let err = testing::try_compile("void main() { signal_1 = 1 / 0; }", &CompileOptions::default()).err().expect("Division by zero compiled");
+        assert!(err.to_string().contains("Division by zero"));
+    }
+
+    #[test]
+    fn constant_negative_exponent_is_an_error() {
+        let err = only_error("void main() {\n    signal_1 = signal_2 ^ -(3 - 2);\n}");
+        assert_eq!(err.msg, "Negative exponent -1. The result of the arithmetic combinator for a negative power isn't known");
+        assert_eq!(position(err.position.as_ref().unwrap()), (2, 25));
+    }
+
+    // The single error given by a program, which is checked to fail to compile.
+    fn only_error(source: &str) -> FileTaggedError {
+        let mut errors = testing::try_compile(source, &CompileOptions::default()).err().expect("Program compiled").0;
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        errors.remove(0)
+    }
+
+    // The line and column, counting from 1, where a reference begins.
+    fn position(file_ref: &FileRef) -> (u32, u32) {
+        (file_ref.line_index + 1, file_ref.begin_char_index + 1)
+    }
+
+    #[test]
+    fn redefined_constant_is_reported_at_second_definition() {
+        let err = only_error("const X = 1;\nconst X = 2;\nvoid main() { signal_1 = X; }");
+        assert_eq!(err.msg, "A constant named `X` already exists");
+        assert_eq!(position(err.position.as_ref().unwrap()), (2, 7));
+        assert_eq!(err.notes.len(), 1);
+        assert_eq!(position(&err.notes[0].0), (1, 7));
+    }
+
+    #[test]
+    fn function_after_constant_is_reported_at_function() {
+        let err = only_error("const f = 1;\nvoid main() { signal_1 = f; }\nvoid f() { }");
+        assert_eq!(err.msg, "A constant named `f` already exists");
+        assert_eq!(position(err.position.as_ref().unwrap()), (3, 6));
+        assert_eq!(position(&err.notes[0].0), (1, 7));
+    }
+
+    #[test]
+    fn constant_after_global_is_reported_at_constant() {
+        let err = only_error("global x;\nconst x = 1;\nvoid main() { }");
+        assert_eq!(err.msg, "A function or global variable named `x` already exists");
+        assert_eq!(position(err.position.as_ref().unwrap()), (2, 7));
+        assert_eq!(position(&err.notes[0].0), (1, 8));
+    }
+}
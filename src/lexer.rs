@@ -9,16 +9,20 @@ use phf::phf_map;
 use crate::error_handling::{CompileResult, FileRef, SourceFile, FileTaggedError, CompileErrors};
 
 // A token is a small group of characters that conveys a particular meaning to the compiler.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Hash)]
 pub enum Token {
     OpenParen,
     CloseParen,
     OpenBrace,
     CloseBrace,
     Identifier(String),
-    Number(i32),
+    // Stored as an i64 since `2147483648` does not fit in an i32, but is valid when negated.
+    Number(i64),
+    // Text between double quotes, e.g. the path of an import.
+    String(String),
     If,
     While,
+    Do,
     Else,
     Semicolon,
     Plus,
@@ -40,6 +44,11 @@ pub enum Token {
     Return,
     Continue,
     Break,
+    Global,
+    Const,
+    True,
+    False,
+    Import,
     EndOfFile
 }
 
@@ -47,28 +56,48 @@ pub enum Token {
 static KEYWORDS: phf::Map<&'static str, Token> = phf_map! {
     "if" => Token::If,
     "while" => Token::While,
+    "do" => Token::Do,
     "else" => Token::Else,
     "int" => Token::Int,
     "void" => Token::Void,
     "continue" => Token::Continue,
     "break" => Token::Break,
-    "return" => Token::Return
+    "return" => Token::Return,
+    "global" => Token::Global,
+    "const" => Token::Const,
+    "true" => Token::True,
+    "false" => Token::False,
+    "import" => Token::Import
 };
 
+// Gets the text of a keyword token, or None if the token is not a keyword.
+pub fn keyword_name(token: &Token) -> Option<&'static str> {
+    KEYWORDS.entries()
+        .find(|(_, keyword)| *keyword == token)
+        .map(|(name, _)| *name)
+}
+
 const NUMBER_BASE: u32 = 10;
 
+// The largest integer literal, which is only valid when negated to give i32::MIN.
+pub const MAX_LITERAL: i64 = i32::MAX as i64 + 1;
+
 fn is_valid_for_identifier(c: char) -> bool {
     c.is_alphanumeric() || c == '_'
 }
 
-fn parse_number(iter: &mut Enumerate<Chars>, first_digit: i32) -> i32 {
-    let mut current: i32 = first_digit;
+// Returns None if the number is larger than MAX_LITERAL.
+// All of the digits are consumed either way, so that the whole number can be included in an error.
+fn parse_number(iter: &mut Enumerate<Chars>, first_digit: i64) -> Option<i64> {
+    let mut current = Some(first_digit);
     loop {
         match iter.clone().next() {
             None => break current, // EOF
             Some((_, c)) => match c.to_digit(NUMBER_BASE) {
                 Some(digit) => {
-                    current = current * NUMBER_BASE as i32 + digit as i32;
+                    current = current.and_then(|current| current.checked_mul(NUMBER_BASE as i64))
+                        .and_then(|current| current.checked_add(digit as i64))
+                        .filter(|current| *current <= MAX_LITERAL);
                     iter.next().unwrap();
                 },
                 None => break current
@@ -77,6 +106,34 @@ fn parse_number(iter: &mut Enumerate<Chars>, first_digit: i32) -> i32 {
     }
 }
 
+// Parses the text of a string literal, assuming the opening `"` has already been consumed.
+// Returns None if the string is not closed before the end of the line, in which case the rest of the line is consumed
+// apart from the line break.
+fn parse_string(iter: &mut Enumerate<Chars>) -> Option<String> {
+    let mut result = String::new();
+    loop {
+        match iter.clone().next() {
+            None | Some((_, '\n')) => break None,
+            Some((_, '"')) => {
+                iter.next().unwrap();
+                break Some(result);
+            },
+            Some((_, c)) => {
+                result.push(c);
+                iter.next().unwrap();
+            }
+        }
+    }
+}
+
+// Gets the index of the character after the token that began at `idx`, once the rest of the token has been consumed.
+fn token_end(iter: &Enumerate<Chars>, idx: usize) -> usize {
+    match iter.clone().next() {
+        Some((next_idx, _)) => next_idx,
+        None => idx + 1
+    }
+}
+
 fn parse_identifier(iter: &mut Enumerate<Chars>, first_char: char) -> String {
     let mut result = String::new();
     result.push(first_char);
@@ -95,14 +152,61 @@ fn parse_identifier(iter: &mut Enumerate<Chars>, first_char: char) -> String {
     }
 }
 
+// Skips the rest of a `//` comment, leaving the newline at the end to be handled by the caller.
+fn skip_line_comment(iter: &mut Enumerate<Chars>) {
+    while let Some((_, c)) = iter.clone().next() {
+        if c == '\n' {
+            break;
+        }
+
+        iter.next().unwrap();
+    }
+}
+
+// Skips a `/* */` comment, assuming the opening `/*` has already been consumed.
+// The line tracking is updated for any newlines within the comment.
+// Returns false if the end of the file was reached before the comment was closed.
+fn skip_block_comment(iter: &mut Enumerate<Chars>, line_index: &mut u32, begin_line_char_index: &mut usize) -> bool {
+    while let Some((idx, c)) = iter.next() {
+        if c == '\n' {
+            *line_index += 1;
+            *begin_line_char_index = idx + 1;
+        }   else if c == '*' && matches!(iter.clone().next(), Some((_, '/'))) {
+            iter.next().unwrap();
+            return true;
+        }
+    }
+
+    false
+}
+
+// A `//` or `/* */` comment, which is skipped by the lexer but kept when formatting the source code.
+pub struct Comment {
+    // Includes the `//` or `/*` and `*/`.
+    pub text: String,
+    pub position: FileRef,
+    // The index of the first token after the comment.
+    pub next_token_idx: usize,
+    // Whether the comment comes after a token on the same line, e.g. `x = 5; // comment`.
+    pub is_trailing: bool
+}
+
+// Each token, along with its position in the file.
+pub type TokenList = Vec<(Token, FileRef)>;
 
 // Takes in a string and splits it into a list of tokens.
 // If an error is encountered, the character is skipped and the error is kept in a log.
 // This allows any other errors later in the file to be logged. No tokens will be returned from the function, even though more may be parsed.
 // The last token is always a Token::EndOfFile
 pub fn tokenize(source: Arc<SourceFile>) -> CompileResult<Vec<(Token, FileRef)>> {
+    tokenize_with_comments(source).map(|(tokens, _)| tokens)
+}
+
+// Splits a file into tokens in the same way as `tokenize`, and also returns the comments in the file.
+pub fn tokenize_with_comments(source: Arc<SourceFile>) -> CompileResult<(TokenList, Vec<Comment>)> {
     let mut iter = source.text.chars().enumerate();
-    let mut result = Vec::new();
+    let mut result: Vec<(Token, FileRef)> = Vec::new();
+    let mut comments = Vec::new();
     let mut errors = Vec::new();
 
     let mut line_index = 0;
@@ -117,8 +221,63 @@ pub fn tokenize(source: Arc<SourceFile>) -> CompileResult<Vec<(Token, FileRef)>>
             continue;
         }
 
+        if c == '/' && matches!(iter.clone().next(), Some((_, '/' | '*'))) {
+            let comment_start = FileRef {
+                line_index,
+                file: source.clone(),
+                begin_char_index: (idx - begin_line_char_index) as u32,
+                end_line_index: line_index,
+                end_char_index: (idx - begin_line_char_index) as u32 + 2
+            };
+
+            if iter.next().unwrap().1 == '/' {
+                skip_line_comment(&mut iter);
+            }   else if !skip_block_comment(&mut iter, &mut line_index, &mut begin_line_char_index) {
+                errors.push(FileTaggedError {
+                    msg: "Unterminated block comment".to_owned(),
+                    position: Some(comment_start.clone()),
+                    notes: Vec::new()
+                });
+            }
+
+            // A comment at the end of the file goes up to the last character.
+            let end_idx = match iter.clone().next() {
+                Some((next_idx, _)) => next_idx,
+                None => source.text.chars().count()
+            };
+            comments.push(Comment {
+                text: source.text.chars().skip(idx).take(end_idx - idx).collect::<String>().trim_end().to_owned(),
+                is_trailing: result.last().is_some_and(|(_, position)| position.end_line_index == comment_start.line_index),
+                next_token_idx: result.len(),
+                position: FileRef {
+                    end_line_index: line_index,
+                    end_char_index: (end_idx - begin_line_char_index) as u32,
+                    ..comment_start
+                }
+            });
+
+            continue;
+        }
+
         let token = if let Some(first_digit) = c.to_digit(NUMBER_BASE) {
-            Token::Number(parse_number(&mut iter, first_digit as i32))
+            match parse_number(&mut iter, first_digit as i64) {
+                Some(number) => Token::Number(number),
+                None => {
+                    errors.push(FileTaggedError {
+                        msg: "Integer literal is too large to fit in a 32 bit signed integer".to_owned(),
+                        position: Some(FileRef {
+                            line_index,
+                            file: source.clone(),
+                            begin_char_index: (idx - begin_line_char_index) as u32,
+                            end_line_index: line_index,
+                            end_char_index: (token_end(&iter, idx) - begin_line_char_index) as u32
+                        }),
+                        notes: Vec::new()
+                    });
+
+                    continue;
+                }
+            }
         }   else if  is_valid_for_identifier(c) {
             let ident = parse_identifier(&mut iter, c);
 
@@ -147,6 +306,24 @@ pub fn tokenize(source: Arc<SourceFile>) -> CompileResult<Vec<(Token, FileRef)>>
             '~' => Token::Tilda,
             '!' => Token::Bang,
             ';' => Token::Semicolon,
+            '"' => match parse_string(&mut iter) {
+                Some(text) => Token::String(text),
+                None => {
+                    errors.push(FileTaggedError {
+                        msg: "Unterminated string literal".to_owned(),
+                        position: Some(FileRef {
+                            line_index,
+                            file: source.clone(),
+                            begin_char_index: (idx - begin_line_char_index) as u32,
+                            end_line_index: line_index,
+                            end_char_index: (token_end(&iter, idx) - begin_line_char_index) as u32
+                        }),
+                        notes: Vec::new()
+                    });
+
+                    continue;
+                }
+            },
             _ => {
                 errors.push(FileTaggedError {
                     msg: "Invalid character".to_owned(), 
@@ -154,8 +331,10 @@ pub fn tokenize(source: Arc<SourceFile>) -> CompileResult<Vec<(Token, FileRef)>>
                         line_index,
                         file: source.clone(),
                         begin_char_index: (idx - begin_line_char_index) as u32,
-                        length: 1
-                    })
+                        end_line_index: line_index,
+                        end_char_index: (idx - begin_line_char_index) as u32 + 1
+                    }),
+                    notes: Vec::new()
                 });
 
                 continue;
@@ -164,17 +343,15 @@ pub fn tokenize(source: Arc<SourceFile>) -> CompileResult<Vec<(Token, FileRef)>>
         };
 
         // Locate the final character of the token.
-        let final_char = match iter.clone().next() {
-            Some((next_idx, _)) => next_idx,
-            None => idx + 1
-        };
+        let final_char = token_end(&iter, idx);
 
         // Tag the token with the correct position within the file.
         result.push((token, FileRef {
             file: source.clone(),
             line_index,
             begin_char_index: (idx - begin_line_char_index) as u32,
-            length: (final_char - idx) as u32
+            end_line_index: line_index,
+            end_char_index: (final_char - begin_line_char_index) as u32
         }))
     }
 
@@ -185,9 +362,82 @@ pub fn tokenize(source: Arc<SourceFile>) -> CompileResult<Vec<(Token, FileRef)>>
             file: source,
             line_index: line_index + 1,
             begin_char_index: 0,
-            length: 5, // Could literally be anything, just for UI purposes.
+            end_line_index: line_index + 1,
+            end_char_index: 5, // Could literally be anything, just for UI purposes.
         }));
 
-        Ok(result)        
+        Ok((result, comments))        
     }
-}
\ No newline at end of file
+}
+// Writes each token on its own line, after its 1-based line and column and its length in characters, e.g. for `--tokens`.
+// The `EndOfFile` token is given a position on the line after the end of the file.
+pub fn dump_tokens(tokens: &[(Token, FileRef)]) -> String {
+    tokens.iter()
+        .map(|(token, position)| {
+            let length = position.end_char_index.saturating_sub(position.begin_char_index);
+            format!("{}:{}:{length} {token:?}\n", position.line_index + 1, position.begin_char_index + 1)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        let source = SourceFile::from_text("test.lfl".to_owned(), source.to_owned());
+        tokenize(Arc::new(source)).unwrap().into_iter().map(|(token, _)| token).collect()
+    }
+
+    // The message of the single error given when tokenizing, and the line and column it begins at, counting from 1.
+    fn error(source: &str) -> (String, u32, u32) {
+        let source = SourceFile::from_text("test.lfl".to_owned(), source.to_owned());
+        let mut errors = tokenize(Arc::new(source)).expect_err("Tokenized successfully").0;
+        assert_eq!(errors.len(), 1);
+        let error = errors.remove(0);
+        let position = error.position.expect("Error has no position");
+        (error.msg, position.line_index + 1, position.begin_char_index + 1)
+    }
+
+    #[test]
+    fn operators_are_single_characters() {
+        assert_eq!(tokens("a<=-b>>c"), [
+            Token::Identifier("a".to_owned()),
+            Token::LeftArrow,
+            Token::Equals,
+            Token::Minus,
+            Token::Identifier("b".to_owned()),
+            Token::RightArrow,
+            Token::RightArrow,
+            Token::Identifier("c".to_owned()),
+            Token::EndOfFile
+        ]);
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        assert_eq!(tokens("1 // line\n/* block\n */ 2"), [Token::Number(1), Token::Number(2), Token::EndOfFile]);
+    }
+
+    #[test]
+    fn literal_can_be_one_above_maximum() {
+        // The parser only allows this after a `-`.
+        assert_eq!(tokens("2147483648"), [Token::Number(MAX_LITERAL), Token::EndOfFile]);
+        assert_eq!(error("x = 2147483649;"), ("Integer literal is too large to fit in a 32 bit signed integer".to_owned(), 1, 5));
+    }
+
+    #[test]
+    fn unterminated_block_comment_is_an_error() {
+        assert_eq!(error("a\n  /* never\nends"), ("Unterminated block comment".to_owned(), 2, 3));
+    }
+
+    #[test]
+    fn unterminated_string_is_an_error() {
+        assert_eq!(error("import \"other.lfl"), ("Unterminated string literal".to_owned(), 1, 8));
+    }
+
+    #[test]
+    fn invalid_character_is_an_error() {
+        assert_eq!(error("a = 1 @ 2;"), ("Invalid character".to_owned(), 1, 7));
+    }
+}
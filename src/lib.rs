@@ -0,0 +1,132 @@
+//! Compiler for LFL, the language of the factorio computer.
+//!
+//! The `lflc` binary is a thin wrapper around this library, which can also be used to compile programs
+//! from other rust code without going through the filesystem.
+//!
+//! Compile errors implement `std::error::Error`, so can be returned with `?` from functions returning e.g. `anyhow::Result`:
+//!
+//! ```
+//! use lflc::blueprint::{BlueprintFormat, RomLayout};
+//!
+//! fn build(source: &str) -> anyhow::Result<String> {
+//!     let instructions = lflc::compile_source("snippet.lfl", source)?;
+//!     lflc::instructions_to_blueprint_string(&instructions, BlueprintFormat::V2_0, RomLayout::default())
+//! }
+//!
+//! let blueprint = build("void main() { write_signal(1, 42); }").unwrap();
+//! assert!(blueprint.starts_with('0'));
+//!
+//! // Each error can also be rendered separately, e.g. to send one message per error.
+//! let err = lflc::compile_source("snippet.lfl", "void main() { write_signal(1, x); }").unwrap_err();
+//! let messages: Vec<String> = err.iter().map(|error| error.render(lflc::error_handling::Palette::PLAIN)).collect();
+//! assert_eq!(messages.len(), 1);
+//! assert!(messages[0].contains("snippet.lfl"));
+//! ```
+
+pub mod blueprint;
+pub mod assembly;
+pub mod assembler;
+pub mod lexer;
+pub mod parser;
+pub mod ast;
+pub mod compiler;
+pub mod error_handling;
+pub mod emulator;
+pub mod folding;
+pub mod semantics;
+pub mod optimizer;
+pub mod lints;
+pub mod visit;
+pub mod modules;
+pub mod printer;
+pub mod eval;
+pub mod profile;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+use std::sync::Arc;
+
+use assembly::Instruction;
+use blueprint::{BlueprintFormat, RomLayout};
+use compiler::{CompileOptions, CompiledModule, FunctionCache, ObjectFile};
+use error_handling::{SourceFile, CompileResult, FileTaggedError, JsonDiagnostic, Warning};
+
+// Compiles a source file, along with the files it imports, into instructions for the program ROM.
+// Any warnings generated are added to `warnings`, even if compilation fails.
+pub fn compile(source: Arc<SourceFile>, options: &CompileOptions, warnings: &mut Vec<Warning>) -> CompileResult<CompiledModule> {
+    compile_with_objects(source, Vec::new(), options, None, warnings, &mut Vec::new())
+}
+
+// Compiles a program in the same way as `compile`, linking it with `objects`, e.g. functions written in assembly that the program calls.
+// With a `cache`, only the functions that have changed since the last build are compiled, e.g. for `--watch`.
+// The path of each source file read is added to `files`, even if compilation fails. See `modules::load_program_files`.
+pub fn compile_with_objects(source: Arc<SourceFile>, objects: Vec<ObjectFile>, options: &CompileOptions, cache: Option<&mut FunctionCache>,
+    warnings: &mut Vec<Warning>, files: &mut Vec<String>) -> CompileResult<CompiledModule> {
+    let ast = modules::load_program_files(source, warnings, files)?;
+
+    lints::check_module(&ast, warnings);
+
+    compiler::compile_module_with_objects(ast, objects, options, cache, warnings)
+}
+
+// Compiles a source file into an object file, to be linked with the objects of the files it imports by `compiler::link`.
+// The functions and globals of the imported files are only used to check how they are called, so aren't part of the object.
+// The same goes for the functions defined by `external_objects`, e.g. those written in assembly.
+// The paths of the files read are added to `files`, as with `compile_with_objects`.
+pub fn compile_object(source: Arc<SourceFile>, external_objects: &[ObjectFile], options: &CompileOptions, warnings: &mut Vec<Warning>,
+    files: &mut Vec<String>) -> CompileResult<ObjectFile> {
+    let ast = modules::load_program_files(source.clone(), warnings, files)?;
+
+    lints::check_module(&ast, warnings);
+
+    compiler::compile_object(ast, Some(&source), external_objects, options, None, warnings)
+}
+
+// Compiles source code held in memory with the default options, ignoring any warnings.
+// `name` is used in place of a file path when reporting errors.
+pub fn compile_source(name: &str, text: &str) -> CompileResult<Vec<Instruction>> {
+    let source = SourceFile::from_text(name.to_owned(), text.to_owned());
+    compile(Arc::new(source), &CompileOptions::default(), &mut Vec::new())
+        .map(|module| module.instructions)
+}
+
+// Compiles source code held in memory with the default options into the blueprint string for its ROM.
+// On failure, gives a JSON array of the errors, in the form written by `--error-format json`, e.g. for the browser playground.
+pub fn compile_or_diagnose(name: &str, text: &str) -> Result<String, String> {
+    let errors = match compile_source(name, text) {
+        Ok(instructions) => match instructions_to_blueprint_string(&instructions, BlueprintFormat::default(), RomLayout::default()) {
+            Ok(blueprint) => return Ok(blueprint),
+            // e.g. the program is too long for the ROM.
+            Err(err) => vec![FileTaggedError {
+                position: None,
+                msg: format!("{err:#}"),
+                notes: Vec::new()
+            }]
+        },
+        Err(err) => error_handling::sort_errors(err.0)
+    };
+
+    let diagnostics: Vec<JsonDiagnostic> = errors.iter().map(JsonDiagnostic::from_error).collect();
+    Err(serde_json::to_string(&diagnostics).expect("Failed to serialize diagnostics"))
+}
+
+// Creates the blueprint string for a program ROM containing the given instructions, which can be imported into factorio.
+// Fails if an instruction cannot be stored with the encoding of the layout.
+pub fn instructions_to_blueprint_string(instructions: &[Instruction], format: BlueprintFormat, layout: RomLayout) -> anyhow::Result<String> {
+    blueprint::SerializedBlueprint {
+        blueprint: blueprint::generate_rom_blueprint(instructions, format, layout)?
+    }.save()
+}
+
+// Reads the instructions from the program ROM in a blueprint string created by `instructions_to_blueprint_string`,
+// or in a blueprint book containing the ROM.
+pub fn blueprint_string_to_instructions(blueprint_string: &str) -> anyhow::Result<Vec<Instruction>> {
+    if let Ok(book) = blueprint::SerializedBlueprintBook::load(blueprint_string) {
+        return blueprint::read_rom_from_book(&book.blueprint_book);
+    }
+
+    let serialized = blueprint::SerializedBlueprint::load(blueprint_string)?;
+    blueprint::read_rom_blueprint(&serialized.blueprint)
+}
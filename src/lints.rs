@@ -0,0 +1,349 @@
+//! Heuristic checks over the syntax tree that generate warnings rather than errors.
+
+use std::collections::HashSet;
+
+use crate::{ast::{Item, Function, Statement, Expression, BinaryOperator, UnaryOperator}, compiler, error_handling::{FileRef, Warning, WarningKind}, warning,
+    visit::{self, Visitor}};
+
+// Runs all checks over the functions in a module.
+pub fn check_module(module: &[Item], warnings: &mut Vec<Warning>) {
+    let globals = module.iter()
+        .filter_map(|item| match item {
+            Item::Global(global) => Some(global.name.as_str()),
+            Item::Function(_) | Item::Constant(_) => None
+        })
+        .collect();
+
+    let functions = module.iter()
+        .filter_map(|item| match item {
+            Item::Function(function) => Some(function.name.as_str()),
+            Item::Global(_) | Item::Constant(_) => None
+        })
+        .collect();
+
+    visit::walk_module(&mut LintVisitor { warnings, globals, functions }, module);
+}
+
+// Checks the amount of each shift that is known at compile time.
+// This runs after constants have been substituted and folded, so that e.g. `x << (BITS - 1)` is checked too.
+pub fn check_shift_amounts(module: &[Item], warnings: &mut Vec<Warning>) {
+    visit::walk_module(&mut ShiftAmounts { warnings }, module);
+}
+
+struct ShiftAmounts<'a> {
+    warnings: &'a mut Vec<Warning>
+}
+
+impl <'ast> Visitor<'ast> for ShiftAmounts<'_> {
+    fn visit_expression(&mut self, expr: &'ast Expression) {
+        if let Expression::Binary { right, operator: BinaryOperator::ShiftLeft | BinaryOperator::ShiftRight, expression_ref, .. } = expr {
+            // Negative literals are only folded into a single literal if folding is enabled.
+            let amount = match &**right {
+                Expression::Literal(amount) => Some(*amount),
+                Expression::Unary { value, operator: UnaryOperator::Negate } => match **value {
+                    Expression::Literal(amount) => Some(amount.wrapping_neg()),
+                    _ => None
+                },
+                _ => None
+            };
+
+            if let Some(amount) = amount.filter(|amount| !(0..32).contains(amount)) {
+                self.warnings.push(warning!(WarningKind::ShiftOutOfRange, expression_ref.clone(),
+                    "Shifting by {amount} is outside the range 0 to 31, so may not give the expected result. \
+                    The emulator only uses the lowest 5 bits of the amount, shifting by {}", amount & 31));
+            }
+        }
+
+        visit::walk_expression(self, expr);
+    }
+}
+
+struct LintVisitor<'a, 'ast> {
+    warnings: &'a mut Vec<Warning>,
+    // The names of the global variables in the module.
+    globals: HashSet<&'ast str>,
+    // The names of the functions in the module.
+    functions: HashSet<&'ast str>
+}
+
+impl <'ast> Visitor<'ast> for LintVisitor<'_, 'ast> {
+    fn visit_function(&mut self, function: &'ast Function) {
+        for (argument, argument_ref) in function.argument_names.iter().zip(&function.argument_name_refs) {
+            if self.functions.contains(argument.as_str()) {
+                self.warnings.push(warning!(WarningKind::ArgumentShadowsFunction, argument_ref.clone(),
+                    "Argument `{argument}` has the same name as a function"));
+            }
+        }
+
+        visit::walk_function(self, function);
+    }
+
+    fn visit_block(&mut self, block: &'ast [Statement]) {
+        // Only the first statement that can never run is reported, as all of the statements after it can't run either.
+        if let Some(exit_idx) = block.iter().position(compiler::always_exits) {
+            if let Some(position) = block[exit_idx + 1..].iter().find_map(first_position) {
+                self.warnings.push(warning!(WarningKind::UnreachableCode, position,
+                    "This code can never run, as it comes after {}", describe_exit(&block[exit_idx])));
+            }
+        }
+
+        visit::walk_block(self, block);
+    }
+
+    fn visit_statement(&mut self, statement: &'ast Statement) {
+        if let Statement::While { condition, condition_ref, block, .. } = statement {
+            if is_condition_unmodified(condition, block, &self.globals) {
+                self.warnings.push(warning!(WarningKind::UnmodifiedLoopCondition, condition_ref.clone(),
+                    "None of the variables in this condition are modified within the loop, so it may never end"));
+            }
+        }
+
+        // Calls are the only expressions that can do anything other than compute their value.
+        if let Statement::Expression { value, value_ref } = statement {
+            let mut reads = ReadVariables::default();
+            reads.visit_expression(value);
+            if !reads.calls_function {
+                let msg = match value {
+                    Expression::Binary { operator: BinaryOperator::Equals, .. } => "This comparison has no effect, as its result is discarded. Did you mean to assign with `=`?",
+                    _ => "This expression has no effect, as its value is discarded"
+                };
+                self.warnings.push(warning!(WarningKind::NoEffect, value_ref.clone(), "{msg}"));
+            }
+        }
+
+        visit::walk_statement(self, statement);
+    }
+}
+
+// Describes a statement that always exits the block containing it.
+fn describe_exit(statement: &Statement) -> &'static str {
+    match statement {
+        Statement::Return(_) | Statement::ReturnValue { .. } => "a `return`",
+        Statement::Break(_) => "a `break`",
+        Statement::Continue(_) => "a `continue`",
+        Statement::Call(_) => "a call to `halt()`",
+        Statement::If { .. } => "an `if` statement where every branch ends with a `return`, `break`, `continue` or `halt()`",
+        Statement::While { .. } => "a `do ... while` loop whose body always ends with a `return` or `halt()`",
+        _ => "a block that ends with a `return`, `break`, `continue` or `halt()`"
+    }
+}
+
+// Gets the position of a statement, or of the first statement with a position within a block.
+fn first_position(statement: &Statement) -> Option<FileRef> {
+    match statement {
+        Statement::Block(block) => block.iter().find_map(first_position),
+        _ => compiler::statement_position(statement)
+    }
+}
+
+// Checks if a loop condition reads variables, none of which are assigned within the loop body.
+// Signals and function calls can change outside of the loop, and a loop that can be exited some other way
+// is likely intentional, so these are not considered.
+fn is_condition_unmodified<'ast>(condition: &'ast Expression, block: &'ast [Statement], globals: &HashSet<&'ast str>) -> bool {
+    let mut reads = ReadVariables::default();
+    reads.visit_expression(condition);
+    if !reads.names.is_disjoint(globals) {
+        // Globals can be modified by the functions called within the loop.
+        return false;
+    }
+
+    if reads.reads_external_value || reads.names.is_empty() {
+        return false;
+    }
+
+    let mut exits = LoopExits::default();
+    exits.visit_block(block);
+    if exits.can_exit {
+        return false;
+    }
+
+    let mut assignments = AssignedVariables::default();
+    assignments.visit_block(block);
+
+    reads.names.is_disjoint(&assignments.names)
+}
+
+// Collects the names of the variables read by an expression.
+#[derive(Default)]
+struct ReadVariables<'ast> {
+    names: HashSet<&'ast str>,
+    // True if the expression reads a value that could change outside of the loop, i.e. a signal or a call.
+    reads_external_value: bool,
+    calls_function: bool
+}
+
+impl <'ast> Visitor<'ast> for ReadVariables<'ast> {
+    fn visit_expression(&mut self, expr: &'ast Expression) {
+        match expr {
+            Expression::Call(_) => {
+                self.reads_external_value = true;
+                self.calls_function = true;
+            },
+            Expression::Variable { name, .. } => {
+                self.names.insert(name);
+                if name.starts_with("signal_") {
+                    self.reads_external_value = true;
+                }
+            },
+            _ => visit::walk_expression(self, expr)
+        }
+    }
+}
+
+// Collects the names of the variables assigned within a block.
+#[derive(Default)]
+struct AssignedVariables<'ast> {
+    names: HashSet<&'ast str>
+}
+
+impl <'ast> Visitor<'ast> for AssignedVariables<'ast> {
+    fn visit_statement(&mut self, statement: &'ast Statement) {
+        if let Statement::Assignment { variable_name, .. } = statement {
+            self.names.insert(variable_name);
+        }
+
+        visit::walk_statement(self, statement);
+    }
+}
+
+// Checks if a loop body contains a `break`, `return` or `halt()`, not counting a `break` within an inner loop.
+#[derive(Default)]
+struct LoopExits {
+    inner_loop_depth: u32,
+    can_exit: bool
+}
+
+impl <'ast> Visitor<'ast> for LoopExits {
+    fn visit_statement(&mut self, statement: &'ast Statement) {
+        match statement {
+            Statement::Break(_) if self.inner_loop_depth == 0 => self.can_exit = true,
+            Statement::Return(_) | Statement::ReturnValue { .. } => self.can_exit = true,
+            Statement::Call(call) if call.function_name == compiler::HALT_FUNCTION => self.can_exit = true,
+            Statement::While { block, .. } => {
+                self.inner_loop_depth += 1;
+                self.visit_block(block);
+                self.inner_loop_depth -= 1;
+            },
+            _ => visit::walk_statement(self, statement)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{error_handling::SourceFile, modules};
+
+    // Gets the kinds of warning given for a program by the checks in this module.
+    fn lint(source: &str) -> Vec<WarningKind> {
+        let source = SourceFile::from_text("test.lfl".to_owned(), source.to_owned());
+        let mut warnings = Vec::new();
+        let module = match modules::load_program(Arc::new(source), &mut warnings) {
+            Ok(module) => module,
+            Err(err) => panic!("{err}")
+        };
+
+        check_module(&module, &mut warnings);
+        warnings.into_iter().map(|warning| warning.kind).collect()
+    }
+
+    // Gets the warnings given by `check_shift_amounts` for the expression assigned to `signal_1`.
+    fn shift_warnings(expression: &str) -> Vec<Warning> {
+        let source = format!("void main(x, y) {{\n    signal_1 = {expression};\n}}");
+        let source = SourceFile::from_text("test.lfl".to_owned(), source);
+        let mut warnings = Vec::new();
+        let module = match modules::load_program(Arc::new(source), &mut warnings) {
+            Ok(module) => module,
+            Err(err) => panic!("{err}")
+        };
+
+        check_shift_amounts(&module, &mut warnings);
+        warnings
+    }
+
+    #[test]
+    fn shift_amounts_in_range_are_not_warned() {
+        for expression in ["x << 0", "x << 31", "x >> 31", "x << y", "x << -y", "x << (y + 40)"] {
+            assert!(shift_warnings(expression).is_empty(), "{expression}");
+        }
+    }
+
+    #[test]
+    fn shift_amounts_out_of_range_are_warned() {
+        for expression in ["x << 32", "x << -1", "x >> 32", "x >> -1"] {
+            let warnings = shift_warnings(expression);
+            assert_eq!(warnings.len(), 1, "{expression}");
+            assert_eq!(warnings[0].kind, WarningKind::ShiftOutOfRange);
+        }
+    }
+
+    #[test]
+    fn shift_warning_covers_whole_expression() {
+        let warnings = shift_warnings("(x + 1) << 32");
+        let position = warnings[0].error.position.as_ref().unwrap();
+        assert_eq!((position.line_index, position.begin_char_index), (1, 15));
+        assert_eq!((position.end_line_index, position.end_char_index), (1, 28));
+    }
+
+    #[test]
+    fn discarded_expression_is_warned() {
+        assert_eq!(lint("void main(x) { x + 1; }"), vec![WarningKind::NoEffect]);
+        assert_eq!(lint("void main(x) { x == 1; }"), vec![WarningKind::NoEffect]);
+        assert_eq!(lint("void main(x) { (signal_1); }"), vec![WarningKind::NoEffect]);
+    }
+
+    #[test]
+    fn discarded_expression_calling_function_is_not_warned() {
+        let functions = "int f() { return 1; } void g() { } ";
+        for statement in ["f();", "(f());", "g();", "(g());", "signal_1 && f();", "f() + 1;"] {
+            assert!(lint(&format!("{functions}void main() {{ {statement} }}")).is_empty(), "{statement}");
+        }
+    }
+
+    #[test]
+    fn unmodified_loop_condition_is_warned() {
+        let warnings = lint("void main() { i = 0; total = 0; while i < 10 { total += 2; } signal_1 = total; }");
+        assert_eq!(warnings, vec![WarningKind::UnmodifiedLoopCondition]);
+    }
+
+    #[test]
+    fn loop_condition_modified_in_body_is_not_warned() {
+        assert!(lint("void main() { i = 0; while i < 10 { i += 1; } }").is_empty());
+    }
+
+    #[test]
+    fn loop_condition_reading_signal_is_not_warned() {
+        assert!(lint("void main() { total = 0; while signal_1 < 10 { total += 1; signal_2 = total; } }").is_empty());
+    }
+
+    #[test]
+    fn loop_with_break_is_not_warned() {
+        assert!(lint("void main() { i = 0; while i < 10 { if signal_1 { break; } } }").is_empty());
+    }
+
+    #[test]
+    fn break_in_inner_loop_does_not_exempt_outer_loop() {
+        let warnings = lint("void main() { i = 0; j = 0; while i < 10 { while j < 5 { j += 1; break; } } }");
+        assert_eq!(warnings, vec![WarningKind::UnmodifiedLoopCondition]);
+    }
+
+    #[test]
+    fn code_after_returning_do_while_is_unreachable() {
+        let warnings = lint("int f(x) { do { return x; } while x; signal_1 = 1; } void main() { signal_2 = f(1); }");
+        assert_eq!(warnings, vec![WarningKind::UnreachableCode]);
+    }
+
+    #[test]
+    fn code_after_do_while_with_break_is_reachable() {
+        assert!(lint("void main() { do { if signal_1 { break; } return; } while 1; signal_2 = 1; }").is_empty());
+        assert!(lint("void main() { do { if signal_1 { continue; } return; } while signal_2; signal_3 = 1; }").is_empty());
+    }
+
+    #[test]
+    fn break_in_loop_nested_in_do_while_is_ignored() {
+        let warnings = lint("void main() { do { while signal_1 { break; } return; } while 1; signal_2 = 1; }");
+        assert_eq!(warnings, vec![WarningKind::UnreachableCode]);
+    }
+}
@@ -1,64 +1,1226 @@
-mod blueprint;
-mod assembly;
-mod lexer;
-mod parser;
-mod ast;
-mod compiler;
-mod error_handling;
+mod cli;
+#[cfg(not(target_arch = "wasm32"))]
+mod watch;
 
-use std::sync::Arc;
+use std::{sync::Arc, process::ExitCode, fmt::Display, path::Path, time::{SystemTime, UNIX_EPOCH}, io::{self, IsTerminal, Read}};
 
-use assembly::Instruction;
-use error_handling::{SourceFile, CompileResult};
+use cli::{Args, Command};
+use lflc::{assembler, assembly::Instruction, blueprint::{self, Blueprint, BlueprintFormat, CompressionLevel, SaveOptions, RomLayout, RamSignals, SignalId, InstructionEncoding, RomBanks, BankSelect, COLUMN_STRIDES}, compiler::{self, CompileOptions, CompiledModule, FunctionCache, FunctionSpan, CallConvention, ObjectFile, Pass, PassDump},
+    emulator::{self, EmulatorOptions, SignalState, RunStatus, STACK_CAPACITY},
+    error_handling::{self, CompileErrors, CompileResult, SourceFile, FileRef, Warning, WarningKind, Palette, Styled, JsonDiagnostic},
+    eval::{self, Evaluation}, ast, lexer, parser::{self, TokenIterator}, printer, profile::InstructionSet};
 
-use crate::parser::TokenIterator;
+// The exit status when the arguments were invalid, as opposed to the program failing to compile, which gives 1.
+const USAGE_ERROR: u8 = 2;
+// The exit status when a file couldn't be read or written.
+const IO_ERROR: u8 = 3;
 
-fn try_compile(source: Arc<SourceFile>) -> CompileResult<Vec<Instruction>>  {
-    let tokens = lexer::tokenize(source)?;
-    let ast = parser::parse_module(&mut TokenIterator::new(tokens))?;
+// Prints a problem with the arguments, followed by the usage message.
+fn usage_error(msg: &str) -> ExitCode {
+    eprintln!("error: {msg}\n\n{}", cli::usage());
+    ExitCode::from(USAGE_ERROR)
+}
 
-    return compiler::compile_module(ast)
+// Prints a failure that isn't an error in the code being compiled, e.g. a file that couldn't be read, and gives the
+// exit status to return.
+fn report_error(msg: impl Display, status: ExitCode) -> ExitCode {
+    eprintln!("error: {msg}");
+    status
 }
 
-fn main() {
-    let path = match std::env::args().nth(1) {
-        Some(file_path) => file_path,
-        None => {
-            eprintln!("Expected file path to compile");
-            return;
+// Reads the input signals given with `--input <signal>=<value>` (or `--signal`), with all others being zero.
+fn parse_inputs(args: &Args, signal_count: i32) -> Result<SignalState, ExitCode> {
+    let mut signals = SignalState::new(signal_count);
+    for input in args.values("--input") {
+        let parsed = input.split_once('=')
+            .and_then(|(signal, value)| Some((signal.parse::<i32>().ok()?, value.parse::<i32>().ok()?)))
+            .filter(|(signal, _)| (1..=signal_count).contains(signal));
+
+        match parsed {
+            Some((signal, value)) => signals.inputs[signal as usize - 1] = value,
+            None => return Err(usage_error(&format!("Invalid input {input}, expected `<signal>=<value>` with a signal from 1 to {signal_count}")))
         }
+    }
+
+    Ok(signals)
+}
+
+// Runs a compiled program with the emulator, and prints the output signals once it halts.
+// Input signals are given with `--input <signal>=<value>`.
+fn emulate(args: &Args, instructions: &[Instruction], call_convention: CallConvention, signal_count: i32, tick_signal: Option<i32>) -> ExitCode {
+    let mut options = EmulatorOptions {
+        call_convention,
+        signal_count,
+        tick_signal,
+        ..Default::default()
+    };
+
+    // The result can be written as JSON for scripts, in the form of `RunResult`.
+    let json_output = match args.value("--output-format") {
+        Some("json") => true,
+        Some("human") | None => false,
+        Some(name) => return usage_error(&format!("Unknown output format {name}, expected `human` or `json`"))
+    };
+
+    if let Some(max_steps) = args.value("--max-steps") {
+        options.max_steps = match max_steps.parse() {
+            Ok(max_steps) => max_steps,
+            Err(_) => return usage_error("Max steps must be a valid integer")
+        };
+    }
+
+    let mut signals = match parse_inputs(args, signal_count) {
+        Ok(signals) => signals,
+        Err(status) => return status
+    };
+
+    let result = emulator::run(instructions, &mut signals, &options);
+    let status = if result.halted() { ExitCode::SUCCESS } else { ExitCode::FAILURE };
+    if json_output {
+        // The result is printed whether or not the program halted, with the exit status still giving failure.
+        return match serde_json::to_string(&result) {
+            Ok(json) => {
+                println!("{json}");
+                status
+            },
+            Err(err) => report_error(format!("Failed to write the result: {err}"), ExitCode::FAILURE)
+        };
+    }
+
+    match result.status {
+        RunStatus::Halted => {
+            println!("Halted after {} cycles", result.steps);
+            for (idx, value) in result.outputs.iter().enumerate() {
+                println!("signal_{} = {value}", idx + 1);
+            }
+
+            status
+        },
+        RunStatus::StepLimit => report_error(format!("Emulation failed: Program did not halt within {} steps", result.steps), status),
+        failed => report_error(format!("Emulation failed: {failed}"), status)
+    }
+}
+
+// Prints the value of the expression given to `eval`. Constant expressions are folded, and others are run on the emulator.
+fn evaluate_expression(args: &Args, source_file: SourceFile, options: &CompileOptions, diagnostics: &DiagnosticSettings) -> ExitCode {
+    let mut signals = match parse_inputs(args, options.signal_count) {
+        Ok(signals) => signals,
+        Err(status) => return status
+    };
+
+    let mut warnings = Vec::new();
+    let result = eval::evaluate(Arc::new(source_file), &mut signals, options, &mut warnings);
+    let evaluation = match report_diagnostics(result, warnings, diagnostics) {
+        Ok(evaluation) => evaluation,
+        Err(status) => return status
     };
-       
-    let display_assembly = std::env::args().any(|arg| arg == "--assembly");
 
-    let source_file = match SourceFile::load_from_path(path.to_string()) {
-        Ok(file) => file,
-        Err(err) => {
-            eprintln!("Failed to read source: {err}");
-            return;
+    match evaluation {
+        Evaluation::Constant(value) => println!("{value}"),
+        Evaluation::Emulated(result) if result.halted() => println!("{}", result.outputs[0]),
+        Evaluation::Emulated(result) => return report_error(format!("Emulation failed: {}", result.status), ExitCode::FAILURE)
+    }
+
+    ExitCode::SUCCESS
+}
+
+// Places text on the system clipboard.
+#[cfg(feature = "clipboard")]
+fn copy_to_clipboard(text: &str) -> Result<(), String> {
+    let mut clipboard = arboard::Clipboard::new().map_err(|err| err.to_string())?;
+
+    // On Linux, the clipboard is emptied when the program that set it exits, so lflc keeps running until something else is copied.
+    #[cfg(target_os = "linux")]
+    let result = {
+        use arboard::SetExtLinux;
+        eprintln!("Keeping the output on the clipboard until something else is copied");
+        clipboard.set().wait().text(text)
+    };
+    #[cfg(not(target_os = "linux"))]
+    let result = clipboard.set_text(text);
+
+    result.map_err(|err| err.to_string())
+}
+
+// Headless builds don't link to the clipboard libraries, so the output is always printed instead.
+#[cfg(not(feature = "clipboard"))]
+fn copy_to_clipboard(_text: &str) -> Result<(), String> {
+    Err("lflc was built without the `clipboard` feature".to_string())
+}
+
+// Writes the output to the file given with `-o`, to the clipboard with `--clipboard`, or to stdout.
+// `contents` describes the output in the summary printed instead of it, e.g. `12 instructions`.
+fn write_output(args: &Args, output: String, contents: &str) -> ExitCode {
+    // With an output file, only a summary is printed so that it isn't mixed up with the output.
+    match args.value("-o") {
+        Some(output_path) => {
+            if let Err(err) = std::fs::write(output_path, output) {
+                return report_error(format!("Failed to write {output_path}: {err}"), ExitCode::from(IO_ERROR));
+            }
+
+            eprintln!("Wrote {contents} to {output_path}");
+        },
+        // The output is still printed if the clipboard can't be used, so that it can be copied by hand.
+        None if args.has("--clipboard") => match copy_to_clipboard(output.trim_end()) {
+            Ok(()) => eprintln!("Copied {contents} to the clipboard"),
+            Err(err) => {
+                eprintln!("Failed to copy to the clipboard, printing instead: {err}");
+                print!("{output}");
+            }
+        },
+        None => print!("{output}")
+    }
+
+    ExitCode::SUCCESS
+}
+
+// Prints the number of instructions in the program and in each function, and the size of its blueprint.
+fn print_stats(module: &CompiledModule, blueprint_format: BlueprintFormat, layout: RomLayout) {
+    eprintln!("Instructions: {}", module.instructions.len());
+    // A program that can't be encoded is reported when the blueprint is output.
+    if let Ok(blueprint) = blueprint::generate_rom_blueprint(&module.instructions, blueprint_format, layout) {
+        eprintln!("Blueprint entities: {}", blueprint.entities.len());
+    }
+
+    for span in &module.function_spans {
+        eprintln!("  {}", describe_function_span(span));
+    }
+
+    // Merged functions take up no space of their own, so are listed separately with what they saved.
+    for merged in &module.merged_functions {
+        let plural = if merged.instructions_saved == 1 { "" } else { "s" };
+        eprintln!("  {}: merged into `{}`, saving {} instruction{plural}", merged.function_name, merged.merged_into, merged.instructions_saved);
+    }
+
+    if !module.merged_functions.is_empty() {
+        let saved: usize = module.merged_functions.iter().map(|merged| merged.instructions_saved).sum();
+        eprintln!("Instructions saved by merging identical functions: {saved}");
+    }
+
+    // Every pass is recorded with `--stats`, to show what each one saved.
+    if !module.pass_dumps.is_empty() {
+        eprintln!("Instructions after each pass:");
+        for dump in &module.pass_dumps {
+            eprintln!("  {}", describe_pass_dump(dump));
         }
+    }
+}
+
+// Describes the size of the program after a pass, e.g. `inlining: 40 instructions, before linking`.
+fn describe_pass_dump(dump: &PassDump) -> String {
+    let mut description = format!("{}: {} instructions", dump.pass.name(), dump.instructions.len());
+    if !dump.linked {
+        description.push_str(", before linking");
+    }
+    if !dump.enabled {
+        description.push_str(", not enabled");
+    }
+
+    description
+}
+
+// Prints the program after each of the passes given with `--dump-after`, or writes each to a file in the `--dump-dir`.
+// The files are numbered in the order the passes run, e.g. `5-optimize.asm`.
+fn write_pass_dumps(args: &Args, dumps: &[PassDump], requested: &[Pass]) -> Result<(), ExitCode> {
+    let dump_dir = args.value("--dump-dir");
+    if let Some(directory) = dump_dir {
+        if let Err(err) = std::fs::create_dir_all(directory) {
+            return Err(report_error(format!("Failed to create {directory}: {err}"), ExitCode::from(IO_ERROR)));
+        }
+    }
+
+    for dump in dumps.iter().filter(|dump| requested.contains(&dump.pass)) {
+        let mut text = format!("; after {}\n", describe_pass_dump(dump));
+        if !dump.linked {
+            text.push_str("; jumps are relative to the start of each function, and JSR gives the index of the function called\n");
+        }
+        text.push_str(&format_assembly(&dump.instructions, &dump.function_spans));
+
+        match dump_dir {
+            Some(directory) => {
+                let number = Pass::ALL.iter().position(|pass| *pass == dump.pass).unwrap_or_default();
+                let path = Path::new(directory).join(format!("{number}-{}.asm", dump.pass.name()));
+                if let Err(err) = std::fs::write(&path, text) {
+                    return Err(report_error(format!("Failed to write {}: {err}", path.display()), ExitCode::from(IO_ERROR)));
+                }
+            },
+            None => eprint!("{text}")
+        }
+    }
+
+    Ok(())
+}
+
+// Describes the size and addresses of a function, e.g. `main: 12 instructions, addresses 5 to 16`.
+fn describe_function_span(span: &FunctionSpan) -> String {
+    let name = match &span.function_name {
+        Some(name) => name.as_str(),
+        None => "(startup)"
     };
 
-    let instructions = match try_compile(Arc::new(source_file)) {
-        Ok(inst) => inst,
-        Err(err) => {
-            eprintln!("{err}");
-            return;
+    let count = span.end - span.start;
+    let plural = if count == 1 { "" } else { "s" };
+
+    // Printed as ROM addresses, which begin at 1.
+    format!("{name}: {count} instruction{plural}, addresses {} to {}", span.start + 1, span.end)
+}
+
+// Creates the error for a program longer than `--max-program-size`, listing the largest functions first to show what could be made smaller.
+fn describe_oversized_program(module: &CompiledModule, max_size: usize) -> String {
+    let mut msg = format!("The program is {} instructions, but the computer can only address {max_size}", module.instructions.len());
+
+    let mut spans: Vec<&FunctionSpan> = module.function_spans.iter().collect();
+    spans.sort_by_key(|span| std::cmp::Reverse(span.end - span.start));
+    for span in spans {
+        msg.push_str("\n  ");
+        msg.push_str(&describe_function_span(span));
+    }
+
+    msg
+}
+
+// Parses a virtual signal given on the command line, e.g. `signal-A`.
+fn parse_virtual_signal(name: &str) -> Option<SignalId> {
+    name.strip_prefix("signal-")
+        .filter(|suffix| !suffix.is_empty())
+        .map(|_| SignalId::virtual_signal(name))
+}
+
+// Parses the number of cells in a RAM, and the signals given for it with `--address-signal` and `--data-signal`.
+fn parse_ram_options(args: &Args, cells: &str) -> Result<(usize, RamSignals), String> {
+    let cells = match cells.parse::<usize>() {
+        Ok(cells) if cells > 0 => cells,
+        _ => return Err("The number of cells in the RAM must be a positive integer".to_string())
+    };
+
+    let mut signals = RamSignals::default();
+    if let Some(name) = args.value("--address-signal") {
+        signals.address = match parse_virtual_signal(name) {
+            Some(signal) => signal,
+            None => return Err(format!("Address signal {name} must be a virtual signal, e.g. `signal-A`"))
+        };
+    }
+
+    if let Some(name) = args.value("--data-signal") {
+        signals.data = match parse_virtual_signal(name) {
+            Some(signal) => signal,
+            None => return Err(format!("Data signal {name} must be a virtual signal, e.g. `signal-D`"))
+        };
+    }
+
+    Ok((cells, signals))
+}
+
+// How blueprints are generated and written, from the command line options.
+struct BlueprintSettings {
+    format: BlueprintFormat,
+    // Must be for the same major version of the game as the format.
+    game_version: u64,
+    save_options: SaveOptions
+}
+
+// Turns the problems found by validating a generated blueprint into an error listing them.
+// Mistakes in the generated wiring would otherwise only be noticed once the blueprint is used in game.
+fn check_generated(validated: Result<(), Vec<String>>) -> anyhow::Result<()> {
+    validated.map_err(|problems| anyhow::anyhow!("The generated blueprint is invalid, which is a bug in lflc:\n  {}", problems.join("\n  ")))
+}
+
+// Writes the blueprint for a RAM with the number of cells given in place of a file.
+fn write_ram_blueprint(args: &Args, cells: &str, settings: &BlueprintSettings) -> ExitCode {
+    let (cells, signals) = match parse_ram_options(args, cells) {
+        Ok(options) => options,
+        Err(msg) => return usage_error(&msg)
+    };
+
+    let mut ram = blueprint::generate_ram_blueprint(cells, settings.format, &signals);
+    ram.version = settings.game_version;
+    let output = check_generated(blueprint::validate_layout(&ram))
+        .and_then(|_| blueprint::SerializedBlueprint { blueprint: ram }.save_with(settings.save_options));
+    let output = match output {
+        Ok(output) => output,
+        Err(err) => return report_error(format!("{err:#}"), ExitCode::FAILURE)
+    };
+
+    let plural = if cells == 1 { "" } else { "s" };
+    write_output(args, format!("{output}\n"), &format!("a RAM with {cells} cell{plural}"))
+}
+
+// Writes a blueprint for a tick counter on the given input signal of the computer, e.g. `5` for `signal-5`.
+fn write_clock_blueprint(args: &Args, signal: &str, settings: &BlueprintSettings) -> ExitCode {
+    let signal = match signal.parse::<u32>() {
+        Ok(signal) if (1..=9).contains(&signal) => signal,
+        _ => return usage_error("The clock signal must be a number from 1 to 9")
+    };
+
+    let mut clock = blueprint::generate_counter_blueprint(&SignalId::virtual_signal(&format!("signal-{signal}")), settings.format);
+    clock.label = format!("Clock (signal {signal})");
+    clock.version = settings.game_version;
+    let output = check_generated(blueprint::validate_layout(&clock))
+        .and_then(|_| blueprint::SerializedBlueprint { blueprint: clock }.save_with(settings.save_options));
+    let output = match output {
+        Ok(output) => output,
+        Err(err) => return report_error(format!("{err:#}"), ExitCode::FAILURE)
+    };
+
+    write_output(args, format!("{output}\n"), &format!("a clock on signal {signal}"))
+}
+
+// The paths given to source read from stdin, or given with `--eval`, which are shown in errors.
+const STDIN_PATH: &str = "-";
+const STDIN_NAME: &str = "<stdin>";
+const EVAL_PATH: &str = "<eval>";
+// The name the expression given to `eval` is reported under.
+const EXPRESSION_PATH: &str = "<expression>";
+
+// Where to write the files read by a build with `--dep-file`, so that a build system can rebuild the output when any of them change.
+struct DepFile<'a> {
+    path: &'a str,
+    // The output file given with `-o`.
+    target: &'a str,
+    // With `--dep-format json`, rather than as a Make rule.
+    json: bool
+}
+
+// Escapes a path for a Make rule, in which spaces separate paths, `#` starts a comment and `$` starts a variable.
+fn escape_make_path(path: &str) -> String {
+    path.replace('$', "$$").replace('#', "\\#").replace(' ', "\\ ")
+}
+
+// Writes the dependency file, listing `files` in the order they were read.
+fn write_dep_file(dep_file: &DepFile, files: &[String]) -> Result<(), ExitCode> {
+    let contents = if dep_file.json {
+        format!("{}\n", serde_json::json!({ "target": dep_file.target, "dependencies": files }))
+    }   else {
+        let dependencies: Vec<String> = files.iter().map(|file| escape_make_path(file)).collect();
+        format!("{}: {}\n", escape_make_path(dep_file.target), dependencies.join(" "))
+    };
+
+    std::fs::write(dep_file.path, contents)
+        .map_err(|err| report_error(format!("Failed to write {}: {err}", dep_file.path), ExitCode::from(IO_ERROR)))
+}
+
+// Reads the source to compile from the file at `path`, from stdin if the path is `-`, or from the text given with `--eval`.
+fn load_source(path: &str, eval_source: Option<&str>) -> io::Result<SourceFile> {
+    match eval_source {
+        Some(text) => Ok(SourceFile::from_text(EVAL_PATH.to_owned(), text.to_owned())),
+        None if path == STDIN_PATH => {
+            let mut text = String::new();
+            io::stdin().read_to_string(&mut text)?;
+            Ok(SourceFile::from_text(STDIN_NAME.to_owned(), text))
+        },
+        None => SourceFile::load_from_path(path.to_owned())
+    }
+}
+
+// Gets the name of a program from the path of its file, without the directory or extension.
+fn program_name(path: &str) -> String {
+    match Path::new(path).file_stem() {
+        Some(stem) => stem.to_string_lossy().into_owned(),
+        None => path.to_owned()
+    }
+}
+
+// Formats a time as a UTC date and time, e.g. `2024-01-31 17:05 UTC`.
+fn format_timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_secs());
+    let (hour, minute) = (seconds % 86400 / 3600, seconds % 3600 / 60);
+
+    // Converts the days since 1970 into a date, using the algorithm from http://howardhinnant.github.io/date_algorithms.html
+    let days = seconds / 86400 + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months are counted from March, so that the leap day is at the end of the year.
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+
+    format!("{year}-{month:02}-{day:02} {hour:02}:{minute:02} UTC")
+}
+
+// Describes a compiled program in its blueprint, so that versions of it can be told apart in the blueprint library.
+fn describe_program(instruction_count: usize, source: Option<&str>) -> String {
+    let mut description = format!("{instruction_count} instructions, compiled {}", format_timestamp(SystemTime::now()));
+    if let Some(source) = source {
+        description.push_str("\n\n");
+        description.push_str(source);
+    }
+
+    description
+}
+
+// Creates a blueprint book string containing the program ROM, followed by a RAM if one was asked for with `--ram`.
+// Each blueprint is labelled with what it contains, so that they can be told apart in game.
+fn format_book(name: &str, mut rom: Blueprint, instruction_count: usize, settings: &BlueprintSettings,
+    ram: Option<&(usize, RamSignals)>) -> anyhow::Result<String> {
+    rom.label = format!("{name} ({instruction_count} instructions)");
+    let mut blueprints = vec![rom];
+
+    if let Some((cells, signals)) = ram {
+        let mut ram = blueprint::generate_ram_blueprint(*cells, settings.format, signals);
+        ram.label = format!("RAM ({cells} cells)");
+        ram.version = settings.game_version;
+        check_generated(blueprint::validate_layout(&ram))?;
+        blueprints.push(ram);
+    }
+
+    let mut book = blueprint::generate_book(name, blueprints, settings.format);
+    book.version = settings.game_version;
+    blueprint::SerializedBlueprintBook { blueprint_book: book }.save_with(settings.save_options)
+}
+
+// Lists the instructions, with a comment before the first instruction of each function.
+fn format_assembly(instructions: &[Instruction], function_spans: &[FunctionSpan]) -> String {
+    let mut output = String::new();
+    for (idx, instruction) in instructions.iter().enumerate() {
+        let function = function_spans.iter()
+            .find(|span| span.start == idx && span.end > span.start)
+            .and_then(|span| span.function_name.as_ref());
+        if let Some(name) = function {
+            output.push_str(&format!("; function {name}\n"));
+        }
+
+        output.push_str(&format!("{}: {instruction}\n", idx + 1));
+    }
+
+    output
+}
+
+// Lists the instructions alongside the file and line that each was compiled from, if any.
+fn format_source_map(instructions: &[Instruction], source_map: &[Option<FileRef>]) -> String {
+    instructions.iter()
+        .zip(source_map)
+        .enumerate()
+        .map(|(idx, (instruction, position))| {
+            let assembly = format!("{}: {instruction}", idx + 1);
+            match position {
+                Some(position) => format!("{assembly:<16}{}:{}\n", position.file.path, position.line_index + 1),
+                None => format!("{assembly}\n")
+            }
+        })
+        .collect()
+}
+
+// Prints the errors from reading a file without compiling it, e.g. for `fmt`.
+fn print_parse_errors(err: CompileErrors, palette: Palette) -> ExitCode {
+    let errors = error_handling::sort_errors(err.0);
+    eprintln!("{}:", error_handling::summary(errors.len(), 0));
+    for error in &errors {
+        eprintln!("{}", Styled(error, palette));
+    }
+
+    ExitCode::FAILURE
+}
+
+// Prints the tokens in a source file, to help find problems with the lexer.
+fn print_tokens(source_file: SourceFile, palette: Palette) -> ExitCode {
+    match lexer::tokenize(Arc::new(source_file)) {
+        Ok(tokens) => {
+            print!("{}", lexer::dump_tokens(&tokens));
+            ExitCode::SUCCESS
+        },
+        Err(err) => print_parse_errors(err, palette)
+    }
+}
+
+// Prints the syntax tree of a source file, without the files it imports, to help find problems with the parser.
+fn print_ast(source_file: SourceFile, palette: Palette) -> ExitCode {
+    let parsed = lexer::tokenize(Arc::new(source_file))
+        .and_then(|tokens| parser::parse_module(&mut TokenIterator::new(tokens)));
+
+    match parsed {
+        Ok((items, imports)) => {
+            print!("{}", ast::dump_module(&items, &imports));
+            ExitCode::SUCCESS
+        },
+        Err(err) => print_parse_errors(err, palette)
+    }
+}
+
+// Rewrites a source file with consistent formatting, or with `--check`, prints the formatted code and fails if
+// the file isn't already formatted. Source read from stdin or given with `--eval` has no path, and is printed.
+fn format_file(path: Option<&str>, source_file: SourceFile, check: bool, palette: Palette) -> ExitCode {
+    let source_file = Arc::new(source_file);
+    let formatted = match printer::format_source(source_file.clone()) {
+        Ok(formatted) => formatted,
+        Err(err) => return print_parse_errors(err, palette)
+    };
+
+    let is_formatted = formatted == source_file.text;
+    let path = match (path, check) {
+        (Some(path), false) => path,
+        _ => {
+            print!("{formatted}");
+            return if is_formatted || !check { ExitCode::SUCCESS } else { ExitCode::FAILURE };
         }
     };
 
-    if display_assembly {
-        println!("Assembly:");
-        for (idx, instruction) in instructions.iter().enumerate() {
-            println!("{}: {instruction}", idx + 1);
+    if !is_formatted {
+        if let Err(err) = std::fs::write(path, formatted) {
+            return report_error(format!("Failed to write {path}: {err}"), ExitCode::from(IO_ERROR));
         }
+    }
+
+    ExitCode::SUCCESS
+}
+
+fn main() -> ExitCode {
+    let args = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(msg) => return usage_error(&msg)
+    };
+
+    if args.has("-h") {
+        print!("{}", cli::usage());
+        return ExitCode::SUCCESS;
+    }
+
+    if args.has("--watch") {
+        let path = match &args.path {
+            Some(path) if path != STDIN_PATH && args.value("-e").is_none() => path,
+            _ => return usage_error("`--watch` needs the path of a file to watch")
+        };
+
+        if matches!(args.command, Command::Format | Command::Ram | Command::Clock | Command::Link | Command::Eval) {
+            return usage_error("`--watch` can only be used to compile, assemble, disassemble or emulate a file");
+        }
+
+        return watch_files(path, &args);
+    }
+
+    build(&args, None)
+}
+
+// Builds the file each time it, or a file it imports, is saved, until stopped with Ctrl-C.
+#[cfg(not(target_arch = "wasm32"))]
+fn watch_files(path: &str, args: &Args) -> ExitCode {
+    watch::watch(path, |cache| build(args, Some(cache)))
+}
+
+// WebAssembly has no file system events to wait for, so lflc can only build once.
+#[cfg(target_arch = "wasm32")]
+fn watch_files(_path: &str, _args: &Args) -> ExitCode {
+    usage_error("`--watch` is not supported when lflc is built for WebAssembly")
+}
+
+// How errors and warnings are reported, from the command line options.
+struct DiagnosticSettings<'a> {
+    palette: Palette,
+    // Print each diagnostic as a line of JSON, with `--error-format json`.
+    json: bool,
+    max_errors: usize,
+    // The names of the warnings passed to `-D`, which are treated as errors.
+    denied_warnings: &'a [String],
+    deny_all_warnings: bool
+}
+
+// Prints the warnings generated while compiling, along with the errors if compiling failed or a warning is treated as an error.
+// Gives the exit status if there were errors.
+fn report_diagnostics<T>(result: CompileResult<T>, warnings: Vec<Warning>, settings: &DiagnosticSettings) -> Result<T, ExitCode> {
+    let (denied, allowed): (Vec<Warning>, Vec<Warning>) = warnings.into_iter()
+        .partition(|warning| settings.deny_all_warnings
+            || settings.denied_warnings.iter().any(|name| name == "warnings" || name == warning.kind.name()));
+
+    for warning in &allowed {
+        if settings.json {
+            eprintln!("{}", JsonDiagnostic::from_warning(warning));
+        }   else {
+            eprintln!("{}", Styled(warning, settings.palette));
+        }
+    }
+
+    let mut errors = match result {
+        Ok(compiled) if denied.is_empty() => {
+            if !allowed.is_empty() && !settings.json {
+                eprintln!("{}", error_handling::summary(0, allowed.len()));
+            }
+
+            return Ok(compiled);
+        },
+        Ok(_) => Vec::new(),
+        Err(err) => err.0
+    };
+    errors.extend(denied.into_iter().map(Warning::into_error));
+    let errors = error_handling::sort_errors(errors);
+
+    // Tools reading JSON are given every error, without the summary.
+    if settings.json {
+        for error in &errors {
+            eprintln!("{}", JsonDiagnostic::from_error(error));
+        }
+        return Err(ExitCode::FAILURE);
+    }
+
+    eprintln!("{}:", error_handling::summary(errors.len(), allowed.len()));
+    for error in errors.iter().take(settings.max_errors) {
+        eprintln!("{}", Styled(error, settings.palette));
+    }
+
+    let hidden = errors.len().saturating_sub(settings.max_errors);
+    if hidden > 0 {
+        let plural = if hidden == 1 { "" } else { "s" };
+        eprintln!("... and {hidden} more error{plural}");
+    }
+    Err(ExitCode::FAILURE)
+}
+
+// Reads the object files given to `link`, in the order they were given.
+fn read_objects<'a>(paths: impl Iterator<Item = &'a String>) -> Result<Vec<ObjectFile>, ExitCode> {
+    let mut objects = Vec::new();
+    for path in paths {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) => return Err(report_error(format!("Failed to read {path}: {err}"), ExitCode::from(IO_ERROR)))
+        };
+
+        match serde_json::from_str(&text) {
+            Ok(object) => objects.push(object),
+            Err(err) => return Err(report_error(format!("Failed to read object file {path}: {err}"), ExitCode::FAILURE))
+        }
+    }
+
+    Ok(objects)
+}
+
+// Assembles files of functions declared with `.func`, which are linked with a program, e.g. `lflc compile main.lfl fast.asm`.
+// Files that can't be read are reported straight away, but errors in the assembly are given to be reported with those of the program.
+fn assemble_objects<'a>(paths: impl Iterator<Item = &'a String>, options: &CompileOptions) -> Result<CompileResult<Vec<ObjectFile>>, ExitCode> {
+    let mut objects = Vec::new();
+    let mut errors = Vec::new();
+    for path in paths {
+        let source = match SourceFile::load_from_path(path.clone()) {
+            Ok(source) => source,
+            Err(err) => return Err(report_error(format!("Failed to read {path}: {err}"), ExitCode::from(IO_ERROR)))
+        };
+
+        match assembler::assemble_object(Arc::new(source), options) {
+            Ok(object) => objects.push(object),
+            Err(mut err) => errors.append(&mut err.0)
+        }
+    }
+
+    Ok(if errors.is_empty() { Ok(objects) } else { Err(CompileErrors(errors)) })
+}
+
+// Carries out the command given on the command line once, which is repeated each time the file changes with `--watch`.
+// With `--watch`, the code of the functions compiled is kept in `cache`, to be reused by the next build.
+fn build(args: &Args, mut cache: Option<&mut FunctionCache>) -> ExitCode {
+    // The source can be given with `--eval` rather than in a file, in which case it is reported as `<eval>`.
+    let takes_source = !matches!(args.command, Command::Ram | Command::Clock | Command::Link | Command::Eval);
+    let eval_source = args.value("-e").filter(|_| takes_source);
+    let path = match (&args.path, eval_source) {
+        (Some(_), Some(_)) => return usage_error("`--eval` can't be used with a file"),
+        (Some(file_path), None) => file_path.clone(),
+        (None, Some(_)) => EVAL_PATH.to_owned(),
+        (None, None) if args.command == Command::Ram => return usage_error("Expected the number of cells in the RAM"),
+        (None, None) if args.command == Command::Clock => return usage_error("Expected the number of the signal to output the tick count on"),
+        (None, None) if args.command == Command::Link => return usage_error("Expected the paths of the object files to link"),
+        (None, None) if args.command == Command::Eval => return usage_error("Expected the expression to evaluate"),
+        (None, None) => return usage_error("Expected file path")
+    };
+
+    let call_convention = match args.value("--call-convention") {
+        Some(name) => match CallConvention::from_name(name) {
+            Some(convention) => convention,
+            None => return usage_error(&format!("Unknown calling convention {name}, expected `interleaved` or `split-stack`"))
+        },
+        None => CallConvention::default()
+    };
+
+    // By default, colors are only used when writing to a terminal, unless disabled with the `NO_COLOR` variable.
+    let palette = match args.value("--color") {
+        Some("always") => Palette::COLORED,
+        Some("never") => Palette::PLAIN,
+        Some("auto") | None => if io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()) {
+            Palette::COLORED
+        }   else {
+            Palette::PLAIN
+        },
+        Some(name) => return usage_error(&format!("Unknown color setting {name}, expected `always`, `never` or `auto`"))
+    };
+
+    // Diagnostics can be written as JSON, one per line, so that they can be read by editors.
+    let json_diagnostics = match args.value("--error-format") {
+        Some("json") => true,
+        Some("human") | None => false,
+        Some(name) => return usage_error(&format!("Unknown error format {name}, expected `human` or `json`"))
+    };
+
+    // A misspelled warning name would otherwise deny nothing, letting the build pass.
+    if let Some(name) = args.values("-D").iter().find(|name| *name != "warnings" && !WarningKind::ALL.iter().any(|kind| kind.name() == *name)) {
+        let names: Vec<&str> = WarningKind::ALL.iter().map(WarningKind::name).collect();
+        return usage_error(&format!("Unknown warning {name}, expected `warnings` or one of {}", names.join(", ")));
+    }
+
+    let blueprint_format = match args.value("--format") {
+        Some(name) => match BlueprintFormat::from_name(name) {
+            Some(format) => format,
+            None => return usage_error(&format!("Unknown blueprint format {name}, expected `1.1` or `2.0`"))
+        },
+        None => BlueprintFormat::default()
+    };
+
+    // The game version is checked against the format, since a blueprint in one format can't be read by other versions.
+    let game_version = match args.value("--game-version") {
+        Some(text) => match blueprint::parse_factorio_version(text) {
+            Some(version) if blueprint::major_version(version) == blueprint_format.major_version() => version,
+            Some(_) => return usage_error(&format!("Game version {text} doesn't match the blueprint format, pass `--format` to change it")),
+            None => return usage_error("Game version must be written as major.minor.patch, e.g. `1.1.110`")
+        },
+        None => blueprint_format.default_version()
+    };
+
+    let mut save_options = SaveOptions { pretty: args.has("--pretty-json"), ..Default::default() };
+    if let Some(name) = args.value("--compression") {
+        save_options.compression = match CompressionLevel::from_name(name) {
+            Some(compression) => compression,
+            None => return usage_error(&format!("Unknown compression level {name}, expected `fast` or `best`"))
+        };
+    }
+
+    let settings = BlueprintSettings { format: blueprint_format, game_version, save_options };
+
+    // `ram` takes the number of cells rather than a file.
+    if args.command == Command::Ram {
+        return write_ram_blueprint(args, &path, &settings);
+    }
+
+    // Similarly, `clock` takes the number of a signal.
+    if args.command == Command::Clock {
+        return write_clock_blueprint(args, &path, &settings);
+    }
+
+    let mut layout = RomLayout::default();
+    if let Some(height) = args.value("--rom-height") {
+        layout.column_height = match height.parse() {
+            Ok(height) if height > 0 => height,
+            _ => return usage_error("ROM height must be a positive integer")
+        };
+    }
+
+    // Padding keeps the size of the ROM the same as the program changes, so that nothing connected to it has to move.
+    let mut pad_to = None;
+    if let Some(size) = args.value("--pad") {
+        pad_to = match size.parse::<usize>() {
+            Ok(size) if size > 0 => Some(size),
+            _ => return usage_error("Padded size must be a positive integer")
+        };
+    }
+
+    // The computer may only compare the program counter against the first part of the ROM, so the rest of a longer program would never run.
+    let mut max_program_size = None;
+    if let Some(size) = args.value("--max-program-size") {
+        max_program_size = match size.parse::<usize>() {
+            Ok(size) if size > 0 => Some(size),
+            _ => return usage_error("Maximum program size must be a positive integer")
+        };
+    }
+
+    if let (Some(padded_size), Some(max_size)) = (pad_to, max_program_size) {
+        if padded_size > max_size {
+            return usage_error(&format!("Padded size {padded_size} is more than the maximum program size of {max_size}"));
+        }
+    }
+
+    if let Some(stride) = args.value("--rom-column-stride") {
+        layout.column_stride = match stride.parse() {
+            Ok(stride) if COLUMN_STRIDES.contains(&stride) => stride,
+            _ => return usage_error(&format!("ROM column stride must be an integer from {} to {}", COLUMN_STRIDES.start(), COLUMN_STRIDES.end()))
+        };
+    }
+
+    // A RAM can be added to the blueprint book alongside the ROM.
+    let mut book_ram = None;
+    if let Some(cells) = args.value("--ram") {
+        if !args.has("--book") {
+            return usage_error("`--ram` can only be used with `--book`");
+        }
+
+        book_ram = match parse_ram_options(args, cells) {
+            Ok(options) => Some(options),
+            Err(msg) => return usage_error(&msg)
+        };
+    }
+
+    if let Some(name) = args.value("--encoding") {
+        layout.encoding = match InstructionEncoding::from_name(name) {
+            Some(encoding) => encoding,
+            None => return usage_error(&format!("Unknown instruction encoding {name}, expected `two-signal` or `packed`"))
+        };
+    }
+
+    if let Some(size) = args.value("--bank-size") {
+        if args.has("--rom-height") {
+            return usage_error("`--rom-height` can't be used with `--bank-size`, since each bank is its own column");
+        }
+
+        let size = match size.parse() {
+            Ok(size) if size > 0 => size,
+            _ => return usage_error("Bank size must be a positive integer")
+        };
+
+        let select = match args.value("--bank-select") {
+            Some(name) => match BankSelect::from_name(name) {
+                Some(select) => select,
+                None => return usage_error(&format!("Unknown bank select method {name}, expected `condition` or `decider`"))
+            },
+            None => BankSelect::default_for(blueprint_format)
+        };
+
+        if select == BankSelect::Condition && blueprint_format == BlueprintFormat::V1_1 {
+            return usage_error("`--bank-select condition` requires `--format 2.0`, since decider combinators only have one condition in 1.1");
+        }
+
+        layout.banks = Some(RomBanks { size, select });
+    }   else if args.has("--bank-select") {
+        return usage_error("`--bank-select` can only be used with `--bank-size`");
+    }
+
+    let dep_file = match (args.value("--dep-file"), args.value("--dep-format")) {
+        (Some(dep_path), format) => {
+            let json = match format {
+                Some("json") => true,
+                Some("make") | None => false,
+                Some(name) => return usage_error(&format!("Unknown dependency file format {name}, expected `make` or `json`"))
+            };
+
+            if !matches!(args.command, Command::Compile | Command::Assemble | Command::Link) {
+                return usage_error("`--dep-file` can only be used to compile, assemble or link a program");
+            }
+
+            match args.value("-o") {
+                Some(target) => Some(DepFile { path: dep_path, target, json }),
+                None => return usage_error("`--dep-file` needs the output file given with `-o`, which is the target of the rule")
+            }
+        },
+        (None, Some(_)) => return usage_error("`--dep-format` can only be used with `--dep-file`"),
+        (None, None) => None
+    };
+
+    let mut options = CompileOptions {
+        fold_identical_functions: args.has("-O2"),
+        fold_constants: !args.has("--no-fold"),
+        optimize: args.has("--optimize"),
+        call_convention,
+        ..Default::default()
+    };
+
+    if let Some(threshold) = args.value("--inline-threshold") {
+        if !options.optimize {
+            return usage_error("`--inline-threshold` can only be used with `--optimize`");
+        }
+
+        options.inline_threshold = match threshold.parse() {
+            Ok(threshold) => threshold,
+            Err(_) => return usage_error("Inline threshold must be a non-negative integer")
+        };
+    }
+
+    if let Some(count) = args.value("--signals") {
+        options.signal_count = match count.parse() {
+            Ok(count) if count > 0 => count,
+            _ => return usage_error("Signal count must be a positive integer")
+        };
+    }
+
+    // A profile is either one of the built in names, or a file listing the supported instructions.
+    if let Some(profile) = args.value("--profile") {
+        options.instruction_set = match InstructionSet::from_name(profile) {
+            Some(instruction_set) => instruction_set,
+            None => {
+                let text = match std::fs::read_to_string(profile) {
+                    Ok(text) => text,
+                    Err(err) => return report_error(format!("Failed to read instruction set profile {profile}: {err}"), ExitCode::from(IO_ERROR))
+                };
+
+                match InstructionSet::parse(&text) {
+                    Ok(instruction_set) => instruction_set,
+                    Err(err) => return report_error(format!("Failed to read instruction set profile {profile}: {err}"), ExitCode::FAILURE)
+                }
+            }
+        };
+    }
+
+    // The passes to print the program after. With `--stats`, every pass is recorded to count its instructions.
+    let mut dump_after = Vec::new();
+    for name in args.values("--dump-after") {
+        match Pass::from_name(name) {
+            Some(pass) => dump_after.push(pass),
+            None if name == "all" => dump_after.extend(Pass::ALL),
+            None => {
+                let names: Vec<&str> = Pass::ALL.iter().map(|pass| pass.name()).collect();
+                return usage_error(&format!("Unknown pass {name}, expected `all` or one of {}", names.join(", ")));
+            }
+        }
+    }
+    if args.has("--dump-dir") && dump_after.is_empty() {
+        return usage_error("`--dump-dir` can only be used with `--dump-after`");
+    }
+    options.dump_after = if args.has("--stats") { Pass::ALL.to_vec() } else { dump_after.clone() };
+
+    if let Some(entry_point) = args.value("--entry") {
+        options.entry_point = entry_point.to_owned();
+    }
+
+    if let Some(signal) = args.value("--tick-signal") {
+        options.tick_signal = match signal.parse() {
+            Ok(signal) => signal,
+            Err(_) => return usage_error("Tick signal must be a valid integer")
+        };
+    }
+
+    // `link` reads its object files when linking, and has no source code.
+    let source_file = if args.command == Command::Link {
+        if let Some(option) = ["--tokens", "--ast", "--asm", "--emit", "--embed-source"].into_iter().find(|option| args.has(option)) {
+            return usage_error(&format!("`{option}` can't be used with `link`, which reads object files rather than source code"));
+        }
+
+        SourceFile::from_text(path.clone(), String::new())
+    }   else if args.command == Command::Eval {
+        // Errors point into the expression as it was given on the command line.
+        SourceFile::from_text(EXPRESSION_PATH.to_owned(), path.clone())
+    }   else {
+        match load_source(&path, eval_source) {
+            Ok(file) => file,
+            Err(err) => return report_error(format!("Failed to read source: {err}"), ExitCode::from(IO_ERROR))
+        }
+    };
+
+    if args.command == Command::Disassemble {
+        return match lflc::blueprint_string_to_instructions(&source_file.text) {
+            Ok(instructions) => write_output(args, format_assembly(&instructions, &[]), &format!("{} instructions", instructions.len())),
+            Err(err) => report_error(format!("Failed to read blueprint: {err:#}"), ExitCode::FAILURE)
+        };
+    }
+
+    if args.has("--tokens") {
+        return print_tokens(source_file, palette);
+    }
+
+    if args.has("--ast") {
+        return print_ast(source_file, palette);
+    }
+
+    // Source that isn't from a file can't be rewritten, so is formatted to stdout.
+    let from_file = path != STDIN_PATH && path != EVAL_PATH;
+    if args.command == Command::Format {
+        return format_file(from_file.then_some(path.as_str()), source_file, args.has("--check"), palette);
+    }
+
+    // Errors after the first few are often caused by earlier ones, so are left out.
+    let mut max_errors = 20;
+    if let Some(count) = args.value("--max-errors") {
+        max_errors = match count.parse() {
+            Ok(count) if count > 0 => count,
+            _ => return usage_error("Max errors must be a positive integer")
+        };
+    }
+
+    // Warnings named with `-D`, or all warnings with `-D warnings` or `--deny-warnings`, are treated as errors.
+    let diagnostics = DiagnosticSettings {
+        palette,
+        json: json_diagnostics,
+        max_errors,
+        denied_warnings: args.values("-D"),
+        deny_all_warnings: args.has("--deny-warnings")
+    };
+
+    if args.command == Command::Eval {
+        return evaluate_expression(args, source_file, &options, &diagnostics);
+    }
+
+    // With `--embed-source`, the source is kept to be added to the description of the blueprint.
+    let embedded_source = args.has("--embed-source").then(|| source_file.text.clone());
+
+    // Assembly is converted directly into instructions, skipping the compiler.
+    let is_assembly = args.command == Command::Assemble || args.has("--asm") || path.ends_with(".asm");
+
+    let mut warnings = Vec::new();
+    // The files read, for `--dep-file`. Source that isn't from a file is left out.
+    let mut files = Vec::new();
+    let write_dependencies = |mut files: Vec<String>| match &dep_file {
+        Some(dep_file) => {
+            files.retain(|file| file != STDIN_NAME && file != EVAL_PATH);
+            write_dep_file(dep_file, &files)
+        },
+        None => Ok(())
+    };
+
+    // Functions written in assembly can be given after the program, and are linked with it.
+    let assembled = if args.command == Command::Link {
+        Ok(Vec::new())
+    }   else {
+        if let Some(other_path) = args.other_paths.iter().find(|other_path| !other_path.ends_with(".asm")) {
+            return usage_error(&format!("Only files of assembly functions, ending in `.asm`, can be given after the program, found `{other_path}`"));
+        }
+
+        if is_assembly && !args.other_paths.is_empty() {
+            return usage_error("Assembly functions can only be linked with a program written in LFL");
+        }
+
+        match assemble_objects(args.other_paths.iter(), &options) {
+            Ok(assembled) => assembled,
+            Err(status) => return status
+        }
+    };
+
+    // An object file holds the functions of the file before they are placed in the program, so is written instead of the program.
+    if let Some(kind) = args.value("--emit") {
+        if kind != "obj" {
+            return usage_error(&format!("Unknown output kind {kind}, expected `obj`"));
+        }
+
+        if args.command != Command::Compile || is_assembly {
+            return usage_error("`--emit obj` can only be used to compile an LFL file");
+        }
+
+        let result = assembled.and_then(|assembled| lflc::compile_object(Arc::new(source_file), &assembled, &options, &mut warnings, &mut files));
+        files.extend(args.other_paths.iter().cloned());
+        // Written even if compiling fails, so that fixing any of the files causes a rebuild.
+        if let Err(status) = write_dependencies(files) {
+            return status;
+        }
+
+        let object = match report_diagnostics(result, warnings, &diagnostics) {
+            Ok(object) => object,
+            Err(status) => return status
+        };
+
+        if let Err(status) = write_pass_dumps(args, &object.pass_dumps, &dump_after) {
+            return status;
+        }
+
+        let function_count = object.functions.iter().filter(|function| function.code.is_some()).count();
+        let plural = if function_count == 1 { "" } else { "s" };
+        return match serde_json::to_string(&object) {
+            Ok(json) => write_output(args, format!("{json}\n"), &format!("an object file with {function_count} function{plural}")),
+            Err(err) => report_error(format!("Failed to write object file: {err}"), ExitCode::FAILURE)
+        };
+    }
+
+    let result = if args.command == Command::Link {
+        files = std::iter::once(&path).chain(&args.other_paths).cloned().collect();
+        // Files of assembly functions are linked after the object files.
+        let (assembly_paths, object_paths): (Vec<&String>, Vec<&String>) = files.iter().partition(|file| file.ends_with(".asm"));
+        let mut objects = match read_objects(object_paths.into_iter()) {
+            Ok(objects) => objects,
+            Err(status) => return status
+        };
+
+        match assemble_objects(assembly_paths.into_iter(), &options) {
+            Ok(assembled) => assembled.and_then(|assembled| {
+                objects.extend(assembled);
+                compiler::link(objects, &options, &mut warnings)
+            }),
+            Err(status) => return status
+        }
+    }   else if is_assembly {
+        files.push(path.clone());
+        assembler::assemble(Arc::new(source_file)).map(|instructions| CompiledModule {
+            source_map: vec![None; instructions.len()],
+            instructions,
+            max_stack_depth: None,
+            // Assembly isn't divided into functions.
+            function_spans: Vec::new(),
+            merged_functions: Vec::new(),
+            uses_ticks: false,
+            pass_dumps: Vec::new()
+        })
     }   else {
-        println!("ROM Blueprint:");
-        let bp_string = blueprint::SerializedBlueprint {
-            blueprint: blueprint::generate_rom_blueprint(&instructions)
-        }.save();
+        let result = assembled.and_then(|assembled| lflc::compile_with_objects(Arc::new(source_file), assembled, &options, cache.as_deref_mut(),
+            &mut warnings, &mut files));
+        files.extend(args.other_paths.iter().cloned());
+        result
+    };
+
+    if let Err(status) = write_dependencies(files) {
+        return status;
+    }
+
+    let module = match report_diagnostics(result, warnings, &diagnostics) {
+        Ok(module) => module,
+        Err(status) => return status
+    };
+
+    if let Err(status) = write_pass_dumps(args, &module.pass_dumps, &dump_after) {
+        return status;
+    }
+
+    // Printed to stderr so that it isn't mixed up with the output.
+    // Recursive programs have no maximum depth, and get a warning instead.
+    // Left out with JSON diagnostics, so that tools reading them aren't given other text.
+    if let Some(depth) = module.max_stack_depth.filter(|_| !json_diagnostics) {
+        if depth as usize > STACK_CAPACITY {
+            eprintln!("Maximum stack depth: {depth} values, but the stack can only hold {STACK_CAPACITY}");
+        }   else {
+            eprintln!("Maximum stack depth: {depth} of {STACK_CAPACITY} values");
+        }
+    }
 
+    if args.has("--stats") {
+        print_stats(&module, blueprint_format, layout);
+        if let Some(cache) = &cache {
+            eprintln!("Functions reused from the last build: {} of {}", cache.hits, cache.hits + cache.misses);
+        }
+    }
 
-        println!("{}", bp_string);
+    // Checked before the blueprint is generated, so that a ROM which can't run the whole program is never output.
+    if let Some(max_size) = max_program_size.filter(|max_size| module.instructions.len() > *max_size) {
+        return report_error(describe_oversized_program(&module, max_size), ExitCode::FAILURE);
     }
+
+    let mut instructions = module.instructions;
+    let mut source_map = module.source_map;
+
+    if args.command == Command::Emulate {
+        return emulate(args, &instructions, call_convention, options.signal_count, module.uses_ticks.then_some(options.tick_signal));
+    }
+
+    if let Some(size) = pad_to {
+        if instructions.len() > size {
+            return report_error(format!("The program has {} instructions, which is more than the {size} it should be padded to", instructions.len()),
+                ExitCode::FAILURE);
+        }
+
+        instructions.resize(size, Instruction::Nop);
+        source_map.resize(size, None);
+    }
+
+    let output = if args.has("--map") {
+        format_source_map(&instructions, &source_map)
+    }   else if args.has("--assembly") {
+        format_assembly(&instructions, &module.function_spans)
+    }   else {
+        let generated = blueprint::generate_rom_blueprint(&instructions, blueprint_format, layout)
+            .and_then(|rom| check_generated(blueprint::validate(&rom)).map(|_| rom));
+        let mut rom = match generated {
+            Ok(rom) => rom,
+            Err(err) => return report_error(format!("{err:#}"), ExitCode::FAILURE)
+        };
+
+        // Blueprints are named after the program's file unless given a label.
+        let label = match args.value("--label") {
+            Some(label) => label.to_owned(),
+            None if from_file => program_name(&path),
+            None => "Program".to_owned()
+        };
+        rom.label = label.clone();
+        rom.version = settings.game_version;
+        rom.description = Some(describe_program(instructions.len(), embedded_source.as_deref()));
+
+        let blueprint_string = if args.has("--book") {
+            format_book(&label, rom, instructions.len(), &settings, book_ram.as_ref())
+        }   else {
+            blueprint::SerializedBlueprint { blueprint: rom }.save_with(settings.save_options)
+        };
+
+        match blueprint_string {
+            Ok(blueprint_string) => format!("{blueprint_string}\n"),
+            Err(err) => return report_error(format!("{err:#}"), ExitCode::FAILURE)
+        }
+    };
+
+    write_output(args, output, &format!("{} instructions", instructions.len()))
 }
@@ -0,0 +1,256 @@
+//! Loading of the files imported by a program, so that the items of every file are compiled together.
+//!
+//! Each file is only loaded once, however many files import it. The items of a file come after those of the
+//! files it imports, so that a file can use the constants declared in the files it imports.
+
+use std::{collections::HashSet, fs, path::{Path, PathBuf}, sync::Arc};
+
+use crate::{ast::{Item, Import}, error, error_handling::{CompileErrors, CompileResult, SourceFile, Warning}, lexer,
+    parser::{self, TokenIterator}};
+
+// Parses a source file and every file that it imports, directly or through other files.
+// Any warnings generated while parsing are added to `warnings`.
+pub fn load_program(source: Arc<SourceFile>, warnings: &mut Vec<Warning>) -> CompileResult<Vec<Item>> {
+    load_program_files(source, warnings, &mut Vec::new())
+}
+
+// Loads a program in the same way as `load_program`, also adding the path of each source file read to `files`, e.g. for
+// `--dep-file`. Paths are added in the order the files are loaded, starting with `source`, and include files that fail
+// to parse, even if loading fails, so that fixing them causes a rebuild. Imports that can't be read are left out.
+pub fn load_program_files(source: Arc<SourceFile>, warnings: &mut Vec<Warning>, files: &mut Vec<String>) -> CompileResult<Vec<Item>> {
+    let mut loader = Loader {
+        items: Vec::new(),
+        loaded: HashSet::new(),
+        import_stack: Vec::new(),
+        warnings,
+        files
+    };
+
+    let id = file_id(Path::new(&source.path));
+    loader.load(source, id)?;
+    Ok(loader.items)
+}
+
+// Gets the paths of the file at `path` and of every file that it imports, directly or through other files,
+// e.g. to watch them for changes. The imports of files that can't be read or parsed are left out.
+pub fn program_files(path: &str) -> Vec<PathBuf> {
+    let mut loader = Loader {
+        items: Vec::new(),
+        loaded: HashSet::new(),
+        import_stack: Vec::new(),
+        warnings: &mut Vec::new(),
+        files: &mut Vec::new()
+    };
+
+    let id = file_id(Path::new(path));
+    if let Ok(source) = SourceFile::load_from_path(path.to_owned()) {
+        // The files loaded before any error are still used.
+        let _ = loader.load(Arc::new(source), id.clone());
+    }
+
+    loader.loaded.insert(id);
+    loader.loaded.into_iter().collect()
+}
+
+struct Loader<'a> {
+    items: Vec<Item>,
+    // The files that have already been loaded, or are being loaded.
+    loaded: HashSet<PathBuf>,
+    // The file being loaded, preceded by the chain of files that imported it, used to detect cycles.
+    // Each file is stored with its path as written, to be used when reporting a cycle.
+    import_stack: Vec<(PathBuf, String)>,
+    warnings: &'a mut Vec<Warning>,
+    // The path of each file read, in the order they were loaded.
+    files: &'a mut Vec<String>
+}
+
+impl Loader<'_> {
+    fn load(&mut self, source: Arc<SourceFile>, id: PathBuf) -> CompileResult<()> {
+        self.loaded.insert(id.clone());
+        self.files.push(source.path.clone());
+
+        let tokens = lexer::tokenize(source.clone())?;
+        let mut iter = TokenIterator::new(tokens);
+        let parse_result = parser::parse_module(&mut iter);
+        self.warnings.append(&mut iter.warnings);
+
+        let (items, imports) = parse_result?;
+
+        self.import_stack.push((id, source.path.clone()));
+        let mut errors = Vec::new();
+        for import in imports {
+            if let Err(mut err) = self.load_import(&source, import) {
+                errors.append(&mut err.0);
+            }
+        }
+        self.import_stack.pop();
+
+        if errors.is_empty() {
+            self.items.extend(items);
+            Ok(())
+        }   else {
+            Err(CompileErrors(errors))
+        }
+    }
+
+    fn load_import(&mut self, importer: &SourceFile, import: Import) -> CompileResult<()> {
+        let path = match Path::new(&importer.path).parent() {
+            Some(directory) => directory.join(&import.path),
+            None => PathBuf::from(&import.path)
+        };
+        let path_text = path.to_string_lossy().into_owned();
+        let id = file_id(&path);
+
+        if let Some(cycle_start) = self.import_stack.iter().position(|(importer_id, _)| *importer_id == id) {
+            let cycle: Vec<&str> = self.import_stack[cycle_start..].iter()
+                .map(|(_, path_text)| path_text.as_str())
+                .chain(std::iter::once(path_text.as_str()))
+                .collect();
+
+            return error!(import.path_ref, "Import cycle: {}", cycle.join(" -> "));
+        }
+
+        if self.loaded.contains(&id) {
+            return Ok(());
+        }
+
+        match SourceFile::load_from_path(path_text.clone()) {
+            Ok(source) => self.load(Arc::new(source), id),
+            Err(err) => error!(import.path_ref, "Failed to read imported file `{path_text}`: {err}")
+        }
+    }
+}
+
+// Gets a path that is the same however a file is referred to, e.g. `./math.lfl` and `math.lfl`.
+// Files that cannot be found keep the path as written, and will fail to load.
+fn file_id(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes each file to a new directory for the test, and gives the path of the first one.
+    fn write_files(test_name: &str, files: &[(&str, &str)]) -> String {
+        let directory = std::env::temp_dir().join(format!("lflc-modules-{test_name}"));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).expect("Failed to create test directory");
+
+        for (name, text) in files {
+            fs::write(directory.join(name), text).expect("Failed to write test file");
+        }
+        directory.join(files[0].0).to_string_lossy().into_owned()
+    }
+
+    fn load(path: &str) -> CompileResult<Vec<Item>> {
+        let source = SourceFile::load_from_path(path.to_owned()).expect("Failed to read test file");
+        load_program(Arc::new(source), &mut Vec::new())
+    }
+
+    // Gets the names of the items of a program, in the order they are compiled.
+    fn item_names(items: &[Item]) -> Vec<&str> {
+        items.iter()
+            .map(|item| match item {
+                Item::Function(function) => function.name.as_str(),
+                Item::Global(global) => global.name.as_str(),
+                Item::Constant(constant) => constant.name.as_str()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn imported_items_come_first_and_are_loaded_once() {
+        let path = write_files("diamond", &[
+            ("main.lfl", "import \"a.lfl\";\nimport \"./b.lfl\";\nvoid main() { }"),
+            ("a.lfl", "import \"common.lfl\";\nint a() { return 1; }"),
+            ("b.lfl", "import \"common.lfl\";\nint b() { return 2; }"),
+            ("common.lfl", "const ONE = 1;")
+        ]);
+
+        let items = load(&path).unwrap();
+        assert_eq!(item_names(&items), ["ONE", "a", "b", "main"]);
+    }
+
+    #[test]
+    fn import_cycle_is_an_error() {
+        let path = write_files("cycle", &[
+            ("main.lfl", "import \"a.lfl\";\nvoid main() { }"),
+            ("a.lfl", "import \"b.lfl\";"),
+            ("b.lfl", "\nimport \"a.lfl\";")
+        ]);
+        let directory = Path::new(&path).parent().unwrap();
+        let [a, b] = ["a.lfl", "b.lfl"].map(|name| directory.join(name).to_string_lossy().into_owned());
+
+        let err = load(&path).expect_err("Import cycle loaded");
+        assert_eq!(err.0.len(), 1);
+        assert_eq!(err.0[0].msg, format!("Import cycle: {a} -> {b} -> {a}"));
+
+        let position = err.0[0].position.as_ref().unwrap();
+        assert_eq!(position.file.path, b);
+        assert_eq!((position.line_index, position.begin_char_index), (1, 7));
+    }
+
+    #[test]
+    fn missing_import_is_reported_at_import() {
+        let path = write_files("missing", &[("main.lfl", "void main() { }\nimport \"missing.lfl\";")]);
+
+        let err = load(&path).expect_err("Missing import loaded");
+        assert_eq!(err.0.len(), 1);
+        assert!(err.0[0].msg.starts_with("Failed to read imported file"), "{}", err.0[0].msg);
+        assert!(err.0[0].msg.contains("missing.lfl"));
+
+        let position = err.0[0].position.as_ref().unwrap();
+        assert_eq!(position.file.path, path);
+        assert_eq!((position.line_index, position.begin_char_index), (1, 7));
+    }
+
+    #[test]
+    fn error_in_imported_file_is_reported_against_that_file() {
+        let path = write_files("imported-error", &[
+            ("main.lfl", "import \"lib.lfl\";\nvoid main() { }"),
+            ("lib.lfl", "int f() {\n    return 1 +;\n}")
+        ]);
+
+        let err = load(&path).expect_err("Imported file with a syntax error loaded");
+        let position = err.0[0].position.as_ref().unwrap();
+        assert!(position.file.path.ends_with("lib.lfl"), "{}", position.file.path);
+        assert_eq!(position.line_index, 1);
+    }
+
+    #[test]
+    fn program_files_include_imports_that_fail_to_parse() {
+        let path = write_files("program-files", &[
+            ("main.lfl", "import \"lib.lfl\";\nvoid main() { }"),
+            ("lib.lfl", "int f( {")
+        ]);
+
+        let mut names: Vec<String> = program_files(&path).iter()
+            .map(|file| file.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["lib.lfl", "main.lfl"]);
+    }
+
+    #[test]
+    fn loaded_files_are_listed_in_load_order() {
+        let path = write_files("loaded-files", &[
+            ("main.lfl", "import \"a.lfl\";\nimport \"c.lfl\";\nvoid main() { }"),
+            ("a.lfl", "import \"b.lfl\";\nimport \"c.lfl\";"),
+            ("b.lfl", "int b() { return 1; }"),
+            ("c.lfl", "int c( {")
+        ]);
+        let directory = Path::new(&path).parent().unwrap();
+
+        // `c.lfl` fails to parse, but is still listed once.
+        let mut files = Vec::new();
+        let source = SourceFile::load_from_path(path.clone()).unwrap();
+        assert!(load_program_files(Arc::new(source), &mut Vec::new(), &mut files).is_err());
+
+        let expected: Vec<String> = ["a.lfl", "b.lfl", "c.lfl"].iter()
+            .map(|name| directory.join(name).to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(files[0], path);
+        assert_eq!(files[1..], expected);
+    }
+}
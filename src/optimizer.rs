@@ -0,0 +1,241 @@
+//! Peephole optimizations over a linked program, which remove instructions that have no effect or
+//! replace short sequences of instructions with shorter ones that do the same thing.
+//!
+//! Removing instructions moves every instruction after them, so each pass finishes by rewriting the
+//! addresses of all jumps and calls to point at the new location of their target.
+
+use std::collections::HashSet;
+
+use crate::assembly::Instruction;
+
+// Optimizes a program whose jump and JSR addresses are absolute, i.e. after linking.
+// Also returns the index in the optimized program of each instruction index in the original, plus the index just past
+// the end. Removed instructions are given the index of the next instruction that remains.
+pub fn optimize(mut program: Vec<Instruction>) -> (Vec<Instruction>, Vec<usize>) {
+    let mut new_indices: Vec<usize> = (0..=program.len()).collect();
+
+    // Each optimization can make others possible, e.g. removing a `CNST` and `POP` may leave a jump to the next instruction.
+    loop {
+        let (optimized, pass_indices) = optimize_pass(&program);
+        // Every optimization removes at least one instruction.
+        if optimized.len() == program.len() {
+            break (program, new_indices);
+        }
+
+        for new_idx in &mut new_indices {
+            *new_idx = pass_indices[*new_idx];
+        }
+        program = optimized;
+    }
+}
+
+// Applies each optimization that matches, without looking at the instructions it produces.
+// Returns the optimized program, and the new index of each instruction.
+fn optimize_pass(program: &[Instruction]) -> (Vec<Instruction>, Vec<usize>) {
+    let targets = find_jump_targets(program);
+
+    // The instruction at each address after optimizing, or None if it has been removed.
+    let mut slots: Vec<Option<Instruction>> = program.iter().copied().map(Some).collect();
+
+    let mut idx = 0;
+    while idx < program.len() {
+        match match_pattern(program, idx, &targets) {
+            Some((length, replacement)) => {
+                for (offset, slot) in slots[idx..idx + length].iter_mut().enumerate() {
+                    *slot = replacement.get(offset).copied();
+                }
+
+                idx += length;
+            },
+            None => idx += 1
+        }
+    }
+
+    relink(&slots)
+}
+
+// Finds the indices of the instructions that can be reached other than by continuing from the previous instruction.
+// This includes the instruction after each JSR, which is returned to by RET.
+fn find_jump_targets(program: &[Instruction]) -> HashSet<usize> {
+    let mut targets = HashSet::new();
+    for (idx, instruction) in program.iter().enumerate() {
+        if let Some(addr) = jump_address(*instruction) {
+            if addr >= 1 {
+                targets.insert(addr as usize - 1);
+            }
+        }
+
+        if let Instruction::JumpSubRoutine(_) = instruction {
+            targets.insert(idx + 1);
+        }
+    }
+
+    targets
+}
+
+// Checks if an optimization can be applied to the instructions beginning at `idx`.
+// Returns the number of instructions replaced, and the instructions to replace them with.
+//
+// Jumps to the first instruction replaced will go to the first instruction of the replacement (or the instruction
+// after if there is no replacement), so patterns can only match if no other instruction within them is a jump target.
+fn match_pattern(program: &[Instruction], idx: usize, targets: &HashSet<usize>) -> Option<(usize, Vec<Instruction>)> {
+    use Instruction::*;
+
+    // Only instructions that cannot be jumped to can be removed after an unconditional jump.
+    if idx > 0 && matches!(program[idx - 1], Jump(_) | Return | Halt) && !targets.contains(&idx) {
+        return Some((1, Vec::new()));
+    }
+
+    let window = &program[idx..];
+    let is_jumped_into = |length: usize| (idx + 1..idx + length).any(|inner_idx| targets.contains(&inner_idx));
+    let next_address = idx as i32 + 2;
+
+    let (length, replacement) = match *window {
+        // Jumping to the next instruction does nothing.
+        [Jump(addr), ..] if addr == next_address => (1, vec![]),
+
+        // A value is pushed, only to be removed straight away.
+        [Constant(_) | Load(_), Pop, ..] => (2, vec![]),
+
+        // A value is loaded and then saved back to the same place. Stack addresses are one higher after the load.
+        [Load(from), Save(to), ..] if (from >= 1 && to == from + 1) || (from < 0 && to == from) => (2, vec![]),
+
+        // The top value is copied, saved elsewhere, then removed, which is the same as saving the value directly.
+        [Load(1), Save(addr), Pop, ..] => (3, vec![Save(below_top(addr)?)]),
+
+        // Skipping over a jump when a condition is false is the same as jumping when the condition is true.
+        [JumpIfZero(skip_addr), Jump(addr), ..] if skip_addr == next_address + 1 => (2, vec![JumpIfNonZero(addr)]),
+        [JumpIfNonZero(skip_addr), Jump(addr), ..] if skip_addr == next_address + 1 => (2, vec![JumpIfZero(addr)]),
+
+        // A negated variable is added to or subtracted from another value, so the opposite operator can be used instead.
+        [Constant(-1), Load(negated), Multiply, other @ (Load(_) | Constant(_)), operator @ (Add | Subtract), ..] => {
+            let opposite = if operator == Add { Subtract } else { Add };
+            (5, vec![Load(below_top(negated)?), other, opposite])
+        },
+        [Load(negated), Negate, other @ (Load(_) | Constant(_)), operator @ (Add | Subtract), ..] => {
+            let opposite = if operator == Add { Subtract } else { Add };
+            (4, vec![Load(negated), other, opposite])
+        },
+
+        _ => return None
+    };
+
+    if is_jumped_into(length) {
+        None
+    }   else {
+        Some((length, replacement))
+    }
+}
+
+// Gets the address that refers to the same place once the top value of the stack is removed.
+// Returns None if the address is the top value itself.
+fn below_top(addr: i32) -> Option<i32> {
+    match addr {
+        1 => None,
+        // Signals and globals have fixed addresses.
+        _ if addr < 0 => Some(addr),
+        _ => Some(addr - 1)
+    }
+}
+
+// Gets the address in the program ROM that an instruction jumps to, if any.
+fn jump_address(instruction: Instruction) -> Option<i32> {
+    match instruction {
+        Instruction::Jump(addr)
+        | Instruction::JumpIfZero(addr)
+        | Instruction::JumpIfNonZero(addr)
+        | Instruction::JumpSubRoutine(addr) => Some(addr),
+        _ => None
+    }
+}
+
+// Removes the instructions that have been optimized away, and moves each jump to the new address of its target.
+// A jump to a removed instruction goes to the next instruction that remains.
+// Returns the new program, and the new index of each instruction.
+fn relink(slots: &[Option<Instruction>]) -> (Vec<Instruction>, Vec<usize>) {
+    // Includes the index just past the end of the program.
+    let mut new_indices = Vec::with_capacity(slots.len() + 1);
+    let mut next_idx = 0;
+    for slot in slots {
+        new_indices.push(next_idx);
+        if slot.is_some() {
+            next_idx += 1;
+        }
+    }
+    new_indices.push(next_idx);
+
+    // Addresses outside of the program halt it, so are left as they are.
+    let relocate = |addr: i32| if (1..=new_indices.len() as i32).contains(&addr) {
+        new_indices[addr as usize - 1] as i32 + 1
+    }   else {
+        addr
+    };
+
+    let program = slots.iter()
+        .flatten()
+        .map(|instruction| match *instruction {
+            Instruction::Jump(addr) => Instruction::Jump(relocate(addr)),
+            Instruction::JumpIfZero(addr) => Instruction::JumpIfZero(relocate(addr)),
+            Instruction::JumpIfNonZero(addr) => Instruction::JumpIfNonZero(relocate(addr)),
+            Instruction::JumpSubRoutine(addr) => Instruction::JumpSubRoutine(relocate(addr)),
+            other => other
+        })
+        .collect();
+
+    (program, new_indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::{CallConvention, CompileOptions}, testing};
+    use Instruction::*;
+
+    #[test]
+    fn jumps_are_moved_to_new_address_of_target() {
+        let program = vec![Constant(1), Pop, JumpIfZero(6), Load(-1), Save(-1), Constant(2), Jump(3)];
+        let (optimized, new_indices) = optimize(program);
+
+        assert_eq!(optimized, [JumpIfZero(2), Constant(2), Jump(1)]);
+        assert_eq!(new_indices, [0, 0, 0, 1, 1, 1, 2, 3]);
+    }
+
+    #[test]
+    fn pattern_containing_jump_target_is_kept() {
+        // The `POP` is jumped to, so removing the `CNST` before it would change what the jump does.
+        let program = vec![JumpIfZero(3), Constant(1), Pop, Halt];
+        assert_eq!(optimize(program.clone()).0, program);
+    }
+
+    #[test]
+    fn code_after_unconditional_jump_is_removed() {
+        let program = vec![Jump(4), Constant(1), Save(-1), Halt];
+        assert_eq!(optimize(program).0, [Halt]);
+    }
+
+    #[test]
+    fn optimized_programs_give_same_results() {
+        let sources = [
+            "void main(a, b) { signal_1 = -a + b; signal_2 = b - -a; signal_3 = a; a = a; signal_4 = a * -1 - b; }",
+            "void main(n) { total = 0; i = 0; while i < n { if i % 3 == 0 { total += i; } else { total -= 1; } i++; } signal_1 = total; }",
+            "void main(n) { i = 0; do { i += 2; if i > 7 { break; } } while i < n; signal_1 = i; }",
+            "int fact(n) { if n <= 1 { return 1; } return n * fact(n - 1); } void main(n) { signal_1 = fact(n); }",
+            "int sum(n, acc) { if n == 0 { return acc; } return sum(n - 1, acc + n); } void main(n) { signal_1 = sum(n, 0); }",
+            "int g(x) { return x * 10; } void main(a, b) { c = a + 1; signal_1 = (a + g(b)) * (g(a) - b); signal_2 = c && !b || a; }"
+        ];
+
+        for call_convention in [CallConvention::Interleaved, CallConvention::SplitStack] {
+            let options = CompileOptions { call_convention, ..CompileOptions::default() };
+            let optimized_options = CompileOptions { optimize: true, call_convention, ..CompileOptions::default() };
+            for source in sources {
+                let unoptimized = testing::compile(source, &options);
+                let optimized = testing::compile(source, &optimized_options);
+
+                for inputs in [[0, 0], [1, 2], [5, -3], [4, 4]] {
+                    assert_eq!(testing::run(&optimized, &inputs, &optimized_options), testing::run(&unoptimized, &inputs, &options),
+                        "{source} with inputs {inputs:?} and {call_convention:?}");
+                }
+            }
+        }
+    }
+}
@@ -1,52 +1,90 @@
 //! Parses the tokens generated by the lexer to create an abstract syntax tree.
 
+use std::hash::{DefaultHasher, Hash, Hasher};
+
 use crate::ast::Call;
+use crate::ast::Constant;
 use crate::ast::Function;
+use crate::ast::Global;
+use crate::ast::Item;
 use crate::ast::IfSegment;
+use crate::ast::Import;
 use crate::ast::Statement;
 use crate::ast::UnaryOperator;
 use crate::error_handling::CompileErrors;
 use crate::error_handling::CompileResult;
 use crate::error_handling::FileRef;
-use crate::{lexer::Token, ast::{BinaryOperator, Expression}};
+use crate::error_handling::Warning;
+use crate::error_handling::WarningKind;
+use crate::{lexer::{self, Token}, ast::{BinaryOperator, Expression}};
 use crate::error;
+use crate::warning;
+
+// The order in which operators of equal precedence are evaluated.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Associativity {
+    // `a - b - c` is `(a - b) - c`
+    Left,
+    // `a ^ b ^ c` is `a ^ (b ^ c)`
+    Right
+}
 
 // The order in which to execute operations.
-// Each array consists of operators of equal precedence, which will be evaluated from left to right.
-const PRECEDENCE: &[&[BinaryOperator]] = &[
-    &[
-        BinaryOperator::Power,
+// Each array consists of operators of equal precedence, along with the order in which they will be evaluated.
+const PRECEDENCE: &[(Associativity, &[BinaryOperator])] = &[
+    (Associativity::Right, &[
+        BinaryOperator::Power
+    ]),
+    (Associativity::Left, &[
         BinaryOperator::ShiftLeft,
         BinaryOperator::ShiftRight
-    ],
-    &[
+    ]),
+    (Associativity::Left, &[
         BinaryOperator::Multiply,
         BinaryOperator::Divide,
         BinaryOperator::Remainder
-    ],
-    &[
+    ]),
+    (Associativity::Left, &[
         BinaryOperator::Add,
         BinaryOperator::Subtract
-    ],
-    &[
+    ]),
+    (Associativity::Left, &[
         BinaryOperator::NotEquals,
         BinaryOperator::Equals,
         BinaryOperator::GreaterThan,
         BinaryOperator::GreaterThanOrEqual,
         BinaryOperator::LessThan,
         BinaryOperator::LessThanOrEqual,
-    ],
-    &[
+    ]),
+    (Associativity::Left, &[
         BinaryOperator::And,
         BinaryOperator::Or,
         BinaryOperator::Xor,
-    ]
+    ]),
+    (Associativity::Left, &[
+        BinaryOperator::LogicalAnd
+    ]),
+    (Associativity::Left, &[
+        BinaryOperator::LogicalOr
+    ])
 ];
 
+// Gets the position of an operator in `PRECEDENCE`, where operators with a lower level are applied first,
+// along with the order in which operators at that level are applied.
+pub fn precedence(operator: BinaryOperator) -> (usize, Associativity) {
+    PRECEDENCE.iter()
+        .enumerate()
+        .find(|(_, (_, operators))| operators.contains(&operator))
+        .map(|(level, (associativity, _))| (level, *associativity))
+        .expect("Every operator must have a precedence")
+}
+
 // Iterates through the tokens in a file.
 pub struct TokenIterator {
     tokens: Vec<(Token, FileRef)>,
-    position: usize
+    position: usize,
+    // Warnings generated while parsing.
+    pub warnings: Vec<Warning>
 }
 
 impl TokenIterator {
@@ -54,7 +92,8 @@ impl TokenIterator {
     pub fn new(tokens: Vec<(Token, FileRef)>) -> Self {
         Self {
             tokens,
-            position: 0
+            position: 0,
+            warnings: Vec::new()
         }
     }
 
@@ -74,6 +113,11 @@ impl TokenIterator {
         self.position -= 1;
     }
 
+    // Moves back to a token that was already consumed, so that it is the next token read.
+    fn move_back_to(&mut self, index: usize) {
+        self.position = index;
+    }
+
     // Returns the location of the token just consumed from the file.
     fn prev_token_ref(&self) -> FileRef {
         self.get_token_or_eof(self.position - 1).1.clone()
@@ -91,22 +135,60 @@ impl TokenIterator {
     // Creates a FileRef ranging between two tokens in the iterator.
     // Useful to get the reference that highlights a whole expression, etc..
     fn get_ref_range(&mut self, from: usize, to: usize) -> FileRef {
-        let start_token = self.tokens[from].1.clone();
-        let end_token = self.tokens[to].1.clone();
-
-        // TODO: The start/end tokens being on separate lines is currently improperly handled due to a limitation in the FileRef struct.
-        let end_char_index = if start_token.line_index != end_token.line_index {
-            start_token.begin_char_index + 1
-        }   else    {
-            end_token.begin_char_index + end_token.length
-        };
+        let start_token = &self.tokens[from].1;
+        let end_token = &self.tokens[to].1;
 
         FileRef {
             file: start_token.file.clone(),
             line_index: start_token.line_index,
             begin_char_index: start_token.begin_char_index,
-            length: end_char_index - start_token.begin_char_index
+            end_line_index: end_token.end_line_index,
+            end_char_index: end_token.end_char_index
+        }
+    }
+
+    // Hashes the tokens between two tokens in the iterator, along with their positions relative to the line of the first,
+    // so that code moved to other lines without being changed gives the same hash.
+    fn hash_range(&self, from: usize, to: usize) -> u64 {
+        let first_line = self.tokens[from].1.line_index;
+        let mut hasher = DefaultHasher::new();
+        for (token, position) in &self.tokens[from..=to] {
+            token.hash(&mut hasher);
+            (position.line_index - first_line, position.begin_char_index, position.end_line_index - first_line, position.end_char_index).hash(&mut hasher);
+        }
+
+        hasher.finish()
+    }
+
+    // Gets the source code between two tokens in the iterator.
+    fn get_text_range(&mut self, from: usize, to: usize) -> String {
+        let range = self.get_ref_range(from, to);
+
+        let lines: Vec<&str> = range.file.text
+            .lines()
+            .skip(range.line_index as usize)
+            .take((range.end_line_index - range.line_index) as usize + 1)
+            .collect();
+
+        // Lines after the first are joined with a space, with their indentation removed.
+        let mut text = String::new();
+        for (idx, line) in lines.iter().enumerate() {
+            let is_first = idx == 0;
+            let is_last = idx == lines.len() - 1;
+
+            let begin = if is_first { range.begin_char_index as usize } else { 0 };
+            let end = if is_last { range.end_char_index as usize } else { line.chars().count() };
+            let part: String = line.chars().take(end).skip(begin).collect();
+
+            if is_first {
+                text.push_str(&part);
+            }   else {
+                text.push(' ');
+                text.push_str(part.trim_start());
+            }
         }
+
+        text
     }
 }
 
@@ -116,6 +198,15 @@ macro_rules! prev_token_error {
     };
 }
 
+// Creates an error for the token just consumed, which is expected to be a name.
+// If the token is a keyword, the error explains that keywords cannot be used as names.
+fn expected_name_error<T>(iter: &TokenIterator, token: &Token, expected: &str) -> CompileResult<T> {
+    match lexer::keyword_name(token) {
+        Some(keyword) => prev_token_error!(iter, "`{keyword}` is a reserved keyword and cannot be used as a name"),
+        None => prev_token_error!(iter, "Expected {expected}")
+    }
+}
+
 
 // Parses a binary operator, which may be made up of multiple tokens, e.g. !=, or ==
 // If returning None then the iterator will have been moved back to where it was before calling.
@@ -125,10 +216,9 @@ fn parse_binary_operator(iter: &mut TokenIterator) -> Option<BinaryOperator> {
         Token::Minus => Some(BinaryOperator::Subtract),
         Token::Star => Some(BinaryOperator::Multiply),
         Token::ForwardSlash => Some(BinaryOperator::Divide),
-        Token::Ampersand => Some(BinaryOperator::And),
         Token::Percent => Some(BinaryOperator::Remainder),
-        Token::Bar => Some(BinaryOperator::Or),
-        // TODO: consider the xor operator. Could do it like python where ** is power and ^ is XOR.
+        // `^` was already taken by the power operator, so XOR uses the same symbol as bitwise NOT.
+        Token::Tilda => Some(BinaryOperator::Xor),
         Token::Carat => Some(BinaryOperator::Power),
 
         Token::Equals => {
@@ -140,12 +230,14 @@ fn parse_binary_operator(iter: &mut TokenIterator) -> Option<BinaryOperator> {
         Token::LeftArrow => {
             match iter.consume() {
                 Token::Equals => Some(BinaryOperator::LessThanOrEqual),
+                Token::LeftArrow => Some(BinaryOperator::ShiftLeft),
                 _ => { iter.move_back(); Some(BinaryOperator::LessThan) }
             }
         },
         Token::RightArrow => {
             match iter.consume() {
                 Token::Equals => Some(BinaryOperator::GreaterThanOrEqual),
+                Token::RightArrow => Some(BinaryOperator::ShiftRight),
                 _ => { iter.move_back(); Some(BinaryOperator::GreaterThan) }
             }
         },
@@ -155,6 +247,18 @@ fn parse_binary_operator(iter: &mut TokenIterator) -> Option<BinaryOperator> {
                 _ => { iter.move_back(); iter.move_back(); None }
             }
         },
+        Token::Ampersand => {
+            match iter.consume() {
+                Token::Ampersand => Some(BinaryOperator::LogicalAnd),
+                _ => { iter.move_back(); Some(BinaryOperator::And) }
+            }
+        },
+        Token::Bar => {
+            match iter.consume() {
+                Token::Bar => Some(BinaryOperator::LogicalOr),
+                _ => { iter.move_back(); Some(BinaryOperator::Or) }
+            }
+        },
         _ => { iter.move_back(); None }
     }
 }
@@ -173,7 +277,7 @@ pub fn parse_block(iter: &mut TokenIterator) -> CompileResult<Vec<Statement>> {
         let token = iter.consume();
         let is_block_statement = match token {
             Token::CloseBrace => break,
-            Token::If | Token::While => true,
+            Token::If | Token::While | Token::OpenBrace => true,
             Token::EndOfFile => break,
             _ => false
         };
@@ -208,15 +312,25 @@ pub fn parse_block(iter: &mut TokenIterator) -> CompileResult<Vec<Statement>> {
     }
 }
 
+// Parses the body of an `if`, `else`, `while` or `do`, which is either a block or a single statement.
+// A single statement is treated as a block containing only that statement.
+fn parse_body(iter: &mut TokenIterator) -> CompileResult<Vec<Statement>> {
+    let token = iter.consume();
+    iter.move_back();
+
+    if token == Token::OpenBrace {
+        parse_block(iter)
+    }   else {
+        Ok(vec![parse_statement(iter)?])
+    }
+}
+
 // Parses an `if` statement, assuming that the initial `if` has already been consumed. 
+// An `else` belongs to the nearest `if` without one, as the innermost `if` statement is parsed first.
 fn parse_if_statement(iter: &mut TokenIterator) -> CompileResult<Statement> {
     // Parse the first segment
 
-    let mut segments = Vec::new();
-    segments.push(IfSegment {
-        condition: parse_expression(iter)?,
-        block: parse_block(iter)?,
-    });
+    let mut segments = vec![parse_if_segment(iter)?];
 
     loop {
         // No `else` or `else if` after the previous block.
@@ -227,73 +341,194 @@ fn parse_if_statement(iter: &mut TokenIterator) -> CompileResult<Statement> {
 
         // `else if` block 
         if iter.consume() == Token::If {
-            segments.push(IfSegment {
-                condition: parse_expression(iter)?,
-                block: parse_block(iter)?,
-            });
+            segments.push(parse_if_segment(iter)?);
         }   else {
             // `else` block
             iter.move_back();
-            return Ok(Statement::If { segments, r#else: Some(parse_block(iter)?) });
+            return Ok(Statement::If { segments, r#else: Some(parse_body(iter)?) });
             // cannot have anything after the `else` block
         }
     }
 }
 
+// Parses the condition and body of an `if` or `else if`, assuming the `if` has already been consumed.
+fn parse_if_segment(iter: &mut TokenIterator) -> CompileResult<IfSegment> {
+    let idx_before_condition = iter.next_token_index();
+    let condition = parse_expression(iter)?;
+
+    Ok(IfSegment {
+        condition,
+        condition_ref: iter.get_ref_range(idx_before_condition, iter.prev_token_index()),
+        block: parse_body(iter)?
+    })
+}
+
 // Parses a `+=`, `-=`, etc. type statement, assuming the operator has already been read. 
 fn parse_modify_in_place(iter: &mut TokenIterator, ident: String, ident_ref: FileRef, operator: BinaryOperator) -> CompileResult<Statement> {
+    // Each character of the operator is a separate token, all of which have been consumed.
+    let operator_idx = iter.prev_token_index() + 1 - operator.symbol().len();
     if iter.consume() != Token::Equals {
-        prev_token_error!(iter, "Expected `=`")
+        // Not an assignment, e.g. `x + f();`, so parse the whole statement again from the variable name.
+        iter.move_back_to(operator_idx - 1);
+        parse_expression_statement(iter)
     }   else {
+        let operator_ref = iter.get_ref_range(operator_idx, iter.prev_token_index());
+        let left = Expression::Variable {
+            name: ident.clone(),
+            pos: iter.prev_token_ref()
+        };
+        let right = parse_expression(iter)?;
+
         Ok(Statement::Assignment {
-            variable_name: ident.clone(),
+            variable_name: ident,
             variable_name_ref: ident_ref,
             value: Expression::Binary {
-                left: Box::new(Expression::Variable {
-                    name: ident,
-                    pos: iter.prev_token_ref()
-                }),
-                right: Box::new(parse_expression(iter)?),
-                operator
+                left: Box::new(left),
+                right: Box::new(right),
+                operator,
+                operator_ref,
+                expression_ref: iter.get_ref_range(operator_idx - 1, iter.prev_token_index())
             }
         })
     }
 }
 
-// Parses all of the functions within the root of a module.
-pub fn parse_module(iter: &mut TokenIterator) -> CompileResult<Vec<Function>> {
+// Creates the statement for `i++` or `i--`, assuming both symbols have been consumed. This is the same as `i += 1;` or `i -= 1;`.
+fn increment_statement(iter: &mut TokenIterator, ident: String, ident_ref: FileRef, operator: BinaryOperator) -> Statement {
+    Statement::Assignment {
+        variable_name: ident.clone(),
+        variable_name_ref: ident_ref.clone(),
+        value: Expression::Binary {
+            left: Box::new(Expression::Variable {
+                name: ident,
+                pos: ident_ref
+            }),
+            operator_ref: iter.get_ref_range(iter.prev_token_index() - 1, iter.prev_token_index()),
+            expression_ref: iter.get_ref_range(iter.prev_token_index() - 2, iter.prev_token_index()),
+            right: Box::new(Expression::Literal(1)),
+            operator
+        }
+    }
+}
+
+// Creates an error for `++` or `--` anywhere other than after the variable of an increment statement,
+// assuming both symbols have been consumed.
+fn misplaced_increment_error<T>(iter: &mut TokenIterator) -> CompileResult<T> {
+    error!(iter.get_ref_range(iter.prev_token_index() - 1, iter.prev_token_index()),
+        "Increments and decrements can only be used as a statement, after the variable name, e.g. `i++;`")
+}
+
+// Checks if the next tokens are a `++` or `--` after a variable within an expression, e.g. `x = i++;`, and consumes them if so.
+// Spaces aren't kept by the lexer, so `i--x` can't be told apart from `i - -x`. Neither `++` nor `--` counts if an operand follows it,
+// so `i--x` subtracts `-x` from `i`, and `i++x` is an error as there is no unary `+`.
+fn consume_misplaced_increment(iter: &mut TokenIterator) -> bool {
+    let first = iter.consume();
+    let second = iter.consume();
+    let next = iter.consume();
+    iter.move_back();
+
+    let is_increment = matches!((&first, &second), (Token::Plus, Token::Plus) | (Token::Minus, Token::Minus))
+        && !starts_operand(&next);
+
+    if !is_increment {
+        iter.move_back();
+        iter.move_back();
+    }
+    is_increment
+}
+
+// Checks if a token can begin the operand of an operator, i.e. a unary expression.
+fn starts_operand(token: &Token) -> bool {
+    matches!(token, Token::Minus | Token::Tilda | Token::Bang | Token::Identifier(_) | Token::Number(_)
+        | Token::True | Token::False | Token::OpenParen)
+}
+
+// Parses an expression whose value is discarded, not including the `;` after it.
+// A call on its own, e.g. `(f(x))`, becomes a call statement.
+fn parse_expression_statement(iter: &mut TokenIterator) -> CompileResult<Statement> {
+    let idx_before_expr = iter.next_token_index();
+    let value = parse_expression(iter)?;
+    let value_ref = iter.get_ref_range(idx_before_expr, iter.prev_token_index());
+
+    Ok(match value {
+        Expression::Call(call) => Statement::Call(call),
+        value => Statement::Expression { value, value_ref }
+    })
+}
+
+// Parses all of the functions and globals within the root of a module, along with the files it imports.
+pub fn parse_module(iter: &mut TokenIterator) -> CompileResult<(Vec<Item>, Vec<Import>)> {
     let mut module = Vec::new();
+    let mut imports = Vec::new();
     let mut errors = Vec::new();
 
     // Continue until EOF
-    while iter.consume() != Token::EndOfFile {
-        iter.move_back();
-        match parse_function(iter) {
-            Ok(function) => module.push(function),
-            Err(mut errs) => {
-                errors.append(&mut errs.0);
-
-                // Continue until we find the start of another function, i.e. an int or void keyword
-                loop {
-                    match iter.consume() {
-                        Token::Int | Token::Void | Token::EndOfFile => break,
-                        _ => {}
+    loop {
+        let item = match iter.consume() {
+            Token::EndOfFile => break,
+            Token::Import => {
+                match parse_import(iter) {
+                    Ok(import) => imports.push(import),
+                    Err(mut errs) => {
+                        errors.append(&mut errs.0);
+                        skip_to_next_item(iter);
                     }
                 }
 
+                continue;
+            },
+            Token::Global => parse_global(iter).map(Item::Global),
+            Token::Const => parse_constant(iter).map(Item::Constant),
+            _ => {
                 iter.move_back();
+                parse_function(iter).map(Item::Function)
+            }
+        };
+
+        match item {
+            Ok(item) => module.push(item),
+            Err(mut errs) => {
+                errors.append(&mut errs.0);
+                skip_to_next_item(iter);
             }
         }
     }
 
     if errors.is_empty() {
-        Ok(module)
+        Ok((module, imports))
     }   else {
         Err(CompileErrors(errors))
     }
 }
 
+// Continues until we find the start of another item, i.e. an int, void, global, const or import keyword, so that
+// parsing can carry on after an error.
+fn skip_to_next_item(iter: &mut TokenIterator) {
+    loop {
+        match iter.consume() {
+            Token::Int | Token::Void | Token::Global | Token::Const | Token::Import | Token::EndOfFile => break,
+            _ => {}
+        }
+    }
+
+    iter.move_back();
+}
+
+// Parses an import, assuming the `import` keyword has already been consumed.
+fn parse_import(iter: &mut TokenIterator) -> CompileResult<Import> {
+    let path = match iter.consume() {
+        Token::String(path) => path,
+        _ => return prev_token_error!(iter, "Expected the path of the file to import in quotes, e.g. `import \"math.lfl\";`")
+    };
+
+    expect_semicolon_and_then(iter, Import {
+        path,
+        path_ref: iter.prev_token_ref()
+    })
+}
+
 pub fn parse_function(iter: &mut TokenIterator) -> CompileResult<Function> {
+    let first_token_idx = iter.next_token_index();
     let returns_value = match iter.consume() {
         Token::Void => false,
         Token::Int => true,
@@ -302,45 +537,105 @@ pub fn parse_function(iter: &mut TokenIterator) -> CompileResult<Function> {
 
     let name = match iter.consume() {
         Token::Identifier(name) => name,
-        _ => return prev_token_error!(iter, "Expected function name")
+        token => return expected_name_error(iter, &token, "function name")
     };
     let name_ref = iter.prev_token_ref();
 
     if iter.consume() != Token::OpenParen {
         return prev_token_error!(iter, "Expected `(`")
     }
+    let open_paren_idx = iter.prev_token_index();
 
+    // A trailing comma is allowed after the last argument.
     let mut argument_names = Vec::new();
-    while let Token::Identifier(ident) = iter.consume() {
-        argument_names.push(ident);
+    let mut argument_name_refs = Vec::new();
+    loop {
+        match iter.consume() {
+            Token::CloseParen => break,
+            Token::Comma => return prev_token_error!(iter, "Expected argument name before `,`"),
+            Token::Identifier(ident) => {
+                if argument_names.contains(&ident) {
+                    return prev_token_error!(iter, "An argument named `{ident}` already exists");
+                }
+
+                argument_names.push(ident);
+                argument_name_refs.push(iter.prev_token_ref());
+            },
+            token => return expected_name_error(iter, &token, "argument name or `)`")
+        }
 
         match iter.consume() {
             Token::Comma => {},
-            _ => break
+            Token::CloseParen => break,
+            _ => return prev_token_error!(iter, "Expected `,` or `)` after argument name")
         }
     }
-
-    iter.move_back(); // Move back to allow the ending chracter to be handled, as it has already been consumed.
-
-    match iter.consume() {
-        Token::CloseParen => {},
-        _ => return prev_token_error!(iter, "Expected ')'")
-    };
+    let argument_list_ref = iter.get_ref_range(open_paren_idx, iter.prev_token_index());
 
     // Parse the block
     let block = parse_block(iter)?;
     Ok(Function {
         name,
         argument_names,
+        argument_name_refs,
+        argument_list_ref,
         block,
         returns_value,
-        name_ref
+        name_ref,
+        token_hash: iter.hash_range(first_token_idx, iter.prev_token_index())
     })
 
 }
 
+// Parses a global variable declaration, assuming the `global` keyword has already been consumed.
+fn parse_global(iter: &mut TokenIterator) -> CompileResult<Global> {
+    let name = match iter.consume() {
+        Token::Identifier(name) => name,
+        token => return expected_name_error(iter, &token, "global variable name")
+    };
+    let name_ref = iter.prev_token_ref();
+
+    let initializer = match iter.consume() {
+        Token::Equals => Some(parse_expression(iter)?),
+        _ => {
+            iter.move_back();
+            None
+        }
+    };
+
+    expect_semicolon_and_then(iter, Global {
+        name,
+        name_ref,
+        initializer
+    })
+}
+
+// Parses a constant declaration, assuming the `const` keyword has already been consumed.
+fn parse_constant(iter: &mut TokenIterator) -> CompileResult<Constant> {
+    let name = match iter.consume() {
+        Token::Identifier(name) => name,
+        token => return expected_name_error(iter, &token, "constant name")
+    };
+    let name_ref = iter.prev_token_ref();
+
+    if iter.consume() != Token::Equals {
+        return prev_token_error!(iter, "Expected `=`, constants must be given a value");
+    }
+
+    let value_start = iter.next_token_index();
+    let value = parse_expression(iter)?;
+    let value_ref = iter.get_ref_range(value_start, iter.prev_token_index());
+
+    expect_semicolon_and_then(iter, Constant {
+        name,
+        name_ref,
+        value,
+        value_ref
+    })
+}
+
 fn expect_semicolon_and_then<T>(iter: &mut TokenIterator, value: T) -> CompileResult<T> {
-    return if iter.consume() != Token::Semicolon {
+    if iter.consume() != Token::Semicolon {
         prev_token_error!(iter, "Expected `;`")
     }   else    {
         Ok(value)
@@ -349,15 +644,77 @@ fn expect_semicolon_and_then<T>(iter: &mut TokenIterator, value: T) -> CompileRe
 
 // Parses a statement
 pub fn parse_statement(iter: &mut TokenIterator) -> CompileResult<Statement> {
+    // A keyword followed by `=` was most likely intended as a variable name, e.g. `int = 5;`
+    let first_token = iter.consume();
+    if iter.consume() == Token::Equals && lexer::keyword_name(&first_token).is_some() {
+        iter.move_back();
+        return match first_token {
+            Token::True | Token::False => prev_token_error!(iter, "Cannot assign to `{}`, as it is a keyword for a constant value",
+                lexer::keyword_name(&first_token).unwrap()),
+            _ => expected_name_error(iter, &first_token, "variable name")
+        };
+    }
+    iter.move_back();
+    iter.move_back();
+
+    let statement_start = iter.next_token_index();
     let ident = match iter.consume() {
-        // If beginning with an identifier, this is an assignment or call expression, which will be handled separately.
+        // If beginning with an identifier, this is an assignment, call or other expression, which will be handled separately.
         Token::Identifier(ident) => ident,
 
+        Token::OpenParen | Token::Number(_) | Token::Tilda | Token::Bang | Token::True | Token::False => {
+            iter.move_back();
+            let statement = parse_expression_statement(iter)?;
+            return expect_semicolon_and_then(iter, statement);
+        },
+
         Token::If => return parse_if_statement(iter),
-        Token::While => return Ok(Statement::While {
-            condition: parse_expression(iter)?,
-            block: parse_block(iter)?,
-        }),
+        Token::While => {
+            let idx_before_condition = iter.next_token_index();
+            let condition = parse_expression(iter)?;
+
+            return Ok(Statement::While {
+                condition,
+                condition_ref: iter.get_ref_range(idx_before_condition, iter.prev_token_index()),
+                block: parse_body(iter)?,
+                is_do_while: false
+            })
+        },
+        Token::Do => {
+            let block = parse_body(iter)?;
+            if iter.consume() != Token::While {
+                return prev_token_error!(iter, "Expected `while` after the body of a `do` loop");
+            }
+
+            let idx_before_condition = iter.next_token_index();
+            let condition = parse_expression(iter)?;
+            let condition_ref = iter.get_ref_range(idx_before_condition, iter.prev_token_index());
+
+            return expect_semicolon_and_then(iter, Statement::While {
+                condition,
+                condition_ref,
+                block,
+                is_do_while: true
+            })
+        },
+
+        Token::OpenBrace => {
+            iter.move_back();
+            return Ok(Statement::Block(parse_block(iter)?));
+        },
+
+        // `++i;` or `--i;`
+        sign @ (Token::Plus | Token::Minus) => return if iter.consume() == sign {
+            misplaced_increment_error(iter)
+        }   else if sign == Token::Minus {
+            // A negated expression, e.g. `-f(x);`
+            iter.move_back_to(statement_start);
+            let statement = parse_expression_statement(iter)?;
+            expect_semicolon_and_then(iter, statement)
+        }   else {
+            iter.move_back();
+            prev_token_error!(iter, "Expected statement")
+        },
 
         Token::Continue => return expect_semicolon_and_then(iter, Statement::Continue(iter.prev_token_ref())),
         Token::Break => return expect_semicolon_and_then(iter, Statement::Break(iter.prev_token_ref())),
@@ -393,25 +750,48 @@ pub fn parse_statement(iter: &mut TokenIterator) -> CompileResult<Statement> {
     let ident_ref = iter.prev_token_ref();
 
     let statement = match iter.consume() {
+        // `x == y;` is a comparison rather than an assignment.
+        Token::Equals if iter.consume() == Token::Equals => {
+            iter.move_back_to(statement_start);
+            parse_expression_statement(iter)?
+        },
         Token::Equals => {
+            iter.move_back();
             let value = parse_expression(iter)?;
 
             Statement::Assignment { variable_name: ident, value, variable_name_ref: ident_ref }
         },
-        Token::Plus => parse_modify_in_place(iter, ident, ident_ref, BinaryOperator::Add)?,
-        Token::Minus => parse_modify_in_place(iter, ident, ident_ref, BinaryOperator::Subtract)?,
+        sign @ (Token::Plus | Token::Minus) => {
+            let operator = if sign == Token::Plus { BinaryOperator::Add } else { BinaryOperator::Subtract };
+            if iter.consume() == sign {
+                increment_statement(iter, ident, ident_ref, operator)
+            }   else {
+                iter.move_back();
+                parse_modify_in_place(iter, ident, ident_ref, operator)?
+            }
+        },
         Token::Star => parse_modify_in_place(iter, ident, ident_ref, BinaryOperator::Multiply)?,
         Token::ForwardSlash => parse_modify_in_place(iter, ident, ident_ref, BinaryOperator::Divide)?,
         Token::Carat => parse_modify_in_place(iter, ident, ident_ref, BinaryOperator::Power)?,
         Token::Ampersand => parse_modify_in_place(iter, ident, ident_ref, BinaryOperator::And)?,
         Token::Bar => parse_modify_in_place(iter, ident, ident_ref, BinaryOperator::Or)?,
-        Token::OpenParen => {
-            iter.move_back();
-            iter.move_back();
-
-            Statement::Call(parse_call(iter)?)
+        Token::Tilda => parse_modify_in_place(iter, ident, ident_ref, BinaryOperator::Xor)?,
+        Token::Percent => parse_modify_in_place(iter, ident, ident_ref, BinaryOperator::Remainder)?,
+        arrow @ (Token::LeftArrow | Token::RightArrow) => {
+            // Only `<<=` and `>>=` can begin with an arrow, so anything else, e.g. `x < 5;`, is a comparison.
+            if iter.consume() != arrow {
+                iter.move_back_to(statement_start);
+                parse_expression_statement(iter)?
+            }   else {
+                let operator = if arrow == Token::LeftArrow { BinaryOperator::ShiftLeft } else { BinaryOperator::ShiftRight };
+                parse_modify_in_place(iter, ident, ident_ref, operator)?
+            }
         },
-        _ => return prev_token_error!(iter, "Expected valid statement")
+        // A call, which may be part of a larger expression, e.g. `f(x) + 1;`
+        _ => {
+            iter.move_back_to(statement_start);
+            parse_expression_statement(iter)?
+        }
     };
 
     match iter.consume() {
@@ -432,45 +812,71 @@ fn parse_call(iter: &mut TokenIterator) -> CompileResult<Call> {
         return prev_token_error!(iter, "Expected `(`");
     }
 
-    let before_args_idx = iter.next_token_index();
+    let open_paren_idx = iter.prev_token_index();
 
-    // Parse arguments
+    // With no arguments, the brackets themselves are used as the position of the arguments.
+    if iter.consume() == Token::CloseParen {
+        return Ok(Call {
+            arguments: Vec::new(),
+            function_name,
+            function_name_ref,
+            arguments_ref: iter.get_ref_range(open_paren_idx, iter.prev_token_index())
+        });
+    }
+    iter.move_back();
+
+    // Parse arguments, allowing a trailing comma after the last one.
     let mut args = Vec::new();
-    while let Ok(expr) = parse_expression(iter) {
-        args.push(expr);
+    let mut last_arg_idx = open_paren_idx;
+    loop {
+        match iter.consume() {
+            Token::CloseParen => break,
+            Token::Comma => return prev_token_error!(iter, "Expected expression before `,`"),
+            _ => iter.move_back()
+        }
+
+        args.push(parse_expression(iter)?);
+        last_arg_idx = iter.prev_token_index();
 
         match iter.consume() {
             Token::Comma => {},
-            _ => { break; }
+            Token::CloseParen => break,
+            _ => return prev_token_error!(iter, "Expected `,` or `)` after argument")
         }
     }
-    iter.move_back();
 
-    let after_args = iter.prev_token_index();
-
-    match iter.consume() {
-        Token::CloseParen => Ok(Call {
-            arguments: args,
-            function_name,
-            function_name_ref,
-            arguments_ref: iter.get_ref_range(before_args_idx, after_args)
-        }),
-        _ => prev_token_error!(iter, "Expected `)`")
-    }
+    Ok(Call {
+        arguments: args,
+        function_name,
+        function_name_ref,
+        arguments_ref: iter.get_ref_range(open_paren_idx + 1, last_arg_idx)
+    })
 }
 
 
 // Parses the unary section of an expression, typically a variable reference or call, but also includes a bracketed inner expression ()
 fn parse_unary_expression(iter: &mut TokenIterator) -> CompileResult<Expression> {
     match iter.consume() {
-        Token::Minus => Ok(Expression::Unary {
-            value: Box::new(parse_unary_expression(iter)?),
-            operator: UnaryOperator::Negate
-        }),
+        Token::Minus => {
+            // `-2147483648` is the only way to write i32::MIN, since its absolute value is too large for an i32.
+            if iter.consume() == Token::Number(lexer::MAX_LITERAL) {
+                return Ok(Expression::Literal(i32::MIN));
+            }
+            iter.move_back();
+
+            Ok(Expression::Unary {
+                value: Box::new(parse_unary_expression(iter)?),
+                operator: UnaryOperator::Negate
+            })
+        },
         Token::Tilda => Ok(Expression::Unary {
             value: Box::new(parse_unary_expression(iter)?),
             operator: UnaryOperator::Not
         }),
+        Token::Bang => Ok(Expression::Unary {
+            value: Box::new(parse_unary_expression(iter)?),
+            operator: UnaryOperator::LogicalNot
+        }),
 
         Token::Identifier(ident) => {
             match iter.consume() {
@@ -482,14 +888,25 @@ fn parse_unary_expression(iter: &mut TokenIterator) -> CompileResult<Expression>
                 },
                 _ => {
                     iter.move_back();
+                    let pos = iter.prev_token_ref();
+                    if consume_misplaced_increment(iter) {
+                        return misplaced_increment_error(iter);
+                    }
+
                     Ok(Expression::Variable {
                         name: ident,
-                        pos: iter.prev_token_ref()
+                        pos
                     })
                 }
             }
         },
-        Token::Number(n) => Ok(Expression::Literal(n)),
+        Token::True => Ok(Expression::Literal(1)),
+        Token::False => Ok(Expression::Literal(0)),
+        Token::Number(n) => match i32::try_from(n) {
+            Ok(n) => Ok(Expression::Literal(n)),
+            Err(_) => prev_token_error!(iter, "Integer literal is too large to fit in a 32 bit signed integer, \
+                `{}` is only valid when negated", lexer::MAX_LITERAL)
+        },
         Token::OpenParen => {
             let inner = parse_expression(iter)?;
             match iter.consume() {
@@ -497,10 +914,40 @@ fn parse_unary_expression(iter: &mut TokenIterator) -> CompileResult<Expression>
                 _ => prev_token_error!(iter, "Expected `)`")
             }
         },
+        // `++i` within an expression.
+        Token::Plus => if iter.consume() == Token::Plus {
+            misplaced_increment_error(iter)
+        }   else {
+            iter.move_back();
+            prev_token_error!(iter, "Expected unary expression, there is no unary `+` operator")
+        },
         _ => prev_token_error!(iter, "Expected unary expression"),
     }
 }
 
+// An operand of a binary operator, along with the range of tokens it was parsed from.
+struct Operand {
+    expr: Expression,
+    first_token_idx: usize,
+    last_token_idx: usize,
+    // If the operand is a comparison that is not in brackets, the range of tokens of its right hand side.
+    comparison_rhs: Option<(usize, usize)>
+}
+
+// Warns about a comparison whose left hand side is another comparison, e.g. `0 < x < 10`,
+// which compares the 0 or 1 result of the first comparison rather than `x`.
+fn warn_chained_comparison(iter: &mut TokenIterator, left: &Operand, left_rhs: (usize, usize), right: &Operand, operator: BinaryOperator) {
+    let left_text = iter.get_text_range(left.first_token_idx, left.last_token_idx);
+    let middle_text = iter.get_text_range(left_rhs.0, left_rhs.1);
+    let right_text = iter.get_text_range(right.first_token_idx, right.last_token_idx);
+    let symbol = operator.symbol();
+
+    let warning = warning!(WarningKind::ChainedComparison, iter.get_ref_range(left.first_token_idx, right.last_token_idx),
+        "Comparisons cannot be chained: this is evaluated as `({left_text}) {symbol} {right_text}`, which compares the result of `{left_text}` (0 or 1). \
+        To check both conditions, use `{left_text} && {middle_text} {symbol} {right_text}`");
+    iter.warnings.push(warning);
+}
+
 // Parses an expression.
 pub fn parse_expression(iter: &mut TokenIterator) -> CompileResult<Expression> {
     let mut expressions = Vec::new();
@@ -508,17 +955,30 @@ pub fn parse_expression(iter: &mut TokenIterator) -> CompileResult<Expression> {
 
     // Keep parsing expressions until we no longer have a valid binary operator to continue.
     loop {
+        let first_token_idx = iter.next_token_index();
         let expr = parse_unary_expression(iter)?;
-        expressions.push(expr);
-
+        expressions.push(Operand {
+            expr,
+            first_token_idx,
+            last_token_idx: iter.prev_token_index(),
+            comparison_rhs: None
+        });
+
+        let operator_idx = iter.next_token_index();
         match parse_binary_operator(iter) {
             None => break,
-            Some(operator) => operators.push(operator)
+            Some(operator) => operators.push((operator, iter.get_ref_range(operator_idx, iter.prev_token_index())))
         }
     }
 
     // Reduce the list of binary operations into one according to the operator precedence.
-    for operator_set in PRECEDENCE {
+    for (associativity, operator_set) in PRECEDENCE {
+        // Operators evaluated from right to left are reduced in the same way, but starting from the last operand.
+        if *associativity == Associativity::Right {
+            expressions.reverse();
+            operators.reverse();
+        }
+
         let mut reduced_expressions = Vec::new();
         let mut reduced_operators = Vec::new();
 
@@ -526,21 +986,37 @@ pub fn parse_expression(iter: &mut TokenIterator) -> CompileResult<Expression> {
         reduced_expressions.push(expr_iter.next().expect("Must have at least one expression"));
 
         let mut operator_iter = operators.into_iter();
-        while let Some(next_expr) = expr_iter.next() {
-            if let Some(operator) = operator_iter.next() {
+        for next_expr in expr_iter {
+            if let Some((operator, operator_ref)) = operator_iter.next() {
                 if operator_set.contains(&operator) {
-                    let prev_expr = reduced_expressions.pop().unwrap();
+                    let prev_expr: Operand = reduced_expressions.pop().unwrap();
+                    let (left, right) = match associativity {
+                        Associativity::Left => (prev_expr, next_expr),
+                        Associativity::Right => (next_expr, prev_expr)
+                    };
+
+                    if let (Some(left_rhs), true) = (left.comparison_rhs, operator.is_comparison()) {
+                        warn_chained_comparison(iter, &left, left_rhs, &right, operator);
+                    }
 
-                    reduced_expressions.push(Expression::Binary {
-                        left: Box::new(prev_expr),
-                        // Should be unreachable
-                        right: Box::new(next_expr),
-                        operator: operator
+                    let expression_ref = iter.get_ref_range(left.first_token_idx, right.last_token_idx);
+                    reduced_expressions.push(Operand {
+                        first_token_idx: left.first_token_idx,
+                        last_token_idx: right.last_token_idx,
+                        comparison_rhs: operator.is_comparison()
+                            .then_some((right.first_token_idx, right.last_token_idx)),
+                        expr: Expression::Binary {
+                            left: Box::new(left.expr),
+                            right: Box::new(right.expr),
+                            operator,
+                            operator_ref,
+                            expression_ref
+                        }
                     });
 
                     continue;
                 }   else {
-                    reduced_operators.push(operator);
+                    reduced_operators.push((operator, operator_ref));
                 }
             } 
 
@@ -550,9 +1026,184 @@ pub fn parse_expression(iter: &mut TokenIterator) -> CompileResult<Expression> {
 
         expressions = reduced_expressions;
         operators = reduced_operators;
+
+        if *associativity == Associativity::Right {
+            expressions.reverse();
+            operators.reverse();
+        }
     }
 
     assert!(expressions.len() == 1, "Operator precedence failed to reduce an expression to one binary operation. This is a bug.
         Check that all operators have an assigned precedence.");
-    Ok(expressions.into_iter().next().unwrap())
-}
\ No newline at end of file
+    Ok(expressions.into_iter().next().unwrap().expr)
+}
+
+// Parses a file containing only an expression, e.g. the one given to `lflc eval`.
+// Gives the expression along with its position, which covers all of the tokens in the file.
+pub fn parse_standalone_expression(iter: &mut TokenIterator) -> CompileResult<(Expression, FileRef)> {
+    let first_token_idx = iter.next_token_index();
+    let expression = parse_expression(iter)?;
+    let expression_ref = iter.get_ref_range(first_token_idx, iter.prev_token_index());
+
+    match iter.consume() {
+        Token::EndOfFile => Ok((expression, expression_ref)),
+        _ => prev_token_error!(iter, "Expected the end of the expression")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{ast, error_handling::SourceFile, lexer};
+
+    // Parses a module, giving its syntax tree as printed by `--ast`.
+    fn parse(source: &str) -> CompileResult<String> {
+        let source = SourceFile::from_text("test.lfl".to_owned(), source.to_owned());
+        let tokens = lexer::tokenize(Arc::new(source))?;
+        let (items, imports) = parse_module(&mut TokenIterator::new(tokens))?;
+        Ok(ast::dump_module(&items, &imports))
+    }
+
+    // Parses the expression assigned to `signal_1` in `main`, giving its syntax tree without the function and assignment around it.
+    fn parse_assigned(expression: &str) -> CompileResult<String> {
+        let tree = parse(&format!("void main() {{ signal_1 = {expression}; }}"))?;
+        Ok(tree.lines().skip(2).map(|line| format!("{}\n", &line[4..])).collect())
+    }
+
+    fn error_message(result: CompileResult<String>) -> String {
+        match result {
+            Ok(tree) => panic!("Parsed successfully as\n{tree}"),
+            Err(err) => err.0.into_iter().map(|error| error.msg).collect::<Vec<String>>().join("\n")
+        }
+    }
+
+    const MISPLACED_INCREMENT: &str = "Increments and decrements can only be used as a statement, after the variable name, e.g. `i++;`";
+
+    #[test]
+    fn increment_statements_add_one() {
+        assert_eq!(parse("void main() { i++; }").unwrap(), parse("void main() { i += 1; }").unwrap());
+        assert_eq!(parse("void main() { i--; }").unwrap(), parse("void main() { i -= 1; }").unwrap());
+    }
+
+    #[test]
+    fn increment_within_expression_is_an_error() {
+        for expression in ["a++", "a--", "a++ + 1", "a-- + 1", "a++ ++b", "++a"] {
+            assert_eq!(error_message(parse_assigned(expression)), MISPLACED_INCREMENT, "{expression}");
+        }
+    }
+
+    #[test]
+    fn double_minus_before_operand_is_subtraction() {
+        for operand in ["3", "b", "(b)", "-b", "~b", "!b", "f()", "true", "false"] {
+            let expected = parse_assigned(&format!("a - -{operand}")).unwrap();
+            assert_eq!(parse_assigned(&format!("a--{operand}")).unwrap(), expected, "a--{operand}");
+        }
+    }
+
+    #[test]
+    fn double_plus_before_operand_is_an_error() {
+        for operand in ["3", "b", "(b)", "true"] {
+            assert_eq!(error_message(parse_assigned(&format!("a++{operand}"))), "Expected unary expression, there is no unary `+` operator");
+        }
+    }
+
+    #[test]
+    fn operators_are_grouped_by_precedence() {
+        assert_eq!(parse_assigned("a + b * 2 - -a << 1 == b || !a && ~b").unwrap(), "\
+binary ||
+  binary ==
+    binary -
+      binary +
+        variable a
+        binary *
+          variable b
+          literal 2
+      binary <<
+        unary -
+          variable a
+        literal 1
+    variable b
+  binary &&
+    unary !
+      variable a
+    unary ~
+      variable b
+");
+    }
+
+    #[test]
+    fn power_groups_from_the_right() {
+        assert_eq!(parse_assigned("2 ^ 3 ^ a - 1 - b").unwrap(), "\
+binary -
+  binary -
+    binary ^
+      literal 2
+      binary ^
+        literal 3
+        variable a
+    literal 1
+  variable b
+");
+    }
+
+    #[test]
+    fn unary_operators_nest() {
+        assert_eq!(parse_assigned("- ~ !-a * -(b)").unwrap(), "\
+binary *
+  unary -
+    unary ~
+      unary !
+        unary -
+          variable a
+  unary -
+    variable b
+");
+        assert_eq!(parse_assigned("-2147483648").unwrap(), "literal -2147483648\n");
+    }
+
+    #[test]
+    fn else_if_chain_has_a_segment_for_each_condition() {
+        assert_eq!(parse("void main(a, b) { if a { b = 1; } else if b { b = 2; } else if a < b { b = 3; } else { b = 4; } }").unwrap(), "\
+function void main(a, b)
+  if
+    variable a
+  then
+    assign b
+      literal 1
+  else if
+    variable b
+  then
+    assign b
+      literal 2
+  else if
+    binary <
+      variable a
+      variable b
+  then
+    assign b
+      literal 3
+  else
+    assign b
+      literal 4
+");
+    }
+
+    #[test]
+    fn syntax_errors_are_reported() {
+        let cases = [
+            ("void main() { a = 1 }", "Expected `;`"),
+            ("void main() { a = (1 + 2; }", "Expected `)`"),
+            ("void main() { a = 1 +; }", "Expected unary expression"),
+            ("void main( { }", "Expected argument name or `)`"),
+            ("void main() { if 1 { } else }", "Expected statement"),
+            ("void main() { } }", "Expected function return type: `int` or `void`"),
+            ("void main() { a = 2147483648; }", "Integer literal is too large to fit in a 32 bit signed integer, `2147483648` is only valid when negated")
+        ];
+
+        for (source, expected) in cases {
+            assert_eq!(error_message(parse(source)), expected, "{source}");
+        }
+    }
+}
@@ -0,0 +1,461 @@
+//! Formatting of source code, used by `lflc fmt`.
+//!
+//! The code is printed from its syntax tree, so it is laid out the same way however it was written. The syntax tree
+//! doesn't include comments, or the positions of keywords and brackets, so the printer also walks through the tokens
+//! of the file alongside it. Each comment is printed before the statement or closing bracket that it came before,
+//! and a comment at the end of a line stays at the end of the line.
+
+use std::{iter::Peekable, sync::Arc, vec};
+
+use crate::{ast::{Call, Expression, Function, Import, Item, Statement},
+    error_handling::{CompileResult, FileRef, SourceFile}, lexer::{self, Comment, Token},
+    parser::{self, Associativity, TokenIterator}};
+
+const INDENT: &str = "    ";
+
+// Formats the code in a source file. Fails if the file cannot be parsed.
+pub fn format_source(source: Arc<SourceFile>) -> CompileResult<String> {
+    let (tokens, comments) = lexer::tokenize_with_comments(source.clone())?;
+
+    let mut iter = TokenIterator::new(tokens.clone());
+    let (items, imports) = parser::parse_module(&mut iter)?;
+
+    Ok(format_module(&source, &items, &imports, &tokens, comments))
+}
+
+// Formats the items and imports parsed from the tokens of a source file.
+pub fn format_module(source: &SourceFile, items: &[Item], imports: &[Import], tokens: &[(Token, FileRef)], comments: Vec<Comment>) -> String {
+    // Imports are parsed separately from the other items, so they are put back in the order they were written.
+    let mut entries: Vec<(&FileRef, Option<&Item>, Option<&Import>)> = items.iter()
+        .map(|item| (item_position(item), Some(item), None))
+        .chain(imports.iter().map(|import| (&import.path_ref, None, Some(import))))
+        .collect();
+    entries.sort_by_key(|(position, _, _)| (position.line_index, position.begin_char_index));
+
+    let mut printer = Printer {
+        output: String::new(),
+        tokens,
+        comments: comments.into_iter().peekable(),
+        blank_lines: source.text.lines().map(|line| line.trim().is_empty()).collect(),
+        depth: 0,
+        last_line: None,
+        blank_line_allowed: false
+    };
+
+    let mut next_idx = 0;
+    let mut prev_was_function = false;
+    for (_, item, import) in entries {
+        let is_function = matches!(item, Some(Item::Function(_)));
+
+        // Functions are always separated from the items around them by a blank line.
+        if (is_function || prev_was_function) && printer.last_line.is_some() {
+            printer.output.push('\n');
+            printer.blank_line_allowed = false;
+        }
+        prev_was_function = is_function;
+
+        next_idx = match (item, import) {
+            (Some(item), _) => printer.item(item, next_idx),
+            (_, Some(import)) => printer.simple_line(next_idx, format!("import \"{}\";", import.path)),
+            _ => unreachable!()
+        } + 1;
+    }
+
+    // The last token is the end of the file.
+    printer.comments_before(tokens.len() - 1);
+    printer.output
+}
+
+fn item_position(item: &Item) -> &FileRef {
+    match item {
+        Item::Function(function) => &function.name_ref,
+        Item::Global(global) => &global.name_ref,
+        Item::Constant(constant) => &constant.name_ref
+    }
+}
+
+struct Printer<'a> {
+    output: String,
+    tokens: &'a [(Token, FileRef)],
+    comments: Peekable<vec::IntoIter<Comment>>,
+    // Whether each line of the source code is empty, so that blank lines between statements can be kept.
+    blank_lines: Vec<bool>,
+    // The number of blocks that the code being printed is within.
+    depth: usize,
+    // The last line of source code that has been printed, or None at the start of the file.
+    last_line: Option<u32>,
+    // Blank lines are left out at the start of a block, and at the start of the file.
+    blank_line_allowed: bool
+}
+
+impl Printer<'_> {
+    // Writes a line at the current indentation.
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.depth {
+            self.output.push_str(INDENT);
+        }
+
+        self.output.push_str(text);
+        self.output.push('\n');
+    }
+
+    // Writes a blank line if there was one in the source code between the last line printed and `line`.
+    fn blank_line_before(&mut self, line: u32) {
+        let had_blank_line = match self.last_line {
+            Some(last_line) => (last_line + 1..line).any(|idx| self.blank_lines.get(idx as usize) == Some(&true)),
+            None => false
+        };
+
+        if had_blank_line && self.blank_line_allowed {
+            self.output.push('\n');
+        }
+    }
+
+    // Writes the comments that come before the token at `token_idx` which haven't been written yet.
+    fn comments_before(&mut self, token_idx: usize) {
+        while let Some(comment) = self.comments.next_if(|comment| comment.next_token_idx <= token_idx) {
+            // Every line written ends with a line break, which the comment goes before.
+            if comment.is_trailing && self.output.ends_with('\n') {
+                // A blank line may have been added after the line the comment was on.
+                let line_breaks = if self.output.ends_with("\n\n") { "\n\n" } else { "\n" };
+                self.output.truncate(self.output.len() - line_breaks.len());
+                self.output.push(' ');
+                self.output.push_str(&comment.text);
+                self.output.push_str(line_breaks);
+            }   else {
+                self.blank_line_before(comment.position.line_index);
+                // The lines of a block comment after the first are kept as they were written.
+                self.line(&comment.text);
+                self.blank_line_allowed = true;
+            }
+
+            // A trailing comment doesn't change whether a blank line can follow, e.g. one already written after a function.
+            self.last_line = Some(comment.position.end_line_index);
+        }
+    }
+
+    // Writes the comments before a token, then a line beginning at that token, e.g. a statement.
+    fn begin_line(&mut self, token_idx: usize, text: &str) {
+        self.comments_before(token_idx);
+        self.blank_line_before(self.tokens[token_idx].1.line_index);
+        self.line(text);
+    }
+
+    // Records that the code up to the token at `token_idx` has been written.
+    fn end_at(&mut self, token_idx: usize) {
+        self.last_line = Some(self.tokens[token_idx].1.end_line_index);
+        self.blank_line_allowed = true;
+    }
+
+    // Finds the first token with the given value, beginning at `token_idx`.
+    fn find(&self, token_idx: usize, token: Token) -> usize {
+        token_idx + self.tokens[token_idx..].iter()
+            .position(|(other, _)| *other == token)
+            .expect("Token must exist in a file that was parsed")
+    }
+
+    // Finds the last token within a range of the source code.
+    fn last_token_of(&self, position: &FileRef) -> usize {
+        self.tokens.partition_point(|(_, token_position)| (token_position.line_index, token_position.begin_char_index)
+            < (position.end_line_index, position.end_char_index)) - 1
+    }
+
+    // Writes a statement or item that fits on one line, beginning at `token_idx` and ending with a `;`.
+    // Returns the index of the `;`.
+    fn simple_line(&mut self, token_idx: usize, text: String) -> usize {
+        let end_idx = self.find(token_idx, Token::Semicolon);
+        self.begin_line(token_idx, &text);
+        self.end_at(end_idx);
+        end_idx
+    }
+
+    // Writes an item beginning at `token_idx`, and returns the index of its last token.
+    fn item(&mut self, item: &Item, token_idx: usize) -> usize {
+        match item {
+            Item::Function(function) => self.function(function, token_idx),
+            Item::Global(global) => {
+                let text = match &global.initializer {
+                    Some(initializer) => format!("global {} = {};", global.name, self.expression(initializer, &mut token_idx.clone())),
+                    None => format!("global {};", global.name)
+                };
+
+                self.simple_line(token_idx, text)
+            },
+            Item::Constant(constant) => {
+                let text = format!("const {} = {};", constant.name, self.expression(&constant.value, &mut token_idx.clone()));
+                self.simple_line(token_idx, text)
+            }
+        }
+    }
+
+    fn function(&mut self, function: &Function, token_idx: usize) -> usize {
+        let return_type = if function.returns_value { "int" } else { "void" };
+        let header = format!("{return_type} {}({}) {{", function.name, function.argument_names.join(", "));
+
+        let open_idx = self.last_token_of(&function.argument_list_ref) + 1;
+        self.begin_line(token_idx, &header);
+        let close_idx = self.block(&function.block, open_idx);
+        self.line("}");
+        self.end_at(close_idx);
+
+        close_idx
+    }
+
+    // Writes the statements of a block beginning with the `{` at `open_idx`, assuming the line containing the
+    // `{` has already been written. Returns the index of the `}`, which is left for the caller to write.
+    fn block(&mut self, statements: &[Statement], open_idx: usize) -> usize {
+        self.depth += 1;
+        self.last_line = Some(self.tokens[open_idx].1.line_index);
+        self.blank_line_allowed = false;
+
+        let mut next_idx = open_idx + 1;
+        for statement in statements {
+            next_idx = self.statement(statement, next_idx) + 1;
+        }
+
+        // Comments at the end of the block are indented along with it.
+        self.comments_before(next_idx);
+        self.depth -= 1;
+        next_idx
+    }
+
+    // Writes the body of an `if`, `else`, `while` or `do` beginning at `token_idx`, after a line ending with `{`.
+    // A body without braces is given them. Returns the index of the last token of the body.
+    fn body(&mut self, statements: &[Statement], token_idx: usize) -> usize {
+        if self.tokens[token_idx].0 == Token::OpenBrace {
+            return self.block(statements, token_idx);
+        }
+
+        self.depth += 1;
+        self.blank_line_allowed = false;
+        let end_idx = self.statement(&statements[0], token_idx);
+        self.depth -= 1;
+
+        end_idx
+    }
+
+    // Writes a statement beginning at `token_idx`, and returns the index of its last token.
+    fn statement(&mut self, statement: &Statement, token_idx: usize) -> usize {
+        let mut literal_idx = token_idx;
+        match statement {
+            Statement::Assignment { variable_name, value, .. } => {
+                let text = match (&self.tokens[token_idx + 1].0, &self.tokens[token_idx + 2].0, value) {
+                    (Token::Equals, _, _) => format!("{variable_name} = {};", self.expression(value, &mut literal_idx)),
+                    // `x++` and `x--` are stored as `x = x + 1` and `x = x - 1`.
+                    (first @ (Token::Plus | Token::Minus), second, _) if first == second => {
+                        let symbol = if *first == Token::Plus { "++" } else { "--" };
+                        format!("{variable_name}{symbol};")
+                    },
+                    // `x += y` is stored as `x = x + y`, but the right hand side is not in brackets.
+                    (_, _, Expression::Binary { right, operator, .. }) => {
+                        format!("{variable_name} {}= {};", operator.symbol(), self.expression(right, &mut literal_idx))
+                    },
+                    _ => unreachable!("Compound assignments must have a binary operator")
+                };
+
+                self.simple_line(token_idx, text)
+            },
+            Statement::Call(call) => {
+                let text = format!("{};", self.call(call, &mut literal_idx));
+                self.simple_line(token_idx, text)
+            },
+            Statement::Expression { value, .. } => {
+                let text = format!("{};", self.expression(value, &mut literal_idx));
+                self.simple_line(token_idx, text)
+            },
+            Statement::Return(_) => self.simple_line(token_idx, "return;".to_string()),
+            Statement::ReturnValue { value, .. } => {
+                let text = format!("return {};", self.expression(value, &mut literal_idx));
+                self.simple_line(token_idx, text)
+            },
+            Statement::Continue(_) => self.simple_line(token_idx, "continue;".to_string()),
+            Statement::Break(_) => self.simple_line(token_idx, "break;".to_string()),
+            Statement::Block(block) => {
+                self.begin_line(token_idx, "{");
+                let close_idx = self.block(block, token_idx);
+                self.line("}");
+                self.end_at(close_idx);
+                close_idx
+            },
+            Statement::If { segments, r#else } => {
+                // The `if` keyword, and `else if` after each segment, come before the condition.
+                let mut header = format!("if {} {{", self.expression(&segments[0].condition, &mut literal_idx));
+                self.begin_line(token_idx, &header);
+
+                let mut end_idx = token_idx;
+                for (idx, segment) in segments.iter().enumerate() {
+                    if idx > 0 {
+                        let mut literal_idx = end_idx + 1;
+                        header = format!("}} else if {} {{", self.expression(&segment.condition, &mut literal_idx));
+                        self.line(&header);
+                    }
+
+                    end_idx = self.body(&segment.block, self.last_token_of(&segment.condition_ref) + 1);
+                }
+
+                if let Some(else_block) = r#else {
+                    self.line("} else {");
+                    // The body begins after the `else`.
+                    end_idx = self.body(else_block, end_idx + 2);
+                }
+
+                self.line("}");
+                self.end_at(end_idx);
+                end_idx
+            },
+            Statement::While { condition, condition_ref, block, is_do_while: false } => {
+                let header = format!("while {} {{", self.expression(condition, &mut literal_idx));
+                self.begin_line(token_idx, &header);
+
+                let end_idx = self.body(block, self.last_token_of(condition_ref) + 1);
+                self.line("}");
+                self.end_at(end_idx);
+                end_idx
+            },
+            Statement::While { condition, condition_ref, block, is_do_while: true } => {
+                self.begin_line(token_idx, "do {");
+                let body_end_idx = self.body(block, token_idx + 1);
+
+                let mut literal_idx = body_end_idx + 1;
+                let footer = format!("}} while {};", self.expression(condition, &mut literal_idx));
+                self.line(&footer);
+
+                let end_idx = self.last_token_of(condition_ref) + 1;
+                self.end_at(end_idx);
+                end_idx
+            }
+        }
+    }
+
+    fn call(&self, call: &Call, literal_idx: &mut usize) -> String {
+        let arguments: Vec<String> = call.arguments.iter()
+            .map(|argument| self.expression(argument, literal_idx))
+            .collect();
+
+        format!("{}({})", call.function_name, arguments.join(", "))
+    }
+
+    // Formats an expression, with brackets only where they are needed.
+    // Literals are stored as their value, so the tokens are searched from `literal_idx` to find how each was
+    // written, e.g. as `true`. Sub-expressions are formatted in the order they were written, so each literal
+    // is the next one in the tokens.
+    fn expression(&self, expression: &Expression, literal_idx: &mut usize) -> String {
+        match expression {
+            Expression::Binary { left, right, operator, .. } => {
+                let (level, associativity) = parser::precedence(*operator);
+                let left_text = self.expression(left, literal_idx);
+                let right_text = self.expression(right, literal_idx);
+
+                let needs_brackets = |operand: &Expression, is_left: bool| match operand {
+                    Expression::Binary { operator: operand_operator, .. } => {
+                        let (operand_level, _) = parser::precedence(*operand_operator);
+                        let is_grouped_later = match associativity {
+                            Associativity::Left => !is_left,
+                            Associativity::Right => is_left
+                        };
+
+                        operand_level > level
+                            || (operand_level == level && is_grouped_later)
+                            // A comparison of a comparison would give a `chained-comparison` warning without brackets.
+                            || (operator.is_comparison() && operand_operator.is_comparison())
+                    },
+                    _ => false
+                };
+
+                let bracket = |text: String, is_bracketed: bool| if is_bracketed { format!("({text})") } else { text };
+                format!("{} {} {}", bracket(left_text, needs_brackets(left, true)), operator.symbol(),
+                    bracket(right_text, needs_brackets(right, false)))
+            },
+            Expression::Unary { value, operator } => {
+                let value_text = self.expression(value, literal_idx);
+                match **value {
+                    Expression::Binary { .. } => format!("{}({value_text})", operator.symbol()),
+                    _ => format!("{}{value_text}", operator.symbol())
+                }
+            },
+            Expression::Call(call) => self.call(call, literal_idx),
+            Expression::Variable { name, .. } => name.clone(),
+            Expression::Literal(value) => {
+                let token_idx = *literal_idx + self.tokens[*literal_idx..].iter()
+                    .position(|(token, _)| matches!(token, Token::Number(_) | Token::True | Token::False))
+                    .expect("Literal must have a token");
+                *literal_idx = token_idx + 1;
+
+                match self.tokens[token_idx].0 {
+                    Token::True => "true".to_string(),
+                    Token::False => "false".to_string(),
+                    _ => value.to_string()
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast;
+
+    // Code written in many different ways, with comments and blank lines in awkward places.
+    const SOURCES: [&str; 4] = [
+        "void main() {\n} // after\nvoid f() {\n}\n",
+        "void main() { // a\n\n    x = 1; // b\n\n\n    y = 2;\n} // c\n\n// d\nglobal g; // e\n",
+        "int f(x) { if x { return 1; } /* inside */ else { return 2; } }\n\n\n// only a comment at the end",
+        r#"import "other.lfl";
+/* header
+   comment */
+const LIMIT = (4 + 2) * 3; // trailing
+global counter = 2;
+global flag;
+
+
+int f(a, b) { // after brace
+    // leading
+    if a > b && (b == 1 || a < (b < 2)) { return a; } else if !a return b;
+    else { return -(-a); }
+} // after function
+void main(x) {
+
+    i = 0;   do { i++; if i == 3 continue; } while i < LIMIT;
+    while x { x -= 1; if x == 2 { break; } }
+
+    /* block */ y = ~x ^ 3 ~ 4 % 2 << 1 >> 1;
+    { z = (y); signal_1 = f(z, counter) + true; }
+    (flag && f(1, 2));
+    if x {} else {}
+    // end of block
+}
+// end of file"#
+    ];
+
+    fn format(source: &str) -> String {
+        format_source(Arc::new(SourceFile::from_text("test.lfl".to_owned(), source.to_owned()))).unwrap()
+    }
+
+    // Parses a file and prints its syntax tree, as with `--ast`.
+    fn syntax_tree(source: &str) -> String {
+        let tokens = lexer::tokenize(Arc::new(SourceFile::from_text("test.lfl".to_owned(), source.to_owned()))).unwrap();
+        let (items, imports) = parser::parse_module(&mut TokenIterator::new(tokens)).unwrap();
+        ast::dump_module(&items, &imports)
+    }
+
+    #[test]
+    fn formatting_twice_changes_nothing() {
+        for source in SOURCES {
+            let formatted = format(source);
+            assert_eq!(format(&formatted), formatted, "{source}");
+        }
+    }
+
+    #[test]
+    fn formatting_keeps_syntax_tree() {
+        for source in SOURCES {
+            assert_eq!(syntax_tree(&format(source)), syntax_tree(source), "{source}");
+        }
+    }
+
+    #[test]
+    fn trailing_comment_after_function_keeps_one_blank_line() {
+        assert_eq!(format(SOURCES[0]), "void main() {\n} // after\n\nvoid f() {\n}\n");
+    }
+}
@@ -0,0 +1,70 @@
+//! Instruction set profiles, which describe the instructions supported by the computer a program is compiled for.
+//!
+//! A computer built in game may only implement some of the instructions, e.g. leaving out `POW`, or the instructions
+//! added after the original design such as `HLT`. The compiler avoids the instructions that have a replacement, and
+//! reports an error for any others used by the program.
+
+use std::{collections::HashSet, hash::{Hash, Hasher}};
+
+use anyhow::{anyhow, Result};
+
+use crate::assembly::Instruction;
+
+// The first opcode added after the original design of the computer, which is `HLT`.
+const FIRST_NEW_OPCODE: i32 = 28;
+
+// The instructions supported by a computer.
+#[derive(Clone, Default)]
+pub struct InstructionSet {
+    // Opcodes of the instructions that are not supported, which is none by default.
+    unsupported: HashSet<i32>
+}
+
+impl InstructionSet {
+    // Gets a built in profile: `full` supports every instruction, and `classic` supports only the instructions
+    // of the original computer, opcodes 1 to 27.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "full" => Some(Self::default()),
+            "classic" => Some(Self::supporting(|opcode| opcode < FIRST_NEW_OPCODE)),
+            _ => None
+        }
+    }
+
+    // Parses a profile listing the mnemonics of the supported instructions, separated by spaces or lines, e.g.
+    // `JUMP JMPIF SAVE LOAD CNST ADD SUB`. Anything after a `#` on a line is a comment.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut supported = HashSet::new();
+        for mnemonic in text.lines().flat_map(|line| line.split('#').next().unwrap_or_default().split_whitespace()) {
+            match Instruction::from_mnemonic(mnemonic) {
+                Some(instruction) => supported.insert(instruction.get_opcode()),
+                None => return Err(anyhow!("Unknown instruction {mnemonic}"))
+            };
+        }
+
+        Ok(Self::supporting(|opcode| supported.contains(&opcode)))
+    }
+
+    fn supporting(is_supported: impl Fn(i32) -> bool) -> Self {
+        Self {
+            unsupported: all_opcodes().filter(|opcode| !is_supported(*opcode)).collect()
+        }
+    }
+
+    pub fn supports(&self, instruction: Instruction) -> bool {
+        !self.unsupported.contains(&instruction.get_opcode())
+    }
+}
+
+// Sets with the same instructions give the same hash, whatever order they were added in.
+impl Hash for InstructionSet {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut unsupported: Vec<i32> = self.unsupported.iter().copied().collect();
+        unsupported.sort_unstable();
+        unsupported.hash(state);
+    }
+}
+
+fn all_opcodes() -> impl Iterator<Item = i32> {
+    (1..).map_while(|opcode| Instruction::from_opcode(opcode, 0).map(|_| opcode))
+}
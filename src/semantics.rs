@@ -0,0 +1,138 @@
+//! The arithmetic of the computer, which is done by factorio's arithmetic combinators on 32 bit signed integers.
+//! Both the constant folder and the emulator use these, so that an operator folded at compile time gives the same result as running it.
+
+// `a + b`. Like every combinator operation, this wraps on overflow, e.g. `2147483647 + 1` is `-2147483648`.
+pub fn fact_add(a: i32, b: i32) -> i32 {
+    a.wrapping_add(b)
+}
+
+// `a - b`, wrapping on overflow.
+pub fn fact_sub(a: i32, b: i32) -> i32 {
+    a.wrapping_sub(b)
+}
+
+// `a * b`, keeping the lowest 32 bits of the result.
+pub fn fact_mul(a: i32, b: i32) -> i32 {
+    a.wrapping_mul(b)
+}
+
+// `a / b`, rounded towards zero, e.g. `-7 / 2` is `-3`.
+// The combinator outputs 0 when dividing by zero, rather than stopping, and `-2147483648 / -1` wraps to `-2147483648`.
+pub fn fact_div(a: i32, b: i32) -> i32 {
+    if b == 0 { 0 } else { a.wrapping_div(b) }
+}
+
+// `a % b`, which has the sign of `a`, e.g. `-7 % 2` is `-1` and `7 % -2` is `1`.
+// The combinator outputs 0 when `b` is zero, and `-2147483648 % -1` is 0.
+pub fn fact_rem(a: i32, b: i32) -> i32 {
+    if b == 0 { 0 } else { a.wrapping_rem(b) }
+}
+
+// `a ^ b`, keeping the lowest 32 bits of the result, the same as multiplying `a` by itself `b` times.
+// This is calculated by squaring, so a huge exponent, e.g. `2 ^ 1000000` (which is 0), doesn't take any longer.
+// Any base to the power of 0 is 1, including `0 ^ 0`, and `-2147483648 ^ 2` wraps to 0.
+// What the combinator gives for a negative exponent isn't known, so a constant negative exponent is a compile error.
+// The emulator gives 0 for one calculated at runtime, including `1 ^ -1`, which may not match factorio.
+pub fn fact_pow(a: i32, b: i32) -> i32 {
+    u32::try_from(b).map_or(0, |b| a.wrapping_pow(b))
+}
+
+// `a << b`. Only the lowest 5 bits of `b` are used, e.g. `1 << 33` is `2`.
+// This isn't known to match the combinator for amounts outside 0 to 31, so the constant folder leaves these to runtime.
+pub fn fact_shl(a: i32, b: i32) -> i32 {
+    a.wrapping_shl(b as u32)
+}
+
+// `a >> b`, which copies the sign bit into the bits shifted in, e.g. `-8 >> 1` is `-4`. Only the lowest 5 bits of `b` are used.
+pub fn fact_shr(a: i32, b: i32) -> i32 {
+    a.wrapping_shr(b as u32)
+}
+
+// `-a`, done by multiplying by -1, or by the NEG instruction with `--optimize` if the computer has it. `-(-2147483648)` wraps to `-2147483648`.
+pub fn fact_neg(a: i32) -> i32 {
+    a.wrapping_neg()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Generates pseudo-random numbers with a linear congruential generator, so that the tests give the same values each run.
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> i32 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 32) as i32
+        }
+    }
+
+    // Multiplies `a` by itself `b` times.
+    fn repeated_multiply(a: i32, b: i32) -> i32 {
+        (0..b).fold(1, |result: i32, _| result.wrapping_mul(a))
+    }
+
+    #[test]
+    fn division_rounds_towards_zero() {
+        assert_eq!(fact_div(-7, 2), -3);
+        assert_eq!(fact_div(7, -2), -3);
+        assert_eq!(fact_div(7, 2), 3);
+    }
+
+    #[test]
+    fn remainder_has_sign_of_dividend() {
+        assert_eq!(fact_rem(-7, 2), -1);
+        assert_eq!(fact_rem(7, -2), 1);
+        assert_eq!(fact_rem(-7, -2), -1);
+    }
+
+    #[test]
+    fn division_by_zero_is_zero() {
+        for a in [0, 1, -7, i32::MAX, i32::MIN] {
+            assert_eq!(fact_div(a, 0), 0, "{a} / 0");
+            assert_eq!(fact_rem(a, 0), 0, "{a} % 0");
+        }
+    }
+
+    #[test]
+    fn minimum_divided_by_minus_one_wraps() {
+        assert_eq!(fact_div(i32::MIN, -1), i32::MIN);
+        assert_eq!(fact_rem(i32::MIN, -1), 0);
+    }
+
+    #[test]
+    fn power_matches_repeated_multiplication() {
+        let mut random = Lcg(1);
+        for _ in 0..300 {
+            let base = random.next();
+            let exponent = random.next().rem_euclid(100);
+            assert_eq!(fact_pow(base, exponent), repeated_multiply(base, exponent), "{base} ^ {exponent}");
+
+            // Small bases are more likely to have a result that doesn't wrap to 0.
+            let small_base = base % 10;
+            assert_eq!(fact_pow(small_base, exponent), repeated_multiply(small_base, exponent), "{small_base} ^ {exponent}");
+        }
+    }
+
+    #[test]
+    fn power_of_zero_is_one() {
+        assert_eq!(fact_pow(0, 0), 1);
+        for base in [1, -1, 7, i32::MAX, i32::MIN] {
+            assert_eq!(fact_pow(base, 0), 1, "{base} ^ 0");
+        }
+    }
+
+    #[test]
+    fn negative_exponent_is_zero() {
+        assert_eq!(fact_pow(0, -1), 0);
+        assert_eq!(fact_pow(1, -1), 0);
+        assert_eq!(fact_pow(2, i32::MIN), 0);
+    }
+
+    #[test]
+    fn power_of_minimum_wraps() {
+        assert_eq!(fact_pow(i32::MIN, 1), i32::MIN);
+        assert_eq!(fact_pow(i32::MIN, 2), 0);
+        assert_eq!(fact_pow(i32::MIN, 3), 0);
+    }
+}
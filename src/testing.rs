@@ -0,0 +1,65 @@
+//! Helpers for the unit tests, which compile programs from source and run them on the emulator.
+
+use std::sync::Arc;
+
+use crate::{assembler, compiler::{CompileOptions, CompiledModule, FunctionCache}, emulator::{self, EmulatorOptions, SignalState}, error_handling::{CompileResult, SourceFile, Warning}};
+
+// Compiles a program held in memory, giving the errors if it fails.
+pub fn try_compile(source: &str, options: &CompileOptions) -> CompileResult<CompiledModule> {
+    let source = SourceFile::from_text("test.lfl".to_owned(), source.to_owned());
+    crate::compile(Arc::new(source), options, &mut Vec::new())
+}
+
+// Compiles a program held in memory with a cache of the functions compiled by earlier builds, as with `--watch`.
+pub fn try_compile_cached(source: &str, options: &CompileOptions, cache: &mut FunctionCache) -> CompileResult<CompiledModule> {
+    let source = SourceFile::from_text("test.lfl".to_owned(), source.to_owned());
+    crate::compile_with_objects(Arc::new(source), Vec::new(), options, Some(cache), &mut Vec::new(), &mut Vec::new())
+}
+
+// Compiles a program held in memory, linked with a file of functions written in assembly, giving the errors if either fails.
+pub fn try_compile_with_assembly(source: &str, assembly: &str, options: &CompileOptions) -> CompileResult<CompiledModule> {
+    let assembly = SourceFile::from_text("test.asm".to_owned(), assembly.to_owned());
+    let object = assembler::assemble_object(Arc::new(assembly), options)?;
+
+    let source = SourceFile::from_text("test.lfl".to_owned(), source.to_owned());
+    crate::compile_with_objects(Arc::new(source), vec![object], options, None, &mut Vec::new(), &mut Vec::new())
+}
+
+// Compiles a program, panicking with the errors if it fails.
+pub fn compile(source: &str, options: &CompileOptions) -> CompiledModule {
+    match try_compile(source, options) {
+        Ok(module) => module,
+        Err(err) => panic!("{err}")
+    }
+}
+
+// Compiles a program, panicking with the errors if it fails, and gives the warnings reported.
+pub fn compile_warnings(source: &str, options: &CompileOptions) -> Vec<Warning> {
+    let source = SourceFile::from_text("test.lfl".to_owned(), source.to_owned());
+    let mut warnings = Vec::new();
+    if let Err(err) = crate::compile(Arc::new(source), options, &mut warnings) {
+        panic!("{err}");
+    }
+
+    warnings
+}
+
+// Runs a compiled program with the given input signals, and returns the output signals.
+// The emulator uses the calling convention and signals the program was compiled with.
+pub fn run(module: &CompiledModule, inputs: &[i32], options: &CompileOptions) -> Vec<i32> {
+    let mut signals = SignalState::new(options.signal_count);
+    signals.inputs[..inputs.len()].copy_from_slice(inputs);
+
+    let emulator_options = EmulatorOptions {
+        call_convention: options.call_convention,
+        signal_count: options.signal_count,
+        tick_signal: module.uses_ticks.then_some(options.tick_signal),
+        ..EmulatorOptions::default()
+    };
+    let result = emulator::run(&module.instructions, &mut signals, &emulator_options);
+    if !result.halted() {
+        panic!("{}", result.status);
+    }
+
+    result.outputs
+}
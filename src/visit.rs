@@ -0,0 +1,314 @@
+//! Traversal of the abstract syntax tree.
+//!
+//! Implement `Visitor` (or `VisitorMut` for passes that rewrite the tree) and override the methods
+//! for the nodes of interest. The default implementation of each method calls the matching `walk_*`
+//! function, which visits the children of that node, so an overriding method should call it too
+//! if it wants to keep descending.
+//!
+//! Stability: methods may be added to the traits as the language grows, but always with a default
+//! implementation, so existing visitors keep compiling. New kinds of statement and expression are
+//! walked by the `walk_*` functions without any change to existing visitors.
+
+use crate::ast::{Item, Function, Global, Constant, Statement, Expression, Call};
+
+pub trait Visitor<'ast> {
+    fn visit_function(&mut self, function: &'ast Function) {
+        walk_function(self, function);
+    }
+
+    fn visit_global(&mut self, global: &'ast Global) {
+        walk_global(self, global);
+    }
+
+    fn visit_constant(&mut self, constant: &'ast Constant) {
+        walk_constant(self, constant);
+    }
+
+    fn visit_block(&mut self, block: &'ast [Statement]) {
+        walk_block(self, block);
+    }
+
+    fn visit_statement(&mut self, statement: &'ast Statement) {
+        walk_statement(self, statement);
+    }
+
+    fn visit_expression(&mut self, expr: &'ast Expression) {
+        walk_expression(self, expr);
+    }
+
+    fn visit_call(&mut self, call: &'ast Call) {
+        walk_call(self, call);
+    }
+}
+
+// Visits each function and global in a module.
+pub fn walk_module<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, module: &'ast [Item]) {
+    for item in module {
+        match item {
+            Item::Function(function) => visitor.visit_function(function),
+            Item::Global(global) => visitor.visit_global(global),
+            Item::Constant(constant) => visitor.visit_constant(constant)
+        }
+    }
+}
+
+pub fn walk_global<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, global: &'ast Global) {
+    if let Some(initializer) = &global.initializer {
+        visitor.visit_expression(initializer);
+    }
+}
+
+pub fn walk_constant<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, constant: &'ast Constant) {
+    visitor.visit_expression(&constant.value);
+}
+
+pub fn walk_function<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, function: &'ast Function) {
+    visitor.visit_block(&function.block);
+}
+
+pub fn walk_block<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, block: &'ast [Statement]) {
+    for statement in block {
+        visitor.visit_statement(statement);
+    }
+}
+
+pub fn walk_statement<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, statement: &'ast Statement) {
+    match statement {
+        Statement::Assignment { value, .. } => visitor.visit_expression(value),
+        Statement::If { segments, r#else } => {
+            for segment in segments {
+                visitor.visit_expression(&segment.condition);
+                visitor.visit_block(&segment.block);
+            }
+
+            if let Some(else_block) = r#else {
+                visitor.visit_block(else_block);
+            }
+        },
+        Statement::While { condition, block, .. } => {
+            visitor.visit_expression(condition);
+            visitor.visit_block(block);
+        },
+        Statement::Block(block) => visitor.visit_block(block),
+        Statement::Call(call) => visitor.visit_call(call),
+        Statement::ReturnValue { value, .. } | Statement::Expression { value, .. } => visitor.visit_expression(value),
+        Statement::Return(_) | Statement::Continue(_) | Statement::Break(_) => {}
+    }
+}
+
+pub fn walk_expression<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, expr: &'ast Expression) {
+    match expr {
+        Expression::Binary { left, right, .. } => {
+            visitor.visit_expression(left);
+            visitor.visit_expression(right);
+        },
+        Expression::Unary { value, .. } => visitor.visit_expression(value),
+        Expression::Call(call) => visitor.visit_call(call),
+        Expression::Variable { .. } | Expression::Literal(_) => {}
+    }
+}
+
+pub fn walk_call<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, call: &'ast Call) {
+    for argument in &call.arguments {
+        visitor.visit_expression(argument);
+    }
+}
+
+// Equivalent of `Visitor` that allows the tree to be modified while it is traversed.
+pub trait VisitorMut {
+    fn visit_function_mut(&mut self, function: &mut Function) {
+        walk_function_mut(self, function);
+    }
+
+    fn visit_global_mut(&mut self, global: &mut Global) {
+        walk_global_mut(self, global);
+    }
+
+    fn visit_constant_mut(&mut self, constant: &mut Constant) {
+        walk_constant_mut(self, constant);
+    }
+
+    fn visit_block_mut(&mut self, block: &mut Vec<Statement>) {
+        walk_block_mut(self, block);
+    }
+
+    fn visit_statement_mut(&mut self, statement: &mut Statement) {
+        walk_statement_mut(self, statement);
+    }
+
+    fn visit_expression_mut(&mut self, expr: &mut Expression) {
+        walk_expression_mut(self, expr);
+    }
+
+    fn visit_call_mut(&mut self, call: &mut Call) {
+        walk_call_mut(self, call);
+    }
+}
+
+pub fn walk_module_mut<V: VisitorMut + ?Sized>(visitor: &mut V, module: &mut [Item]) {
+    for item in module {
+        match item {
+            Item::Function(function) => visitor.visit_function_mut(function),
+            Item::Global(global) => visitor.visit_global_mut(global),
+            Item::Constant(constant) => visitor.visit_constant_mut(constant)
+        }
+    }
+}
+
+pub fn walk_global_mut<V: VisitorMut + ?Sized>(visitor: &mut V, global: &mut Global) {
+    if let Some(initializer) = &mut global.initializer {
+        visitor.visit_expression_mut(initializer);
+    }
+}
+
+pub fn walk_constant_mut<V: VisitorMut + ?Sized>(visitor: &mut V, constant: &mut Constant) {
+    visitor.visit_expression_mut(&mut constant.value);
+}
+
+pub fn walk_function_mut<V: VisitorMut + ?Sized>(visitor: &mut V, function: &mut Function) {
+    visitor.visit_block_mut(&mut function.block);
+}
+
+// Blocks are passed as a `Vec` so that passes can add or remove statements.
+pub fn walk_block_mut<V: VisitorMut + ?Sized>(visitor: &mut V, block: &mut Vec<Statement>) {
+    for statement in block {
+        visitor.visit_statement_mut(statement);
+    }
+}
+
+pub fn walk_statement_mut<V: VisitorMut + ?Sized>(visitor: &mut V, statement: &mut Statement) {
+    match statement {
+        Statement::Assignment { value, .. } => visitor.visit_expression_mut(value),
+        Statement::If { segments, r#else } => {
+            for segment in segments {
+                visitor.visit_expression_mut(&mut segment.condition);
+                visitor.visit_block_mut(&mut segment.block);
+            }
+
+            if let Some(else_block) = r#else {
+                visitor.visit_block_mut(else_block);
+            }
+        },
+        Statement::While { condition, block, .. } => {
+            visitor.visit_expression_mut(condition);
+            visitor.visit_block_mut(block);
+        },
+        Statement::Block(block) => visitor.visit_block_mut(block),
+        Statement::Call(call) => visitor.visit_call_mut(call),
+        Statement::ReturnValue { value, .. } | Statement::Expression { value, .. } => visitor.visit_expression_mut(value),
+        Statement::Return(_) | Statement::Continue(_) | Statement::Break(_) => {}
+    }
+}
+
+pub fn walk_expression_mut<V: VisitorMut + ?Sized>(visitor: &mut V, expr: &mut Expression) {
+    match expr {
+        Expression::Binary { left, right, .. } => {
+            visitor.visit_expression_mut(left);
+            visitor.visit_expression_mut(right);
+        },
+        Expression::Unary { value, .. } => visitor.visit_expression_mut(value),
+        Expression::Call(call) => visitor.visit_call_mut(call),
+        Expression::Variable { .. } | Expression::Literal(_) => {}
+    }
+}
+
+pub fn walk_call_mut<V: VisitorMut + ?Sized>(visitor: &mut V, call: &mut Call) {
+    for argument in &mut call.arguments {
+        visitor.visit_expression_mut(argument);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{error_handling::SourceFile, lexer, parser::{self, TokenIterator}};
+
+    fn parse(source: &str) -> Vec<Item> {
+        let source = SourceFile::from_text("test.lfl".to_owned(), source.to_owned());
+        let tokens = lexer::tokenize(Arc::new(source)).unwrap();
+        parser::parse_module(&mut TokenIterator::new(tokens)).unwrap().0
+    }
+
+    // Records the nodes visited, in the order they are visited.
+    #[derive(Default)]
+    struct Recorder {
+        functions: Vec<String>,
+        statements: usize,
+        variables: Vec<String>,
+        literals: Vec<i32>,
+        calls: Vec<String>
+    }
+
+    impl <'ast> Visitor<'ast> for Recorder {
+        fn visit_function(&mut self, function: &'ast Function) {
+            self.functions.push(function.name.clone());
+            walk_function(self, function);
+        }
+
+        fn visit_statement(&mut self, statement: &'ast Statement) {
+            self.statements += 1;
+            walk_statement(self, statement);
+        }
+
+        fn visit_expression(&mut self, expr: &'ast Expression) {
+            match expr {
+                Expression::Variable { name, .. } => self.variables.push(name.clone()),
+                Expression::Literal(value) => self.literals.push(*value),
+                _ => {}
+            }
+            walk_expression(self, expr);
+        }
+
+        fn visit_call(&mut self, call: &'ast Call) {
+            self.calls.push(call.function_name.clone());
+            walk_call(self, call);
+        }
+    }
+
+    #[test]
+    fn visitor_sees_every_node() {
+        let module = parse("const LIMIT = 10;
+            global total = 1;
+            int twice(x) { return x * 2; }
+            void main(n) {
+                while n < LIMIT {
+                    if n == 3 { continue; } else { total += twice(n); }
+                    { log(-n); }
+                }
+                (twice(4) + 5);
+            }");
+        let mut recorder = Recorder::default();
+        walk_module(&mut recorder, &module);
+
+        assert_eq!(recorder.functions, ["twice", "main"]);
+        // return, while, if, continue, assignment, block, log, and the discarded expression.
+        assert_eq!(recorder.statements, 8);
+        assert_eq!(recorder.variables, ["x", "n", "LIMIT", "n", "total", "n", "n"]);
+        assert_eq!(recorder.literals, [10, 1, 2, 3, 4, 5]);
+        assert_eq!(recorder.calls, ["twice", "log", "twice"]);
+    }
+
+    // Replaces every literal with its double.
+    struct DoubleLiterals;
+
+    impl VisitorMut for DoubleLiterals {
+        fn visit_expression_mut(&mut self, expr: &mut Expression) {
+            if let Expression::Literal(value) = expr {
+                *value *= 2;
+            }
+            walk_expression_mut(self, expr);
+        }
+    }
+
+    #[test]
+    fn mutable_visitor_rewrites_nested_expressions() {
+        let mut module = parse("global g = 1; void main() { if f(2) { signal_1 = -(3 + g); } }");
+        walk_module_mut(&mut DoubleLiterals, &mut module);
+
+        let mut recorder = Recorder::default();
+        walk_module(&mut recorder, &module);
+        assert_eq!(recorder.literals, [2, 4, 6]);
+    }
+}
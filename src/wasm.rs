@@ -0,0 +1,13 @@
+//! Bindings for compiling programs from javascript, e.g. in a browser playground, built with the `wasm` feature.
+
+use wasm_bindgen::prelude::*;
+
+// The path given to the source code in diagnostics, since it isn't read from a file.
+const SOURCE_NAME: &str = "<playground>";
+
+// Compiles a program with the default options into the blueprint string for its ROM.
+// On failure, gives a JSON array of the errors, in the form written by `--error-format json`.
+#[wasm_bindgen]
+pub fn compile_to_blueprint(source: &str) -> Result<String, JsValue> {
+    crate::compile_or_diagnose(SOURCE_NAME, source).map_err(|diagnostics| JsValue::from_str(&diagnostics))
+}
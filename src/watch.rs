@@ -0,0 +1,195 @@
+//! Rebuilding a program each time one of its files is saved, for `--watch`.
+
+use std::{collections::HashSet, io::{self, IsTerminal}, path::{Path, PathBuf}, process::ExitCode, sync::mpsc,
+    time::{Duration, Instant}};
+
+use lflc::compiler::FunctionCache;
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::report_error;
+
+// How long to wait after a change for any others, so that an editor saving a file in several steps, or several files
+// being saved at once, only causes one rebuild.
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(200);
+
+enum WatchEvent {
+    // Files were created, written, renamed or removed.
+    Changed(Vec<PathBuf>),
+    Failed(notify::Error),
+    // Ctrl-C was pressed.
+    Stop
+}
+
+// Decides when to rebuild after a series of changes: once none have been seen for the delay.
+pub struct Debouncer {
+    delay: Duration,
+    last_change: Option<Instant>
+}
+
+impl Debouncer {
+    pub fn new(delay: Duration) -> Self {
+        Self { delay, last_change: None }
+    }
+
+    // Records a change to one of the watched files.
+    pub fn change(&mut self, now: Instant) {
+        self.last_change = Some(now);
+    }
+
+    // Gets how long to wait for another event before rebuilding, or None if nothing has changed since the last build.
+    pub fn timeout(&self, now: Instant) -> Option<Duration> {
+        self.last_change.map(|last_change| (last_change + self.delay).saturating_duration_since(now))
+    }
+
+    // Checks if it is time to rebuild, in which case the changes are cleared.
+    pub fn ready(&mut self, now: Instant) -> bool {
+        match self.last_change {
+            Some(last_change) if now >= last_change + self.delay => {
+                self.last_change = None;
+                true
+            },
+            _ => false
+        }
+    }
+}
+
+// The files of the program, and the directories being watched for changes to them.
+// Directories are watched rather than files, since many editors save a file by replacing it with a new one.
+struct WatchedFiles {
+    files: HashSet<PathBuf>,
+    directories: HashSet<PathBuf>
+}
+
+impl WatchedFiles {
+    // Watches the file at `path` and the files it imports, which may have changed since they were last found.
+    fn update(&mut self, path: &str, watcher: &mut impl Watcher) {
+        self.files = lflc::modules::program_files(path).into_iter().collect();
+
+        let directories: HashSet<PathBuf> = self.files.iter()
+            .filter_map(|file| file.parent())
+            .map(|directory| if directory.as_os_str().is_empty() { Path::new(".") } else { directory })
+            .map(Path::to_path_buf)
+            .collect();
+
+        for directory in self.directories.difference(&directories) {
+            let _ = watcher.unwatch(directory);
+        }
+
+        for directory in directories.difference(&self.directories) {
+            if let Err(err) = watcher.watch(directory, RecursiveMode::NonRecursive) {
+                eprintln!("error: Failed to watch {} for changes: {err}", directory.display());
+            }
+        }
+
+        self.directories = directories;
+    }
+}
+
+// Runs `build`, and then runs it again each time the file at `path`, or a file it imports, changes, until Ctrl-C is
+// pressed. Errors are printed by `build`, and don't stop the watching.
+// Each build is given the cache of compiled functions, so that only the functions which have changed are compiled again.
+pub fn watch(path: &str, mut build: impl FnMut(&mut FunctionCache) -> ExitCode) -> ExitCode {
+    let (sender, receiver) = mpsc::channel();
+
+    let stop_sender = sender.clone();
+    if let Err(err) = ctrlc::set_handler(move || { let _ = stop_sender.send(WatchEvent::Stop); }) {
+        return report_error(format!("Failed to handle Ctrl-C: {err}"), ExitCode::FAILURE);
+    }
+
+    let watcher = notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+        let event = match result {
+            Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)) => WatchEvent::Changed(event.paths),
+            Ok(_) => return, // Files being read
+            Err(err) => WatchEvent::Failed(err)
+        };
+        let _ = sender.send(event);
+    });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(err) => return report_error(format!("Failed to watch for changes: {err}"), ExitCode::FAILURE)
+    };
+
+    let mut watched = WatchedFiles { files: HashSet::new(), directories: HashSet::new() };
+    let mut debouncer = Debouncer::new(DEBOUNCE_DELAY);
+    let mut cache = FunctionCache::default();
+    let mut first_build = true;
+    loop {
+        // The screen is cleared so that only the output of the latest build is shown.
+        if !first_build && io::stderr().is_terminal() {
+            eprint!("\x1B[2J\x1B[H");
+        }
+
+        // Invalid arguments would be invalid every time, so there is no point watching.
+        let status = build(&mut cache);
+        if first_build && status == ExitCode::from(crate::USAGE_ERROR) {
+            return status;
+        }
+        first_build = false;
+
+        watched.update(path, &mut watcher);
+        let plural = if watched.files.len() == 1 { "" } else { "s" };
+        eprintln!("Watching {} file{plural} for changes, press Ctrl-C to stop", watched.files.len());
+
+        loop {
+            let event = match debouncer.timeout(Instant::now()) {
+                Some(timeout) => receiver.recv_timeout(timeout).ok(),
+                None => receiver.recv().ok()
+            };
+
+            match event {
+                Some(WatchEvent::Changed(paths)) if paths.iter().any(|path| watched.files.contains(path)) => {
+                    debouncer.change(Instant::now());
+                },
+                Some(WatchEvent::Failed(err)) => eprintln!("error: Failed to watch for changes: {err}"),
+                Some(WatchEvent::Stop) => {
+                    eprintln!("Stopped watching");
+                    return ExitCode::SUCCESS;
+                },
+                Some(WatchEvent::Changed(_)) | None => {}
+            }
+
+            if debouncer.ready(Instant::now()) {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_of_changes_gives_one_rebuild() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(DEBOUNCE_DELAY);
+        assert_eq!(debouncer.timeout(start), None);
+
+        // Each change restarts the delay, so nothing is built until the last one has settled.
+        let mut rebuilds = 0;
+        for millis in [0, 50, 120, 250, 400] {
+            let now = start + Duration::from_millis(millis);
+            debouncer.change(now);
+            rebuilds += debouncer.ready(now) as i32;
+        }
+        assert_eq!(debouncer.timeout(start + Duration::from_millis(500)), Some(Duration::from_millis(100)));
+        assert!(!debouncer.ready(start + Duration::from_millis(599)));
+
+        let settled = start + Duration::from_millis(600);
+        rebuilds += debouncer.ready(settled) as i32;
+        assert_eq!(rebuilds, 1);
+    }
+
+    #[test]
+    fn ready_clears_changes() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(DEBOUNCE_DELAY);
+        debouncer.change(start);
+
+        let settled = start + DEBOUNCE_DELAY;
+        assert_eq!(debouncer.timeout(settled), Some(Duration::ZERO));
+        assert!(debouncer.ready(settled));
+        assert_eq!(debouncer.timeout(settled), None);
+        assert!(!debouncer.ready(settled + DEBOUNCE_DELAY));
+    }
+}
@@ -0,0 +1,61 @@
+//! Uses the compiler through the public API of the library, as another rust program would.
+
+use lflc::{assembly::Instruction, blueprint::{BlueprintFormat, RomLayout}};
+
+#[test]
+fn compiles_source_held_in_memory() {
+    let instructions = lflc::compile_source("snippet.lfl", "void main() { write_signal(1, 42); }").unwrap();
+    assert_eq!(instructions, [
+        Instruction::JumpSubRoutine(3),
+        Instruction::Halt,
+        Instruction::Constant(42),
+        Instruction::Save(-1),
+        Instruction::Return
+    ]);
+}
+
+#[test]
+fn compile_errors_name_the_source() {
+    let err = lflc::compile_source("snippet.lfl", "void main() { signal_1 = x; }").unwrap_err();
+    let messages: Vec<String> = err.iter().map(|error| error.render(lflc::error_handling::Palette::PLAIN)).collect();
+
+    assert_eq!(messages.len(), 1);
+    assert!(messages[0].contains("snippet.lfl"));
+    assert!(messages[0].contains("No variable exists with this name"));
+}
+
+#[test]
+fn blueprint_string_round_trips() {
+    let instructions = lflc::compile_source("snippet.lfl", "void main(n) { total = 0; while n > 0 { total += n; n -= 1; } signal_1 = total; }").unwrap();
+
+    for format in [BlueprintFormat::V1_1, BlueprintFormat::V2_0] {
+        let blueprint = lflc::instructions_to_blueprint_string(&instructions, format, RomLayout::default()).unwrap();
+        assert_eq!(lflc::blueprint_string_to_instructions(&blueprint).unwrap(), instructions);
+    }
+}
+
+#[test]
+fn invalid_blueprint_string_is_an_error() {
+    assert!(lflc::blueprint_string_to_instructions("0not a blueprint").is_err());
+}
+
+#[test]
+fn compile_or_diagnose_gives_blueprint() {
+    let blueprint = lflc::compile_or_diagnose("<playground>", "void main() { signal_1 = 42; }").unwrap();
+    let instructions = lflc::blueprint_string_to_instructions(&blueprint).unwrap();
+    assert_eq!(instructions, lflc::compile_source("<playground>", "void main() { signal_1 = 42; }").unwrap());
+}
+
+#[test]
+fn compile_or_diagnose_gives_json_errors() {
+    let diagnostics = lflc::compile_or_diagnose("<playground>", "void main() {\n    signal_1 = x;\n}").unwrap_err();
+    let diagnostics: serde_json::Value = serde_json::from_str(&diagnostics).unwrap();
+
+    let diagnostics = diagnostics.as_array().unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0]["severity"], "error");
+    assert_eq!(diagnostics[0]["message"], "No variable exists with this name");
+    assert_eq!(diagnostics[0]["file"], "<playground>");
+    assert_eq!(diagnostics[0]["start_line"], 2);
+    assert_eq!(diagnostics[0]["start_col"], 16);
+}
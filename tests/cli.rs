@@ -0,0 +1,259 @@
+//! Runs the `lflc` binary to check its exit statuses and the messages it prints.
+
+use std::{fs, path::PathBuf, process::{Command, Output}};
+
+// Runs `lflc` with the given arguments.
+fn lflc(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_lflc"))
+        .args(args)
+        .output()
+        .expect("Failed to run lflc")
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+// Writes a file to the directory cargo provides for the files of integration tests, and gives its path.
+fn write_file(name: &str, contents: &str) -> String {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::write(&path, contents).expect("Failed to write test file");
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn successful_compile_exits_with_zero() {
+    let path = write_file("success.lfl", "void main() { signal_1 = 3; }");
+    let output = lflc(&["compile", &path]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(&output).starts_with('0'));
+}
+
+#[test]
+fn compile_error_exits_with_one() {
+    let path = write_file("compile_error.lfl", "void main() { signal_1 = x; }");
+    let output = lflc(&["compile", &path]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("No variable exists with this name"));
+}
+
+#[test]
+fn unknown_option_is_a_usage_error() {
+    let path = write_file("unknown_option.lfl", "void main() { }");
+    let output = lflc(&["--bogus", &path]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("--bogus"));
+}
+
+#[test]
+fn missing_path_is_a_usage_error() {
+    let output = lflc(&["compile"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("Expected file path"));
+}
+
+#[test]
+fn missing_file_exits_with_io_error() {
+    let output = lflc(&["compile", "this-file-does-not-exist.lfl"]);
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn bare_path_with_assembly_flag_still_works() {
+    let path = write_file("legacy.lfl", "void main() { signal_1 = 3; }");
+    let output = lflc(&[&path, "--assembly"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(&output).contains("CNST 3"));
+}
+
+#[test]
+fn emulate_prints_outputs() {
+    let path = write_file("emulate.lfl", "void main(n) { signal_1 = n * 2; }");
+    let output = lflc(&["emulate", &path, "--input", "1=21"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(&output).contains("signal_1 = 42"));
+}
+
+#[test]
+fn emulate_links_assembly_functions() {
+    let program = write_file("calls_asm.lfl", "void main(n) { signal_1 = triple(n); }");
+    let assembly = write_file("triple.asm", ".func triple args=1 returns=1\nCNST 3\nLOAD 3\nMUL\nSAVE 4\nRET\n");
+    let output = lflc(&["emulate", &program, &assembly, "--input", "1=7"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(stdout(&output).contains("signal_1 = 21"));
+
+    // Calls are checked against the directive.
+    let wrong_arity = write_file("calls_asm_wrongly.lfl", "void main(n) { signal_1 = triple(n, n); }");
+    let output = lflc(&["compile", &wrong_arity, &assembly]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Wrong number of arguments, expected 1, got 2"));
+    assert!(stderr(&output).contains("triple.asm:1"));
+
+    assert_eq!(lflc(&["compile", &program, &program]).status.code(), Some(2));
+}
+
+#[test]
+fn dep_file_lists_import_chain() {
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("dep file");
+    fs::create_dir_all(&directory).unwrap();
+    let main = directory.join("main.lfl");
+    fs::write(&main, "import \"a.lfl\";\nvoid main() { signal_1 = a(); }").unwrap();
+    fs::write(directory.join("a.lfl"), "import \"b.lfl\";\nint a() { return b() + 1; }").unwrap();
+    fs::write(directory.join("b.lfl"), "int b() { return 2; }").unwrap();
+
+    let [main, a, b, output, dep_file] = ["main.lfl", "a.lfl", "b.lfl", "out.txt", "out.d"]
+        .map(|name| directory.join(name).to_string_lossy().into_owned());
+    let escaped = |path: &str| path.replace(' ', "\\ ");
+
+    let status = lflc(&["compile", &main, "-o", &output, "--dep-file", &dep_file]).status;
+    assert_eq!(status.code(), Some(0));
+    assert_eq!(fs::read_to_string(&dep_file).unwrap(), format!("{}: {} {} {}\n", escaped(&output), escaped(&main), escaped(&a), escaped(&b)));
+
+    let status = lflc(&["compile", &main, "-o", &output, "--dep-file", &dep_file, "--dep-format", "json"]).status;
+    assert_eq!(status.code(), Some(0));
+    let dependencies: serde_json::Value = serde_json::from_str(&fs::read_to_string(&dep_file).unwrap()).unwrap();
+    assert_eq!(dependencies, serde_json::json!({ "target": output, "dependencies": [main, a, b] }));
+
+    // A file that fails to parse is still listed, so that fixing it causes a rebuild.
+    fs::write(directory.join("b.lfl"), "int b( {").unwrap();
+    let status = lflc(&["compile", &main, "-o", &output, "--dep-file", &dep_file]).status;
+    assert_eq!(status.code(), Some(1));
+    assert!(fs::read_to_string(&dep_file).unwrap().ends_with(&format!(" {}\n", escaped(&b))));
+
+    assert_eq!(lflc(&["compile", &main, "--dep-file", &dep_file]).status.code(), Some(2));
+}
+
+#[test]
+fn dump_after_writes_file_for_each_pass() {
+    let path = write_file("dump.lfl", "int twice(x) { return x * 2; }\nvoid main(n) { signal_1 = twice(n); }");
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("dumps");
+    let _ = fs::remove_dir_all(&directory);
+
+    let output = lflc(&["compile", &path, "--optimize", "--dump-after=tco", "--dump-after", "inlining", "--dump-dir", &directory.to_string_lossy(), "--assembly"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+
+    let mut names: Vec<String> = fs::read_dir(&directory).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["1-tco.asm", "2-inlining.asm"]);
+
+    // Inlining replaces the call to `twice` with its body.
+    let tco = fs::read_to_string(directory.join("1-tco.asm")).unwrap();
+    let inlining = fs::read_to_string(directory.join("2-inlining.asm")).unwrap();
+    assert_ne!(tco, inlining);
+    assert!(tco.starts_with("; after tco: ") && tco.contains("; function twice\n") && tco.contains(": JSR 0\n"), "{tco}");
+    assert!(inlining.starts_with("; after inlining: ") && !inlining.contains(": JSR"), "{inlining}");
+
+    let output = lflc(&["compile", &path, "--dump-after", "inline"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("Unknown pass inline, expected `all` or one of folding, tco, inlining, dce, fold-identical-functions, optimize"));
+}
+
+#[test]
+fn eval_prints_value() {
+    let output = lflc(&["eval", "(-7 % 3) * 2"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "-2\n");
+
+    // An expression starting with a minus sign isn't taken for an option.
+    assert_eq!(stdout(&lflc(&["eval", "-7 / 2"])), "-3\n");
+    assert_eq!(stdout(&lflc(&["eval", "signal_1 * 2 + signal_2", "--signal", "1=21", "--signal", "2=-1"])), "41\n");
+}
+
+#[test]
+fn eval_errors_point_into_expression() {
+    let output = lflc(&["eval", "1 + y", "--color", "never"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("at <expression>:1:\n\n-> 1 + y\n->     ^ No variable exists with this name"), "{}", stderr(&output));
+
+    assert_eq!(lflc(&["eval"]).status.code(), Some(2));
+    assert_eq!(lflc(&["eval", "1", "--signal", "9=1"]).status.code(), Some(2));
+}
+
+#[test]
+fn emulate_json_output() {
+    let path = write_file("emulate_json.lfl", "void main(n) { signal_1 = n * 2; signal_2 = n; }");
+    let output = lflc(&["emulate", &path, "--input", "1=21", "--output-format", "json"]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let result: serde_json::Value = serde_json::from_str(&stdout(&output)).expect("Output wasn't JSON");
+    assert_eq!(result["version"], 1);
+    assert_eq!(result["status"]["kind"], "halted");
+    assert_eq!(result["outputs"][0], 42);
+    assert_eq!(result["outputs"][1], 21);
+
+    // A program that doesn't halt still prints its result, but fails.
+    let path = write_file("emulate_json_loop.lfl", "void main() { while 1 { signal_1 += 1; } }");
+    let output = lflc(&["emulate", &path, "--max-steps", "50", "--output-format", "json"]);
+    assert_eq!(output.status.code(), Some(1));
+    let result: serde_json::Value = serde_json::from_str(&stdout(&output)).expect("Output wasn't JSON");
+    assert_eq!(result["status"]["kind"], "step-limit");
+    assert_eq!(result["steps"], 50);
+
+    assert_eq!(lflc(&["emulate", &path, "--output-format", "xml"]).status.code(), Some(2));
+}
+
+#[test]
+fn disassembled_blueprint_assembles_again() {
+    let source = write_file("round_trip.lfl", "void main(n) { while n > 0 { signal_1 = n; n -= 1; } }");
+    let assembly = stdout(&lflc(&[&source, "--assembly"]));
+
+    let blueprint = write_file("round_trip.txt", &stdout(&lflc(&["compile", &source])));
+    let disassembled = lflc(&["disasm", &blueprint]);
+    assert_eq!(disassembled.status.code(), Some(0));
+
+    // `--assembly` also marks the start of each function, which can't be stored in the blueprint.
+    let without_comments: String = assembly.lines().filter(|line| !line.starts_with(';')).map(|line| format!("{line}\n")).collect();
+    assert_eq!(stdout(&disassembled), without_comments);
+
+    let assembly_path = write_file("round_trip.asm", &stdout(&disassembled));
+    let reassembled = lflc(&["asm", &assembly_path]);
+    assert_eq!(reassembled.status.code(), Some(0));
+    assert_eq!(stdout(&reassembled), stdout(&lflc(&["compile", &source])));
+}
+
+#[test]
+fn denied_warning_fails_the_build() {
+    let output = lflc(&["-D", "unused-variable", "-e", "void main() { x = 1; }"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("unused-variable"));
+}
+
+#[test]
+fn unknown_denied_warning_is_a_usage_error() {
+    let output = lflc(&["-D", "unsused-variable", "-e", "void main() { }"]);
+    assert_eq!(output.status.code(), Some(2));
+
+    let stderr = stderr(&output);
+    assert!(stderr.contains("Unknown warning unsused-variable"));
+    assert!(stderr.contains("unused-variable"));
+}
+
+#[test]
+fn disassembles_rom_within_book() {
+    let source = write_file("book.lfl", "void main() { signal_1 = 3; }");
+    let book = write_file("book.txt", &stdout(&lflc(&["compile", &source, "--book", "--ram", "16"])));
+
+    let output = lflc(&["disasm", &book]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "1: JSR 3\n2: HLT\n3: CNST 3\n4: SAVE -1\n5: RET\n");
+}
+
+#[test]
+fn oversized_program_lists_functions_largest_first() {
+    let path = write_file("oversized.lfl", "int weighted(a, b, c) { return a * 3 + b * 2 + c; }
+void main(x) { signal_1 = weighted(x, x + 1, x + 2); }");
+    let output = lflc(&["compile", &path, "--max-program-size", "10"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).ends_with("error: The program is 28 instructions, but the computer can only address 10
+  main: 14 instructions, addresses 15 to 28
+  weighted: 11 instructions, addresses 4 to 14
+  (startup): 3 instructions, addresses 1 to 3
+"), "{}", stderr(&output));
+    assert!(stdout(&output).is_empty());
+}